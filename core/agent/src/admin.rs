@@ -0,0 +1,192 @@
+use crate::bo::state::ServerState;
+use crate::reload::ConfigReloader;
+use crate::stats::ClientTraffic;
+use crate::transport::ProxyAddress;
+use chrono::{DateTime, Utc};
+use ppaass_codec::RsaCryptoReloadReport;
+use ppaass_common::admin::{serve_admin, AdminAddress};
+use ppaass_common::tunnel_registry::TunnelSnapshot;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::{error, info};
+/// The request to the admin endpoint of the agent
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum AdminRequest {
+    Status,
+    DrainPool,
+    RefillPool,
+    SwitchProxyGroup { group: Option<String> },
+    ReloadConfig,
+}
+/// The status of a proxy seen by the agent
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProxyStatus {
+    pub proxy_address: ProxyAddress,
+    pub pool_size: usize,
+    pub ping_count: u64,
+    pub pong_count: u64,
+    pub loss_rate: f64,
+    pub last_rtt_millis: Option<u128>,
+    pub ewma_rtt_millis: Option<u128>,
+    pub jitter_millis: Option<u128>,
+    pub last_pong_time: Option<DateTime<Utc>>,
+    pub healthy: Option<bool>,
+}
+/// The status of the running agent
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AgentStatus {
+    pub active_proxy_group: Option<String>,
+    pub pool_size: usize,
+    pub proxies: Vec<ProxyStatus>,
+    pub tunnels: Vec<TunnelSnapshot>,
+    pub clients: Vec<ClientTraffic>,
+}
+/// The response from the admin endpoint of the agent
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AdminResponse {
+    Status {
+        status: AgentStatus,
+    },
+    PoolDrained {
+        closed_connections: usize,
+    },
+    PoolRefilling,
+    ProxyGroupSwitched {
+        group: Option<String>,
+        proxy_addresses: Vec<ProxyAddress>,
+    },
+    /// The configuration file and the rsa directory are reloaded independently
+    ConfigReloaded {
+        restart_required_changes: Result<Vec<String>, String>,
+        rsa_crypto: Result<RsaCryptoReloadReport, String>,
+    },
+}
+/// The admin endpoint to inspect and control the running agent
+pub struct AdminServer {
+    server_state: ServerState,
+    config_reloader: ConfigReloader,
+}
+impl AdminServer {
+    pub(crate) fn new(server_state: ServerState, config_reloader: ConfigReloader) -> Self {
+        Self {
+            server_state,
+            config_reloader,
+        }
+    }
+    fn status(&self) -> AgentStatus {
+        let proxy_connection_pool = self.server_state.proxy_connection_pool();
+        let pool_size_by_proxy = proxy_connection_pool.pool_size_by_proxy();
+        let proxy_stats = self.server_state.proxy_stats_holder().snapshot();
+        let mut proxy_addresses = proxy_connection_pool.proxy_addresses();
+        proxy_addresses.sort();
+        proxy_addresses.dedup();
+        let proxies = proxy_addresses
+            .into_iter()
+            .map(|proxy_address| {
+                let stats = proxy_stats.get(&proxy_address).cloned().unwrap_or_default();
+                ProxyStatus {
+                    proxy_address,
+                    pool_size: pool_size_by_proxy
+                        .get(&proxy_address)
+                        .copied()
+                        .unwrap_or_default(),
+                    ping_count: stats.ping_count,
+                    pong_count: stats.pong_count,
+                    loss_rate: stats.loss_rate(),
+                    last_rtt_millis: stats.last_rtt.map(|rtt| rtt.as_millis()),
+                    ewma_rtt_millis: stats.ewma_rtt.map(|rtt| rtt.as_millis()),
+                    jitter_millis: stats.jitter.map(|jitter| jitter.as_millis()),
+                    last_pong_time: stats.last_pong_time,
+                    healthy: stats.healthy,
+                }
+            })
+            .collect();
+        let mut tunnels = self.server_state.tunnel_registry().list();
+        tunnels.sort_by_key(|tunnel| tunnel.start_time);
+        let clients = self.server_state.client_traffic_holder().snapshot(&tunnels);
+        AgentStatus {
+            active_proxy_group: self.server_state.config().active_proxy_group().clone(),
+            pool_size: pool_size_by_proxy.values().sum(),
+            proxies,
+            tunnels,
+            clients,
+        }
+    }
+    async fn handle(&self, request: AdminRequest) -> Result<AdminResponse, String> {
+        info!("Receive admin request: {request:?}");
+        match request {
+            AdminRequest::Status => Ok(AdminResponse::Status {
+                status: self.status(),
+            }),
+            AdminRequest::DrainPool => Ok(AdminResponse::PoolDrained {
+                closed_connections: self.server_state.proxy_connection_pool().drain(),
+            }),
+            AdminRequest::RefillPool => {
+                self.server_state.proxy_connection_pool().refill().await;
+                Ok(AdminResponse::PoolRefilling)
+            }
+            AdminRequest::SwitchProxyGroup { group } => {
+                let new_config = self
+                    .server_state
+                    .config()
+                    .with_active_proxy_group(group.clone())
+                    .map_err(|e| e.to_string())?;
+                self.server_state.swap_config(Arc::new(new_config));
+                let proxy_connection_pool = self.server_state.proxy_connection_pool();
+                proxy_connection_pool
+                    .refresh_proxy_addresses()
+                    .map_err(|e| e.to_string())?;
+                Ok(AdminResponse::ProxyGroupSwitched {
+                    group,
+                    proxy_addresses: proxy_connection_pool.proxy_addresses(),
+                })
+            }
+            AdminRequest::ReloadConfig => {
+                let config_reload_report = self.config_reloader.reload();
+                Ok(AdminResponse::ConfigReloaded {
+                    restart_required_changes: config_reload_report
+                        .restart_required_changes
+                        .map(|restart_required_changes| {
+                            restart_required_changes
+                                .into_iter()
+                                .map(str::to_string)
+                                .collect()
+                        })
+                        .map_err(|e| e.to_string()),
+                    rsa_crypto: config_reload_report.rsa_crypto.map_err(|e| e.to_string()),
+                })
+            }
+        }
+    }
+    /// Start the admin endpoint when the `admin_listen_address` configured
+    pub fn start(self) {
+        let Some(admin_listen_address) = self.server_state.config().admin_listen_address().clone()
+        else {
+            return;
+        };
+        let admin_address = match admin_listen_address.parse::<AdminAddress>() {
+            Ok(admin_address) => admin_address,
+            Err(e) => {
+                error!("Fail to start admin endpoint: {e:?}");
+                return;
+            }
+        };
+        let stop_token = self
+            .server_state
+            .shutdown_coordinator()
+            .accept_token()
+            .clone();
+        let admin_server = Arc::new(self);
+        tokio::spawn(async move {
+            let handler = move |request: AdminRequest| {
+                let admin_server = admin_server.clone();
+                async move { admin_server.handle(request).await }
+            };
+            if let Err(e) = serve_admin(admin_address.clone(), handler, stop_token).await {
+                error!("Fail to serve admin endpoint on [{admin_address}]: {e:?}");
+            }
+        });
+    }
+}
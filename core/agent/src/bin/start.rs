@@ -1,15 +1,18 @@
-use agent::command::CommandArgs;
+use agent::admin::AdminRequest;
+use agent::command::{AgentCommand, CommandArgs};
 use agent::config::Config;
+use agent::ctl::run_ctl;
 use agent::server::AgentServer;
 use anyhow::Result;
 use clap::Parser;
 use ppaass_common::init_logger;
-use std::fs::read_to_string;
+use ppaass_common::shutdown::wait_for_shutdown_signal;
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::runtime::Builder;
-use tracing::{error, info};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{error, info, warn};
 const LOG_FILE_NAME_PREFIX: &str = "ppaass-v2-agent.log";
 
 #[global_allocator]
@@ -20,9 +23,17 @@ pub fn main() -> Result<()> {
     let config_file_path = command
         .config
         .unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG_FILE));
-    let config_file_content = read_to_string(config_file_path)?;
-    let config = Arc::new(toml::from_str::<Config>(&config_file_content)?);
-    let _trace_append_guard = init_logger(
+    if let Some(agent_command) = command.command {
+        let (admin_args, request) = match agent_command {
+            AgentCommand::Status(admin_args) => (admin_args, AdminRequest::Status),
+            AgentCommand::Ctl(ctl_args) => (ctl_args.admin_args, ctl_args.action.into()),
+        };
+        let runtime = Builder::new_current_thread().enable_all().build()?;
+        runtime.block_on(run_ctl(admin_args, request, &config_file_path))?;
+        return Ok(());
+    }
+    let config = Arc::new(Config::load(&config_file_path)?);
+    let (_trace_append_guard, log_level_handle) = init_logger(
         config.log_folder(),
         LOG_FILE_NAME_PREFIX,
         config.max_log_level(),
@@ -40,6 +51,13 @@ pub fn main() -> Result<()> {
                 return;
             }
         };
+        server
+            .admin_server(config_file_path.clone(), Some(log_level_handle.clone()))
+            .start();
+        server
+            .config_reloader(config_file_path, Some(log_level_handle))
+            .start();
+        let shutdown_handle = server.shutdown_handle();
         let mut server_event_rx = match server.start().await {
             Ok(server_event_rx) => server_event_rx,
             Err(e) => {
@@ -47,8 +65,27 @@ pub fn main() -> Result<()> {
                 return;
             }
         };
-        while let Some(server_event) = server_event_rx.recv().await {
-            info!("Server event received: {:?}", server_event);
+        let shutdown_signal = wait_for_shutdown_signal();
+        tokio::pin!(shutdown_signal);
+        loop {
+            tokio::select! {
+                server_event = server_event_rx.recv() => {
+                    match server_event {
+                        Ok(server_event) => info!("Server event received: {:?}", server_event),
+                        Err(RecvError::Lagged(skipped)) => {
+                            warn!("Server event receiver lagged, skipped {skipped} events.")
+                        }
+                        Err(RecvError::Closed) => break,
+                    }
+                }
+                _ = &mut shutdown_signal => {
+                    info!("Shutdown signal received, start graceful shutdown.");
+                    if !shutdown_handle.shutdown().await {
+                        info!("Some tunnels are cancelled because of drain timeout.");
+                    }
+                    break;
+                }
+            }
         }
     });
     Ok(())
@@ -1,7 +1,52 @@
-#[derive(Debug)]
+use crate::transport::ProxyAddress;
+use ppaass_domain::address::UnifiedAddress;
+use std::net::SocketAddr;
+use std::time::Duration;
+/// The lifecycle event of the agent server, subscribe with `AgentServer::subscribe`
+#[derive(Debug, Clone)]
 pub enum AgentServerEvent {
     ServerStartup,
-    ServerStartFail,
-    ServerTcpBind,
-    AgentTcpConnected,
+    ServerStartFail {
+        reason: String,
+    },
+    ClientAccepted {
+        client_address: SocketAddr,
+    },
+    TunnelEstablished {
+        tunnel_id: String,
+        client_address: SocketAddr,
+        destination_address: UnifiedAddress,
+        proxy_address: ProxyAddress,
+    },
+    TunnelClosed {
+        tunnel_id: String,
+        client_address: SocketAddr,
+        destination_address: UnifiedAddress,
+        upload_bytes: u64,
+        download_bytes: u64,
+        duration: Duration,
+        /// The reason when the tunnel is not closed normally
+        error: Option<String>,
+    },
+    PoolSizeChanged {
+        pool_size: usize,
+    },
+    ProxyHealthChanged {
+        proxy_address: ProxyAddress,
+        healthy: bool,
+        rtt: Option<Duration>,
+    },
+    ConfigReloaded {
+        restart_required_changes: Vec<String>,
+    },
+    ConfigReloadFail {
+        reason: String,
+    },
+    RsaCryptoReloaded {
+        added_users: Vec<String>,
+        removed_users: Vec<String>,
+    },
+    RsaCryptoReloadFail {
+        reason: String,
+    },
 }
@@ -1,15 +1,44 @@
+use crate::bo::event::AgentServerEvent;
 use crate::config::Config;
 use crate::crypto::AgentRsaCryptoHolder;
+use crate::metrics::AgentMetrics;
 use crate::pool::ProxyConnectionPool;
+use crate::stats::{ClientTrafficHolder, ProxyStatsHolder};
 use accessory::Accessors;
+use arc_swap::ArcSwap;
 use derive_builder::Builder;
+use ppaass_common::shutdown::ShutdownCoordinator;
+use ppaass_common::tunnel_registry::TunnelRegistry;
 use std::sync::Arc;
+use tokio::sync::broadcast::Sender;
 #[derive(Clone, Accessors, Builder)]
 pub struct ServerState {
+    config: Arc<ArcSwap<Config>>,
     #[access(get)]
-    config: Arc<Config>,
+    shutdown_coordinator: ShutdownCoordinator,
+    #[access(get)]
+    tunnel_registry: Arc<TunnelRegistry>,
     #[access(get)]
     rsa_crypto_holder: Arc<AgentRsaCryptoHolder>,
     #[access(get)]
     proxy_connection_pool: Arc<ProxyConnectionPool>,
+    #[access(get)]
+    proxy_stats_holder: Arc<ProxyStatsHolder>,
+    #[access(get)]
+    client_traffic_holder: Arc<ClientTrafficHolder>,
+    #[access(get)]
+    metrics: Arc<AgentMetrics>,
+    #[access(get)]
+    server_event_tx: Sender<AgentServerEvent>,
+}
+impl ServerState {
+    /// The current configuration, it can be replaced by reload
+    pub fn config(&self) -> Arc<Config> {
+        self.config.load_full()
+    }
+    /// Replace the configuration, the new configuration
+    /// will take effect on the new connections
+    pub fn swap_config(&self, config: Arc<Config>) -> Arc<Config> {
+        self.config.swap(config)
+    }
 }
@@ -0,0 +1,257 @@
+use crate::bo::state::ServerState;
+use crate::codec::DataPacketCodec;
+use crate::config::Config;
+use crate::error::AgentError;
+use crate::handler::{tunnel_init, TunnelInitHandlerResponse};
+use crate::metrics::AgentMetrics;
+use crate::pool::PooledProxyConnection;
+use crate::server::new_server_state;
+use crate::transport::ProxyStream;
+use bytes::{Buf, Bytes};
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::{ready, Sink, SinkExt, Stream, StreamExt};
+use ppaass_common::quic::DatagramFramed;
+use ppaass_domain::address::UnifiedAddress;
+use ppaass_domain::tunnel::TunnelType;
+use ppaass_domain::{AgentDataPacket, ProxyDataPacket};
+use std::io::Error;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::Mutex;
+use tokio_util::codec::Framed;
+use tracing::debug;
+type ProxyDataFramed = Framed<PooledProxyConnection<ProxyStream>, DataPacketCodec>;
+type ProxyUdpFramed = DatagramFramed<PooledProxyConnection<ProxyStream>, DataPacketCodec>;
+/// The in-process client to tunnel traffic through the proxy without a local listener
+#[derive(Clone)]
+pub struct PpaassClient {
+    server_state: ServerState,
+}
+impl PpaassClient {
+    /// Create the client with its own proxy connection pool
+    pub async fn new(config: Arc<Config>) -> Result<Self, AgentError> {
+        Ok(Self::from_server_state(new_server_state(config).await?))
+    }
+    pub(crate) fn from_server_state(server_state: ServerState) -> Self {
+        Self { server_state }
+    }
+    /// Open a tcp tunnel to the destination, the returned stream
+    /// sends end of stream to the destination on shutdown
+    pub async fn connect(
+        &self,
+        destination_address: UnifiedAddress,
+    ) -> Result<PpaassTcpStream, AgentError> {
+        let TunnelInitHandlerResponse {
+            proxy_tcp_stream,
+            agent_encryption,
+            proxy_encryption,
+            ..
+        } = self
+            .open_tunnel(destination_address, TunnelType::Tcp { keepalive: true })
+            .await?;
+        let proxy_data_framed = self.proxy_data_framed(
+            proxy_tcp_stream,
+            DataPacketCodec::new(agent_encryption, proxy_encryption),
+        );
+        self.server_state.metrics().inc_active_tunnels();
+        Ok(PpaassTcpStream {
+            proxy_data_framed,
+            read_buf: Bytes::new(),
+            read_eof: false,
+            write_eof: false,
+            metrics: self.server_state.metrics().clone(),
+        })
+    }
+    /// Open a udp tunnel to the destination, every send is relayed
+    /// as one datagram by the proxy
+    pub async fn connect_udp(
+        &self,
+        destination_address: UnifiedAddress,
+    ) -> Result<PpaassUdpSocket, AgentError> {
+        let TunnelInitHandlerResponse {
+            mut proxy_tcp_stream,
+            agent_encryption,
+            proxy_encryption,
+            ..
+        } = self
+            .open_tunnel(destination_address.clone(), TunnelType::Udp)
+            .await?;
+        let proxy_quic_datagrams = proxy_tcp_stream.get_mut().take_quic_datagrams();
+        let proxy_data_framed = self.proxy_data_framed(
+            proxy_tcp_stream,
+            DataPacketCodec::new(agent_encryption.clone(), proxy_encryption.clone()),
+        );
+        let proxy_data_framed = match proxy_quic_datagrams {
+            None => DatagramFramed::new(proxy_data_framed),
+            Some(proxy_quic_datagrams) => DatagramFramed::with_datagrams(
+                proxy_data_framed,
+                proxy_quic_datagrams,
+                DataPacketCodec::new(agent_encryption, proxy_encryption),
+            ),
+        };
+        self.server_state.metrics().inc_active_tunnels();
+        let (proxy_data_framed_tx, proxy_data_framed_rx) = proxy_data_framed.split();
+        Ok(PpaassUdpSocket {
+            proxy_data_framed_tx: Mutex::new(proxy_data_framed_tx),
+            proxy_data_framed_rx: Mutex::new(proxy_data_framed_rx),
+            destination_address,
+            metrics: self.server_state.metrics().clone(),
+        })
+    }
+    async fn open_tunnel(
+        &self,
+        destination_address: UnifiedAddress,
+        tunnel_type: TunnelType,
+    ) -> Result<TunnelInitHandlerResponse, AgentError> {
+        let tunnel_init_response =
+            tunnel_init(destination_address, self.server_state.clone(), tunnel_type).await?;
+        debug!(
+            tunnel_id = { tunnel_init_response.tunnel_id.as_str() },
+            "In-process tunnel created to destination: {}",
+            tunnel_init_response.destination_address
+        );
+        Ok(tunnel_init_response)
+    }
+    fn proxy_data_framed(
+        &self,
+        proxy_tcp_stream: PooledProxyConnection<ProxyStream>,
+        data_packet_codec: DataPacketCodec,
+    ) -> ProxyDataFramed {
+        Framed::with_capacity(
+            proxy_tcp_stream,
+            data_packet_codec,
+            *self.server_state.config().proxy_relay_buffer_size(),
+        )
+    }
+}
+/// The tcp stream tunneled through the proxy
+pub struct PpaassTcpStream {
+    proxy_data_framed: ProxyDataFramed,
+    read_buf: Bytes,
+    read_eof: bool,
+    write_eof: bool,
+    metrics: Arc<AgentMetrics>,
+}
+impl AsyncRead for PpaassTcpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if !this.read_buf.is_empty() {
+                let read_size = this.read_buf.len().min(buf.remaining());
+                buf.put_slice(&this.read_buf[..read_size]);
+                this.read_buf.advance(read_size);
+                return Poll::Ready(Ok(()));
+            }
+            if this.read_eof {
+                return Poll::Ready(Ok(()));
+            }
+            match ready!(Pin::new(&mut this.proxy_data_framed).poll_next(cx)) {
+                None | Some(Ok(ProxyDataPacket::TcpEof)) => this.read_eof = true,
+                Some(Ok(ProxyDataPacket::Tcp(proxy_data))) => {
+                    this.metrics.add_download_bytes(proxy_data.len());
+                    this.read_buf = Bytes::from(proxy_data);
+                }
+                Some(Ok(ProxyDataPacket::Udp { .. })) => {
+                    return Poll::Ready(Err(AgentError::InvalidProxyDataType.into()))
+                }
+                Some(Err(e)) => return Poll::Ready(Err(e.into())),
+            }
+        }
+    }
+}
+impl AsyncWrite for PpaassTcpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Error>> {
+        let this = self.get_mut();
+        let mut proxy_data_framed = Pin::new(&mut this.proxy_data_framed);
+        ready!(proxy_data_framed.as_mut().poll_ready(cx))?;
+        proxy_data_framed
+            .as_mut()
+            .start_send(AgentDataPacket::Tcp(buf.to_vec()))?;
+        this.metrics.add_upload_bytes(buf.len());
+        // Push the data out eagerly, the caller is not required to flush like a tcp stream
+        if let Poll::Ready(Err(e)) = proxy_data_framed.poll_flush(cx) {
+            return Poll::Ready(Err(e.into()));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Poll::Ready(Ok(ready!(
+            Pin::new(&mut self.get_mut().proxy_data_framed).poll_flush(cx)
+        )?))
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let this = self.get_mut();
+        let mut proxy_data_framed = Pin::new(&mut this.proxy_data_framed);
+        if !this.write_eof {
+            ready!(proxy_data_framed.as_mut().poll_ready(cx))?;
+            proxy_data_framed
+                .as_mut()
+                .start_send(AgentDataPacket::TcpEof)?;
+            this.write_eof = true;
+        }
+        Poll::Ready(Ok(ready!(proxy_data_framed.poll_flush(cx))?))
+    }
+}
+impl Drop for PpaassTcpStream {
+    fn drop(&mut self) {
+        self.metrics.dec_active_tunnels();
+    }
+}
+/// The udp socket tunneled through the proxy, it is connected to one destination
+pub struct PpaassUdpSocket {
+    proxy_data_framed_tx: Mutex<SplitSink<ProxyUdpFramed, AgentDataPacket>>,
+    proxy_data_framed_rx: Mutex<SplitStream<ProxyUdpFramed>>,
+    destination_address: UnifiedAddress,
+    metrics: Arc<AgentMetrics>,
+}
+impl PpaassUdpSocket {
+    pub fn destination_address(&self) -> &UnifiedAddress {
+        &self.destination_address
+    }
+    /// Send one datagram to the destination
+    pub async fn send(&self, buf: &[u8]) -> Result<usize, AgentError> {
+        self.proxy_data_framed_tx
+            .lock()
+            .await
+            .send(AgentDataPacket::Udp {
+                destination_address: self.destination_address.clone(),
+                payload: buf.to_vec(),
+            })
+            .await?;
+        self.metrics.add_upload_bytes(buf.len());
+        Ok(buf.len())
+    }
+    /// Receive one datagram from the destination, the datagram
+    /// is truncated when the buffer is too small like a udp socket
+    pub async fn recv(&self, buf: &mut [u8]) -> Result<usize, AgentError> {
+        let proxy_data_packet = self
+            .proxy_data_framed_rx
+            .lock()
+            .await
+            .next()
+            .await
+            .ok_or(AgentError::ProxyConnectionExhausted)??;
+        let ProxyDataPacket::Udp { payload, .. } = proxy_data_packet else {
+            return Err(AgentError::InvalidProxyDataType);
+        };
+        self.metrics.add_download_bytes(payload.len());
+        let datagram_size = payload.len().min(buf.len());
+        buf[..datagram_size].copy_from_slice(&payload[..datagram_size]);
+        Ok(datagram_size)
+    }
+}
+impl Drop for PpaassUdpSocket {
+    fn drop(&mut self) {
+        self.metrics.dec_active_tunnels();
+    }
+}
@@ -1,12 +1,47 @@
-use clap::Parser;
+use clap::{Args, Parser, Subcommand};
 use std::path::PathBuf;
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 pub struct CommandArgs {
     /// The configuration file path of the proxy
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     pub config: Option<PathBuf>,
     /// The rsa folder path of the proxy
     #[arg(short, long)]
     pub rsa: Option<PathBuf>,
+    #[command(subcommand)]
+    pub command: Option<AgentCommand>,
+}
+#[derive(Subcommand, Debug)]
+pub enum AgentCommand {
+    /// Show the status of the running agent
+    Status(AdminArgs),
+    /// Control the running agent through the admin endpoint
+    Ctl(CtlArgs),
+}
+#[derive(Args, Debug)]
+pub struct AdminArgs {
+    /// The admin address of the running agent, use the
+    /// `admin_listen_address` in the configuration file when not given
+    #[arg(short, long)]
+    pub admin: Option<String>,
+}
+#[derive(Args, Debug)]
+pub struct CtlArgs {
+    #[command(flatten)]
+    pub admin_args: AdminArgs,
+    #[command(subcommand)]
+    pub action: CtlAction,
+}
+#[derive(Subcommand, Debug)]
+pub enum CtlAction {
+    /// Close all the idle proxy connections in the pool
+    DrainPool,
+    /// Fill the pool to the max pool size
+    RefillPool,
+    /// Switch the active proxy group until the next configuration reload,
+    /// use the `proxy_addresses` when no group given
+    SwitchProxyGroup { group: Option<String> },
+    /// Reload the configuration file
+    ReloadConfig,
 }
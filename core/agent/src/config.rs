@@ -1,6 +1,16 @@
+use crate::error::AgentError;
+use crate::transport::{ProxyTlsConfig, ProxyTransport, ProxyWebSocketConfig};
+use crate::upstream::UpstreamProxyConfig;
 use accessory::Accessors;
+use ppaass_common::admin::AdminAddress;
+use ppaass_common::tunnel_timeout::TunnelTimeoutConfig;
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::fs::read_to_string;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tracing::Level;
 #[derive(Debug, Clone, Serialize, Deserialize, Accessors)]
 pub struct Config {
     #[access(get)]
@@ -10,6 +20,11 @@ pub struct Config {
     #[access(get)]
     proxy_addresses: Vec<String>,
     #[access(get)]
+    #[serde(default)]
+    proxy_groups: HashMap<String, Vec<String>>,
+    #[access(get)]
+    active_proxy_group: Option<String>,
+    #[access(get)]
     worker_threads: usize,
     #[access(get)]
     worker_thread_keep_alive: u64,
@@ -26,9 +41,8 @@ pub struct Config {
     #[access(get)]
     client_connection_tcp_keepalive_time: Option<u64>,
     #[access(get)]
-    client_connection_read_timeout: Option<u64>,
-    #[access(get)]
-    client_connection_write_timeout: Option<u64>,
+    #[serde(default = "default_client_connection_tcp_keepalive_retry")]
+    client_connection_tcp_keepalive_retry: u32,
     #[access(get)]
     client_socket_receive_buffer_size: Option<usize>,
     #[access(get)]
@@ -55,10 +69,9 @@ pub struct Config {
     proxy_connection_pool_fill_interval: Option<u64>,
     #[access(get)]
     proxy_connect_timeout: u64,
+    /// The seconds to wait for the tunnel init response from the proxy after the request sent
     #[access(get)]
-    proxy_connection_read_timeout: Option<u64>,
-    #[access(get)]
-    proxy_connection_write_timeout: Option<u64>,
+    proxy_init_response_timeout: Option<u64>,
     #[access(get)]
     proxy_socket_receive_buffer_size: Option<usize>,
     #[access(get)]
@@ -73,6 +86,215 @@ pub struct Config {
     log_folder: PathBuf,
     #[access(get)]
     server_event_max_size: usize,
+    #[access(get)]
+    config_watch_interval: Option<u64>,
+    #[access(get)]
+    #[serde(default = "default_shutdown_drain_timeout")]
+    shutdown_drain_timeout: u64,
+    #[access(get)]
+    #[serde(default)]
+    tcp_tunnel_timeout: TunnelTimeoutConfig,
+    #[access(get)]
+    metrics_listen_address: Option<String>,
+    #[access(get)]
+    admin_listen_address: Option<String>,
+    /// The tls of the proxy connections, the proxy connections are plain tcp when not given
+    #[access(get)]
+    proxy_tls: Option<ProxyTlsConfig>,
+    /// The websocket of the proxy connections, it runs on the tls when `proxy_tls` given
+    #[access(get)]
+    proxy_websocket: Option<ProxyWebSocketConfig>,
+    /// The upstream proxy which the tcp proxy connections go through, the new
+    /// proxy connections take the change without restart
+    #[access(get)]
+    upstream_proxy: Option<UpstreamProxyConfig>,
+}
+impl Config {
+    /// Load the configuration from the toml file and validate it
+    pub fn load(config_file_path: &Path) -> Result<Self, AgentError> {
+        let config_file_content = read_to_string(config_file_path)?;
+        let config = toml::from_str::<Config>(&config_file_content)
+            .map_err(|e| AgentError::InvalidConfig(e.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+    /// Validate the configuration
+    pub fn validate(&self) -> Result<(), AgentError> {
+        if self.auth_token.is_empty() {
+            return Err(AgentError::InvalidConfig(
+                "auth_token can not be empty".to_string(),
+            ));
+        }
+        if self.proxy_addresses.is_empty() {
+            return Err(AgentError::InvalidConfig(
+                "proxy_addresses can not be empty".to_string(),
+            ));
+        }
+        if let Some(proxy_address) = self
+            .proxy_addresses
+            .iter()
+            .chain(self.proxy_groups.values().flatten())
+            .find(|proxy_address| !is_valid_proxy_address(proxy_address))
+        {
+            return Err(AgentError::InvalidConfig(format!(
+                "invalid proxy address: {proxy_address}"
+            )));
+        }
+        if self.proxy_tls.is_none()
+            && self
+                .proxy_addresses
+                .iter()
+                .chain(self.proxy_groups.values().flatten())
+                .any(|proxy_address| ProxyTransport::parse(proxy_address).0 == ProxyTransport::Quic)
+        {
+            return Err(AgentError::InvalidConfig(
+                "proxy_tls must be given for the quic proxy addresses".to_string(),
+            ));
+        }
+        if let Some(active_proxy_group) = &self.active_proxy_group {
+            match self.proxy_groups.get(active_proxy_group) {
+                None => {
+                    return Err(AgentError::InvalidConfig(format!(
+                        "active proxy group not exist: {active_proxy_group}"
+                    )));
+                }
+                Some(group_proxy_addresses) if group_proxy_addresses.is_empty() => {
+                    return Err(AgentError::InvalidConfig(format!(
+                        "proxy group can not be empty: {active_proxy_group}"
+                    )));
+                }
+                Some(_) => {}
+            }
+        }
+        if Level::from_str(&self.max_log_level).is_err() {
+            return Err(AgentError::InvalidConfig(format!(
+                "invalid max_log_level: {}",
+                self.max_log_level
+            )));
+        }
+        if self.proxy_connection_pool_size == Some(0) {
+            return Err(AgentError::InvalidConfig(
+                "proxy_connection_pool_size can not be 0".to_string(),
+            ));
+        }
+        if self.client_relay_buffer_size == 0 || self.proxy_relay_buffer_size == 0 {
+            return Err(AgentError::InvalidConfig(
+                "relay buffer size can not be 0".to_string(),
+            ));
+        }
+        if self.proxy_connection_tcp_keepalive
+            && (self.proxy_connection_tcp_keepalive_interval.is_none()
+                || self.proxy_connection_tcp_keepalive_time.is_none())
+        {
+            return Err(AgentError::InvalidConfig(
+                "proxy connection tcp keepalive interval and time must be given".to_string(),
+            ));
+        }
+        if self.client_connection_tcp_keepalive
+            && (self.client_connection_tcp_keepalive_interval.is_none()
+                || self.client_connection_tcp_keepalive_time.is_none())
+        {
+            return Err(AgentError::InvalidConfig(
+                "client connection tcp keepalive interval and time must be given".to_string(),
+            ));
+        }
+        if let Some(metrics_listen_address) = &self.metrics_listen_address {
+            if metrics_listen_address.parse::<SocketAddr>().is_err() {
+                return Err(AgentError::InvalidConfig(format!(
+                    "invalid metrics_listen_address: {metrics_listen_address}"
+                )));
+            }
+        }
+        if let Some(admin_listen_address) = &self.admin_listen_address {
+            admin_listen_address.parse::<AdminAddress>()?;
+        }
+        if let Some(proxy_tls) = &self.proxy_tls {
+            proxy_tls.validate()?;
+        }
+        if let Some(upstream_proxy) = &self.upstream_proxy {
+            upstream_proxy.validate()?;
+            if self
+                .active_proxy_addresses()
+                .iter()
+                .any(|proxy_address| ProxyTransport::parse(proxy_address).0 == ProxyTransport::Quic)
+            {
+                return Err(AgentError::InvalidConfig(
+                    "quic proxy addresses can not go through the upstream_proxy".to_string(),
+                ));
+            }
+        }
+        if let Some(proxy_websocket) = &self.proxy_websocket {
+            if !proxy_websocket.path.starts_with('/') {
+                return Err(AgentError::InvalidConfig(format!(
+                    "proxy_websocket path must start with '/': {}",
+                    proxy_websocket.path
+                )));
+            }
+        }
+        Ok(())
+    }
+    /// The proxy addresses of the active proxy group,
+    /// fallback to `proxy_addresses` when no group is active
+    pub fn active_proxy_addresses(&self) -> &[String] {
+        self.active_proxy_group
+            .as_ref()
+            .and_then(|active_proxy_group| self.proxy_groups.get(active_proxy_group))
+            .unwrap_or(&self.proxy_addresses)
+    }
+    /// Copy the configuration with another active proxy group,
+    /// `None` means using the `proxy_addresses`
+    pub fn with_active_proxy_group(
+        &self,
+        active_proxy_group: Option<String>,
+    ) -> Result<Config, AgentError> {
+        let mut config = self.clone();
+        config.active_proxy_group = active_proxy_group;
+        config.validate()?;
+        Ok(config)
+    }
+    /// The changed fields which can only take effect after restart
+    pub fn restart_required_changes(&self, new_config: &Config) -> Vec<&'static str> {
+        ppaass_common::changed_fields!(
+            self,
+            new_config,
+            port,
+            worker_threads,
+            worker_thread_keep_alive,
+            rsa_dir,
+            log_folder,
+            server_socket_backlog,
+            server_event_max_size,
+            client_connection_tcp_keepalive,
+            client_connection_tcp_keepalive_interval,
+            client_connection_tcp_keepalive_time,
+            client_connection_tcp_keepalive_retry,
+            client_socket_receive_buffer_size,
+            client_socket_send_buffer_size,
+            proxy_connection_pool_size,
+            proxy_connection_pool_fill_interval,
+            proxy_connection_start_check_timer,
+            config_watch_interval,
+            metrics_listen_address,
+            admin_listen_address,
+            proxy_tls,
+            proxy_websocket
+        )
+    }
+}
+fn default_client_connection_tcp_keepalive_retry() -> u32 {
+    9
+}
+fn default_shutdown_drain_timeout() -> u64 {
+    30
+}
+/// Check the proxy address is written as `host:port`, it is resolved by the
+/// proxy connection pool, so the dns failure doesn't fail the start or the reload
+fn is_valid_proxy_address(proxy_address: &str) -> bool {
+    let (_, proxy_address) = ProxyTransport::parse(proxy_address);
+    match proxy_address.rsplit_once(':') {
+        None => false,
+        Some((host, port)) => !host.is_empty() && port.parse::<u16>().is_ok(),
+    }
 }
 impl Default for Config {
     fn default() -> Self {
@@ -80,12 +302,15 @@ impl Default for Config {
             port: 80,
             auth_token: "user1".to_string(),
             proxy_addresses: vec!["45.76.0.10:80".to_string()],
+            proxy_groups: HashMap::new(),
+            active_proxy_group: None,
             worker_threads: 256,
             max_log_level: "INFO".to_string(),
             rsa_dir: PathBuf::from("/resources/agent/rsa"),
             client_connection_tcp_keepalive: false,
             client_connection_tcp_keepalive_interval: Some(75),
             client_connection_tcp_keepalive_time: Some(7200),
+            client_connection_tcp_keepalive_retry: default_client_connection_tcp_keepalive_retry(),
             server_socket_backlog: 1024,
             client_relay_buffer_size: 65536,
             proxy_relay_buffer_size: 65536,
@@ -94,15 +319,12 @@ impl Default for Config {
             proxy_connection_check_interval: 60,
             proxy_connection_pool_fill_interval: Some(20),
             proxy_connect_timeout: 20,
-            proxy_connection_read_timeout: None,
-            proxy_connection_write_timeout: None,
+            proxy_init_response_timeout: None,
             proxy_socket_receive_buffer_size: None,
             proxy_socket_send_buffer_size: None,
             proxy_connection_tcp_keepalive: false,
             proxy_connection_tcp_keepalive_interval: Some(75),
             proxy_connection_tcp_keepalive_time: Some(7200),
-            client_connection_read_timeout: None,
-            client_connection_write_timeout: None,
             client_socket_receive_buffer_size: None,
             proxy_connection_start_check_timer_interval: 120,
             proxy_connection_max_lifetime: 300,
@@ -110,8 +332,84 @@ impl Default for Config {
             proxy_connection_retake_interval: 5,
             client_socket_send_buffer_size: None,
             log_folder: PathBuf::from("/logs"),
-            server_event_max_size: u32::MAX as usize,
+            server_event_max_size: 1024,
             worker_thread_keep_alive: 10,
+            config_watch_interval: None,
+            shutdown_drain_timeout: default_shutdown_drain_timeout(),
+            tcp_tunnel_timeout: TunnelTimeoutConfig::default(),
+            metrics_listen_address: None,
+            admin_listen_address: None,
+            proxy_tls: None,
+            proxy_websocket: None,
+            upstream_proxy: None,
         }
     }
 }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn validate_config() {
+        assert!(Config::default().validate().is_ok());
+        let mut config = Config {
+            proxy_addresses: vec!["quic://127.0.0.1:443".to_string()],
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+        config.proxy_tls = Some(ProxyTlsConfig {
+            server_name: None,
+            ca_file: None,
+            cert_fingerprint: Some("00".repeat(32)),
+        });
+        assert!(config.validate().is_ok());
+        // The proxy address is resolved by the pool, not in the validation
+        let mut config = Config {
+            proxy_addresses: vec!["proxy.invalid:80".to_string()],
+            ..Default::default()
+        };
+        assert!(config.validate().is_ok());
+        for proxy_address in ["proxy.invalid", ":80", "proxy.invalid:99999"] {
+            config.proxy_addresses = vec![proxy_address.to_string()];
+            assert!(config.validate().is_err());
+        }
+        let config = Config {
+            active_proxy_group: Some("backup".to_string()),
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+        let config = Config {
+            proxy_connection_pool_size: Some(0),
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+    }
+    #[test]
+    fn load_config_without_new_fields() {
+        let mut config = toml::Table::try_from(Config::default()).unwrap();
+        config.remove("client_connection_tcp_keepalive_retry");
+        config.remove("shutdown_drain_timeout");
+        config.remove("tcp_tunnel_timeout");
+        config.remove("proxy_groups");
+        let config: Config = config.try_into().unwrap();
+        assert_eq!(*config.client_connection_tcp_keepalive_retry(), 9);
+        assert_eq!(*config.shutdown_drain_timeout(), 30);
+    }
+    #[test]
+    fn collect_restart_required_changes() {
+        let current_config = Config::default();
+        let new_config = Config {
+            port: 8080,
+            proxy_addresses: vec!["127.0.0.1:80".to_string()],
+            max_log_level: "DEBUG".to_string(),
+            proxy_connection_pool_size: None,
+            ..Default::default()
+        };
+        assert!(current_config
+            .restart_required_changes(&current_config.clone())
+            .is_empty());
+        assert_eq!(
+            current_config.restart_required_changes(&new_config),
+            vec!["port", "proxy_connection_pool_size"]
+        );
+    }
+}
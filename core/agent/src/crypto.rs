@@ -1,21 +1,43 @@
 use crate::config::Config;
+use arc_swap::ArcSwap;
 use ppaass_codec::error::CodecError;
-use ppaass_codec::RsaCryptoHolder;
+use ppaass_codec::{RsaCryptoHolder, RsaCryptoReloadReport};
 use ppaass_crypto::error::CryptoError;
 use ppaass_crypto::rsa::RsaCrypto;
 use std::collections::HashMap;
 use std::fs::{read_dir, File};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use tracing::error;
 pub struct AgentRsaCryptoHolder {
-    cache: Arc<HashMap<String, Arc<RsaCrypto>>>,
+    rsa_dir_path: PathBuf,
+    cache: ArcSwap<HashMap<String, Arc<RsaCrypto>>>,
 }
 
 impl AgentRsaCryptoHolder {
     pub fn new(config: Arc<Config>) -> Result<Self, CryptoError> {
+        let rsa_dir_path = config.rsa_dir().to_path_buf();
+        let cache = Self::load_rsa_dir(&rsa_dir_path)?;
+        Ok(Self {
+            rsa_dir_path,
+            cache: ArcSwap::from_pointee(cache),
+        })
+    }
+    /// The directory which the rsa crypto loaded from
+    pub fn rsa_dir_path(&self) -> &Path {
+        &self.rsa_dir_path
+    }
+    /// Scan the rsa directory again, the new proxy connections will use the reloaded rsa crypto
+    pub fn reload(&self) -> Result<RsaCryptoReloadReport, CryptoError> {
+        let cache = Self::load_rsa_dir(&self.rsa_dir_path)?;
+        let previous_cache = self.cache.swap(Arc::new(cache));
+        Ok(RsaCryptoReloadReport::new(
+            &previous_cache,
+            &self.cache.load(),
+        ))
+    }
+    fn load_rsa_dir(rsa_dir_path: &Path) -> Result<HashMap<String, Arc<RsaCrypto>>, CryptoError> {
         let mut cache = HashMap::new();
-        let rsa_dir_path = config.rsa_dir();
         let rsa_dir = read_dir(rsa_dir_path)?;
         rsa_dir.for_each(|entry| {
             let Ok(entry) = entry else {
@@ -48,9 +70,7 @@ impl AgentRsaCryptoHolder {
             };
             cache.insert(user_token.to_string(), Arc::new(rsa_crypto));
         });
-        Ok(Self {
-            cache: Arc::new(cache),
-        })
+        Ok(cache)
     }
 }
 
@@ -59,7 +79,7 @@ impl RsaCryptoHolder for AgentRsaCryptoHolder {
         &self,
         auth_token: impl AsRef<str>,
     ) -> Result<Option<Arc<RsaCrypto>>, CodecError> {
-        match self.cache.get(auth_token.as_ref()) {
+        match self.cache.load().get(auth_token.as_ref()) {
             None => Ok(None),
             Some(val) => Ok(Some(val.clone())),
         }
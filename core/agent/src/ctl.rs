@@ -0,0 +1,144 @@
+use crate::admin::{AdminRequest, AdminResponse, AgentStatus};
+use crate::command::{AdminArgs, CtlAction};
+use crate::config::Config;
+use crate::error::AgentError;
+use chrono::Utc;
+use ppaass_common::admin::{send_admin_request, AdminAddress};
+use std::path::Path;
+/// Send the request to the admin endpoint of the running agent and print the response
+pub async fn run_ctl(
+    admin_args: AdminArgs,
+    request: AdminRequest,
+    config_file_path: &Path,
+) -> Result<(), AgentError> {
+    let admin_address = match admin_args.admin {
+        Some(admin_address) => admin_address,
+        None => Config::load(config_file_path)?
+            .admin_listen_address()
+            .clone()
+            .ok_or(AgentError::InvalidConfig(
+                "admin_listen_address not given".to_string(),
+            ))?,
+    };
+    let admin_address = admin_address.parse::<AdminAddress>()?;
+    let response: AdminResponse = send_admin_request(&admin_address, &request).await?;
+    print_response(response);
+    Ok(())
+}
+impl From<CtlAction> for AdminRequest {
+    fn from(value: CtlAction) -> Self {
+        match value {
+            CtlAction::DrainPool => AdminRequest::DrainPool,
+            CtlAction::RefillPool => AdminRequest::RefillPool,
+            CtlAction::SwitchProxyGroup { group } => AdminRequest::SwitchProxyGroup { group },
+            CtlAction::ReloadConfig => AdminRequest::ReloadConfig,
+        }
+    }
+}
+fn print_status(status: AgentStatus) {
+    println!(
+        "Active proxy group: {}",
+        status.active_proxy_group.as_deref().unwrap_or("<default>")
+    );
+    println!("Pool size: {}", status.pool_size);
+    println!();
+    println!(
+        "{:<40} {:>9} {:>6} {:>8} {:>8} {:>10} {:>10}",
+        "PROXY", "HEALTH", "POOL", "LOSS(%)", "RTT(MS)", "SRTT(MS)", "JITTER(MS)"
+    );
+    status.proxies.iter().for_each(|proxy| {
+        println!(
+            "{:<40} {:>9} {:>6} {:>8.1} {:>8} {:>10} {:>10}",
+            proxy.proxy_address.to_string(),
+            match proxy.healthy {
+                None => "unchecked",
+                Some(true) => "healthy",
+                Some(false) => "unhealthy",
+            },
+            proxy.pool_size,
+            proxy.loss_rate * 100f64,
+            format_millis(proxy.last_rtt_millis),
+            format_millis(proxy.ewma_rtt_millis),
+            format_millis(proxy.jitter_millis),
+        );
+    });
+    println!();
+    println!(
+        "{:<36} {:<22} {:<32} {:>12} {:>12} {:>8}",
+        "TUNNEL", "CLIENT", "DESTINATION", "UPLOAD", "DOWNLOAD", "AGE(S)"
+    );
+    let now = Utc::now();
+    status.tunnels.iter().for_each(|tunnel| {
+        println!(
+            "{:<36} {:<22} {:<32} {:>12} {:>12} {:>8}",
+            tunnel.id,
+            tunnel.client_address.to_string(),
+            tunnel.destination_address.to_string(),
+            tunnel.upload_bytes,
+            tunnel.download_bytes,
+            (now - tunnel.start_time).num_seconds()
+        );
+    });
+    println!();
+    println!(
+        "{:<40} {:>8} {:>8} {:>12} {:>12}",
+        "CLIENT", "ACTIVE", "TOTAL", "UPLOAD", "DOWNLOAD"
+    );
+    status.clients.iter().for_each(|client| {
+        println!(
+            "{:<40} {:>8} {:>8} {:>12} {:>12}",
+            client.client_ip.to_string(),
+            client.active_tunnels,
+            client.total_tunnels,
+            client.upload_bytes,
+            client.download_bytes
+        );
+    });
+}
+fn format_millis(millis: Option<u128>) -> String {
+    millis
+        .map(|millis| millis.to_string())
+        .unwrap_or_else(|| "-".to_string())
+}
+fn print_response(response: AdminResponse) {
+    match response {
+        AdminResponse::Status { status } => print_status(status),
+        AdminResponse::PoolDrained { closed_connections } => {
+            println!("{closed_connections} proxy connections closed")
+        }
+        AdminResponse::PoolRefilling => println!("Proxy connection pool refilling"),
+        AdminResponse::ProxyGroupSwitched {
+            group,
+            proxy_addresses,
+        } => println!(
+            "Switched to proxy group {}: [{}]",
+            group.as_deref().unwrap_or("<default>"),
+            proxy_addresses
+                .iter()
+                .map(|proxy_address| proxy_address.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        AdminResponse::ConfigReloaded {
+            restart_required_changes,
+            rsa_crypto,
+        } => {
+            match restart_required_changes {
+                Ok(restart_required_changes) if restart_required_changes.is_empty() => {
+                    println!("Configuration reloaded")
+                }
+                Ok(restart_required_changes) => println!(
+                    "Configuration reloaded, these changes only take effect after restart: {restart_required_changes:?}"
+                ),
+                Err(e) => println!("Fail to reload configuration: {e}"),
+            }
+            match rsa_crypto {
+                Ok(rsa_crypto) => println!(
+                    "Rsa crypto reloaded, added users: {:?}, removed users: {:?}",
+                    rsa_crypto.added_users, rsa_crypto.removed_users
+                ),
+                Err(e) => println!("Fail to reload rsa crypto: {e}"),
+            }
+        }
+    }
+}
@@ -1,7 +1,9 @@
 use crate::bo::state::ServerStateBuilderError;
 use ppaass_codec::error::CodecError;
+use ppaass_common::error::CommonError;
 use ppaass_crypto::error::CryptoError;
 use ppaass_domain::error::DomainError;
+use ppaass_domain::tunnel::TunnelInitFailure;
 use std::net::AddrParseError;
 use thiserror::Error;
 #[derive(Error, Debug)]
@@ -44,9 +46,25 @@ pub enum AgentError {
     Unknown(String),
     #[error(transparent)]
     ConnectProxyTimeout(#[from] tokio::time::error::Elapsed),
+    #[error("Relay idle timeout")]
+    RelayIdleTimeout,
+    #[error("Proxy init response timeout")]
+    ProxyInitResponseTimeout,
+    #[error("Invalid configuration: {0}")]
+    InvalidConfig(String),
+    #[error(transparent)]
+    Common(#[from] CommonError),
+    #[error(transparent)]
+    Metrics(#[from] prometheus::Error),
+    #[error(transparent)]
+    Tls(#[from] rustls::Error),
+    #[error("Upstream proxy error: {0}")]
+    UpstreamProxy(String),
+    #[error("Tunnel init rejected by proxy: {0}")]
+    TunnelInitRejected(TunnelInitFailure),
 }
 impl From<AgentError> for std::io::Error {
     fn from(value: AgentError) -> Self {
-        std::io::Error::new(std::io::ErrorKind::Other, value)
+        std::io::Error::other(value)
     }
 }
@@ -10,6 +10,7 @@ use httpcodec::{
     RequestEncoder, Response, ResponseEncoder, StatusCode,
 };
 use ppaass_domain::address::UnifiedAddress;
+use ppaass_domain::tunnel::{TunnelInitFailure, TunnelType};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
 use tracing::{debug, error};
@@ -17,6 +18,12 @@ use url::Url;
 const CONNECT_METHOD: &str = "connect";
 const OK_CODE: u16 = 200;
 const CONNECTION_ESTABLISHED: &str = "Connection Established";
+const FORBIDDEN_CODE: u16 = 403;
+const FORBIDDEN: &str = "Forbidden";
+const TOO_MANY_REQUESTS_CODE: u16 = 429;
+const TOO_MANY_REQUESTS: &str = "Too Many Requests";
+const SERVICE_UNAVAILABLE_CODE: u16 = 503;
+const SERVICE_UNAVAILABLE: &str = "Service Unavailable";
 const PROXY_CONNECTION_HEADER_NAME: &str = "Proxy-Connection";
 const CONNECTION_HEADER_NAME: &str = "Connection";
 const KEEP_ALIVE_HEADER_VALUE: &str = "keep-alive";
@@ -62,11 +69,7 @@ pub async fn handle_http_client_tcp_stream(
             .name()
             .eq_ignore_ascii_case(PROXY_CONNECTION_HEADER_NAME)
         {
-            if header_field.value() == KEEP_ALIVE_HEADER_VALUE {
-                connection_keep_alive = true;
-            } else {
-                connection_keep_alive = false;
-            }
+            connection_keep_alive = header_field.value() == KEEP_ALIVE_HEADER_VALUE;
             let connection_field =
                 match HeaderField::new(CONNECTION_HEADER_NAME, header_field.value()) {
                     Ok(connection_field) => connection_field,
@@ -124,17 +127,43 @@ pub async fn handle_http_client_tcp_stream(
         "HTTP proxy begin connect to remote: {}",
         destination_address
     );
+    let tunnel_init_result = tunnel_init(
+        destination_address,
+        server_state.clone(),
+        TunnelType::Tcp {
+            keepalive: connection_keep_alive,
+        },
+    )
+    .await;
     let TunnelInitHandlerResponse {
+        tunnel_id,
         proxy_tcp_stream,
         agent_encryption,
         proxy_encryption,
         destination_address,
-    } = tunnel_init(
-        destination_address,
-        server_state.clone(),
-        connection_keep_alive,
-    )
-    .await?;
+    } = match tunnel_init_result {
+        Ok(tunnel_init_response) => tunnel_init_response,
+        Err(AgentError::TunnelInitRejected(tunnel_init_failure)) => {
+            let (status_code, reason_phrase) = match &tunnel_init_failure {
+                TunnelInitFailure::AccessDenied(_) => (FORBIDDEN_CODE, FORBIDDEN),
+                TunnelInitFailure::QuotaExceeded(_) => (TOO_MANY_REQUESTS_CODE, TOO_MANY_REQUESTS),
+                TunnelInitFailure::ServerBusy(_) => (SERVICE_UNAVAILABLE_CODE, SERVICE_UNAVAILABLE),
+            };
+            let http_rejected_response = Response::new(
+                HttpVersion::V1_1,
+                StatusCode::new(status_code)?,
+                ReasonPhrase::new(reason_phrase)?,
+                vec![],
+            );
+            let mut http_rejected_response_encoder =
+                ResponseEncoder::<BodyEncoder<BytesEncoder>>::default();
+            let response_bytes =
+                http_rejected_response_encoder.encode_into_bytes(http_rejected_response)?;
+            client_tcp_stream.write_all(&response_bytes).await?;
+            return Err(AgentError::TunnelInitRejected(tunnel_init_failure));
+        }
+        Err(e) => return Err(e),
+    };
     debug!(
         "HTTP proxy connect to remote success: {}",
         destination_address
@@ -156,6 +185,7 @@ pub async fn handle_http_client_tcp_stream(
     debug!("HTTP proxy begin to relay: {}", destination_address);
     relay(
         RelayRequest {
+            tunnel_id,
             client_tcp_stream,
             proxy_tcp_stream,
             agent_encryption,
@@ -1,29 +1,67 @@
+use crate::bo::event::AgentServerEvent;
 use crate::bo::state::ServerState;
 use crate::codec::{ControlPacketCodec, DataPacketCodec};
 use crate::error::AgentError;
+use crate::metrics::{RESULT_FAIL, RESULT_SUCCESS};
 use crate::pool::PooledProxyConnection;
+use crate::publish_server_event;
+use crate::transport::ProxyStream;
 use bytes::{Bytes, BytesMut};
 use futures_util::{SinkExt, StreamExt};
+use ppaass_common::tunnel_timeout::{read_with_idle_timeout, wait_tunnel_timeout};
 use ppaass_crypto::random_32_bytes;
 use ppaass_domain::address::UnifiedAddress;
+use ppaass_domain::generate_uuid;
 use ppaass_domain::tunnel::{Encryption, TunnelInitRequest, TunnelInitResponse, TunnelType};
 use ppaass_domain::{AgentControlPacket, AgentDataPacket, ProxyControlPacket, ProxyDataPacket};
+use std::future::Future;
+use std::time::Duration;
 use tokio::net::TcpStream;
-use tokio_stream::StreamExt as TokioStreamExt;
+use tokio::task::JoinHandle;
+use tokio::time::{timeout, Instant};
 use tokio_util::codec::{BytesCodec, Framed, FramedParts};
-use tracing::{error, trace};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, trace};
 pub mod http;
 pub mod socks5;
+/// The default time to wait for the tunnel init response, it
+/// should be longer than the destination connect timeout of the proxy
+const DEFAULT_PROXY_INIT_RESPONSE_TIMEOUT: Duration = Duration::from_secs(30);
 pub struct TunnelInitHandlerResponse {
-    proxy_tcp_stream: PooledProxyConnection<TcpStream>,
-    agent_encryption: Encryption,
-    proxy_encryption: Encryption,
-    destination_address: UnifiedAddress,
+    pub(crate) tunnel_id: String,
+    pub(crate) proxy_tcp_stream: PooledProxyConnection<ProxyStream>,
+    pub(crate) agent_encryption: Encryption,
+    pub(crate) proxy_encryption: Encryption,
+    pub(crate) destination_address: UnifiedAddress,
 }
 pub async fn tunnel_init(
     destination_address: UnifiedAddress,
     server_state: ServerState,
-    connection_keep_alive: bool,
+    tunnel_type: TunnelType,
+) -> Result<TunnelInitHandlerResponse, AgentError> {
+    let tunnel_init_start = Instant::now();
+    let protocol = match tunnel_type {
+        TunnelType::Tcp { .. } => "tcp",
+        TunnelType::Udp => "udp",
+    };
+    let tunnel_init_result =
+        concrete_tunnel_init(destination_address, &server_state, tunnel_type).await;
+    let tunnel_init_result_label = if tunnel_init_result.is_ok() {
+        RESULT_SUCCESS
+    } else {
+        RESULT_FAIL
+    };
+    server_state.metrics().record_tunnel_init(
+        protocol,
+        tunnel_init_result_label,
+        tunnel_init_start.elapsed(),
+    );
+    tunnel_init_result
+}
+async fn concrete_tunnel_init(
+    destination_address: UnifiedAddress,
+    server_state: &ServerState,
+    tunnel_type: TunnelType,
 ) -> Result<TunnelInitHandlerResponse, AgentError> {
     let proxy_tcp_stream = server_state
         .proxy_connection_pool()
@@ -37,37 +75,58 @@ pub async fn tunnel_init(
         ),
     );
     let agent_encryption = Encryption::Aes(random_32_bytes());
+    let tunnel_id = generate_uuid();
+    debug!(
+        tunnel_id = { tunnel_id.as_str() },
+        "Init tunnel to destination: {destination_address}"
+    );
     control_framed
         .send(AgentControlPacket::TunnelInit(TunnelInitRequest {
+            tunnel_id: tunnel_id.clone(),
             agent_encryption: agent_encryption.clone(),
             auth_token: server_state.config().auth_token().to_owned(),
             dst_address: destination_address.clone(),
-            tunnel_type: TunnelType::Tcp {
-                keepalive: connection_keep_alive,
-            },
+            tunnel_type,
         }))
         .await?;
-    let TunnelInitResponse { proxy_encryption } = {
+    let receive_tunnel_init_response = async {
         loop {
             let proxy_control_packet = StreamExt::next(&mut control_framed)
                 .await
                 .ok_or(AgentError::ProxyConnectionExhausted)??;
             match proxy_control_packet {
                 ProxyControlPacket::TunnelInit((_, tunnel_init_response)) => {
-                    break tunnel_init_response;
+                    return Ok(tunnel_init_response);
                 }
                 ProxyControlPacket::Heartbeat(heartbeat_pong) => {
                     error!("Receive heartbeat pong from proxy: {:?}", heartbeat_pong);
                     continue;
                 }
+                ProxyControlPacket::TunnelInitFail(tunnel_init_failure) => {
+                    error!(
+                        tunnel_id = { tunnel_id.as_str() },
+                        "Proxy rejected tunnel to destination [{destination_address}]: {tunnel_init_failure}"
+                    );
+                    return Err(AgentError::TunnelInitRejected(tunnel_init_failure));
+                }
             }
         }
     };
+    let proxy_init_response_timeout = server_state
+        .config()
+        .proxy_init_response_timeout()
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_PROXY_INIT_RESPONSE_TIMEOUT);
+    let TunnelInitResponse { proxy_encryption } =
+        timeout(proxy_init_response_timeout, receive_tunnel_init_response)
+            .await
+            .map_err(|_| AgentError::ProxyInitResponseTimeout)??;
     let FramedParts {
         io: proxy_tcp_stream,
         ..
     } = control_framed.into_parts();
     Ok(TunnelInitHandlerResponse {
+        tunnel_id,
         proxy_tcp_stream,
         agent_encryption,
         proxy_encryption,
@@ -75,8 +134,9 @@ pub async fn tunnel_init(
     })
 }
 pub struct RelayRequest {
+    pub tunnel_id: String,
     pub client_tcp_stream: TcpStream,
-    pub proxy_tcp_stream: PooledProxyConnection<TcpStream>,
+    pub proxy_tcp_stream: PooledProxyConnection<ProxyStream>,
     pub init_data: Option<Bytes>,
     pub agent_encryption: Encryption,
     pub proxy_encryption: Encryption,
@@ -87,6 +147,7 @@ pub async fn relay(
     server_state: ServerState,
 ) -> Result<(), AgentError> {
     let RelayRequest {
+        tunnel_id,
         client_tcp_stream,
         proxy_tcp_stream,
         init_data,
@@ -94,18 +155,38 @@ pub async fn relay(
         proxy_encryption,
         destination_address,
     } = relay_request;
+    let shutdown_coordinator = server_state.shutdown_coordinator();
+    let client_address = client_tcp_stream.peer_addr()?;
+    publish_server_event(
+        server_state.server_event_tx(),
+        AgentServerEvent::TunnelEstablished {
+            tunnel_id: tunnel_id.clone(),
+            client_address,
+            destination_address: destination_address.clone(),
+            proxy_address: proxy_tcp_stream.proxy_address(),
+        },
+    );
+    let tunnel_registration = server_state.tunnel_registry().register(
+        tunnel_id,
+        None,
+        server_state.config().auth_token().to_owned(),
+        client_address,
+        destination_address.clone(),
+        shutdown_coordinator.tunnel_token(),
+    );
     let client_tcp_framed = Framed::with_capacity(
         client_tcp_stream,
         BytesCodec::new(),
         *server_state.config().client_relay_buffer_size(),
     );
-    let (mut client_tcp_framed_tx, client_tcp_framed_rx) = client_tcp_framed.split::<BytesMut>();
+    let (mut client_tcp_framed_tx, mut client_tcp_framed_rx) =
+        client_tcp_framed.split::<BytesMut>();
     let proxy_data_framed = Framed::with_capacity(
         proxy_tcp_stream,
         DataPacketCodec::new(agent_encryption, proxy_encryption),
         *server_state.config().proxy_relay_buffer_size(),
     );
-    let (proxy_data_framed_tx, proxy_data_framed_rx) = proxy_data_framed.split();
+    let (mut proxy_data_framed_tx, mut proxy_data_framed_rx) = proxy_data_framed.split();
     if let Some(init_data) = init_data {
         trace!(
             "Receive http proxy request packet from client (initial data):\n{}\n",
@@ -115,61 +196,175 @@ pub async fn relay(
             .send(BytesMut::from(init_data.as_ref()))
             .await?;
     }
-    let client_tcp_framed_rx = {
-        let destination_address = destination_address.clone();
-        client_tcp_framed_rx.map_while(move |client_item| {
-            let client_data = match client_item {
-                Ok(client_data) => client_data.freeze(),
-                Err(e) => {
-                    error!(
-                        destination_address = { format!("{}", &destination_address) },
-                        "Fail to read client data: {e:?}"
-                    );
-                    return Some(Err(AgentError::Io(e)));
-                }
-            };
-            trace!(
-                "Receive http proxy request packet from client:\n{}\n",
-                pretty_hex::pretty_hex(&client_data)
+    let tunnel_timeout_config = *server_state.config().tcp_tunnel_timeout();
+    let direction_idle_timeout = tunnel_timeout_config.direction_idle_timeout();
+    let client_to_proxy = {
+        let tunnel_entry = tunnel_registration.entry().clone();
+        let metrics = server_state.metrics().clone();
+        async move {
+            while let Some(client_data) = read_with_idle_timeout(
+                &tunnel_entry,
+                direction_idle_timeout,
+                client_tcp_framed_rx.next(),
+            )
+            .await
+            .map_err(|_| AgentError::RelayIdleTimeout)?
+            {
+                let client_data = client_data?;
+                trace!(
+                    "Receive http proxy request packet from client:\n{}\n",
+                    pretty_hex::pretty_hex(&client_data)
+                );
+                tunnel_entry.add_upload_bytes(client_data.len());
+                metrics.add_upload_bytes(client_data.len());
+                proxy_data_framed_tx
+                    .send(AgentDataPacket::Tcp(client_data.to_vec()))
+                    .await?;
+            }
+            // Client closed the write side, tell the proxy to half-close the destination
+            debug!(
+                tunnel_id = { tunnel_entry.id() },
+                "Client data exhausted, send end of stream to proxy."
             );
-            Some(Ok(AgentDataPacket::Tcp(client_data.to_vec())))
-        })
+            proxy_data_framed_tx.send(AgentDataPacket::TcpEof).await?;
+            Ok::<(), AgentError>(())
+        }
     };
-    let proxy_data_framed_rx = {
-        let destination_address = destination_address.clone();
-        proxy_data_framed_rx.map_while(move |proxy_data_packet| {
-            let proxy_packet_data = match proxy_data_packet {
-                Ok(proxy_packet_data) => proxy_packet_data,
-                Err(e) => {
-                    error!(
-                        destination_address = { format!("{}", &destination_address) },
-                        "Failed to read proxy data: {}", e
-                    );
-                    return Some(Err(e.into()));
-                }
-            };
-            match proxy_packet_data {
-                ProxyDataPacket::Tcp(proxy_data) => {
-                    trace!(
-                        "Receive http proxy response packet from proxy:\n{}\n",
-                        pretty_hex::pretty_hex(&proxy_data)
-                    );
-                    Some(Ok(BytesMut::from_iter(proxy_data)))
-                }
-                ProxyDataPacket::Udp {
-                    destination_address,
-                    ..
-                } => {
-                    error!(
-                        destination_address = { format!("{}", &destination_address) },
-                        "Invalid kind of proxy data, destination address."
-                    );
-                    Some(Err(AgentError::InvalidProxyDataType.into()))
+    let proxy_to_client = {
+        let tunnel_entry = tunnel_registration.entry().clone();
+        let metrics = server_state.metrics().clone();
+        async move {
+            while let Some(proxy_data_packet) = read_with_idle_timeout(
+                &tunnel_entry,
+                direction_idle_timeout,
+                proxy_data_framed_rx.next(),
+            )
+            .await
+            .map_err(|_| AgentError::RelayIdleTimeout)?
+            {
+                match proxy_data_packet? {
+                    ProxyDataPacket::Tcp(proxy_data) => {
+                        trace!(
+                            "Receive http proxy response packet from proxy:\n{}\n",
+                            pretty_hex::pretty_hex(&proxy_data)
+                        );
+                        tunnel_entry.add_download_bytes(proxy_data.len());
+                        metrics.add_download_bytes(proxy_data.len());
+                        client_tcp_framed_tx
+                            .send(BytesMut::from_iter(proxy_data))
+                            .await?;
+                    }
+                    ProxyDataPacket::TcpEof => {
+                        debug!(
+                            tunnel_id = { tunnel_entry.id() },
+                            "Receive end of stream from proxy."
+                        );
+                        break;
+                    }
+                    ProxyDataPacket::Udp {
+                        destination_address,
+                        ..
+                    } => {
+                        error!(
+                            destination_address = { format!("{}", &destination_address) },
+                            "Invalid kind of proxy data, destination address."
+                        );
+                        return Err(AgentError::InvalidProxyDataType);
+                    }
                 }
             }
-        })
+            // Shutdown the write side of the client connection
+            client_tcp_framed_tx.close().await?;
+            Ok::<(), AgentError>(())
+        }
     };
-    tokio::spawn(client_tcp_framed_rx.forward(proxy_data_framed_tx));
-    tokio::spawn(proxy_data_framed_rx.forward(client_tcp_framed_tx));
+    let tunnel_token = tunnel_registration.entry().cancellation_token().clone();
+    let client_to_proxy = spawn_relay_direction(
+        &server_state,
+        tunnel_token.clone(),
+        destination_address.clone(),
+        client_to_proxy,
+    );
+    let proxy_to_client = spawn_relay_direction(
+        &server_state,
+        tunnel_token.clone(),
+        destination_address.clone(),
+        proxy_to_client,
+    );
+    let metrics = server_state.metrics().clone();
+    let client_traffic_holder = server_state.client_traffic_holder().clone();
+    let server_event_tx = server_state.server_event_tx().clone();
+    metrics.inc_active_tunnels();
+    shutdown_coordinator.spawn_tunnel_task(async move {
+        let relay_finished = async {
+            let client_to_proxy_error = client_to_proxy.await.ok().flatten().flatten();
+            let proxy_to_client_error = proxy_to_client.await.ok().flatten().flatten();
+            client_to_proxy_error.or(proxy_to_client_error)
+        };
+        tokio::pin!(relay_finished);
+        let close_error = tokio::select! {
+            relay_error = &mut relay_finished => relay_error,
+            tunnel_timeout = wait_tunnel_timeout(tunnel_registration.entry(), tunnel_timeout_config) => {
+                info!(
+                    tunnel_id = { tunnel_registration.entry().id() },
+                    "Close tunnel because of timeout: {tunnel_timeout:?}"
+                );
+                tunnel_token.cancel();
+                relay_finished.await;
+                Some(format!("{tunnel_timeout:?} timeout"))
+            }
+        };
+        debug!(
+            tunnel_id = { tunnel_registration.entry().id() },
+            "Tunnel closed, upload bytes: {}, download bytes: {}",
+            tunnel_registration.entry().upload_bytes(),
+            tunnel_registration.entry().download_bytes()
+        );
+        client_traffic_holder.record_closed_tunnel(
+            tunnel_registration.entry().client_address().ip(),
+            tunnel_registration.entry().upload_bytes(),
+            tunnel_registration.entry().download_bytes(),
+        );
+        metrics.dec_active_tunnels();
+        publish_server_event(
+            &server_event_tx,
+            AgentServerEvent::TunnelClosed {
+                tunnel_id: tunnel_registration.entry().id().to_owned(),
+                client_address,
+                destination_address,
+                upload_bytes: tunnel_registration.entry().upload_bytes(),
+                download_bytes: tunnel_registration.entry().download_bytes(),
+                duration: tunnel_registration.entry().lifetime(),
+                error: close_error,
+            },
+        );
+    });
     Ok(())
 }
+/// Spawn one direction of the relay, the whole tunnel is cancelled when the direction fails,
+/// the failure reason is the output of the returned task
+fn spawn_relay_direction<F>(
+    server_state: &ServerState,
+    tunnel_token: CancellationToken,
+    destination_address: UnifiedAddress,
+    relay_direction: F,
+) -> JoinHandle<Option<Option<String>>>
+where
+    F: Future<Output = Result<(), AgentError>> + Send + 'static,
+{
+    let metrics = server_state.metrics().clone();
+    server_state
+        .shutdown_coordinator()
+        .spawn_tunnel_task(async move {
+            let Some(Err(e)) = tunnel_token.run_until_cancelled(relay_direction).await else {
+                return None;
+            };
+            metrics.record_error(&e);
+            error!(
+                destination_address = { format!("{}", &destination_address) },
+                "Fail to relay, close the tunnel: {e:?}"
+            );
+            tunnel_token.cancel();
+            Some(e.to_string())
+        })
+}
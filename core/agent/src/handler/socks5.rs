@@ -2,6 +2,7 @@ use crate::bo::state::ServerState;
 use crate::error::AgentError;
 use crate::handler::{relay, tunnel_init, RelayRequest, TunnelInitHandlerResponse};
 use ppaass_domain::address::UnifiedAddress;
+use ppaass_domain::tunnel::{TunnelInitFailure, TunnelType};
 use socks5_impl::protocol::{
     handshake::Request as Socks5HandshakeRequest, handshake::Response as Socks5HandshakeResponse,
     Address, AsyncStreamOperation, AuthMethod, Command, Reply, Request as Socks5Request, Response,
@@ -24,12 +25,7 @@ pub async fn handle_socks5_client_tcp_stream(
     match init_request.command {
         Command::Connect => {
             debug!("Receive socks5 CONNECT command: {client_tcp_stream:?}");
-            let TunnelInitHandlerResponse {
-                proxy_tcp_stream,
-                agent_encryption,
-                proxy_encryption,
-                destination_address,
-            } = tunnel_init(
+            let tunnel_init_result = tunnel_init(
                 match &init_request.address {
                     Address::SocketAddress(dst_addr) => dst_addr.into(),
                     Address::DomainAddress(host, port) => UnifiedAddress::Domain {
@@ -38,9 +34,31 @@ pub async fn handle_socks5_client_tcp_stream(
                     },
                 },
                 server_state.clone(),
-                true,
+                TunnelType::Tcp { keepalive: true },
             )
-            .await?;
+            .await;
+            let TunnelInitHandlerResponse {
+                tunnel_id,
+                proxy_tcp_stream,
+                agent_encryption,
+                proxy_encryption,
+                destination_address,
+            } = match tunnel_init_result {
+                Ok(tunnel_init_response) => tunnel_init_response,
+                Err(AgentError::TunnelInitRejected(tunnel_init_failure)) => {
+                    let reply = match &tunnel_init_failure {
+                        TunnelInitFailure::ServerBusy(_) => Reply::GeneralFailure,
+                        TunnelInitFailure::AccessDenied(_)
+                        | TunnelInitFailure::QuotaExceeded(_) => Reply::ConnectionNotAllowed,
+                    };
+                    let init_response = Response::new(reply, init_request.address);
+                    init_response
+                        .write_to_async_stream(&mut client_tcp_stream)
+                        .await?;
+                    return Err(AgentError::TunnelInitRejected(tunnel_init_failure));
+                }
+                Err(e) => return Err(e),
+            };
             debug!("Socks5 client tunnel init success with remote: {proxy_tcp_stream:?}");
             let init_response = Response::new(Reply::Succeeded, init_request.address);
             init_response
@@ -49,6 +67,7 @@ pub async fn handle_socks5_client_tcp_stream(
             debug!("Socks5 client tunnel init success begin to relay, : {proxy_tcp_stream:?}");
             relay(
                 RelayRequest {
+                    tunnel_id,
                     client_tcp_stream,
                     proxy_tcp_stream,
                     agent_encryption,
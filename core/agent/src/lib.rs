@@ -1,20 +1,26 @@
 use crate::bo::event::AgentServerEvent;
-use tokio::sync::mpsc::Sender;
-use tracing::error;
+use tokio::sync::broadcast::Sender;
+use tracing::trace;
+pub mod admin;
 pub mod bo;
+pub mod client;
 pub mod codec;
 pub mod command;
 pub mod config;
 pub mod crypto;
+pub mod ctl;
 mod error;
 pub mod handler;
+pub mod metrics;
 mod pool;
+pub mod reload;
 pub mod server;
-pub async fn publish_server_event(
-    server_event_tx: Sender<AgentServerEvent>,
-    event: AgentServerEvent,
-) {
-    if let Err(e) = server_event_tx.send(event).await {
-        error!("Failed to publish server event: {}", e);
+pub mod stats;
+pub mod transport;
+pub mod upstream;
+/// Publish the event to all the subscribers, the event is dropped when there is no subscriber
+pub fn publish_server_event(server_event_tx: &Sender<AgentServerEvent>, event: AgentServerEvent) {
+    if let Err(e) = server_event_tx.send(event) {
+        trace!("No subscriber for server event: {:?}", e.0);
     }
 }
@@ -0,0 +1,137 @@
+use crate::error::AgentError;
+use crate::transport::ProxyAddress;
+use prometheus::{
+    exponential_buckets, Histogram, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts,
+    Registry,
+};
+use std::fmt::{Debug, Formatter};
+use std::time::Duration;
+const METRICS_NAMESPACE: &str = "ppaass_agent";
+pub const RESULT_SUCCESS: &str = "success";
+pub const RESULT_FAIL: &str = "fail";
+/// The prometheus metrics of the agent
+pub struct AgentMetrics {
+    registry: Registry,
+    accepted_connections: IntCounterVec,
+    tunnels: IntCounterVec,
+    relay_bytes: IntCounterVec,
+    active_tunnels: IntGauge,
+    proxy_pool_size: IntGauge,
+    tunnel_init_latency: Histogram,
+    heartbeat_rtt: HistogramVec,
+    codec_errors: IntCounterVec,
+}
+impl Debug for AgentMetrics {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AgentMetrics").finish_non_exhaustive()
+    }
+}
+impl AgentMetrics {
+    pub fn new() -> Result<Self, AgentError> {
+        let registry = Registry::new_custom(Some(METRICS_NAMESPACE.to_string()), None)?;
+        let accepted_connections = IntCounterVec::new(
+            Opts::new(
+                "accepted_connections_total",
+                "The client connections accepted by protocol and result",
+            ),
+            &["protocol", "result"],
+        )?;
+        let tunnels = IntCounterVec::new(
+            Opts::new(
+                "tunnels_total",
+                "The tunnels initialized by protocol and result",
+            ),
+            &["protocol", "result"],
+        )?;
+        let relay_bytes = IntCounterVec::new(
+            Opts::new("relay_bytes_total", "The bytes relayed by direction"),
+            &["direction"],
+        )?;
+        let active_tunnels = IntGauge::new("active_tunnels", "The tunnels in relaying")?;
+        let proxy_pool_size =
+            IntGauge::new("proxy_pool_size", "The idle proxy connections in the pool")?;
+        let tunnel_init_latency = Histogram::with_opts(
+            HistogramOpts::new(
+                "tunnel_init_latency_seconds",
+                "The latency of tunnel initialization",
+            )
+            .buckets(exponential_buckets(0.005, 2f64, 14)?),
+        )?;
+        let heartbeat_rtt = HistogramVec::new(
+            HistogramOpts::new(
+                "heartbeat_rtt_seconds",
+                "The heartbeat round trip time by proxy",
+            )
+            .buckets(exponential_buckets(0.005, 2f64, 12)?),
+            &["proxy_address"],
+        )?;
+        let codec_errors = IntCounterVec::new(
+            Opts::new("codec_errors_total", "The crypto and codec errors by kind"),
+            &["kind"],
+        )?;
+        registry.register(Box::new(accepted_connections.clone()))?;
+        registry.register(Box::new(tunnels.clone()))?;
+        registry.register(Box::new(relay_bytes.clone()))?;
+        registry.register(Box::new(active_tunnels.clone()))?;
+        registry.register(Box::new(proxy_pool_size.clone()))?;
+        registry.register(Box::new(tunnel_init_latency.clone()))?;
+        registry.register(Box::new(heartbeat_rtt.clone()))?;
+        registry.register(Box::new(codec_errors.clone()))?;
+        Ok(Self {
+            registry,
+            accepted_connections,
+            tunnels,
+            relay_bytes,
+            active_tunnels,
+            proxy_pool_size,
+            tunnel_init_latency,
+            heartbeat_rtt,
+            codec_errors,
+        })
+    }
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+    pub fn record_accepted_connection(&self, protocol: &str, result: &str) {
+        self.accepted_connections
+            .with_label_values(&[protocol, result])
+            .inc();
+    }
+    pub fn record_tunnel_init(&self, protocol: &str, result: &str, latency: Duration) {
+        self.tunnels.with_label_values(&[protocol, result]).inc();
+        self.tunnel_init_latency.observe(latency.as_secs_f64());
+    }
+    pub fn add_upload_bytes(&self, bytes: usize) {
+        self.relay_bytes
+            .with_label_values(&["upload"])
+            .inc_by(bytes as u64);
+    }
+    pub fn add_download_bytes(&self, bytes: usize) {
+        self.relay_bytes
+            .with_label_values(&["download"])
+            .inc_by(bytes as u64);
+    }
+    pub fn inc_active_tunnels(&self) {
+        self.active_tunnels.inc();
+    }
+    pub fn dec_active_tunnels(&self) {
+        self.active_tunnels.dec();
+    }
+    pub fn set_proxy_pool_size(&self, pool_size: usize) {
+        self.proxy_pool_size.set(pool_size as i64);
+    }
+    pub fn observe_heartbeat_rtt(&self, proxy_address: ProxyAddress, rtt: Duration) {
+        self.heartbeat_rtt
+            .with_label_values(&[&proxy_address.to_string()])
+            .observe(rtt.as_secs_f64());
+    }
+    /// Count the error when it is caused by crypto or codec
+    pub fn record_error(&self, error: &AgentError) {
+        let kind = match error {
+            AgentError::Codec(e) => e.kind(),
+            AgentError::Crypto(_) => "crypto",
+            _ => return,
+        };
+        self.codec_errors.with_label_values(&[kind]).inc();
+    }
+}
@@ -0,0 +1,15 @@
+use chrono::{DateTime, Utc};
+/// The clock used by the connection pool to decide
+/// when a proxy connection should be checked or closed
+pub trait Clock: Send + Sync + 'static {
+    /// The current time
+    fn now(&self) -> DateTime<Utc>;
+}
+/// The clock backed by the system time
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
@@ -1,8 +1,8 @@
 use crate::config::Config;
+use crate::transport::ProxyAddress;
 use chrono::{DateTime, Utc};
 use std::io::Error;
 use std::pin::Pin;
-use std::sync::Arc;
 use std::task::{Context, Poll};
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 /// Pooled proxy connection
@@ -13,7 +13,7 @@ where
     T: AsyncRead + AsyncWrite + Unpin,
 {
     inner: T,
-    config: Arc<Config>,
+    proxy_address: ProxyAddress,
     last_check_time: DateTime<Utc>,
     create_time: DateTime<Utc>,
 }
@@ -21,32 +21,40 @@ impl<T> PooledProxyConnection<T>
 where
     T: AsyncRead + AsyncWrite + Unpin,
 {
-    pub fn new(inner: T, config: Arc<Config>) -> PooledProxyConnection<T> {
+    pub fn new(
+        inner: T,
+        proxy_address: ProxyAddress,
+        now: DateTime<Utc>,
+    ) -> PooledProxyConnection<T> {
         PooledProxyConnection {
             inner,
-            config,
-            last_check_time: Utc::now(),
-            create_time: Utc::now(),
+            proxy_address,
+            last_check_time: now,
+            create_time: now,
         }
     }
-    pub fn need_check(&self) -> bool {
-        let now = Utc::now();
+    pub fn need_check(&self, config: &Config, now: DateTime<Utc>) -> bool {
         let delta = now - self.last_check_time;
-        delta.num_seconds() > *self.config.proxy_connection_check_interval()
+        delta.num_seconds() > *config.proxy_connection_check_interval()
     }
 
-    pub fn need_close(&self) -> bool {
-        let now = Utc::now();
+    pub fn need_close(&self, config: &Config, now: DateTime<Utc>) -> bool {
         let delta = now - self.create_time;
-        delta.num_seconds() > *self.config.proxy_connection_max_lifetime()
+        delta.num_seconds() > *config.proxy_connection_max_lifetime()
     }
 
-    pub fn update_check_time(&mut self) {
-        self.last_check_time = Utc::now();
+    pub fn update_check_time(&mut self, now: DateTime<Utc>) {
+        self.last_check_time = now;
     }
     pub fn last_check_time(&self) -> &DateTime<Utc> {
         &self.last_check_time
     }
+    pub fn proxy_address(&self) -> ProxyAddress {
+        self.proxy_address
+    }
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
 }
 impl<T> AsyncRead for PooledProxyConnection<T>
 where
@@ -0,0 +1,252 @@
+use crate::config::Config;
+use crate::error::AgentError;
+use crate::transport::{ProxyAddress, ProxyStream, ProxyTransport};
+use arc_swap::ArcSwap;
+use ppaass_common::error::CommonError;
+use ppaass_common::quic::QuicConnection;
+use ppaass_common::websocket::WebSocketTransport;
+use quinn::Endpoint;
+use socket2::{SockRef, TcpKeepalive};
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio::time::timeout;
+use tokio_rustls::TlsConnector;
+use tracing::{debug, error};
+/// The connector used by the connection pool to build
+/// the transport between agent and proxy
+pub trait ProxyConnector: Send + Sync + 'static {
+    /// The transport between agent and proxy
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send + Debug + 'static;
+    /// Build and tune the transport to the given proxy address
+    fn connect(
+        &self,
+        proxy_address: ProxyAddress,
+    ) -> impl Future<Output = Result<Self::Stream, AgentError>> + Send;
+}
+/// The connector build the tcp transport to proxy,
+/// the tcp connection is wrapped with tls when `proxy_tls` configured,
+/// the `quic://` proxy addresses are connected with the streams of a shared quic connection
+pub struct TcpProxyConnector {
+    config: Arc<ArcSwap<Config>>,
+    tls_connector: Option<TlsConnector>,
+    quic_client_config: Option<quinn::ClientConfig>,
+    quic_connections: Mutex<HashMap<SocketAddr, QuicConnection>>,
+}
+impl TcpProxyConnector {
+    pub fn new(config: Arc<ArcSwap<Config>>) -> Result<Self, AgentError> {
+        let (tls_connector, quic_client_config) = match config.load().proxy_tls() {
+            None => (None, None),
+            Some(proxy_tls) => (
+                Some(proxy_tls.build_connector()?),
+                Some(proxy_tls.build_quic_client_config()?),
+            ),
+        };
+        Ok(Self {
+            config,
+            tls_connector,
+            quic_client_config,
+            quic_connections: Mutex::new(HashMap::new()),
+        })
+    }
+}
+impl ProxyConnector for TcpProxyConnector {
+    type Stream = ProxyStream;
+    async fn connect(&self, proxy_address: ProxyAddress) -> Result<ProxyStream, AgentError> {
+        let config = self.config.load();
+        let (transport, proxy_address) =
+            (proxy_address.transport(), proxy_address.socket_address());
+        if transport == ProxyTransport::Quic {
+            return self.connect_quic(proxy_address, &config).await;
+        }
+        debug!("Creating proxy tcp stream on: {proxy_address}");
+        let proxy_tcp_stream = match timeout(
+            Duration::from_secs(*config.proxy_connect_timeout()),
+            Self::connect_tcp(proxy_address, &config),
+        )
+        .await
+        {
+            Ok(Ok(proxy_tcp_stream)) => proxy_tcp_stream,
+            Ok(Err(e)) => {
+                error!("Fail connect to proxy: {e:?}");
+                return Err(e);
+            }
+            Err(e) => {
+                error!(
+                    "Fail connect to proxy because of timeout: {}",
+                    *config.proxy_connect_timeout()
+                );
+                return Err(e.into());
+            }
+        };
+        let proxy_socket = SockRef::from(&proxy_tcp_stream);
+        proxy_socket.set_reuse_address(true)?;
+        if *config.proxy_connection_tcp_keepalive() {
+            let keepalive = TcpKeepalive::new()
+                .with_interval(Duration::from_secs(
+                    config
+                        .proxy_connection_tcp_keepalive_interval()
+                        .ok_or(AgentError::Unknown("Fail to create proxy connection tcp socket becauause of no keepalive interval provided".to_string()))?,
+                ))
+                .with_time(Duration::from_secs(
+                    config.proxy_connection_tcp_keepalive_time().ok_or(AgentError::Unknown("Fail to create proxy connection tcp socket becauause of no keepalive time provided".to_string()))?
+                ));
+            proxy_socket.set_tcp_keepalive(&keepalive)?;
+        }
+        proxy_socket.set_linger(None)?;
+        proxy_socket.set_nodelay(true)?;
+        if let Some(buffer_size) = config.proxy_socket_receive_buffer_size() {
+            proxy_socket.set_recv_buffer_size(*buffer_size)?;
+        }
+        if let Some(buffer_size) = config.proxy_socket_send_buffer_size() {
+            proxy_socket.set_send_buffer_size(*buffer_size)?;
+        }
+        debug!("Create proxy connection: {proxy_tcp_stream:?}");
+        let proxy_stream = self
+            .wrap_tls(proxy_tcp_stream, proxy_address, &config)
+            .await?;
+        let Some(proxy_websocket) = config.proxy_websocket() else {
+            return Ok(proxy_stream);
+        };
+        let host = proxy_websocket
+            .host
+            .clone()
+            .unwrap_or_else(|| proxy_address.to_string());
+        match timeout(
+            Duration::from_secs(*config.proxy_connect_timeout()),
+            WebSocketTransport::connect(proxy_stream, &host, &proxy_websocket.path),
+        )
+        .await
+        {
+            Ok(Ok(proxy_websocket_transport)) => {
+                Ok(ProxyStream::WebSocket(Box::new(proxy_websocket_transport)))
+            }
+            Ok(Err(e)) => {
+                error!("Fail to upgrade websocket with proxy [{proxy_address}]: {e:?}");
+                Err(e.into())
+            }
+            Err(e) => {
+                error!("Fail to upgrade websocket with proxy [{proxy_address}] because of timeout");
+                Err(e.into())
+            }
+        }
+    }
+}
+impl TcpProxyConnector {
+    /// Connect to the proxy directly, or through the upstream proxy when `upstream_proxy` configured
+    async fn connect_tcp(
+        proxy_address: SocketAddr,
+        config: &Config,
+    ) -> Result<TcpStream, AgentError> {
+        match config.upstream_proxy() {
+            None => Ok(TcpStream::connect(proxy_address).await?),
+            Some(upstream_proxy) => upstream_proxy.connect(proxy_address).await,
+        }
+    }
+    /// Open a stream to the proxy, the quic connection to the proxy
+    /// is created on the first stream and shared by the later ones
+    async fn connect_quic(
+        &self,
+        proxy_address: SocketAddr,
+        config: &Config,
+    ) -> Result<ProxyStream, AgentError> {
+        let connect_timeout = Duration::from_secs(*config.proxy_connect_timeout());
+        let quic_connection = {
+            let mut quic_connections = self.quic_connections.lock().await;
+            match quic_connections.get(&proxy_address) {
+                Some(quic_connection) if !quic_connection.is_closed() => quic_connection.clone(),
+                _ => {
+                    debug!("Creating proxy quic connection on: {proxy_address}");
+                    let quic_connection = match timeout(
+                        connect_timeout,
+                        self.new_quic_connection(proxy_address, config),
+                    )
+                    .await
+                    {
+                        Ok(Ok(quic_connection)) => quic_connection,
+                        Ok(Err(e)) => {
+                            error!("Fail connect to proxy [{proxy_address}] with quic: {e:?}");
+                            return Err(e);
+                        }
+                        Err(e) => {
+                            error!(
+                                "Fail connect to proxy [{proxy_address}] with quic because of timeout"
+                            );
+                            return Err(e.into());
+                        }
+                    };
+                    quic_connections.insert(proxy_address, quic_connection.clone());
+                    quic_connection
+                }
+            }
+        };
+        // Wait for the proxy to allow more streams when the stream limit reached
+        let quic_stream = timeout(connect_timeout, quic_connection.open_stream()).await??;
+        Ok(ProxyStream::Quic(quic_stream))
+    }
+    async fn new_quic_connection(
+        &self,
+        proxy_address: SocketAddr,
+        config: &Config,
+    ) -> Result<QuicConnection, AgentError> {
+        let (Some(quic_client_config), Some(proxy_tls)) =
+            (&self.quic_client_config, config.proxy_tls())
+        else {
+            return Err(AgentError::InvalidConfig(
+                "proxy_tls must be given for the quic proxy addresses".to_string(),
+            ));
+        };
+        // The socket is not bound to an interface, so the connection
+        // migrates when the agent moves to another network
+        let bind_address = match proxy_address {
+            SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+            SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+        };
+        let quic_endpoint = Endpoint::client(bind_address)?;
+        let connection = quic_endpoint
+            .connect_with(
+                quic_client_config.clone(),
+                proxy_address,
+                &proxy_tls.quic_server_name(proxy_address),
+            )
+            .map_err(|e| CommonError::Quic(e.to_string()))?
+            .await
+            .map_err(|e| CommonError::Quic(e.to_string()))?;
+        Ok(QuicConnection::new(connection))
+    }
+    /// Wrap the tcp connection with tls when `proxy_tls` configured
+    async fn wrap_tls(
+        &self,
+        proxy_tcp_stream: TcpStream,
+        proxy_address: SocketAddr,
+        config: &Config,
+    ) -> Result<ProxyStream, AgentError> {
+        let (Some(tls_connector), Some(proxy_tls)) = (&self.tls_connector, config.proxy_tls())
+        else {
+            return Ok(ProxyStream::Tcp(proxy_tcp_stream));
+        };
+        let proxy_tls_stream = match timeout(
+            Duration::from_secs(*config.proxy_connect_timeout()),
+            tls_connector.connect(proxy_tls.server_name(proxy_address)?, proxy_tcp_stream),
+        )
+        .await
+        {
+            Ok(Ok(proxy_tls_stream)) => proxy_tls_stream,
+            Ok(Err(e)) => {
+                error!("Fail to handshake tls with proxy [{proxy_address}]: {e:?}");
+                return Err(e.into());
+            }
+            Err(e) => {
+                error!("Fail to handshake tls with proxy [{proxy_address}] because of timeout");
+                return Err(e.into());
+            }
+        };
+        Ok(ProxyStream::Tls(Box::new(proxy_tls_stream)))
+    }
+}
@@ -1,49 +1,167 @@
 use crate::config::Config;
 use crate::crypto::AgentRsaCryptoHolder;
 use crate::error::AgentError;
+pub use crate::pool::clock::{Clock, SystemClock};
 pub use crate::pool::connection::PooledProxyConnection;
+pub use crate::pool::connector::{ProxyConnector, TcpProxyConnector};
 use crate::pool::pooled::Pooled;
 use crate::pool::unpooled::UnPooled;
-use std::net::{SocketAddr, ToSocketAddrs};
+use crate::stats::ProxyStatsHolder;
+use crate::transport::{ProxyAddress, ProxyTransport};
+use arc_swap::ArcSwap;
+use std::collections::HashMap;
+use std::net::ToSocketAddrs;
 use std::sync::Arc;
-use tokio::net::TcpStream;
+mod clock;
 mod connection;
+mod connector;
 mod pooled;
 mod unpooled;
-fn resolve_proxy_address(config: &Config) -> Result<Vec<SocketAddr>, AgentError> {
+/// Resolve the active proxy address entries with their transports, the pool only
+/// resolves on creation and refresh, so the connect never blocks on the dns
+fn resolve_proxy_address(config: &Config) -> Result<Vec<ProxyAddress>, AgentError> {
     let proxy_addresses = config
-        .proxy_addresses()
+        .active_proxy_addresses()
         .iter()
-        .filter_map(|addr| addr.to_socket_addrs().ok())
+        .filter_map(|addr| {
+            let (transport, addr) = ProxyTransport::parse(addr);
+            let socket_addresses = addr.to_socket_addrs().ok()?;
+            Some(
+                socket_addresses
+                    .map(move |socket_address| ProxyAddress::new(transport, socket_address)),
+            )
+        })
         .flatten()
-        .collect::<Vec<SocketAddr>>();
+        .collect::<Vec<ProxyAddress>>();
 
     Ok(proxy_addresses)
 }
-pub enum ProxyConnectionPool {
-    UnPooled(UnPooled),
-    Pooled(Pooled),
+pub enum ProxyConnectionPool<C = TcpProxyConnector, K = SystemClock>
+where
+    C: ProxyConnector,
+    K: Clock,
+{
+    UnPooled(UnPooled<C, K>),
+    Pooled(Pooled<C, K>),
 }
-impl ProxyConnectionPool {
+impl<C, K> ProxyConnectionPool<C, K>
+where
+    C: ProxyConnector,
+    K: Clock,
+{
     pub async fn new(
-        config: Arc<Config>,
+        config: Arc<ArcSwap<Config>>,
         rsa_crypto_holder: Arc<AgentRsaCryptoHolder>,
+        proxy_stats_holder: Arc<ProxyStatsHolder>,
+        connector: C,
+        clock: K,
     ) -> Result<Self, AgentError> {
-        match *config.proxy_connection_pool_size() {
+        let connector = Arc::new(connector);
+        let clock = Arc::new(clock);
+        let pool_size = *config.load().proxy_connection_pool_size();
+        match pool_size {
             None => Ok(Self::UnPooled(
-                UnPooled::new(config, rsa_crypto_holder).await?,
+                UnPooled::new(config, proxy_stats_holder, connector, clock).await?,
             )),
             Some(pool_size) => Ok(Self::Pooled(
-                Pooled::new(config, pool_size, rsa_crypto_holder).await?,
+                Pooled::new(
+                    config,
+                    pool_size,
+                    rsa_crypto_holder,
+                    proxy_stats_holder,
+                    connector,
+                    clock,
+                )
+                .await?,
             )),
         }
     }
     pub async fn take_proxy_connection(
         &self,
-    ) -> Result<PooledProxyConnection<TcpStream>, AgentError> {
+    ) -> Result<PooledProxyConnection<C::Stream>, AgentError> {
         match self {
             ProxyConnectionPool::UnPooled(un_pooled) => un_pooled.take_proxy_connection().await,
             ProxyConnectionPool::Pooled(pooled) => pooled.take_proxy_connection().await,
         }
     }
+    /// The count of the idle proxy connections in the pool
+    pub fn pool_size(&self) -> usize {
+        match self {
+            ProxyConnectionPool::UnPooled(_) => 0,
+            ProxyConnectionPool::Pooled(pooled) => pooled.pool_size(),
+        }
+    }
+    /// The resolved proxy addresses which the connections are created to
+    pub fn proxy_addresses(&self) -> Vec<ProxyAddress> {
+        match self {
+            ProxyConnectionPool::UnPooled(un_pooled) => un_pooled.proxy_addresses(),
+            ProxyConnectionPool::Pooled(pooled) => pooled.proxy_addresses(),
+        }
+    }
+    /// The count of the idle proxy connections in the pool of each proxy
+    pub fn pool_size_by_proxy(&self) -> HashMap<ProxyAddress, usize> {
+        match self {
+            ProxyConnectionPool::UnPooled(_) => HashMap::new(),
+            ProxyConnectionPool::Pooled(pooled) => pooled.pool_size_by_proxy(),
+        }
+    }
+    /// Close all the idle proxy connections in the pool,
+    /// return the count of the closed connections
+    pub fn drain(&self) -> usize {
+        match self {
+            ProxyConnectionPool::UnPooled(_) => 0,
+            ProxyConnectionPool::Pooled(pooled) => pooled.drain(),
+        }
+    }
+    /// Fill the pool to the max pool size in background
+    pub async fn refill(&self) {
+        if let ProxyConnectionPool::Pooled(pooled) = self {
+            pooled.refill().await;
+        }
+    }
+    /// Resolve the proxy addresses again after the configuration changed,
+    /// the pooled connections to the removed proxies will be closed
+    pub fn refresh_proxy_addresses(&self) -> Result<(), AgentError> {
+        match self {
+            ProxyConnectionPool::UnPooled(un_pooled) => un_pooled.refresh_proxy_addresses(),
+            ProxyConnectionPool::Pooled(pooled) => pooled.refresh_proxy_addresses(),
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn resolve_proxy_address_with_transport() {
+        let mut config = toml::Table::try_from(Config::default()).unwrap();
+        config.insert(
+            "proxy_addresses".to_string(),
+            vec![
+                "127.0.0.1:443".to_string(),
+                "quic://127.0.0.1:443".to_string(),
+            ]
+            .into(),
+        );
+        let config: Config = config.try_into().unwrap();
+        let proxy_addresses = resolve_proxy_address(&config).unwrap();
+        assert_eq!(
+            proxy_addresses,
+            vec![
+                ProxyAddress::new(ProxyTransport::Tcp, "127.0.0.1:443".parse().unwrap()),
+                ProxyAddress::new(ProxyTransport::Quic, "127.0.0.1:443".parse().unwrap()),
+            ]
+        );
+        // The proxy address is written as the entry
+        assert_eq!(
+            proxy_addresses
+                .iter()
+                .map(|proxy_address| proxy_address.to_string())
+                .collect::<Vec<_>>(),
+            config.proxy_addresses().clone()
+        );
+        assert_eq!(
+            "quic://127.0.0.1:443".parse::<ProxyAddress>().unwrap(),
+            proxy_addresses[1]
+        );
+    }
 }
@@ -2,117 +2,233 @@ use crate::codec::ControlPacketCodec;
 use crate::config::Config;
 use crate::crypto::AgentRsaCryptoHolder;
 use crate::error::AgentError;
-use crate::pool::{resolve_proxy_address, PooledProxyConnection};
-use chrono::Utc;
+use crate::pool::{resolve_proxy_address, Clock, PooledProxyConnection, ProxyConnector};
+use crate::stats::ProxyStatsHolder;
+use crate::transport::ProxyAddress;
+use arc_swap::ArcSwap;
 use concurrent_queue::{ConcurrentQueue, PopError, PushError};
 use futures_util::{SinkExt, StreamExt};
 use ppaass_domain::heartbeat::HeartbeatPing;
 use ppaass_domain::{AgentControlPacket, ProxyControlPacket};
-use socket2::{SockRef, TcpKeepalive};
-use std::net::SocketAddr;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
-use std::time::Duration;
-use tokio::net::TcpStream;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::sync::mpsc::{channel, Sender};
 use tokio::time::{sleep, timeout};
 use tokio_util::codec::{Framed, FramedParts};
 use tracing::{debug, error};
+/// The queue of the pooled connections, the count of each proxy is kept on
+/// push and pop, so it is read without taking the connections out of the queue
+struct ProxyConnectionQueue<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    queue: ConcurrentQueue<PooledProxyConnection<S>>,
+    size_by_proxy: RwLock<HashMap<ProxyAddress, AtomicUsize>>,
+}
+impl<S> ProxyConnectionQueue<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn bounded(max_pool_size: usize) -> Self {
+        Self {
+            queue: ConcurrentQueue::bounded(max_pool_size),
+            size_by_proxy: RwLock::new(HashMap::new()),
+        }
+    }
+    fn len(&self) -> usize {
+        self.queue.len()
+    }
+    fn push(
+        &self,
+        proxy_connection: PooledProxyConnection<S>,
+    ) -> Result<(), PushError<PooledProxyConnection<S>>> {
+        let proxy_address = proxy_connection.proxy_address();
+        // Count before push, so the concurrent pop never counts below zero
+        self.update_size(proxy_address, |size| {
+            size.fetch_add(1, Ordering::Relaxed);
+        });
+        let result = self.queue.push(proxy_connection);
+        if result.is_err() {
+            self.update_size(proxy_address, |size| {
+                size.fetch_sub(1, Ordering::Relaxed);
+            });
+        }
+        result
+    }
+    fn pop(&self) -> Result<PooledProxyConnection<S>, PopError> {
+        let proxy_connection = self.queue.pop()?;
+        self.update_size(proxy_connection.proxy_address(), |size| {
+            size.fetch_sub(1, Ordering::Relaxed);
+        });
+        Ok(proxy_connection)
+    }
+    fn update_size(&self, proxy_address: ProxyAddress, update: impl Fn(&AtomicUsize)) {
+        if let Some(size) = self
+            .size_by_proxy
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .get(&proxy_address)
+        {
+            update(size);
+            return;
+        }
+        let mut size_by_proxy = self
+            .size_by_proxy
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        update(size_by_proxy.entry(proxy_address).or_default());
+    }
+    fn size_by_proxy(&self) -> HashMap<ProxyAddress, usize> {
+        self.size_by_proxy
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .iter()
+            .map(|(proxy_address, size)| (*proxy_address, size.load(Ordering::Relaxed)))
+            .filter(|(_, size)| *size > 0)
+            .collect()
+    }
+}
 /// The connection pool for proxy connection.
-pub struct Pooled {
+/// C is the connector to build the transport to proxy,
+/// K is the clock to decide when to check or close a proxy connection
+pub struct Pooled<C, K>
+where
+    C: ProxyConnector,
+    K: Clock,
+{
     /// The pool to store the proxy connection
-    pool: Arc<ConcurrentQueue<PooledProxyConnection<TcpStream>>>,
+    pool: Arc<ProxyConnectionQueue<C::Stream>>,
     /// The configuration
-    config: Arc<Config>,
+    config: Arc<ArcSwap<Config>>,
     /// The proxy addresses
-    proxy_addresses: Arc<Vec<SocketAddr>>,
+    proxy_addresses: Arc<ArcSwap<Vec<ProxyAddress>>>,
     /// The max pool size
     max_pool_size: usize,
     /// The rsa crypto holder used to store the rsa crypto
     rsa_crypto_holder: Arc<AgentRsaCryptoHolder>,
     /// If the filling process is happening
     filling: Arc<AtomicBool>,
+    /// The quality statistics of the proxies
+    proxy_stats_holder: Arc<ProxyStatsHolder>,
+    /// The connector to build the transport to proxy
+    connector: Arc<C>,
+    /// The clock of the pool
+    clock: Arc<K>,
 }
-impl Pooled {
+impl<C, K> Clone for Pooled<C, K>
+where
+    C: ProxyConnector,
+    K: Clock,
+{
+    fn clone(&self) -> Self {
+        Self {
+            pool: self.pool.clone(),
+            config: self.config.clone(),
+            proxy_addresses: self.proxy_addresses.clone(),
+            max_pool_size: self.max_pool_size,
+            rsa_crypto_holder: self.rsa_crypto_holder.clone(),
+            filling: self.filling.clone(),
+            proxy_stats_holder: self.proxy_stats_holder.clone(),
+            connector: self.connector.clone(),
+            clock: self.clock.clone(),
+        }
+    }
+}
+impl<C, K> Pooled<C, K>
+where
+    C: ProxyConnector,
+    K: Clock,
+{
     /// Create the proxy connection pool
     pub async fn new(
-        config: Arc<Config>,
+        config: Arc<ArcSwap<Config>>,
         max_pool_size: usize,
         rsa_crypto_holder: Arc<AgentRsaCryptoHolder>,
+        proxy_stats_holder: Arc<ProxyStatsHolder>,
+        connector: Arc<C>,
+        clock: Arc<K>,
     ) -> Result<Self, AgentError> {
-        let proxy_addresses = Arc::new(resolve_proxy_address(&config)?);
-        let pool = Arc::new(ConcurrentQueue::bounded(max_pool_size));
-        let proxy_addresses = proxy_addresses.clone();
-        let filling = Arc::new(AtomicBool::new(false));
-        match &config.proxy_connection_pool_fill_interval() {
+        let proxy_addresses = Arc::new(ArcSwap::from_pointee(resolve_proxy_address(
+            &config.load(),
+        )?));
+        let pooled = Self {
+            pool: Arc::new(ProxyConnectionQueue::bounded(max_pool_size)),
+            config: config.clone(),
+            proxy_addresses,
+            max_pool_size,
+            rsa_crypto_holder,
+            filling: Arc::new(AtomicBool::new(false)),
+            proxy_stats_holder,
+            connector,
+            clock,
+        };
+        let current_config = config.load_full();
+        match current_config.proxy_connection_pool_fill_interval() {
             None => {
-                Self::fill_pool(
-                    pool.clone(),
-                    proxy_addresses.clone(),
-                    config.clone(),
-                    max_pool_size,
-                    filling.clone(),
-                )
-                .await;
+                pooled.fill_pool().await;
             }
             Some(interval) => {
-                let config = config.clone();
                 let interval = *interval;
-                let pool = pool.clone();
-                let proxy_addresses = proxy_addresses.clone();
-                let filling = filling.clone();
+                let pooled = pooled.clone();
                 tokio::spawn(async move {
                     loop {
                         debug!("Starting connection pool auto filling loop.");
-                        Self::fill_pool(
-                            pool.clone(),
-                            proxy_addresses.clone(),
-                            config.clone(),
-                            max_pool_size,
-                            filling.clone(),
-                        )
-                        .await;
+                        pooled.fill_pool().await;
                         sleep(Duration::from_secs(interval)).await;
                     }
                 });
             }
         }
-        if *config.proxy_connection_start_check_timer() {
-            let config = config.clone();
-            let rsa_crypto_holder = rsa_crypto_holder.clone();
-            let pool = pool.clone();
-            let filling = filling.clone();
-            Self::start_connection_check_task(
-                config,
-                rsa_crypto_holder,
-                pool,
-                filling,
-                max_pool_size,
-            );
+        if *current_config.proxy_connection_start_check_timer() {
+            pooled.start_connection_check_task();
         }
-        Ok(Self {
-            pool,
-            config,
-            proxy_addresses,
-            max_pool_size,
-            rsa_crypto_holder,
-            filling,
-        })
+        Ok(pooled)
+    }
+    /// The current size of the pool
+    pub fn pool_size(&self) -> usize {
+        self.pool.len()
+    }
+    /// The resolved proxy addresses
+    pub fn proxy_addresses(&self) -> Vec<ProxyAddress> {
+        self.proxy_addresses.load().to_vec()
+    }
+    /// The count of the pooled connections of each proxy
+    pub fn pool_size_by_proxy(&self) -> HashMap<ProxyAddress, usize> {
+        self.pool.size_by_proxy()
+    }
+    /// Close all the pooled connections, return the count of the closed connections
+    pub fn drain(&self) -> usize {
+        let mut drained = 0;
+        while self.pool.pop().is_ok() {
+            drained += 1;
+        }
+        debug!("Drain {drained} proxy connections from pool.");
+        drained
+    }
+    /// Fill the pool to the max pool size in background
+    pub async fn refill(&self) {
+        self.fill_pool().await;
     }
     /// Start the task to check connection activity
-    fn start_connection_check_task(
-        config: Arc<Config>,
-        rsa_crypto_holder: Arc<AgentRsaCryptoHolder>,
-        pool: Arc<ConcurrentQueue<PooledProxyConnection<TcpStream>>>,
-        filling: Arc<AtomicBool>,
-        max_pool_size: usize,
-    ) {
+    fn start_connection_check_task(&self) {
+        let pooled = self.clone();
         tokio::spawn(async move {
+            let Pooled {
+                pool,
+                config,
+                filling,
+                max_pool_size,
+                clock,
+                ..
+            } = &pooled;
             loop {
                 if filling.load(Ordering::Relaxed) {
                     debug!("Cancel checking proxy connection pool, because of filling loop is in parallel.");
                     sleep(Duration::from_secs(
-                        *config.proxy_connection_start_check_timer_interval(),
+                        *config.load().proxy_connection_start_check_timer_interval(),
                     ))
                     .await;
                     continue;
@@ -122,7 +238,7 @@ impl Pooled {
                     pool.len()
                 );
                 let (checking_tx, mut checking_rx) =
-                    channel::<PooledProxyConnection<TcpStream>>(max_pool_size);
+                    channel::<PooledProxyConnection<C::Stream>>(*max_pool_size);
                 'checking_single: loop {
                     let proxy_connection = match pool.pop() {
                         Ok(proxy_connection) => proxy_connection,
@@ -135,33 +251,29 @@ impl Pooled {
                             break 'checking_single;
                         }
                     };
-                    if !proxy_connection.need_check() {
+                    let now = clock.now();
+                    let current_config = config.load_full();
+                    if !proxy_connection.need_check(&current_config, now) {
                         if let Err(e) = checking_tx.send(proxy_connection).await {
                             error!("Fail to push proxy connection back to pool: {}", e);
                         }
                         continue 'checking_single;
                     }
-                    if proxy_connection.need_close() {
+                    if proxy_connection.need_close(&current_config, now) {
                         debug!("Close proxy connection because of it exceed max life time: {proxy_connection:?}");
                         continue 'checking_single;
                     }
                     let checking_tx = checking_tx.clone();
-                    let config = config.clone();
-                    let rsa_crypto_holder = rsa_crypto_holder.clone();
+                    let pooled = pooled.clone();
                     tokio::spawn(async move {
-                        let proxy_connection = match Self::check_proxy_connection(
-                            proxy_connection,
-                            &config,
-                            rsa_crypto_holder.clone(),
-                        )
-                        .await
-                        {
-                            Ok(proxy_connection) => proxy_connection,
-                            Err(e) => {
-                                error!("Failed to check proxy connection: {}", e);
-                                return;
-                            }
-                        };
+                        let proxy_connection =
+                            match pooled.check_proxy_connection(proxy_connection).await {
+                                Ok(proxy_connection) => proxy_connection,
+                                Err(e) => {
+                                    error!("Failed to check proxy connection: {}", e);
+                                    return;
+                                }
+                            };
                         if let Err(e) = checking_tx.send(proxy_connection).await {
                             error!("Fail to push proxy connection back to pool: {}", e);
                         };
@@ -188,103 +300,21 @@ impl Pooled {
                     };
                 }
                 sleep(Duration::from_secs(
-                    *config.proxy_connection_start_check_timer_interval(),
+                    *config.load().proxy_connection_start_check_timer_interval(),
                 ))
                 .await;
             }
         });
     }
+    /// Take a proxy connection from the pool, the connection will be checked
+    /// before return if it is not checked for a long time
     pub async fn take_proxy_connection(
         &self,
-    ) -> Result<PooledProxyConnection<TcpStream>, AgentError> {
-        Self::concrete_take_proxy_connection(
-            self.pool.clone(),
-            self.proxy_addresses.clone(),
-            self.config.clone(),
-            self.max_pool_size,
-            self.rsa_crypto_holder.clone(),
-            self.filling.clone(),
-        )
-        .await
-    }
-    async fn create_proxy_tcp_stream(
-        config: Arc<Config>,
-        proxy_addresses: Arc<Vec<SocketAddr>>,
-        proxy_connection_tx: Sender<PooledProxyConnection<TcpStream>>,
-    ) -> Result<(), AgentError> {
-        let random_proxy_addr_index = rand::random::<usize>() % proxy_addresses.len();
-        let proxy_address = &proxy_addresses[random_proxy_addr_index];
-        debug!("Creating proxy tcp stream on: {proxy_address}");
-        let proxy_tcp_stream = match timeout(
-            Duration::from_secs(*config.proxy_connect_timeout()),
-            TcpStream::connect(proxy_address),
-        )
-        .await
-        {
-            Ok(Ok(proxy_tcp_stream)) => proxy_tcp_stream,
-            Ok(Err(e)) => {
-                error!("Fail connect to proxy: {e:?}");
-                return Err(e.into());
-            }
-            Err(e) => {
-                error!(
-                    "Fail connect to proxy because of timeout: {}",
-                    *config.proxy_connect_timeout()
-                );
-                return Err(e.into());
-            }
-        };
-        let proxy_socket = SockRef::from(&proxy_tcp_stream);
-        if *config.proxy_connection_tcp_keepalive() {
-            let keepalive = TcpKeepalive::new()
-                .with_interval(Duration::from_secs(
-                    config
-                        .proxy_connection_tcp_keepalive_interval()
-                        .ok_or(AgentError::Unknown("Fail to create proxy connection tcp socket becauause of no keepalive interval provided".to_string()))?,
-                ))
-                .with_time(Duration::from_secs(
-                    config.proxy_connection_tcp_keepalive_time().ok_or(AgentError::Unknown("Fail to create proxy connection tcp socket becauause of no keepalive time provided".to_string()))?
-                ));
-            proxy_socket.set_tcp_keepalive(&keepalive)?;
-        }
-
-        proxy_socket.set_linger(None)?;
-        proxy_socket.set_nodelay(true)?;
-        if let Some(buffer_size) = config.proxy_socket_receive_buffer_size() {
-            proxy_socket.set_recv_buffer_size(*buffer_size)?;
-        }
-        if let Some(buffer_size) = config.proxy_socket_send_buffer_size() {
-            proxy_socket.set_send_buffer_size(*buffer_size)?;
-        }
-        if let Some(timeout) = config.proxy_connection_read_timeout() {
-            proxy_socket.set_read_timeout(Some(Duration::from_secs(*timeout)))?;
-        }
-        if let Some(timeout) = config.proxy_connection_write_timeout() {
-            proxy_socket.set_write_timeout(Some(Duration::from_secs(*timeout)))?;
-        }
-        debug!("Create proxy connection: {proxy_tcp_stream:?}");
-        proxy_connection_tx
-            .send(PooledProxyConnection::new(proxy_tcp_stream, config))
-            .await
-            .map_err(|_| {
-                AgentError::ProxyConnectionPool("Fail to send proxy connection".to_string())
-            })?;
-        Ok(())
-    }
-    /// The concrete take proxy connection implementation
-    async fn concrete_take_proxy_connection(
-        pool: Arc<ConcurrentQueue<PooledProxyConnection<TcpStream>>>,
-        proxy_addresses: Arc<Vec<SocketAddr>>,
-        config: Arc<Config>,
-        pool_size: usize,
-        rsa_crypto_holder: Arc<AgentRsaCryptoHolder>,
-        filling: Arc<AtomicBool>,
-    ) -> Result<PooledProxyConnection<TcpStream>, AgentError> {
+    ) -> Result<PooledProxyConnection<C::Stream>, AgentError> {
         loop {
-            let pool = pool.clone();
-            let current_pool_size = pool.len();
+            let current_pool_size = self.pool.len();
             debug!("Taking proxy connection, current pool size: {current_pool_size}");
-            let proxy_connection = pool.pop();
+            let proxy_connection = self.pool.pop();
             match proxy_connection {
                 Err(PopError::Closed) => {
                     return Err(AgentError::ProxyConnectionPool(
@@ -293,59 +323,108 @@ impl Pooled {
                 }
                 Err(PopError::Empty) => {
                     debug!("No proxy connection available, current pool size: {current_pool_size}");
-                    Self::fill_pool(
-                        pool,
-                        proxy_addresses.clone(),
-                        config.clone(),
-                        pool_size,
-                        filling.clone(),
-                    )
-                    .await;
+                    self.fill_pool().await;
                     sleep(Duration::from_secs(
-                        *config.proxy_connection_retake_interval(),
+                        *self.config.load().proxy_connection_retake_interval(),
                     ))
                     .await;
                     continue;
                 }
                 Ok(proxy_connection) => {
                     debug!("Proxy connection available, current pool size before take: {current_pool_size}");
-                    if !proxy_connection.need_check() {
+                    let now = self.clock.now();
+                    let current_config = self.config.load_full();
+                    if proxy_connection.need_close(&current_config, now) {
+                        debug!("Drop proxy connection because of it exceed max life time: {proxy_connection:?}");
+                        continue;
+                    }
+                    if !proxy_connection.need_check(&current_config, now) {
                         debug!("No need to do proxy connection check: {proxy_connection:?}");
                         return Ok(proxy_connection);
-                    } else {
-                        match Self::check_proxy_connection(
-                            proxy_connection,
-                            &config,
-                            rsa_crypto_holder.clone(),
-                        )
-                        .await
-                        {
-                            Ok(proxy_connection) => return Ok(proxy_connection),
-                            Err(e) => {
-                                error!("Failed to check proxy connection: {e}");
-                                continue;
-                            }
+                    }
+                    match self.check_proxy_connection(proxy_connection).await {
+                        Ok(proxy_connection) => return Ok(proxy_connection),
+                        Err(e) => {
+                            error!("Failed to check proxy connection: {e}");
+                            continue;
                         }
                     }
                 }
             }
         }
     }
-    /// Check the proxy connection with sending a ping-pong messasge between agent and proxy
+    async fn create_proxy_connection(
+        self,
+        proxy_connection_tx: Sender<PooledProxyConnection<C::Stream>>,
+    ) -> Result<(), AgentError> {
+        if self.proxy_addresses.load().is_empty() {
+            // None of the proxy addresses resolved before, for example the dns failed on start
+            self.refresh_proxy_addresses()?;
+        }
+        let proxy_address = *self
+            .proxy_stats_holder
+            .select_proxy_address(&self.proxy_addresses.load())
+            .ok_or(AgentError::ProxyConnectionPool(
+                "No proxy address available.".to_string(),
+            ))?;
+        let proxy_stream = match self.connector.connect(proxy_address).await {
+            Ok(proxy_stream) => proxy_stream,
+            Err(e) => {
+                self.proxy_stats_holder.record_check_failure(proxy_address);
+                return Err(e);
+            }
+        };
+        // Ping the new connection at once, the proxy closes the new connection
+        // without any control packet received in its `first_byte` deadline
+        let proxy_connection = self
+            .check_proxy_connection(PooledProxyConnection::new(
+                proxy_stream,
+                proxy_address,
+                self.clock.now(),
+            ))
+            .await?;
+        proxy_connection_tx
+            .send(proxy_connection)
+            .await
+            .map_err(|_| {
+                AgentError::ProxyConnectionPool("Fail to send proxy connection".to_string())
+            })?;
+        Ok(())
+    }
+    /// Check the proxy connection, the proxy is marked unhealthy when the check fails
     async fn check_proxy_connection(
-        proxy_connection: PooledProxyConnection<TcpStream>,
-        config: &Config,
-        rsa_crypto_holder: Arc<AgentRsaCryptoHolder>,
-    ) -> Result<PooledProxyConnection<TcpStream>, AgentError> {
+        &self,
+        proxy_connection: PooledProxyConnection<C::Stream>,
+    ) -> Result<PooledProxyConnection<C::Stream>, AgentError> {
+        let proxy_address = proxy_connection.proxy_address();
+        let check_result = self.ping_proxy_connection(proxy_connection).await;
+        if check_result.is_err() {
+            self.proxy_stats_holder.record_check_failure(proxy_address);
+        }
+        check_result
+    }
+    /// Check the proxy connection with sending a ping-pong messasge between agent and proxy,
+    /// the timing of the ping-pong will be recorded into the proxy quality statistics
+    async fn ping_proxy_connection(
+        &self,
+        proxy_connection: PooledProxyConnection<C::Stream>,
+    ) -> Result<PooledProxyConnection<C::Stream>, AgentError> {
         debug!("Checking proxy connection : {proxy_connection:?}");
-        let rsa_crypto_holder = rsa_crypto_holder.clone();
+        let proxy_address = proxy_connection.proxy_address();
+        let config = self.config.load_full();
         let mut proxy_ctl_framed = Framed::new(
             proxy_connection,
-            ControlPacketCodec::new(config.auth_token().to_owned(), rsa_crypto_holder.clone()),
+            ControlPacketCodec::new(
+                config.auth_token().to_owned(),
+                self.rsa_crypto_holder.clone(),
+            ),
         );
+        let ping_time = self.clock.now();
+        let ping_instant = Instant::now();
+        self.proxy_stats_holder.record_ping(proxy_address);
         proxy_ctl_framed
             .send(AgentControlPacket::Heartbeat(HeartbeatPing {
-                heartbeat_time: Utc::now(),
+                heartbeat_time: ping_time,
             }))
             .await?;
         let pong_packet = match timeout(
@@ -370,41 +449,76 @@ impl Pooled {
             Ok(Some(Ok(pong_packet))) => pong_packet,
         };
         match pong_packet {
-            ProxyControlPacket::TunnelInit(_) => {
+            ProxyControlPacket::TunnelInit(_) | ProxyControlPacket::TunnelInitFail(_) => {
                 error!("Fail to send heartbeat ping to proxy because of receive invalid control packet from proxy.");
                 Err(AgentError::InvalidProxyDataType)
             }
             ProxyControlPacket::Heartbeat(pong) => {
                 debug!("Received heartbeat from {pong:?}");
+                // The pong of the older proxy can't be paired
+                if pong
+                    .ping_heartbeat_time
+                    .is_some_and(|ping_heartbeat_time| ping_heartbeat_time != ping_time)
+                {
+                    error!("Fail to pair heartbeat pong with ping, ping time: {ping_time}, pong: {pong:?}");
+                    return Err(AgentError::InvalidProxyDataType);
+                }
+                self.proxy_stats_holder.record_pong(
+                    proxy_address,
+                    ping_time,
+                    ping_instant.elapsed(),
+                    pong.heartbeat_time,
+                );
                 let FramedParts {
                     io: mut proxy_connection,
                     ..
                 } = proxy_ctl_framed.into_parts();
-                proxy_connection.update_check_time();
+                proxy_connection.update_check_time(self.clock.now());
                 Ok(proxy_connection)
             }
         }
     }
+    /// Resolve the proxy addresses again with the current configuration,
+    /// the pooled connections to the removed proxies will be closed
+    pub fn refresh_proxy_addresses(&self) -> Result<(), AgentError> {
+        let proxy_addresses = resolve_proxy_address(&self.config.load())?;
+        let mut retained_connections = Vec::new();
+        while let Ok(proxy_connection) = self.pool.pop() {
+            if proxy_addresses.contains(&proxy_connection.proxy_address()) {
+                retained_connections.push(proxy_connection);
+                continue;
+            }
+            debug!("Close proxy connection because of proxy address removed: {proxy_connection:?}");
+        }
+        self.proxy_addresses.store(Arc::new(proxy_addresses));
+        for proxy_connection in retained_connections {
+            if let Err(e) = self.pool.push(proxy_connection) {
+                error!("Fail to push proxy connection back to pool after refresh proxy addresses: {e:?}");
+            }
+        }
+        Ok(())
+    }
     /// Fill the pool with proxy connection
-    async fn fill_pool(
-        pool: Arc<ConcurrentQueue<PooledProxyConnection<TcpStream>>>,
-        proxy_addresses: Arc<Vec<SocketAddr>>,
-        config: Arc<Config>,
-        max_pool_size: usize,
-        filling: Arc<AtomicBool>,
-    ) {
-        if pool.len() == max_pool_size {
+    async fn fill_pool(&self) {
+        if self.pool.len() == self.max_pool_size {
             debug!("Cancel filling proxy connection pool, no need to start filling task(outside task).");
             return;
         }
+        let pooled = self.clone();
         tokio::spawn(async move {
+            let Pooled {
+                pool,
+                filling,
+                max_pool_size,
+                ..
+            } = &pooled;
             if filling.load(Ordering::Relaxed) {
                 debug!(
                     "Cancel filling proxy connection pool, because of filling process is running."
                 );
                 return;
             }
-            if pool.len() == max_pool_size {
+            if pool.len() == *max_pool_size {
                 debug!(
                     "Cancel filling proxy connection pool, no need to start filling task(inside task)."
                 );
@@ -413,16 +527,17 @@ impl Pooled {
             debug!("Begin to fill proxy connection pool");
             filling.store(true, Ordering::Relaxed);
             let (proxy_connection_tx, mut proxy_connection_rx) =
-                channel::<PooledProxyConnection<TcpStream>>(max_pool_size);
+                channel::<PooledProxyConnection<C::Stream>>(*max_pool_size);
             let current_pool_size = pool.len();
             debug!("Current pool size: {current_pool_size}");
-            for _ in current_pool_size..max_pool_size {
-                let proxy_addresses = proxy_addresses.clone();
-                tokio::spawn(Self::create_proxy_tcp_stream(
-                    config.clone(),
-                    proxy_addresses,
-                    proxy_connection_tx.clone(),
-                ));
+            for _ in current_pool_size..*max_pool_size {
+                let proxy_connection_tx = proxy_connection_tx.clone();
+                let pooled = pooled.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = pooled.create_proxy_connection(proxy_connection_tx).await {
+                        error!("Fail to create proxy connection: {e:?}");
+                    }
+                });
             }
             drop(proxy_connection_tx);
             debug!("Waiting for proxy connection creation");
@@ -446,3 +561,226 @@ impl Pooled {
         });
     }
 }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{DateTime, TimeDelta, Utc};
+    use ppaass_codec::{AgentControlPacketDecoder, ProxyControlPacketEncoder};
+    use ppaass_domain::heartbeat::HeartbeatPong;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Mutex;
+    use tokio::io::{duplex, split, DuplexStream};
+    use tokio_util::codec::{FramedRead, FramedWrite};
+    /// The connector build in-memory transport with a fake proxy answering heartbeat
+    struct DuplexProxyConnector {
+        connect_count: AtomicUsize,
+        rsa_crypto_holder: Arc<AgentRsaCryptoHolder>,
+    }
+    impl ProxyConnector for DuplexProxyConnector {
+        type Stream = DuplexStream;
+        async fn connect(&self, _proxy_address: ProxyAddress) -> Result<DuplexStream, AgentError> {
+            self.connect_count.fetch_add(1, Ordering::Relaxed);
+            let (agent_stream, proxy_stream) = duplex(4096);
+            let rsa_crypto_holder = self.rsa_crypto_holder.clone();
+            tokio::spawn(async move {
+                let (proxy_read, proxy_write) = split(proxy_stream);
+                let mut ping_rx = FramedRead::new(
+                    proxy_read,
+                    AgentControlPacketDecoder::new(rsa_crypto_holder.clone()),
+                );
+                let mut pong_tx = FramedWrite::new(
+                    proxy_write,
+                    ProxyControlPacketEncoder::new(rsa_crypto_holder),
+                );
+                while let Some(Ok(AgentControlPacket::Heartbeat(ping))) = ping_rx.next().await {
+                    let pong = ProxyControlPacket::Heartbeat(HeartbeatPong::reply(&ping));
+                    if pong_tx.send(pong).await.is_err() {
+                        return;
+                    }
+                }
+            });
+            Ok(agent_stream)
+        }
+    }
+    /// The clock only moves when the test advances it
+    struct ManualClock {
+        now: Mutex<DateTime<Utc>>,
+    }
+    impl ManualClock {
+        fn advance(&self, seconds: i64) {
+            let mut now = self.now.lock().unwrap();
+            *now += TimeDelta::seconds(seconds);
+        }
+    }
+    impl Clock for ManualClock {
+        fn now(&self) -> DateTime<Utc> {
+            *self.now.lock().unwrap()
+        }
+    }
+    struct TestPool {
+        pooled: Pooled<DuplexProxyConnector, ManualClock>,
+        connector: Arc<DuplexProxyConnector>,
+        clock: Arc<ManualClock>,
+        proxy_stats_holder: Arc<ProxyStatsHolder>,
+    }
+    fn test_config(start_check_timer: bool) -> Arc<ArcSwap<Config>> {
+        let mut config = toml::Table::try_from(Config::default()).unwrap();
+        config.insert(
+            "rsa_dir".to_string(),
+            concat!(env!("CARGO_MANIFEST_DIR"), "/../../resources/agent/rsa").into(),
+        );
+        config.insert(
+            "proxy_addresses".to_string(),
+            vec!["127.0.0.1:80".to_string()].into(),
+        );
+        config.insert(
+            "proxy_connection_start_check_timer".to_string(),
+            start_check_timer.into(),
+        );
+        config.remove("proxy_connection_pool_fill_interval");
+        Arc::new(ArcSwap::from_pointee(config.try_into().unwrap()))
+    }
+    async fn new_test_pool(max_pool_size: usize, start_check_timer: bool) -> TestPool {
+        let config = test_config(start_check_timer);
+        let rsa_crypto_holder = Arc::new(AgentRsaCryptoHolder::new(config.load_full()).unwrap());
+        let connector = Arc::new(DuplexProxyConnector {
+            connect_count: AtomicUsize::new(0),
+            rsa_crypto_holder: rsa_crypto_holder.clone(),
+        });
+        let clock = Arc::new(ManualClock {
+            now: Mutex::new(Utc::now()),
+        });
+        let proxy_stats_holder = Arc::new(ProxyStatsHolder::new());
+        let pooled = Pooled::new(
+            config,
+            max_pool_size,
+            rsa_crypto_holder,
+            proxy_stats_holder.clone(),
+            connector.clone(),
+            clock.clone(),
+        )
+        .await
+        .unwrap();
+        TestPool {
+            pooled,
+            connector,
+            clock,
+            proxy_stats_holder,
+        }
+    }
+    async fn wait_pool_size(pooled: &Pooled<DuplexProxyConnector, ManualClock>, size: usize) {
+        timeout(Duration::from_secs(3600), async {
+            while pooled.pool_size() != size {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap_or_else(|_| {
+            panic!(
+                "Pool size not reach {size}, current: {}",
+                pooled.pool_size()
+            )
+        });
+    }
+    #[tokio::test(start_paused = true)]
+    async fn fill_pool_to_max_size() {
+        let test_pool = new_test_pool(4, false).await;
+        wait_pool_size(&test_pool.pooled, 4).await;
+        assert_eq!(test_pool.connector.connect_count.load(Ordering::Relaxed), 4);
+    }
+    #[tokio::test(start_paused = true)]
+    async fn take_fresh_connection_without_check() {
+        let test_pool = new_test_pool(4, false).await;
+        wait_pool_size(&test_pool.pooled, 4).await;
+        let proxy_connection = test_pool.pooled.take_proxy_connection().await.unwrap();
+        assert_eq!(test_pool.pooled.pool_size(), 3);
+        assert_eq!(
+            *proxy_connection.last_check_time(),
+            test_pool.clock.now(),
+            "Fresh connection should not be checked"
+        );
+        // Only the pings of the connection creation
+        let proxy_stats = test_pool
+            .proxy_stats_holder
+            .get(&proxy_connection.proxy_address())
+            .unwrap();
+        assert_eq!(proxy_stats.ping_count, 4);
+        assert_eq!(proxy_stats.pong_count, 4);
+    }
+    #[tokio::test(start_paused = true)]
+    async fn count_pool_size_by_proxy() {
+        let test_pool = new_test_pool(4, false).await;
+        wait_pool_size(&test_pool.pooled, 4).await;
+        let proxy_address = "127.0.0.1:80".parse::<ProxyAddress>().unwrap();
+        assert_eq!(
+            test_pool.pooled.pool_size_by_proxy(),
+            HashMap::from([(proxy_address, 4)])
+        );
+        let _proxy_connection = test_pool.pooled.take_proxy_connection().await.unwrap();
+        assert_eq!(
+            test_pool.pooled.pool_size_by_proxy(),
+            HashMap::from([(proxy_address, 3)])
+        );
+        assert_eq!(test_pool.pooled.drain(), 3);
+        assert!(test_pool.pooled.pool_size_by_proxy().is_empty());
+    }
+    #[tokio::test(start_paused = true)]
+    async fn take_stale_connection_with_check() {
+        let test_pool = new_test_pool(2, false).await;
+        wait_pool_size(&test_pool.pooled, 2).await;
+        test_pool.clock.advance(61);
+        let proxy_connection = test_pool.pooled.take_proxy_connection().await.unwrap();
+        assert_eq!(*proxy_connection.last_check_time(), test_pool.clock.now());
+        let proxy_stats = test_pool
+            .proxy_stats_holder
+            .get(&proxy_connection.proxy_address())
+            .unwrap();
+        // The pings of the connection creation and the check
+        assert_eq!(proxy_stats.ping_count, 3);
+        assert_eq!(proxy_stats.pong_count, 3);
+        assert!(proxy_stats.ewma_rtt.is_some());
+    }
+    #[tokio::test(start_paused = true)]
+    async fn take_replaces_expired_connection() {
+        let test_pool = new_test_pool(1, false).await;
+        wait_pool_size(&test_pool.pooled, 1).await;
+        test_pool.clock.advance(301);
+        let proxy_connection = test_pool.pooled.take_proxy_connection().await.unwrap();
+        assert_eq!(test_pool.connector.connect_count.load(Ordering::Relaxed), 2);
+        assert!(
+            !proxy_connection.need_close(&test_pool.pooled.config.load(), test_pool.clock.now())
+        );
+    }
+    #[tokio::test(start_paused = true)]
+    async fn check_task_closes_expired_connections() {
+        let test_pool = new_test_pool(2, true).await;
+        wait_pool_size(&test_pool.pooled, 2).await;
+        test_pool.clock.advance(301);
+        wait_pool_size(&test_pool.pooled, 0).await;
+        assert_eq!(test_pool.connector.connect_count.load(Ordering::Relaxed), 2);
+    }
+    #[tokio::test(start_paused = true)]
+    async fn check_task_keeps_checked_connections() {
+        let test_pool = new_test_pool(2, true).await;
+        wait_pool_size(&test_pool.pooled, 2).await;
+        test_pool.clock.advance(61);
+        timeout(Duration::from_secs(3600), async {
+            loop {
+                let pong_count = test_pool
+                    .proxy_stats_holder
+                    .snapshot()
+                    .values()
+                    .map(|proxy_stats| proxy_stats.pong_count)
+                    .sum::<u64>();
+                // The pongs of the connection creation and the check
+                if pong_count == 4 && test_pool.pooled.pool_size() == 2 {
+                    break;
+                }
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(test_pool.connector.connect_count.load(Ordering::Relaxed), 2);
+    }
+}
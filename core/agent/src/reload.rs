@@ -0,0 +1,240 @@
+use crate::bo::event::AgentServerEvent;
+use crate::bo::state::ServerState;
+use crate::config::Config;
+use crate::error::AgentError;
+use crate::publish_server_event;
+use ppaass_codec::RsaCryptoReloadReport;
+use ppaass_common::reload_trigger::listen_reload_trigger;
+use ppaass_common::LogLevelHandle;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, warn};
+/// The result of a configuration reload, the configuration file and
+/// the rsa directory are reloaded independently
+#[derive(Debug)]
+pub struct ConfigReloadReport {
+    /// The changed fields which can only take effect after restart,
+    /// the error when the configuration file can not be loaded
+    pub restart_required_changes: Result<Vec<&'static str>, AgentError>,
+    /// The users changed in the rsa directory
+    pub rsa_crypto: Result<RsaCryptoReloadReport, AgentError>,
+}
+/// Reload the configuration file and the rsa directory into the running server
+pub struct ConfigReloader {
+    config_file_path: PathBuf,
+    server_state: ServerState,
+    log_level_handle: Option<LogLevelHandle>,
+}
+impl ConfigReloader {
+    pub fn new(
+        config_file_path: PathBuf,
+        server_state: ServerState,
+        log_level_handle: Option<LogLevelHandle>,
+    ) -> Self {
+        Self {
+            config_file_path,
+            server_state,
+            log_level_handle,
+        }
+    }
+    /// Reload the configuration file and the rsa directory, a broken
+    /// configuration file does not stop the rsa directory from reloading
+    pub fn reload(&self) -> ConfigReloadReport {
+        let restart_required_changes = self.reload_config();
+        let server_event = match &restart_required_changes {
+            Ok(restart_required_changes) => AgentServerEvent::ConfigReloaded {
+                restart_required_changes: restart_required_changes
+                    .iter()
+                    .map(|field| field.to_string())
+                    .collect(),
+            },
+            Err(e) => AgentServerEvent::ConfigReloadFail {
+                reason: e.to_string(),
+            },
+        };
+        publish_server_event(self.server_state.server_event_tx(), server_event);
+        let rsa_crypto = self.reload_rsa_crypto();
+        let server_event = match &rsa_crypto {
+            Ok(rsa_crypto) => AgentServerEvent::RsaCryptoReloaded {
+                added_users: rsa_crypto.added_users.clone(),
+                removed_users: rsa_crypto.removed_users.clone(),
+            },
+            Err(e) => AgentServerEvent::RsaCryptoReloadFail {
+                reason: e.to_string(),
+            },
+        };
+        publish_server_event(self.server_state.server_event_tx(), server_event);
+        ConfigReloadReport {
+            restart_required_changes,
+            rsa_crypto,
+        }
+    }
+    /// Load and validate the configuration file, then swap it into the server,
+    /// the existing tunnels keep going with the old configuration.
+    fn reload_config(&self) -> Result<Vec<&'static str>, AgentError> {
+        let new_config = Config::load(&self.config_file_path)?;
+        let current_config = self.server_state.config();
+        let restart_required_changes = current_config.restart_required_changes(&new_config);
+        let log_level_changed = current_config.max_log_level() != new_config.max_log_level();
+        let proxy_addresses_changed =
+            current_config.active_proxy_addresses() != new_config.active_proxy_addresses();
+        let new_max_log_level = new_config.max_log_level().to_owned();
+        self.server_state.swap_config(Arc::new(new_config));
+        if log_level_changed {
+            if let Some(log_level_handle) = &self.log_level_handle {
+                log_level_handle.set_max_log_level(&new_max_log_level)?;
+            }
+        }
+        if proxy_addresses_changed {
+            self.server_state
+                .proxy_connection_pool()
+                .refresh_proxy_addresses()?;
+        }
+        Ok(restart_required_changes)
+    }
+    /// Scan the rsa directory again
+    fn reload_rsa_crypto(&self) -> Result<RsaCryptoReloadReport, AgentError> {
+        let rsa_crypto = self.server_state.rsa_crypto_holder().reload()?;
+        if rsa_crypto
+            .removed_users
+            .iter()
+            .any(|user_token| user_token == self.server_state.config().auth_token())
+        {
+            warn!(
+                "The rsa crypto of current auth token is removed, new proxy connections will fail."
+            );
+        }
+        Ok(rsa_crypto)
+    }
+    /// Reload the configuration when SIGHUP received or the
+    /// configuration file modified if `config_watch_interval` is given
+    pub fn start(self) {
+        let watch_interval = self
+            .server_state
+            .config()
+            .config_watch_interval()
+            .map(Duration::from_secs);
+        let watch_paths = vec![
+            self.config_file_path.clone(),
+            self.server_state
+                .rsa_crypto_holder()
+                .rsa_dir_path()
+                .to_path_buf(),
+        ];
+        let mut reload_trigger_rx = listen_reload_trigger(watch_paths, watch_interval);
+        tokio::spawn(async move {
+            while let Some(reload_trigger) = reload_trigger_rx.recv().await {
+                info!(
+                    "Reload configuration [{:?}] because of {reload_trigger:?}",
+                    self.config_file_path
+                );
+                let ConfigReloadReport {
+                    restart_required_changes,
+                    rsa_crypto,
+                } = self.reload();
+                match restart_required_changes {
+                    Ok(restart_required_changes) if restart_required_changes.is_empty() => {
+                        info!("Configuration reloaded.");
+                    }
+                    Ok(restart_required_changes) => {
+                        warn!("Configuration reloaded, but these changes only take effect after restart: {restart_required_changes:?}");
+                    }
+                    Err(e) => {
+                        error!("Fail to reload configuration, keep using the current one: {e:?}");
+                    }
+                }
+                match rsa_crypto {
+                    Ok(rsa_crypto) => {
+                        info!(
+                            "Rsa crypto reloaded, added users: {:?}, removed users: {:?}",
+                            rsa_crypto.added_users, rsa_crypto.removed_users
+                        );
+                    }
+                    Err(e) => {
+                        error!("Fail to reload rsa crypto, keep using the current one: {e:?}");
+                    }
+                }
+            }
+        });
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::new_server_state;
+    use std::fs::{copy, create_dir_all, remove_dir_all, write};
+    use std::path::Path;
+    #[tokio::test]
+    async fn reload_rsa_crypto_even_when_config_invalid() {
+        let test_dir =
+            std::env::temp_dir().join(format!("ppaass-agent-reload-test-{}", std::process::id()));
+        let rsa_dir_path = test_dir.join("rsa");
+        let user_rsa_dir_path =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("../../resources/agent/rsa/user1");
+        for user in ["user1", "user2"] {
+            create_dir_all(rsa_dir_path.join(user)).unwrap();
+            for key_file_name in ["AgentPrivateKey.pem", "ProxyPublicKey.pem"] {
+                copy(
+                    user_rsa_dir_path.join(key_file_name),
+                    rsa_dir_path.join(user).join(key_file_name),
+                )
+                .unwrap();
+            }
+        }
+        let config_file_path = test_dir.join("config.toml");
+        // The top level keys go first, they can not follow the tables
+        let config_content = format!(
+            "rsa_dir = {rsa_dir_path:?}\n{}",
+            toml::to_string(&Config::default())
+                .unwrap()
+                .replace("rsa_dir = \"/resources/agent/rsa\"\n", "")
+                .replace("proxy_connection_pool_size = 32\n", "")
+        );
+        let server_state = new_server_state(Arc::new(toml::from_str(&config_content).unwrap()))
+            .await
+            .unwrap();
+        let mut server_event_rx = server_state.server_event_tx().subscribe();
+        let config_reloader = ConfigReloader::new(config_file_path.clone(), server_state, None);
+        write(
+            &config_file_path,
+            config_content.replace("port = 80\n", "port = 8080\n"),
+        )
+        .unwrap();
+        let config_reload_report = config_reloader.reload();
+        assert_eq!(
+            config_reload_report.restart_required_changes.unwrap(),
+            vec!["port"]
+        );
+        assert!(config_reload_report
+            .rsa_crypto
+            .unwrap()
+            .removed_users
+            .is_empty());
+        // The user is removed while the configuration is broken
+        write(&config_file_path, "port = \"not a port\"").unwrap();
+        remove_dir_all(rsa_dir_path.join("user2")).unwrap();
+        let config_reload_report = config_reloader.reload();
+        remove_dir_all(&test_dir).unwrap();
+        assert!(config_reload_report.restart_required_changes.is_err());
+        assert_eq!(
+            config_reload_report.rsa_crypto.unwrap().removed_users,
+            vec!["user2".to_string()]
+        );
+        assert_eq!(*config_reloader.server_state.config().port(), 8080);
+        let server_events = std::iter::from_fn(|| server_event_rx.try_recv().ok())
+            .filter(|server_event| {
+                !matches!(server_event, AgentServerEvent::PoolSizeChanged { .. })
+            })
+            .collect::<Vec<AgentServerEvent>>();
+        assert!(matches!(
+            server_events.as_slice(),
+            [
+                AgentServerEvent::ConfigReloaded { .. },
+                AgentServerEvent::RsaCryptoReloaded { .. },
+                AgentServerEvent::ConfigReloadFail { .. },
+                AgentServerEvent::RsaCryptoReloaded { removed_users, .. },
+            ] if removed_users == &vec!["user2".to_string()]
+        ));
+    }
+}
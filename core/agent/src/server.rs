@@ -1,38 +1,143 @@
+use crate::admin::AdminServer;
 use crate::bo::event::AgentServerEvent;
 use crate::bo::state::{ServerState, ServerStateBuilder};
+use crate::client::PpaassClient;
 use crate::config::Config;
 use crate::crypto::AgentRsaCryptoHolder;
 use crate::error::AgentError;
 use crate::handler::http::handle_http_client_tcp_stream;
 use crate::handler::socks5::handle_socks5_client_tcp_stream;
-use crate::pool::ProxyConnectionPool;
+use crate::metrics::{AgentMetrics, RESULT_FAIL, RESULT_SUCCESS};
+use crate::pool::{ProxyConnectionPool, SystemClock, TcpProxyConnector};
 use crate::publish_server_event;
+use crate::reload::ConfigReloader;
+use crate::stats::{ClientTrafficHolder, ProxyStatsHolder};
+use arc_swap::ArcSwap;
+use ppaass_common::metrics::serve_metrics;
+use ppaass_common::shutdown::ShutdownCoordinator;
+use ppaass_common::tunnel_registry::TunnelRegistry;
+use ppaass_common::LogLevelHandle;
 use socket2::{SockRef, TcpKeepalive};
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::mpsc::{channel, Receiver};
-use tracing::{debug, error};
+use tokio::sync::broadcast::{channel, Receiver};
+use tokio::time::interval;
+use tracing::{debug, error, info};
 const SOCKS5_VERSION: u8 = 0x05;
 const SOCKS4_VERSION: u8 = 0x04;
+/// The max capacity of the server event channel, the slow subscribers lag behind it
+const MAX_SERVER_EVENT_CAPACITY: usize = 65536;
+/// The interval to sample the pool size for the pool size changed event
+const POOL_SIZE_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+/// Create the state shared by the server and the in-process client
+pub(crate) async fn new_server_state(config: Arc<Config>) -> Result<ServerState, AgentError> {
+    let rsa_crypto_holder = Arc::new(AgentRsaCryptoHolder::new(config.clone())?);
+    let metrics = Arc::new(AgentMetrics::new()?);
+    let (server_event_tx, _) = channel::<AgentServerEvent>(
+        (*config.server_event_max_size()).clamp(1, MAX_SERVER_EVENT_CAPACITY),
+    );
+    let proxy_stats_holder = Arc::new(ProxyStatsHolder::with_observers(
+        metrics.clone(),
+        server_event_tx.clone(),
+    ));
+    let config = Arc::new(ArcSwap::new(config));
+    let mut server_state_builder = ServerStateBuilder::default();
+    server_state_builder
+        .config(config.clone())
+        .shutdown_coordinator(ShutdownCoordinator::new())
+        .tunnel_registry(Arc::new(TunnelRegistry::new()))
+        .rsa_crypto_holder(rsa_crypto_holder.clone())
+        .proxy_connection_pool(Arc::new(
+            ProxyConnectionPool::new(
+                config.clone(),
+                rsa_crypto_holder,
+                proxy_stats_holder.clone(),
+                TcpProxyConnector::new(config)?,
+                SystemClock,
+            )
+            .await?,
+        ))
+        .proxy_stats_holder(proxy_stats_holder)
+        .client_traffic_holder(Arc::new(ClientTrafficHolder::new()))
+        .metrics(metrics)
+        .server_event_tx(server_event_tx);
+    Ok(server_state_builder.build()?)
+}
 pub struct AgentServer {
     server_state: ServerState,
 }
+/// The handle to shut down the agent server gracefully
+#[derive(Clone)]
+pub struct AgentServerShutdownHandle {
+    server_state: ServerState,
+}
+impl AgentServerShutdownHandle {
+    /// Stop accepting client connections and drain the tunnels until
+    /// the `shutdown_drain_timeout`, return `true` when all the tunnels drained.
+    pub async fn shutdown(&self) -> bool {
+        let drain_timeout =
+            Duration::from_secs(*self.server_state.config().shutdown_drain_timeout());
+        self.server_state
+            .shutdown_coordinator()
+            .shutdown(drain_timeout)
+            .await
+    }
+}
 impl AgentServer {
     pub async fn new(config: Arc<Config>) -> Result<Self, AgentError> {
-        let rsa_crypto_holder = Arc::new(AgentRsaCryptoHolder::new(config.clone())?);
-        let mut server_state_builder = ServerStateBuilder::default();
-        server_state_builder
-            .config(config.clone())
-            .rsa_crypto_holder(rsa_crypto_holder.clone())
-            .proxy_connection_pool(Arc::new(
-                ProxyConnectionPool::new(config, rsa_crypto_holder).await?,
-            ));
         Ok(Self {
-            server_state: server_state_builder.build()?,
+            server_state: new_server_state(config).await?,
         })
     }
+    /// The in-process tunnel client sharing the proxy connection pool of the server
+    pub fn client(&self) -> PpaassClient {
+        PpaassClient::from_server_state(self.server_state.clone())
+    }
+    /// Subscribe the lifecycle events of the server, the events published
+    /// before subscribing are not received
+    pub fn subscribe(&self) -> Receiver<AgentServerEvent> {
+        self.server_state.server_event_tx().subscribe()
+    }
+    /// The quality statistics of the proxies, it keeps updating after the server started
+    pub fn proxy_stats_holder(&self) -> Arc<ProxyStatsHolder> {
+        self.server_state.proxy_stats_holder().clone()
+    }
+    /// The handle to shut down the server, it can be taken before the server started
+    pub fn shutdown_handle(&self) -> AgentServerShutdownHandle {
+        AgentServerShutdownHandle {
+            server_state: self.server_state.clone(),
+        }
+    }
+    /// The live tunnels of the server
+    pub fn tunnel_registry(&self) -> Arc<TunnelRegistry> {
+        self.server_state.tunnel_registry().clone()
+    }
+    /// The reloader to reload the configuration file into the server
+    pub fn config_reloader(
+        &self,
+        config_file_path: PathBuf,
+        log_level_handle: Option<LogLevelHandle>,
+    ) -> ConfigReloader {
+        ConfigReloader::new(
+            config_file_path,
+            self.server_state.clone(),
+            log_level_handle,
+        )
+    }
+    /// The admin endpoint to inspect and control the running server
+    pub fn admin_server(
+        &self,
+        config_file_path: PathBuf,
+        log_level_handle: Option<LogLevelHandle>,
+    ) -> AdminServer {
+        AdminServer::new(
+            self.server_state.clone(),
+            self.config_reloader(config_file_path, log_level_handle),
+        )
+    }
     async fn switch_protocol(client_tcp_stream: &TcpStream) -> Result<u8, AgentError> {
         let mut protocol = [0u8; 1];
         client_tcp_stream.peek(&mut protocol).await?;
@@ -49,13 +154,71 @@ impl AgentServer {
     ) -> Result<(), AgentError> {
         debug!("Handling client TCP connection: {client_socket_addr}");
         let protocol = Self::switch_protocol(&client_tcp_stream).await?;
-        match protocol {
-            SOCKS5_VERSION => {
-                handle_socks5_client_tcp_stream(client_tcp_stream, server_state).await
+        let metrics = server_state.metrics().clone();
+        let (protocol_name, handle_result) = match protocol {
+            SOCKS5_VERSION => (
+                "socks5",
+                handle_socks5_client_tcp_stream(client_tcp_stream, server_state).await,
+            ),
+            SOCKS4_VERSION => ("socks4", Err(AgentError::UnsupportedSocksV4Protocol)),
+            _ => (
+                "http",
+                handle_http_client_tcp_stream(client_tcp_stream, server_state).await,
+            ),
+        };
+        match &handle_result {
+            Ok(()) => metrics.record_accepted_connection(protocol_name, RESULT_SUCCESS),
+            Err(e) => {
+                metrics.record_accepted_connection(protocol_name, RESULT_FAIL);
+                metrics.record_error(e);
             }
-            SOCKS4_VERSION => Err(AgentError::UnsupportedSocksV4Protocol),
-            _ => handle_http_client_tcp_stream(client_tcp_stream, server_state).await,
         }
+        handle_result
+    }
+    /// Start the metrics listener when the `metrics_listen_address` configured
+    fn start_metrics_listener(server_state: &ServerState) -> Result<(), AgentError> {
+        let Some(metrics_listen_address) = server_state.config().metrics_listen_address().clone()
+        else {
+            return Ok(());
+        };
+        let metrics_listen_address = metrics_listen_address.parse::<SocketAddr>()?;
+        let registry = server_state.metrics().registry().clone();
+        let stop_token = server_state.shutdown_coordinator().accept_token().clone();
+        let refresh_state = server_state.clone();
+        tokio::spawn(async move {
+            let refresh = move || {
+                refresh_state
+                    .metrics()
+                    .set_proxy_pool_size(refresh_state.proxy_connection_pool().pool_size());
+            };
+            if let Err(e) =
+                serve_metrics(metrics_listen_address, registry, refresh, stop_token).await
+            {
+                error!("Fail to serve metrics on [{metrics_listen_address}]: {e:?}");
+            }
+        });
+        Ok(())
+    }
+    /// Publish the pool size changed event when the sampled pool size changed
+    fn start_pool_size_sampler(server_state: &ServerState) {
+        let server_state = server_state.clone();
+        let stop_token = server_state.shutdown_coordinator().accept_token().clone();
+        tokio::spawn(stop_token.run_until_cancelled_owned(async move {
+            let mut sample_interval = interval(POOL_SIZE_SAMPLE_INTERVAL);
+            let mut previous_pool_size = None;
+            loop {
+                sample_interval.tick().await;
+                let pool_size = server_state.proxy_connection_pool().pool_size();
+                if previous_pool_size == Some(pool_size) {
+                    continue;
+                }
+                previous_pool_size = Some(pool_size);
+                publish_server_event(
+                    server_state.server_event_tx(),
+                    AgentServerEvent::PoolSizeChanged { pool_size },
+                );
+            }
+        }));
     }
     async fn concrete_start_server(server_state: ServerState) -> Result<(), AgentError> {
         let server_socket_addr = SocketAddr::new(
@@ -100,17 +263,29 @@ impl AgentServer {
         if let Some(buffer_size) = server_state.config().client_socket_send_buffer_size() {
             server_socket.set_send_buffer_size(*buffer_size)?;
         }
-        if let Some(timeout) = server_state.config().client_connection_read_timeout() {
-            server_socket.set_read_timeout(Some(Duration::from_secs(*timeout)))?;
-        }
-        if let Some(timeout) = server_state.config().client_connection_write_timeout() {
-            server_socket.set_write_timeout(Some(Duration::from_secs(*timeout)))?;
-        }
 
+        Self::start_metrics_listener(&server_state)?;
+        Self::start_pool_size_sampler(&server_state);
+        let shutdown_coordinator = server_state.shutdown_coordinator().clone();
         loop {
-            let (client_tcp_stream, client_socket_addr) = server_listener.accept().await?;
+            let (client_tcp_stream, client_socket_addr) = tokio::select! {
+                accept_result = server_listener.accept() => accept_result?,
+                _ = shutdown_coordinator.accept_token().cancelled() => {
+                    info!("Stop accepting client connections because of shutdown.");
+                    return Ok(());
+                }
+            };
+            publish_server_event(
+                server_state.server_event_tx(),
+                AgentServerEvent::ClientAccepted {
+                    client_address: client_socket_addr,
+                },
+            );
             let server_state = server_state.clone();
-            tokio::spawn(async move {
+            // Only the started relays are drained on shutdown, the
+            // client connections still in handshake are closed on shutdown
+            let accept_token = shutdown_coordinator.accept_token().clone();
+            tokio::spawn(accept_token.run_until_cancelled_owned(async move {
                 if let Err(e) = Self::handle_client_tcp_stream(
                     client_tcp_stream,
                     client_socket_addr,
@@ -120,23 +295,29 @@ impl AgentServer {
                 {
                     error!("Fail to handle client tcp stream [{client_socket_addr:?}]: {e:?}")
                 }
-            });
+            }));
         }
     }
+    /// Start the server, the returned receiver is a subscription of the lifecycle events,
+    /// more subscriptions can be created with `subscribe` before start
     pub async fn start(self) -> Result<Receiver<AgentServerEvent>, AgentError> {
-        let (server_event_tx, server_event_rx) =
-            channel::<AgentServerEvent>(*self.server_state.config().server_event_max_size());
-        {
-            let server_event_tx = server_event_tx.clone();
-            let server_state = self.server_state.clone();
-            tokio::spawn(async move {
-                if let Err(e) = Self::concrete_start_server(server_state).await {
-                    error!("Fail to start agent server: {e:?}");
-                    publish_server_event(server_event_tx, AgentServerEvent::ServerStartFail).await;
-                }
-            });
-        }
-        publish_server_event(server_event_tx, AgentServerEvent::ServerStartup).await;
+        let server_event_rx = self.subscribe();
+        let server_state = self.server_state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = Self::concrete_start_server(server_state.clone()).await {
+                error!("Fail to start agent server: {e:?}");
+                publish_server_event(
+                    server_state.server_event_tx(),
+                    AgentServerEvent::ServerStartFail {
+                        reason: e.to_string(),
+                    },
+                );
+            }
+        });
+        publish_server_event(
+            self.server_state.server_event_tx(),
+            AgentServerEvent::ServerStartup,
+        );
         Ok(server_event_rx)
     }
 }
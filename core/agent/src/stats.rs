@@ -0,0 +1,449 @@
+use crate::bo::event::AgentServerEvent;
+use crate::metrics::AgentMetrics;
+use crate::publish_server_event;
+use crate::transport::ProxyAddress;
+use chrono::{DateTime, TimeDelta, Utc};
+use ppaass_common::tunnel_registry::TunnelSnapshot;
+use rand::random;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::broadcast::Sender;
+use tracing::{debug, error};
+/// The gain used to smooth the round trip time, same as the SRTT gain in RFC 6298
+const RTT_EWMA_ALPHA: f64 = 0.125;
+/// The gain used to smooth the round trip time variation, same as the RTTVAR gain in RFC 6298
+const RTT_JITTER_BETA: f64 = 0.25;
+/// The gain used to smooth the clock skew between agent and proxy
+const CLOCK_SKEW_ALPHA: f64 = 0.125;
+/// The gain used to smooth the loss rate, so the proxy recovers from an early outage
+const LOSS_EWMA_ALPHA: f64 = 0.125;
+/// The quality statistics of a proxy calculated from the heartbeat ping-pong
+#[derive(Debug, Clone, Default)]
+pub struct ProxyQualityStats {
+    /// The count of heartbeat ping sent to the proxy
+    pub ping_count: u64,
+    /// The count of heartbeat pong received from the proxy which paired with a ping
+    pub pong_count: u64,
+    /// The latest round trip time
+    pub last_rtt: Option<Duration>,
+    /// The smoothed round trip time
+    pub ewma_rtt: Option<Duration>,
+    /// The smoothed variation of the round trip time
+    pub jitter: Option<Duration>,
+    /// The smoothed estimation of proxy clock minus agent clock, in milliseconds
+    pub clock_skew_millis: Option<i64>,
+    /// The last time a pong received from the proxy
+    pub last_pong_time: Option<DateTime<Utc>>,
+    /// The result of the last check, `None` before the proxy checked
+    pub healthy: Option<bool>,
+    /// The smoothed ratio of the failed checks, the pong is a success and
+    /// the failure to connect or to reply the heartbeat is a loss
+    pub ewma_loss_rate: f64,
+}
+impl ProxyQualityStats {
+    /// The smoothed ratio of the failed checks, the recent checks weigh more
+    pub fn loss_rate(&self) -> f64 {
+        self.ewma_loss_rate
+    }
+    fn record_loss_sample(&mut self, lost: bool) {
+        let sample = if lost { 1f64 } else { 0f64 };
+        self.ewma_loss_rate =
+            (1f64 - LOSS_EWMA_ALPHA) * self.ewma_loss_rate + LOSS_EWMA_ALPHA * sample;
+    }
+    /// The weight of the proxy used by proxy selection, bigger is better
+    fn selection_weight(&self) -> Option<f64> {
+        let ewma_rtt = self.ewma_rtt?;
+        let jitter = self.jitter.unwrap_or_default();
+        let cost_millis = (ewma_rtt + jitter * 2).as_secs_f64() * 1000f64 + 1f64;
+        Some((1f64 - self.loss_rate()).max(0.01) / cost_millis)
+    }
+    fn record_pong(&mut self, rtt: Duration, clock_skew_millis: i64) {
+        self.pong_count += 1;
+        self.healthy = Some(true);
+        self.record_loss_sample(false);
+        self.last_rtt = Some(rtt);
+        self.last_pong_time = Some(Utc::now());
+        match (self.ewma_rtt, self.jitter) {
+            (Some(ewma_rtt), Some(jitter)) => {
+                let ewma_rtt_secs = ewma_rtt.as_secs_f64();
+                let rtt_secs = rtt.as_secs_f64();
+                let jitter_secs = (1f64 - RTT_JITTER_BETA) * jitter.as_secs_f64()
+                    + RTT_JITTER_BETA * (ewma_rtt_secs - rtt_secs).abs();
+                let ewma_rtt_secs =
+                    (1f64 - RTT_EWMA_ALPHA) * ewma_rtt_secs + RTT_EWMA_ALPHA * rtt_secs;
+                self.jitter = Some(Duration::from_secs_f64(jitter_secs));
+                self.ewma_rtt = Some(Duration::from_secs_f64(ewma_rtt_secs));
+            }
+            _ => {
+                self.ewma_rtt = Some(rtt);
+                self.jitter = Some(rtt / 2);
+            }
+        }
+        self.clock_skew_millis = match self.clock_skew_millis {
+            None => Some(clock_skew_millis),
+            Some(current) => Some(
+                ((1f64 - CLOCK_SKEW_ALPHA) * current as f64
+                    + CLOCK_SKEW_ALPHA * clock_skew_millis as f64) as i64,
+            ),
+        };
+    }
+}
+/// The holder of the quality statistics of all the proxies
+#[derive(Debug, Default)]
+pub struct ProxyStatsHolder {
+    stats: RwLock<HashMap<ProxyAddress, ProxyQualityStats>>,
+    metrics: Option<Arc<AgentMetrics>>,
+    server_event_tx: Option<Sender<AgentServerEvent>>,
+}
+impl ProxyStatsHolder {
+    pub fn new() -> Self {
+        Default::default()
+    }
+    /// Create the holder which also observes the heartbeat round trip time into the metrics
+    /// and publishes the proxy health changes as server events
+    pub fn with_observers(
+        metrics: Arc<AgentMetrics>,
+        server_event_tx: Sender<AgentServerEvent>,
+    ) -> Self {
+        Self {
+            stats: Default::default(),
+            metrics: Some(metrics),
+            server_event_tx: Some(server_event_tx),
+        }
+    }
+    fn publish_health_change(
+        &self,
+        proxy_address: ProxyAddress,
+        previous_healthy: Option<bool>,
+        healthy: bool,
+        rtt: Option<Duration>,
+    ) {
+        if previous_healthy == Some(healthy) {
+            return;
+        }
+        if let Some(server_event_tx) = &self.server_event_tx {
+            publish_server_event(
+                server_event_tx,
+                AgentServerEvent::ProxyHealthChanged {
+                    proxy_address,
+                    healthy,
+                    rtt,
+                },
+            );
+        }
+    }
+    /// Record the proxy fails to connect or fails to reply the heartbeat
+    pub fn record_check_failure(&self, proxy_address: ProxyAddress) {
+        let previous_healthy = {
+            let Ok(mut stats) = self.stats.write() else {
+                error!("Fail to record check failure because of stats lock poisoned.");
+                return;
+            };
+            let proxy_stats = stats.entry(proxy_address).or_default();
+            proxy_stats.record_loss_sample(true);
+            proxy_stats.healthy.replace(false)
+        };
+        self.publish_health_change(proxy_address, previous_healthy, false, None);
+    }
+    /// Record a heartbeat ping is sent to the proxy
+    pub fn record_ping(&self, proxy_address: ProxyAddress) {
+        let Ok(mut stats) = self.stats.write() else {
+            error!("Fail to record heartbeat ping because of stats lock poisoned.");
+            return;
+        };
+        stats.entry(proxy_address).or_default().ping_count += 1;
+    }
+    /// Record a heartbeat pong is received from the proxy.
+    ///
+    /// The `ping_time` is the agent time when the paired ping sent,
+    /// the `proxy_time` is the proxy time when the pong generated.
+    pub fn record_pong(
+        &self,
+        proxy_address: ProxyAddress,
+        ping_time: DateTime<Utc>,
+        rtt: Duration,
+        proxy_time: DateTime<Utc>,
+    ) {
+        let half_rtt = TimeDelta::from_std(rtt / 2).unwrap_or_default();
+        let clock_skew_millis = (proxy_time - (ping_time + half_rtt)).num_milliseconds();
+        debug!("Proxy [{proxy_address}] heartbeat rtt: {rtt:?}, clock skew: {clock_skew_millis}ms");
+        if let Some(metrics) = &self.metrics {
+            metrics.observe_heartbeat_rtt(proxy_address, rtt);
+        }
+        let previous_healthy = {
+            let Ok(mut stats) = self.stats.write() else {
+                error!("Fail to record heartbeat pong because of stats lock poisoned.");
+                return;
+            };
+            let proxy_stats = stats.entry(proxy_address).or_default();
+            let previous_healthy = proxy_stats.healthy;
+            proxy_stats.record_pong(rtt, clock_skew_millis);
+            previous_healthy
+        };
+        self.publish_health_change(proxy_address, previous_healthy, true, Some(rtt));
+    }
+    /// Get the quality statistics of a proxy
+    pub fn get(&self, proxy_address: &ProxyAddress) -> Option<ProxyQualityStats> {
+        let stats = self.stats.read().ok()?;
+        stats.get(proxy_address).cloned()
+    }
+    /// Get the quality statistics of all the proxies
+    pub fn snapshot(&self) -> HashMap<ProxyAddress, ProxyQualityStats> {
+        match self.stats.read() {
+            Ok(stats) => stats.clone(),
+            Err(_) => {
+                error!("Fail to take stats snapshot because of stats lock poisoned.");
+                HashMap::new()
+            }
+        }
+    }
+    /// Select a proxy address with the weight calculated from the quality statistics,
+    /// the proxy without statistics will be treated as good as the best one so that
+    /// it can be probed.
+    pub fn select_proxy_address<'a>(
+        &self,
+        proxy_addresses: &'a [ProxyAddress],
+    ) -> Option<&'a ProxyAddress> {
+        if proxy_addresses.is_empty() {
+            return None;
+        }
+        let weights = {
+            let Ok(stats) = self.stats.read() else {
+                return proxy_addresses.get(random::<usize>() % proxy_addresses.len());
+            };
+            proxy_addresses
+                .iter()
+                .map(|proxy_address| {
+                    stats
+                        .get(proxy_address)
+                        .and_then(ProxyQualityStats::selection_weight)
+                })
+                .collect::<Vec<Option<f64>>>()
+        };
+        let best_weight = weights.iter().flatten().cloned().fold(0f64, f64::max);
+        if best_weight <= 0f64 {
+            return proxy_addresses.get(random::<usize>() % proxy_addresses.len());
+        }
+        let weights = weights
+            .into_iter()
+            .map(|weight| weight.unwrap_or(best_weight))
+            .collect::<Vec<f64>>();
+        let total_weight: f64 = weights.iter().sum();
+        let mut point = random::<f64>() * total_weight;
+        for (index, weight) in weights.iter().enumerate() {
+            if point < *weight {
+                return proxy_addresses.get(index);
+            }
+            point -= weight;
+        }
+        proxy_addresses.last()
+    }
+}
+/// The traffic of a client, including the closed and the active tunnels
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientTraffic {
+    pub client_ip: IpAddr,
+    pub active_tunnels: usize,
+    pub total_tunnels: u64,
+    pub upload_bytes: u64,
+    pub download_bytes: u64,
+}
+#[derive(Debug, Default, Clone, Copy)]
+struct ClosedTunnelTraffic {
+    tunnels: u64,
+    upload_bytes: u64,
+    download_bytes: u64,
+}
+/// The holder of the traffic of the closed tunnels by client ip
+#[derive(Debug, Default)]
+pub struct ClientTrafficHolder {
+    closed: RwLock<HashMap<IpAddr, ClosedTunnelTraffic>>,
+}
+impl ClientTrafficHolder {
+    pub fn new() -> Self {
+        Default::default()
+    }
+    /// Record the traffic of a closed tunnel
+    pub fn record_closed_tunnel(&self, client_ip: IpAddr, upload_bytes: u64, download_bytes: u64) {
+        let Ok(mut closed) = self.closed.write() else {
+            error!("Fail to record client traffic because of lock poisoned.");
+            return;
+        };
+        let traffic = closed.entry(client_ip).or_default();
+        traffic.tunnels += 1;
+        traffic.upload_bytes += upload_bytes;
+        traffic.download_bytes += download_bytes;
+    }
+    /// Merge the traffic of the closed tunnels and the active tunnels by client ip
+    pub fn snapshot(&self, active_tunnels: &[TunnelSnapshot]) -> Vec<ClientTraffic> {
+        let mut traffic_by_client = match self.closed.read() {
+            Ok(closed) => closed
+                .iter()
+                .map(|(client_ip, closed_traffic)| {
+                    (
+                        *client_ip,
+                        ClientTraffic {
+                            client_ip: *client_ip,
+                            active_tunnels: 0,
+                            total_tunnels: closed_traffic.tunnels,
+                            upload_bytes: closed_traffic.upload_bytes,
+                            download_bytes: closed_traffic.download_bytes,
+                        },
+                    )
+                })
+                .collect::<HashMap<IpAddr, ClientTraffic>>(),
+            Err(_) => {
+                error!("Fail to take client traffic snapshot because of lock poisoned.");
+                HashMap::new()
+            }
+        };
+        active_tunnels.iter().for_each(|tunnel| {
+            let client_ip = tunnel.client_address.ip();
+            let traffic = traffic_by_client
+                .entry(client_ip)
+                .or_insert_with(|| ClientTraffic {
+                    client_ip,
+                    active_tunnels: 0,
+                    total_tunnels: 0,
+                    upload_bytes: 0,
+                    download_bytes: 0,
+                });
+            traffic.active_tunnels += 1;
+            traffic.total_tunnels += 1;
+            traffic.upload_bytes += tunnel.upload_bytes;
+            traffic.download_bytes += tunnel.download_bytes;
+        });
+        let mut traffic = traffic_by_client
+            .into_values()
+            .collect::<Vec<ClientTraffic>>();
+        traffic.sort_by_key(|traffic| traffic.client_ip);
+        traffic
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::broadcast::channel;
+    fn assert_duration_millis(duration: Option<Duration>, expected_millis: f64) {
+        let actual_millis = duration.unwrap().as_secs_f64() * 1000f64;
+        assert!(
+            (actual_millis - expected_millis).abs() < 0.001,
+            "expected {expected_millis}ms, actual {actual_millis}ms"
+        );
+    }
+    #[test]
+    fn smooth_rtt_jitter_and_clock_skew() {
+        let proxy_stats_holder = ProxyStatsHolder::new();
+        let proxy_address: ProxyAddress = "127.0.0.1:80".parse().unwrap();
+        let ping_time = Utc::now();
+        // The proxy clock is 1s ahead, the pong is generated at the half of the rtt
+        proxy_stats_holder.record_pong(
+            proxy_address,
+            ping_time,
+            Duration::from_millis(100),
+            ping_time + TimeDelta::milliseconds(1050),
+        );
+        let proxy_stats = proxy_stats_holder.get(&proxy_address).unwrap();
+        assert_duration_millis(proxy_stats.ewma_rtt, 100f64);
+        assert_duration_millis(proxy_stats.jitter, 50f64);
+        assert_eq!(proxy_stats.clock_skew_millis, Some(1000));
+        // The proxy clock is in sync for the second pong
+        proxy_stats_holder.record_pong(
+            proxy_address,
+            ping_time,
+            Duration::from_millis(200),
+            ping_time + TimeDelta::milliseconds(100),
+        );
+        let proxy_stats = proxy_stats_holder.get(&proxy_address).unwrap();
+        assert_duration_millis(proxy_stats.last_rtt, 200f64);
+        assert_duration_millis(proxy_stats.ewma_rtt, 112.5f64);
+        assert_duration_millis(proxy_stats.jitter, 62.5f64);
+        assert_eq!(proxy_stats.clock_skew_millis, Some(875));
+    }
+    #[test]
+    fn loss_rate_recovers_after_outage() {
+        let proxy_stats_holder = ProxyStatsHolder::new();
+        let proxy_address: ProxyAddress = "127.0.0.1:80".parse().unwrap();
+        assert_eq!(ProxyQualityStats::default().loss_rate(), 0f64);
+        for _ in 0..4 {
+            proxy_stats_holder.record_ping(proxy_address);
+            proxy_stats_holder.record_check_failure(proxy_address);
+        }
+        let loss_rate = proxy_stats_holder.get(&proxy_address).unwrap().loss_rate();
+        assert!((loss_rate - (1f64 - 0.875f64.powi(4))).abs() < 1e-9);
+        for _ in 0..32 {
+            proxy_stats_holder.record_ping(proxy_address);
+            proxy_stats_holder.record_pong(
+                proxy_address,
+                Utc::now(),
+                Duration::from_millis(10),
+                Utc::now(),
+            );
+        }
+        let proxy_stats = proxy_stats_holder.get(&proxy_address).unwrap();
+        assert_eq!(proxy_stats.ping_count, 36);
+        assert_eq!(proxy_stats.pong_count, 32);
+        // The early outage no longer drags the weight down
+        assert!(proxy_stats.loss_rate() < 0.01);
+    }
+    #[test]
+    fn select_proxy_address_prefers_lossless_proxy() {
+        let proxy_stats_holder = ProxyStatsHolder::new();
+        assert!(proxy_stats_holder.select_proxy_address(&[]).is_none());
+        let good_proxy_address: ProxyAddress = "127.0.0.1:80".parse().unwrap();
+        let lossy_proxy_address: ProxyAddress = "127.0.0.2:80".parse().unwrap();
+        for proxy_address in [good_proxy_address, lossy_proxy_address] {
+            proxy_stats_holder.record_ping(proxy_address);
+            proxy_stats_holder.record_pong(
+                proxy_address,
+                Utc::now(),
+                Duration::from_millis(10),
+                Utc::now(),
+            );
+        }
+        // All the later checks of the lossy proxy fail, its weight is about 1%
+        for _ in 0..40 {
+            proxy_stats_holder.record_ping(lossy_proxy_address);
+            proxy_stats_holder.record_check_failure(lossy_proxy_address);
+        }
+        let proxy_addresses = [good_proxy_address, lossy_proxy_address];
+        let good_selected = (0..1000)
+            .filter(|_| {
+                proxy_stats_holder.select_proxy_address(&proxy_addresses)
+                    == Some(&good_proxy_address)
+            })
+            .count();
+        assert!(
+            good_selected > 900,
+            "good proxy selected {good_selected} times"
+        );
+    }
+    #[test]
+    fn publish_proxy_health_change_only_on_transition() {
+        let (server_event_tx, mut server_event_rx) = channel(16);
+        let proxy_stats_holder = ProxyStatsHolder::with_observers(
+            Arc::new(AgentMetrics::new().unwrap()),
+            server_event_tx,
+        );
+        let proxy_address: ProxyAddress = "127.0.0.1:80".parse().unwrap();
+        let rtt = Duration::from_millis(10);
+        proxy_stats_holder.record_pong(proxy_address, Utc::now(), rtt, Utc::now());
+        proxy_stats_holder.record_pong(proxy_address, Utc::now(), rtt, Utc::now());
+        proxy_stats_holder.record_check_failure(proxy_address);
+        proxy_stats_holder.record_check_failure(proxy_address);
+        let health_changes = std::iter::from_fn(|| server_event_rx.try_recv().ok())
+            .map(|server_event| match server_event {
+                AgentServerEvent::ProxyHealthChanged { healthy, .. } => healthy,
+                other => panic!("Unexpected server event: {other:?}"),
+            })
+            .collect::<Vec<bool>>();
+        assert_eq!(health_changes, vec![true, false]);
+        assert_eq!(
+            proxy_stats_holder.get(&proxy_address).unwrap().healthy,
+            Some(false)
+        );
+    }
+}
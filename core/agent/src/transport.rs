@@ -0,0 +1,449 @@
+use crate::error::AgentError;
+use ppaass_common::quic::{QuicDatagrams, QuicStream, QUIC_ALPN};
+use ppaass_common::tls::{crypto_provider, load_certificates};
+use ppaass_common::websocket::WebSocketTransport;
+use quinn::crypto::rustls::QuicClientConfig;
+use quinn::TransportConfig;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt::{Display, Formatter};
+use std::io::Error;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+/// The scheme of the proxy address entries connected with quic
+const QUIC_PROXY_ADDRESS_SCHEME: &str = "quic://";
+/// The interval to keep the quic connection alive when no tunnel is active
+const QUIC_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+/// The tls of the proxy connections, the proxy certificate is pinned by the ca
+/// certificate, the sha256 fingerprint of the proxy certificate, or both
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProxyTlsConfig {
+    /// The sni sent to the proxy and the name to verify with the ca,
+    /// the ip address of the proxy is used when not given
+    pub server_name: Option<String>,
+    /// The pem file of the ca certificates which issue the proxy certificate
+    pub ca_file: Option<PathBuf>,
+    /// The sha256 fingerprint of the proxy certificate in hex, the colons are ignored
+    pub cert_fingerprint: Option<String>,
+}
+impl ProxyTlsConfig {
+    /// Validate the pinning of the proxy certificate
+    pub fn validate(&self) -> Result<(), AgentError> {
+        if self.ca_file.is_none() && self.cert_fingerprint.is_none() {
+            return Err(AgentError::InvalidConfig(
+                "ca_file or cert_fingerprint must be given in proxy_tls".to_string(),
+            ));
+        }
+        if let Some(server_name) = &self.server_name {
+            ServerName::try_from(server_name.as_str()).map_err(|_| {
+                AgentError::InvalidConfig(format!("invalid proxy_tls server_name: {server_name}"))
+            })?;
+        }
+        if let Some(cert_fingerprint) = &self.cert_fingerprint {
+            parse_fingerprint(cert_fingerprint)?;
+        }
+        Ok(())
+    }
+    /// Build the connector verifying the proxy certificate with the pinning
+    pub fn build_connector(&self) -> Result<TlsConnector, AgentError> {
+        Ok(TlsConnector::from(Arc::new(self.build_client_config()?)))
+    }
+    /// Build the quic client verifying the proxy certificate with the pinning
+    pub fn build_quic_client_config(&self) -> Result<quinn::ClientConfig, AgentError> {
+        let mut client_config = self.build_client_config()?;
+        client_config.alpn_protocols = vec![QUIC_ALPN.to_vec()];
+        let quic_client_config = QuicClientConfig::try_from(client_config)
+            .map_err(|e| AgentError::InvalidConfig(format!("invalid quic tls config: {e}")))?;
+        // Keep the idle connection alive for the pooled streams
+        let mut transport_config = TransportConfig::default();
+        transport_config.keep_alive_interval(Some(QUIC_KEEP_ALIVE_INTERVAL));
+        let mut quic_client_config = quinn::ClientConfig::new(Arc::new(quic_client_config));
+        quic_client_config.transport_config(Arc::new(transport_config));
+        Ok(quic_client_config)
+    }
+    fn build_client_config(&self) -> Result<ClientConfig, AgentError> {
+        let provider = crypto_provider();
+        let ca_verifier = match &self.ca_file {
+            None => None,
+            Some(ca_file) => {
+                let mut root_cert_store = RootCertStore::empty();
+                for ca_certificate in load_certificates(ca_file)? {
+                    root_cert_store
+                        .add(ca_certificate)
+                        .map_err(|e| AgentError::InvalidConfig(format!("invalid ca_file: {e}")))?;
+                }
+                Some(
+                    WebPkiServerVerifier::builder_with_provider(
+                        Arc::new(root_cert_store),
+                        provider.clone(),
+                    )
+                    .build()
+                    .map_err(|e| AgentError::InvalidConfig(format!("invalid ca_file: {e}")))?,
+                )
+            }
+        };
+        let client_config = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+        let client_config = match (ca_verifier, &self.cert_fingerprint) {
+            (Some(ca_verifier), None) => client_config.with_webpki_verifier(ca_verifier),
+            (ca_verifier, Some(cert_fingerprint)) => client_config
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(FingerprintServerCertVerifier {
+                    fingerprint: parse_fingerprint(cert_fingerprint)?,
+                    ca_verifier,
+                    provider,
+                })),
+            (None, None) => {
+                return Err(AgentError::InvalidConfig(
+                    "ca_file or cert_fingerprint must be given in proxy_tls".to_string(),
+                ))
+            }
+        }
+        .with_no_client_auth();
+        Ok(client_config)
+    }
+    /// The sni of the proxy in the quic handshake
+    pub fn quic_server_name(&self, proxy_address: SocketAddr) -> String {
+        self.server_name
+            .clone()
+            .unwrap_or_else(|| proxy_address.ip().to_string())
+    }
+    /// The sni of the proxy
+    pub fn server_name(
+        &self,
+        proxy_address: SocketAddr,
+    ) -> Result<ServerName<'static>, AgentError> {
+        match &self.server_name {
+            None => Ok(ServerName::from(proxy_address.ip())),
+            Some(server_name) => ServerName::try_from(server_name.clone()).map_err(|_| {
+                AgentError::InvalidConfig(format!("invalid proxy_tls server_name: {server_name}"))
+            }),
+        }
+    }
+}
+fn parse_fingerprint(cert_fingerprint: &str) -> Result<[u8; 32], AgentError> {
+    let hex_digits = cert_fingerprint.replace(':', "");
+    let invalid_fingerprint = || {
+        AgentError::InvalidConfig(format!(
+            "invalid proxy_tls cert_fingerprint: {cert_fingerprint}"
+        ))
+    };
+    if hex_digits.len() != 64 || !hex_digits.is_ascii() {
+        return Err(invalid_fingerprint());
+    }
+    let mut fingerprint = [0u8; 32];
+    for (index, byte) in fingerprint.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex_digits[index * 2..index * 2 + 2], 16)
+            .map_err(|_| invalid_fingerprint())?;
+    }
+    Ok(fingerprint)
+}
+/// Accept the proxy certificate only when its sha256 fingerprint matches,
+/// the ca verification is also required when the ca given
+#[derive(Debug)]
+struct FingerprintServerCertVerifier {
+    fingerprint: [u8; 32],
+    ca_verifier: Option<Arc<WebPkiServerVerifier>>,
+    provider: Arc<CryptoProvider>,
+}
+impl ServerCertVerifier for FingerprintServerCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if Sha256::digest(end_entity.as_ref()).as_slice() != self.fingerprint {
+            return Err(rustls::Error::General(
+                "proxy certificate fingerprint mismatch".to_string(),
+            ));
+        }
+        match &self.ca_verifier {
+            None => Ok(ServerCertVerified::assertion()),
+            Some(ca_verifier) => ca_verifier.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                ocsp_response,
+                now,
+            ),
+        }
+    }
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+/// Carry the proxy connection on the websocket, so it can pass the http gateways
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProxyWebSocketConfig {
+    /// The path of the upgrade request, it must be the same as the proxy
+    pub path: String,
+    /// The host header of the upgrade request, the proxy address is used when not given
+    pub host: Option<String>,
+}
+/// The transport selected by the proxy address entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ProxyTransport {
+    Tcp,
+    Quic,
+}
+impl ProxyTransport {
+    /// Split the transport from the proxy address entry, the entry starts with `quic://` uses quic
+    pub fn parse(proxy_address: &str) -> (ProxyTransport, &str) {
+        match proxy_address.strip_prefix(QUIC_PROXY_ADDRESS_SCHEME) {
+            None => (ProxyTransport::Tcp, proxy_address),
+            Some(proxy_address) => (ProxyTransport::Quic, proxy_address),
+        }
+    }
+}
+/// The resolved proxy address with the transport of its entry, written as the entry,
+/// for example `quic://127.0.0.1:443`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ProxyAddress {
+    transport: ProxyTransport,
+    socket_address: SocketAddr,
+}
+impl ProxyAddress {
+    pub fn new(transport: ProxyTransport, socket_address: SocketAddr) -> Self {
+        Self {
+            transport,
+            socket_address,
+        }
+    }
+    pub fn transport(&self) -> ProxyTransport {
+        self.transport
+    }
+    pub fn socket_address(&self) -> SocketAddr {
+        self.socket_address
+    }
+}
+impl FromStr for ProxyAddress {
+    type Err = AgentError;
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (transport, socket_address) = ProxyTransport::parse(value);
+        let socket_address = socket_address
+            .parse::<SocketAddr>()
+            .map_err(|_| AgentError::InvalidConfig(format!("invalid proxy address: {value}")))?;
+        Ok(Self::new(transport, socket_address))
+    }
+}
+impl TryFrom<String> for ProxyAddress {
+    type Error = AgentError;
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+impl Display for ProxyAddress {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.transport {
+            ProxyTransport::Tcp => write!(f, "{}", self.socket_address),
+            ProxyTransport::Quic => {
+                write!(f, "{QUIC_PROXY_ADDRESS_SCHEME}{}", self.socket_address)
+            }
+        }
+    }
+}
+impl From<ProxyAddress> for String {
+    fn from(value: ProxyAddress) -> Self {
+        value.to_string()
+    }
+}
+/// The transport of the proxy connection
+#[derive(Debug)]
+pub enum ProxyStream {
+    Tcp(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+    WebSocket(Box<WebSocketTransport<ProxyStream>>),
+    Quic(QuicStream),
+}
+impl ProxyStream {
+    /// The datagrams of the quic stream for the udp tunnel, `None` on the other transports
+    pub fn take_quic_datagrams(&mut self) -> Option<QuicDatagrams> {
+        match self {
+            ProxyStream::Quic(quic_stream) => quic_stream.take_datagrams(),
+            _ => None,
+        }
+    }
+}
+impl AsyncRead for ProxyStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            ProxyStream::Tcp(tcp_stream) => Pin::new(tcp_stream).poll_read(cx, buf),
+            ProxyStream::Tls(tls_stream) => Pin::new(tls_stream).poll_read(cx, buf),
+            ProxyStream::WebSocket(websocket_transport) => {
+                Pin::new(websocket_transport).poll_read(cx, buf)
+            }
+            ProxyStream::Quic(quic_stream) => Pin::new(quic_stream).poll_read(cx, buf),
+        }
+    }
+}
+impl AsyncWrite for ProxyStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Error>> {
+        match self.get_mut() {
+            ProxyStream::Tcp(tcp_stream) => Pin::new(tcp_stream).poll_write(cx, buf),
+            ProxyStream::Tls(tls_stream) => Pin::new(tls_stream).poll_write(cx, buf),
+            ProxyStream::WebSocket(websocket_transport) => {
+                Pin::new(websocket_transport).poll_write(cx, buf)
+            }
+            ProxyStream::Quic(quic_stream) => Pin::new(quic_stream).poll_write(cx, buf),
+        }
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        match self.get_mut() {
+            ProxyStream::Tcp(tcp_stream) => Pin::new(tcp_stream).poll_flush(cx),
+            ProxyStream::Tls(tls_stream) => Pin::new(tls_stream).poll_flush(cx),
+            ProxyStream::WebSocket(websocket_transport) => {
+                Pin::new(websocket_transport).poll_flush(cx)
+            }
+            ProxyStream::Quic(quic_stream) => Pin::new(quic_stream).poll_flush(cx),
+        }
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        match self.get_mut() {
+            ProxyStream::Tcp(tcp_stream) => Pin::new(tcp_stream).poll_shutdown(cx),
+            ProxyStream::Tls(tls_stream) => Pin::new(tls_stream).poll_shutdown(cx),
+            ProxyStream::WebSocket(websocket_transport) => {
+                Pin::new(websocket_transport).poll_shutdown(cx)
+            }
+            ProxyStream::Quic(quic_stream) => Pin::new(quic_stream).poll_shutdown(cx),
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{generate_simple_self_signed, CertifiedKey};
+    use rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+    use rustls::ServerConfig;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::TlsAcceptor;
+    async fn handshake(
+        proxy_tls: &ProxyTlsConfig,
+        CertifiedKey { cert, key_pair }: &CertifiedKey,
+    ) -> Result<(), AgentError> {
+        let server_config = ServerConfig::builder_with_provider(crypto_provider())
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![cert.der().clone()],
+                PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key_pair.serialize_der())),
+            )?;
+        let tls_acceptor = TlsAcceptor::from(Arc::new(server_config));
+        let (agent_io, proxy_io) = duplex(16384);
+        let proxy_task = tokio::spawn(async move {
+            let mut proxy_tls_stream = tls_acceptor.accept(proxy_io).await?;
+            proxy_tls_stream.write_all(b"pong").await?;
+            proxy_tls_stream.flush().await
+        });
+        let proxy_address = "127.0.0.1:80".parse().unwrap();
+        let mut agent_tls_stream = proxy_tls
+            .build_connector()?
+            .connect(proxy_tls.server_name(proxy_address)?, agent_io)
+            .await?;
+        let mut pong = [0u8; 4];
+        agent_tls_stream.read_exact(&mut pong).await?;
+        assert_eq!(&pong, b"pong");
+        let _ = proxy_task.await;
+        Ok(())
+    }
+    #[tokio::test]
+    async fn pin_proxy_certificate() {
+        let certified_key =
+            generate_simple_self_signed(vec!["proxy.ppaass.test".to_string()]).unwrap();
+        let cert_fingerprint = Sha256::digest(certified_key.cert.der().as_ref())
+            .iter()
+            .map(|byte| format!("{byte:02X}"))
+            .collect::<Vec<_>>()
+            .join(":");
+        let fingerprint_tls = ProxyTlsConfig {
+            server_name: None,
+            ca_file: None,
+            cert_fingerprint: Some(cert_fingerprint),
+        };
+        fingerprint_tls.validate().unwrap();
+        handshake(&fingerprint_tls, &certified_key).await.unwrap();
+        let other_certified_key =
+            generate_simple_self_signed(vec!["proxy.ppaass.test".to_string()]).unwrap();
+        assert!(handshake(&fingerprint_tls, &other_certified_key)
+            .await
+            .is_err());
+        let ca_file =
+            std::env::temp_dir().join(format!("ppaass-ca-test-{}.pem", std::process::id()));
+        std::fs::write(&ca_file, certified_key.cert.pem()).unwrap();
+        let mut ca_tls = ProxyTlsConfig {
+            server_name: Some("proxy.ppaass.test".to_string()),
+            ca_file: Some(ca_file.clone()),
+            cert_fingerprint: None,
+        };
+        let ca_result = handshake(&ca_tls, &certified_key).await;
+        ca_tls.server_name = Some("other.ppaass.test".to_string());
+        let wrong_name_result = handshake(&ca_tls, &certified_key).await;
+        std::fs::remove_file(&ca_file).unwrap();
+        ca_result.unwrap();
+        assert!(wrong_name_result.is_err());
+    }
+    #[test]
+    fn parse_proxy_transport() {
+        assert_eq!(
+            ProxyTransport::parse("quic://10.0.0.1:443"),
+            (ProxyTransport::Quic, "10.0.0.1:443")
+        );
+        assert_eq!(
+            ProxyTransport::parse("10.0.0.1:80"),
+            (ProxyTransport::Tcp, "10.0.0.1:80")
+        );
+    }
+}
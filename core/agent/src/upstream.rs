@@ -0,0 +1,283 @@
+use crate::error::AgentError;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use socks5_impl::protocol::UserKey;
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+/// The max size of the response header of the http connect request
+const HTTP_CONNECT_RESPONSE_MAX_SIZE: usize = 8192;
+/// The protocol of the upstream proxy
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UpstreamProxyProtocol {
+    /// Http connect, the credential is sent with basic authentication
+    Http,
+    /// Socks5 connect, the credential is sent with username and password authentication
+    Socks5,
+}
+/// The upstream proxy which the proxy connections go through, for the networks
+/// which can only reach the internet through a corporate proxy
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UpstreamProxyConfig {
+    pub protocol: UpstreamProxyProtocol,
+    /// The address of the upstream proxy
+    pub address: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+impl UpstreamProxyConfig {
+    /// Validate the address and the credential of the upstream proxy
+    pub fn validate(&self) -> Result<(), AgentError> {
+        if self.address.is_empty() {
+            return Err(AgentError::InvalidConfig(
+                "upstream_proxy address can not be empty".to_string(),
+            ));
+        }
+        if self.username.is_some() != self.password.is_some() {
+            return Err(AgentError::InvalidConfig(
+                "upstream_proxy username and password must be given together".to_string(),
+            ));
+        }
+        Ok(())
+    }
+    /// Connect to the proxy through the upstream proxy, the returned
+    /// connection carries the bytes to the proxy after the handshake
+    pub async fn connect(&self, proxy_address: SocketAddr) -> Result<TcpStream, AgentError> {
+        let mut upstream_tcp_stream = TcpStream::connect(&self.address).await?;
+        match self.protocol {
+            UpstreamProxyProtocol::Http => {
+                self.http_connect(&mut upstream_tcp_stream, proxy_address)
+                    .await?
+            }
+            UpstreamProxyProtocol::Socks5 => {
+                let user_key = self
+                    .username
+                    .as_ref()
+                    .zip(self.password.as_ref())
+                    .map(|(username, password)| UserKey::new(username, password));
+                socks5_impl::client::connect(&mut upstream_tcp_stream, proxy_address, user_key)
+                    .await
+                    .map_err(|e| {
+                        AgentError::UpstreamProxy(format!("socks5 connect rejected: {e}"))
+                    })?;
+            }
+        }
+        Ok(upstream_tcp_stream)
+    }
+    async fn http_connect(
+        &self,
+        upstream_tcp_stream: &mut TcpStream,
+        proxy_address: SocketAddr,
+    ) -> Result<(), AgentError> {
+        let mut connect_request =
+            format!("CONNECT {proxy_address} HTTP/1.1\r\nHost: {proxy_address}\r\n");
+        if let (Some(username), Some(password)) = (&self.username, &self.password) {
+            let credential = STANDARD.encode(format!("{username}:{password}"));
+            connect_request.push_str(&format!("Proxy-Authorization: Basic {credential}\r\n"));
+        }
+        connect_request.push_str("\r\n");
+        upstream_tcp_stream
+            .write_all(connect_request.as_bytes())
+            .await?;
+        // Peek before reading, the bytes after the response header belong to the proxy
+        let mut connect_response = Vec::new();
+        let mut peek_buf = [0u8; 1024];
+        loop {
+            if connect_response.len() >= HTTP_CONNECT_RESPONSE_MAX_SIZE {
+                return Err(AgentError::UpstreamProxy(
+                    "http connect response too large".to_string(),
+                ));
+            }
+            let peek_size = upstream_tcp_stream.peek(&mut peek_buf).await?;
+            if peek_size == 0 {
+                return Err(AgentError::UpstreamProxy(
+                    "http connect response closed before the header end".to_string(),
+                ));
+            }
+            // The header end can be split between the peeks
+            let search_start = connect_response.len().saturating_sub(3);
+            let read_size = connect_response.len();
+            connect_response.extend_from_slice(&peek_buf[..peek_size]);
+            let header_end = connect_response[search_start..]
+                .windows(4)
+                .position(|window| window == b"\r\n\r\n")
+                .map(|position| search_start + position + 4);
+            match header_end {
+                None => {
+                    upstream_tcp_stream
+                        .read_exact(&mut peek_buf[..peek_size])
+                        .await?;
+                }
+                Some(header_end) => {
+                    upstream_tcp_stream
+                        .read_exact(&mut peek_buf[..header_end - read_size])
+                        .await?;
+                    connect_response.truncate(header_end);
+                    break;
+                }
+            }
+        }
+        let status_line = String::from_utf8_lossy(&connect_response);
+        let status_line = status_line.lines().next().unwrap_or_default();
+        match status_line.split_whitespace().nth(1) {
+            Some(status_code) if status_code.starts_with('2') => Ok(()),
+            _ => Err(AgentError::UpstreamProxy(format!(
+                "http connect rejected: {status_line}"
+            ))),
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+    #[tokio::test]
+    async fn connect_through_http_upstream_proxy() {
+        let upstream_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_proxy = UpstreamProxyConfig {
+            protocol: UpstreamProxyProtocol::Http,
+            address: upstream_listener.local_addr().unwrap().to_string(),
+            username: Some("user".to_string()),
+            password: Some("pass".to_string()),
+        };
+        let upstream_task = tokio::spawn(async move {
+            let (mut agent_tcp_stream, _) = upstream_listener.accept().await.unwrap();
+            let mut connect_request = vec![0u8; 256];
+            let size = agent_tcp_stream.read(&mut connect_request).await.unwrap();
+            agent_tcp_stream
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\nproxy")
+                .await
+                .unwrap();
+            String::from_utf8(connect_request[..size].to_vec()).unwrap()
+        });
+        let mut proxy_tcp_stream = upstream_proxy
+            .connect("10.0.0.1:80".parse().unwrap())
+            .await
+            .unwrap();
+        let mut proxy_bytes = [0u8; 5];
+        proxy_tcp_stream.read_exact(&mut proxy_bytes).await.unwrap();
+        assert_eq!(&proxy_bytes, b"proxy");
+        let connect_request = upstream_task.await.unwrap();
+        assert!(connect_request.starts_with("CONNECT 10.0.0.1:80 HTTP/1.1\r\n"));
+        assert!(connect_request.contains("Proxy-Authorization: Basic dXNlcjpwYXNz\r\n"));
+    }
+    /// Answer the http connect request with the response chunks
+    async fn serve_http_connect(response_chunks: Vec<Vec<u8>>) -> UpstreamProxyConfig {
+        let upstream_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_proxy = UpstreamProxyConfig {
+            protocol: UpstreamProxyProtocol::Http,
+            address: upstream_listener.local_addr().unwrap().to_string(),
+            username: None,
+            password: None,
+        };
+        tokio::spawn(async move {
+            let (mut agent_tcp_stream, _) = upstream_listener.accept().await.unwrap();
+            let mut connect_request = vec![0u8; 256];
+            let _ = agent_tcp_stream.read(&mut connect_request).await.unwrap();
+            for response_chunk in response_chunks {
+                agent_tcp_stream.write_all(&response_chunk).await.unwrap();
+                agent_tcp_stream.flush().await.unwrap();
+                tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+            }
+            // Keep the connection until the agent closes it
+            let _ = agent_tcp_stream.read(&mut connect_request).await;
+        });
+        upstream_proxy
+    }
+    #[tokio::test]
+    async fn read_http_connect_response_split_in_chunks() {
+        let upstream_proxy = serve_http_connect(vec![
+            b"HTTP/1.1 200 Connection established\r\n\r".to_vec(),
+            b"\nproxy".to_vec(),
+        ])
+        .await;
+        let mut proxy_tcp_stream = upstream_proxy
+            .connect("10.0.0.1:80".parse().unwrap())
+            .await
+            .unwrap();
+        let mut proxy_bytes = [0u8; 5];
+        proxy_tcp_stream.read_exact(&mut proxy_bytes).await.unwrap();
+        assert_eq!(&proxy_bytes, b"proxy");
+    }
+    #[tokio::test]
+    async fn reject_http_connect_with_non_success_status() {
+        let upstream_proxy = serve_http_connect(vec![
+            b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n".to_vec(),
+        ])
+        .await;
+        let result = upstream_proxy.connect("10.0.0.1:80".parse().unwrap()).await;
+        assert!(matches!(
+            result,
+            Err(AgentError::UpstreamProxy(reason)) if reason.contains("407")
+        ));
+    }
+    #[tokio::test]
+    async fn reject_http_connect_with_too_large_response() {
+        let large_header = format!(
+            "HTTP/1.1 200 OK\r\nX-Padding: {}\r\n\r\n",
+            "a".repeat(HTTP_CONNECT_RESPONSE_MAX_SIZE)
+        );
+        let upstream_proxy = serve_http_connect(vec![large_header.into_bytes()]).await;
+        let result = upstream_proxy.connect("10.0.0.1:80".parse().unwrap()).await;
+        assert!(matches!(
+            result,
+            Err(AgentError::UpstreamProxy(reason)) if reason.contains("too large")
+        ));
+    }
+    #[tokio::test]
+    async fn connect_through_socks5_upstream_proxy() {
+        let upstream_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_proxy = UpstreamProxyConfig {
+            protocol: UpstreamProxyProtocol::Socks5,
+            address: upstream_listener.local_addr().unwrap().to_string(),
+            username: Some("user".to_string()),
+            password: Some("pass".to_string()),
+        };
+        let upstream_task = tokio::spawn(async move {
+            let (mut agent_tcp_stream, _) = upstream_listener.accept().await.unwrap();
+            // The greeting offers the username and password authentication
+            let mut greeting = [0u8; 2];
+            agent_tcp_stream.read_exact(&mut greeting).await.unwrap();
+            let mut methods = vec![0u8; greeting[1] as usize];
+            agent_tcp_stream.read_exact(&mut methods).await.unwrap();
+            assert!(methods.contains(&2));
+            agent_tcp_stream.write_all(&[5, 2]).await.unwrap();
+            let mut auth_version_and_username_size = [0u8; 2];
+            agent_tcp_stream
+                .read_exact(&mut auth_version_and_username_size)
+                .await
+                .unwrap();
+            let mut username = vec![0u8; auth_version_and_username_size[1] as usize];
+            agent_tcp_stream.read_exact(&mut username).await.unwrap();
+            let password_size = agent_tcp_stream.read_u8().await.unwrap();
+            let mut password = vec![0u8; password_size as usize];
+            agent_tcp_stream.read_exact(&mut password).await.unwrap();
+            agent_tcp_stream.write_all(&[1, 0]).await.unwrap();
+            // The connect request to the ipv4 proxy address
+            let mut connect_request = [0u8; 10];
+            agent_tcp_stream
+                .read_exact(&mut connect_request)
+                .await
+                .unwrap();
+            agent_tcp_stream
+                .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+            agent_tcp_stream.write_all(b"proxy").await.unwrap();
+            (username, password, connect_request)
+        });
+        let mut proxy_tcp_stream = upstream_proxy
+            .connect("10.0.0.1:80".parse().unwrap())
+            .await
+            .unwrap();
+        let mut proxy_bytes = [0u8; 5];
+        proxy_tcp_stream.read_exact(&mut proxy_bytes).await.unwrap();
+        assert_eq!(&proxy_bytes, b"proxy");
+        let (username, password, connect_request) = upstream_task.await.unwrap();
+        assert_eq!(username, b"user");
+        assert_eq!(password, b"pass");
+        assert_eq!(connect_request, [5, 1, 0, 1, 10, 0, 0, 1, 0, 80]);
+    }
+}
@@ -21,3 +21,19 @@ pub enum CodecError {
     #[error("Fail to get encryption holder lock")]
     EncryptionHolderLock,
 }
+impl CodecError {
+    /// The kind of the error, used as the metrics label
+    pub fn kind(&self) -> &'static str {
+        match self {
+            CodecError::Io(_) => "io",
+            CodecError::Crypto(_) => "crypto",
+            CodecError::Bincode(_) => "bincode",
+            CodecError::InvalidRelayResponseStatusByte(_) => "invalid_relay_response_status_byte",
+            CodecError::InvalidRelayTypeByte(_) => "invalid_relay_type_byte",
+            CodecError::InvalidAgentPacketByte(_) => "invalid_agent_packet_byte",
+            CodecError::NotEnoughRemainingBytes(_) => "not_enough_remaining_bytes",
+            CodecError::EncryptionNotExist(_) => "encryption_not_exist",
+            CodecError::EncryptionHolderLock => "encryption_holder_lock",
+        }
+    }
+}
@@ -38,9 +38,46 @@ impl Decoder for HeartbeatPongDecoder {
         let ping_request_bytes = self.length_delimited_codec.decode(src)?;
         match ping_request_bytes {
             None => Ok(None),
-            Some(ping_request_bytes) => Ok(Some(bincode::deserialize::<HeartbeatPong>(
-                &ping_request_bytes,
-            )?)),
+            Some(ping_request_bytes) => {
+                match bincode::deserialize::<HeartbeatPong>(&ping_request_bytes) {
+                    Ok(heartbeat_pong) => Ok(Some(heartbeat_pong)),
+                    // The pong of the older proxy only has the heartbeat time
+                    Err(_) => Ok(Some(HeartbeatPong {
+                        heartbeat_time: bincode::deserialize(&ping_request_bytes)?,
+                        ping_heartbeat_time: None,
+                    })),
+                }
+            }
         }
     }
 }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ppaass_domain::heartbeat::HeartbeatPing;
+    fn decode(heartbeat_pong_bytes: Vec<u8>) -> HeartbeatPong {
+        let mut src = BytesMut::new();
+        LengthDelimitedCodec::new()
+            .encode(heartbeat_pong_bytes.into(), &mut src)
+            .unwrap();
+        HeartbeatPongDecoder::new()
+            .decode(&mut src)
+            .unwrap()
+            .unwrap()
+    }
+    #[test]
+    fn decode_pong_of_current_and_older_proxy() {
+        let heartbeat_ping = HeartbeatPing::default();
+        let heartbeat_pong = HeartbeatPong::reply(&heartbeat_ping);
+        let decoded = decode(bincode::serialize(&heartbeat_pong).unwrap());
+        assert_eq!(decoded.heartbeat_time, heartbeat_pong.heartbeat_time);
+        assert_eq!(
+            decoded.ping_heartbeat_time,
+            Some(heartbeat_ping.heartbeat_time)
+        );
+        // The pong of the older proxy only has the heartbeat time
+        let decoded = decode(bincode::serialize(&heartbeat_pong.heartbeat_time).unwrap());
+        assert_eq!(decoded.heartbeat_time, heartbeat_pong.heartbeat_time);
+        assert_eq!(decoded.ping_heartbeat_time, None);
+    }
+}
@@ -1,5 +1,7 @@
 use crate::error::CodecError;
 use ppaass_crypto::rsa::RsaCrypto;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::sync::Arc;
 /// The rsa crypto fetcher,
 /// each player have a rsa crypto
@@ -45,3 +47,32 @@ where
         RsaCryptoHolder::get_rsa_crypto(*self, auth_token)
     }
 }
+/// The users changed by reloading the rsa crypto holder
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RsaCryptoReloadReport {
+    /// The users which are newly added
+    pub added_users: Vec<String>,
+    /// The users which are removed, they are revoked
+    pub removed_users: Vec<String>,
+}
+impl RsaCryptoReloadReport {
+    /// Compare the users before and after the reload
+    pub fn new<V>(previous: &HashMap<String, V>, current: &HashMap<String, V>) -> Self {
+        let mut added_users = current
+            .keys()
+            .filter(|user| !previous.contains_key(*user))
+            .cloned()
+            .collect::<Vec<String>>();
+        added_users.sort();
+        let mut removed_users = previous
+            .keys()
+            .filter(|user| !current.contains_key(*user))
+            .cloned()
+            .collect::<Vec<String>>();
+        removed_users.sort();
+        Self {
+            added_users,
+            removed_users,
+        }
+    }
+}
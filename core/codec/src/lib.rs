@@ -7,6 +7,7 @@ use crate::heartbeat::ping::{HeartbeatPingDecoder, HeartbeatPingEncoder};
 use crate::heartbeat::pong::{HeartbeatPongDecoder, HeartbeatPongEncoder};
 pub use holder::EncryptionHolder;
 pub use holder::RsaCryptoHolder;
+pub use holder::RsaCryptoReloadReport;
 use ppaass_crypto::aes::{decrypt_with_aes, encrypt_with_aes};
 use ppaass_domain::tunnel::Encryption;
 use ppaass_domain::{AgentControlPacket, AgentDataPacket, ProxyControlPacket, ProxyDataPacket};
@@ -57,6 +58,8 @@ where
 {
     tunnel_init_request_decoder: TunnelInitRequestDecoder<F>,
     heartbeat_ping_decoder: HeartbeatPingDecoder,
+    /// The type of the packet partially decoded
+    packet_type: Option<u8>,
 }
 impl<F> AgentControlPacketDecoder<F>
 where
@@ -66,6 +69,7 @@ where
         Self {
             tunnel_init_request_decoder: TunnelInitRequestDecoder::new(rsa_crypto_holder),
             heartbeat_ping_decoder: HeartbeatPingDecoder::new(),
+            packet_type: None,
         }
     }
 }
@@ -76,29 +80,26 @@ where
     type Item = AgentControlPacket;
     type Error = CodecError;
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        if src.len() < size_of::<u8>() {
-            return Ok(None);
-        }
-        let packet_type = src.get_u8();
-        match packet_type {
-            0 => {
-                let tunnel_init_request = self.tunnel_init_request_decoder.decode(src)?;
-                match tunnel_init_request {
-                    None => Ok(None),
-                    Some(tunnel_init_request) => {
-                        Ok(Some(AgentControlPacket::TunnelInit(tunnel_init_request)))
-                    }
-                }
-            }
-            1 => {
-                let heartbeat_ping = self.heartbeat_ping_decoder.decode(src)?;
-                match heartbeat_ping {
-                    None => Ok(None),
-                    Some(heartbeat_ping) => Ok(Some(AgentControlPacket::Heartbeat(heartbeat_ping))),
-                }
-            }
-            packet_type => Err(CodecError::InvalidAgentPacketByte(packet_type)),
+        let packet_type = match self.packet_type.take() {
+            Some(packet_type) => packet_type,
+            None if src.is_empty() => return Ok(None),
+            None => src.get_u8(),
+        };
+        let agent_control_packet = match packet_type {
+            0 => self
+                .tunnel_init_request_decoder
+                .decode(src)?
+                .map(AgentControlPacket::TunnelInit),
+            1 => self
+                .heartbeat_ping_decoder
+                .decode(src)?
+                .map(AgentControlPacket::Heartbeat),
+            packet_type => return Err(CodecError::InvalidAgentPacketByte(packet_type)),
+        };
+        if agent_control_packet.is_none() {
+            self.packet_type = Some(packet_type);
         }
+        Ok(agent_control_packet)
     }
 }
 pub struct ProxyControlPacketEncoder<F>
@@ -107,6 +108,7 @@ where
 {
     tunnel_init_response_encoder: TunnelInitResponseEncoder<F>,
     heartbeat_pong_encoder: HeartbeatPongEncoder,
+    tunnel_init_failure_encoder: TunnelInitFailureEncoder,
 }
 impl<F> ProxyControlPacketEncoder<F>
 where
@@ -116,6 +118,7 @@ where
         Self {
             tunnel_init_response_encoder: TunnelInitResponseEncoder::new(rsa_crypto_holder),
             heartbeat_pong_encoder: HeartbeatPongEncoder::new(),
+            tunnel_init_failure_encoder: TunnelInitFailureEncoder::new(),
         }
     }
 }
@@ -135,6 +138,11 @@ where
                 dst.put_u8(1);
                 self.heartbeat_pong_encoder.encode(heartbeat_ping, dst)
             }
+            ProxyControlPacket::TunnelInitFail(tunnel_init_failure) => {
+                dst.put_u8(2);
+                self.tunnel_init_failure_encoder
+                    .encode(tunnel_init_failure, dst)
+            }
         }
     }
 }
@@ -144,7 +152,10 @@ where
 {
     tunnel_init_response_decoder: TunnelInitResponseDecoder<F>,
     heartbeat_pong_decoder: HeartbeatPongDecoder,
+    tunnel_init_failure_decoder: TunnelInitFailureDecoder,
     auth_token: String,
+    /// The type of the packet partially decoded
+    packet_type: Option<u8>,
 }
 impl<F> ProxyControlPacketDecoder<F>
 where
@@ -157,7 +168,9 @@ where
                 rsa_crypto_holder,
             ),
             heartbeat_pong_decoder: HeartbeatPongDecoder::new(),
+            tunnel_init_failure_decoder: TunnelInitFailureDecoder::new(),
             auth_token,
+            packet_type: None,
         }
     }
 }
@@ -168,30 +181,32 @@ where
     type Item = ProxyControlPacket;
     type Error = CodecError;
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        if src.len() < size_of::<u8>() {
-            return Ok(None);
-        }
-        let packet_type = src.get_u8();
-        match packet_type {
-            0 => {
-                let tunnel_init_response = self.tunnel_init_response_decoder.decode(src)?;
-                match tunnel_init_response {
-                    None => Ok(None),
-                    Some(tunnel_init_response) => Ok(Some(ProxyControlPacket::TunnelInit((
-                        self.auth_token.clone(),
-                        tunnel_init_response,
-                    )))),
-                }
-            }
-            1 => {
-                let heartbeat_pong = self.heartbeat_pong_decoder.decode(src)?;
-                match heartbeat_pong {
-                    None => Ok(None),
-                    Some(heartbeat_pong) => Ok(Some(ProxyControlPacket::Heartbeat(heartbeat_pong))),
-                }
-            }
-            packet_type => Err(CodecError::InvalidAgentPacketByte(packet_type)),
+        let packet_type = match self.packet_type.take() {
+            Some(packet_type) => packet_type,
+            None if src.is_empty() => return Ok(None),
+            None => src.get_u8(),
+        };
+        let proxy_control_packet = match packet_type {
+            0 => self
+                .tunnel_init_response_decoder
+                .decode(src)?
+                .map(|tunnel_init_response| {
+                    ProxyControlPacket::TunnelInit((self.auth_token.clone(), tunnel_init_response))
+                }),
+            1 => self
+                .heartbeat_pong_decoder
+                .decode(src)?
+                .map(ProxyControlPacket::Heartbeat),
+            2 => self
+                .tunnel_init_failure_decoder
+                .decode(src)?
+                .map(ProxyControlPacket::TunnelInitFail),
+            packet_type => return Err(CodecError::InvalidAgentPacketByte(packet_type)),
+        };
+        if proxy_control_packet.is_none() {
+            self.packet_type = Some(packet_type);
         }
+        Ok(proxy_control_packet)
     }
 }
 pub struct AgentDataPacketEncoder {
@@ -31,6 +31,7 @@ where
     type Error = CodecError;
     fn encode(&mut self, item: TunnelInitRequest, dst: &mut BytesMut) -> Result<(), Self::Error> {
         let TunnelInitRequest {
+            tunnel_id,
             agent_encryption,
             auth_token,
             dst_address,
@@ -47,6 +48,7 @@ where
             Encryption::Aes(aes_token) => Encryption::Aes(rsa_crypto.encrypt(&aes_token)?),
         };
         let tunnel_init_request = TunnelInitRequest {
+            tunnel_id,
             agent_encryption,
             auth_token,
             dst_address,
@@ -89,6 +91,7 @@ where
             None => Ok(None),
             Some(tunnel_init_request_bytes) => {
                 let TunnelInitRequest {
+                    tunnel_id,
                     agent_encryption,
                     auth_token,
                     dst_address,
@@ -102,6 +105,7 @@ where
                     Encryption::Aes(aes_token) => Encryption::Aes(rsa_crypto.decrypt(&aes_token)?),
                 };
                 Ok(Some(TunnelInitRequest {
+                    tunnel_id,
                     agent_encryption,
                     auth_token,
                     dst_address,
@@ -1,7 +1,7 @@
 use crate::error::CodecError;
 use crate::RsaCryptoHolder;
 use ppaass_crypto::error::CryptoError;
-use ppaass_domain::tunnel::{Encryption, TunnelInitResponse};
+use ppaass_domain::tunnel::{Encryption, TunnelInitFailure, TunnelInitResponse};
 use std::sync::Arc;
 use tokio_util::bytes::BytesMut;
 use tokio_util::codec::{Decoder, Encoder, LengthDelimitedCodec};
@@ -102,3 +102,48 @@ where
         }
     }
 }
+/// Tunnel init failure encoder will be used by proxy side, the failure
+/// is not encrypted because it carries no secret
+pub(crate) struct TunnelInitFailureEncoder {
+    length_delimited_codec: LengthDelimitedCodec,
+}
+impl TunnelInitFailureEncoder {
+    pub fn new() -> Self {
+        Self {
+            length_delimited_codec: LengthDelimitedCodec::new(),
+        }
+    }
+}
+impl Encoder<TunnelInitFailure> for TunnelInitFailureEncoder {
+    type Error = CodecError;
+    fn encode(&mut self, item: TunnelInitFailure, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let tunnel_init_failure_bytes = bincode::serialize(&item)?;
+        Ok(self
+            .length_delimited_codec
+            .encode(tunnel_init_failure_bytes.into(), dst)?)
+    }
+}
+/// Tunnel init failure decoder will be used by agent side
+pub(crate) struct TunnelInitFailureDecoder {
+    length_delimited_codec: LengthDelimitedCodec,
+}
+impl TunnelInitFailureDecoder {
+    pub fn new() -> Self {
+        Self {
+            length_delimited_codec: LengthDelimitedCodec::new(),
+        }
+    }
+}
+impl Decoder for TunnelInitFailureDecoder {
+    type Item = TunnelInitFailure;
+    type Error = CodecError;
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let tunnel_init_failure_bytes = self.length_delimited_codec.decode(src)?;
+        match tunnel_init_failure_bytes {
+            None => Ok(None),
+            Some(tunnel_init_failure_bytes) => Ok(Some(bincode::deserialize::<TunnelInitFailure>(
+                &tunnel_init_failure_bytes,
+            )?)),
+        }
+    }
+}
@@ -0,0 +1,313 @@
+use crate::error::CommonError;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+#[cfg(unix)]
+use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
+const UNIX_ADDRESS_PREFIX: &str = "unix:";
+/// The address of the admin endpoint, a unix socket path prefixed with `unix:`
+/// or a loopback tcp address.
+///
+/// The admin endpoint has no authentication, the unix socket is only accessible by
+/// the owner, while every local user and process can reach the loopback tcp address,
+/// so prefer the unix socket on the shared hosts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AdminAddress {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+impl FromStr for AdminAddress {
+    type Err = CommonError;
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = value.strip_prefix(UNIX_ADDRESS_PREFIX) {
+            if path.is_empty() {
+                return Err(CommonError::InvalidAdminAddress(value.to_string()));
+            }
+            return Ok(AdminAddress::Unix(PathBuf::from(path)));
+        }
+        let socket_address = value
+            .parse::<SocketAddr>()
+            .map_err(|_| CommonError::InvalidAdminAddress(value.to_string()))?;
+        // The admin endpoint has no authentication, never expose it to the network
+        if !socket_address.ip().is_loopback() {
+            return Err(CommonError::InvalidAdminAddress(format!(
+                "{value} is not a loopback address"
+            )));
+        }
+        Ok(AdminAddress::Tcp(socket_address))
+    }
+}
+impl Display for AdminAddress {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AdminAddress::Tcp(socket_address) => write!(f, "{socket_address}"),
+            AdminAddress::Unix(path) => write!(f, "{UNIX_ADDRESS_PREFIX}{}", path.display()),
+        }
+    }
+}
+/// Serve the admin requests on the address until the `stop_token` cancelled.
+///
+/// Each request and response is a line of json, the response is
+/// `{"Ok": <response>}` or `{"Err": "<reason>"}`.
+pub async fn serve_admin<Req, Resp, H, F>(
+    admin_address: AdminAddress,
+    handler: H,
+    stop_token: CancellationToken,
+) -> Result<(), CommonError>
+where
+    Req: DeserializeOwned + Send + 'static,
+    Resp: Serialize + Send + 'static,
+    H: Fn(Req) -> F + Clone + Send + Sync + 'static,
+    F: Future<Output = Result<Resp, String>> + Send,
+{
+    match &admin_address {
+        AdminAddress::Tcp(socket_address) => {
+            let listener = TcpListener::bind(socket_address).await?;
+            warn!("Admin listener started on: {admin_address}, every local user can reach it without authentication, prefer the unix socket.");
+            loop {
+                let (admin_stream, _) = tokio::select! {
+                    accept_result = listener.accept() => accept_result?,
+                    _ = stop_token.cancelled() => break,
+                };
+                spawn_admin_connection(admin_stream, handler.clone());
+            }
+        }
+        #[cfg(unix)]
+        AdminAddress::Unix(path) => {
+            remove_stale_socket(path)?;
+            let listener = tokio::net::UnixListener::bind(path)?;
+            // Only the owner can control the server through the socket
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+            info!("Admin listener started on: {admin_address}");
+            loop {
+                let (admin_stream, _) = tokio::select! {
+                    accept_result = listener.accept() => accept_result?,
+                    _ = stop_token.cancelled() => break,
+                };
+                spawn_admin_connection(admin_stream, handler.clone());
+            }
+            let _ = std::fs::remove_file(path);
+        }
+        #[cfg(not(unix))]
+        AdminAddress::Unix(_) => {
+            return Err(CommonError::InvalidAdminAddress(format!(
+                "{admin_address} is not supported on this platform"
+            )));
+        }
+    }
+    info!("Admin listener stopped.");
+    Ok(())
+}
+/// Remove the socket file left by the previous run, the other files are never removed
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> Result<(), CommonError> {
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(CommonError::InvalidAdminAddress(format!(
+            "{} exists and is not a unix socket",
+            path.display()
+        )));
+    }
+    std::fs::remove_file(path)?;
+    Ok(())
+}
+fn spawn_admin_connection<S, Req, Resp, H, F>(admin_stream: S, handler: H)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    Req: DeserializeOwned + Send + 'static,
+    Resp: Serialize + Send + 'static,
+    H: Fn(Req) -> F + Send + Sync + 'static,
+    F: Future<Output = Result<Resp, String>> + Send,
+{
+    tokio::spawn(async move {
+        if let Err(e) = handle_admin_connection(admin_stream, handler).await {
+            debug!("Fail to handle admin connection: {e:?}");
+        }
+    });
+}
+async fn handle_admin_connection<S, Req, Resp, H, F>(
+    admin_stream: S,
+    handler: H,
+) -> Result<(), CommonError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    Req: DeserializeOwned,
+    Resp: Serialize,
+    H: Fn(Req) -> F,
+    F: Future<Output = Result<Resp, String>>,
+{
+    let mut admin_stream = BufReader::new(admin_stream);
+    let mut request_line = String::new();
+    loop {
+        request_line.clear();
+        if admin_stream.read_line(&mut request_line).await? == 0 {
+            return Ok(());
+        }
+        let response = match serde_json::from_str::<Req>(request_line.trim()) {
+            Ok(request) => handler(request).await,
+            Err(e) => {
+                error!("Receive invalid admin request: {e}");
+                Err(format!("invalid admin request: {e}"))
+            }
+        };
+        let mut response_line = serde_json::to_vec(&response)?;
+        response_line.push(b'\n');
+        admin_stream.get_mut().write_all(&response_line).await?;
+    }
+}
+/// Send one request to the admin endpoint and wait for the response
+pub async fn send_admin_request<Req, Resp>(
+    admin_address: &AdminAddress,
+    request: &Req,
+) -> Result<Resp, CommonError>
+where
+    Req: Serialize,
+    Resp: DeserializeOwned,
+{
+    match admin_address {
+        AdminAddress::Tcp(socket_address) => {
+            let admin_stream = TcpStream::connect(socket_address).await?;
+            exchange_admin_request(admin_stream, request).await
+        }
+        #[cfg(unix)]
+        AdminAddress::Unix(path) => {
+            let admin_stream = tokio::net::UnixStream::connect(path).await?;
+            exchange_admin_request(admin_stream, request).await
+        }
+        #[cfg(not(unix))]
+        AdminAddress::Unix(_) => Err(CommonError::InvalidAdminAddress(format!(
+            "{admin_address} is not supported on this platform"
+        ))),
+    }
+}
+async fn exchange_admin_request<S, Req, Resp>(
+    admin_stream: S,
+    request: &Req,
+) -> Result<Resp, CommonError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    Req: Serialize,
+    Resp: DeserializeOwned,
+{
+    let mut admin_stream = BufReader::new(admin_stream);
+    let mut request_line = serde_json::to_vec(request)?;
+    request_line.push(b'\n');
+    admin_stream.get_mut().write_all(&request_line).await?;
+    let mut response_line = String::new();
+    if admin_stream.read_line(&mut response_line).await? == 0 {
+        return Err(CommonError::AdminConnectionClosed);
+    }
+    serde_json::from_str::<Result<Resp, String>>(response_line.trim())?
+        .map_err(CommonError::AdminRequestRejected)
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    #[derive(Debug, Serialize, Deserialize)]
+    enum TestRequest {
+        Echo(String),
+        Reject,
+    }
+    #[test]
+    fn parse_admin_address() {
+        assert_eq!(
+            "127.0.0.1:9000".parse::<AdminAddress>().unwrap(),
+            AdminAddress::Tcp("127.0.0.1:9000".parse().unwrap())
+        );
+        assert_eq!(
+            "unix:/tmp/admin.sock".parse::<AdminAddress>().unwrap(),
+            AdminAddress::Unix(PathBuf::from("/tmp/admin.sock"))
+        );
+        assert!("0.0.0.0:9000".parse::<AdminAddress>().is_err());
+        assert!("unix:".parse::<AdminAddress>().is_err());
+    }
+    #[tokio::test]
+    async fn exchange_request_and_response() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let admin_address = AdminAddress::Tcp(listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (admin_stream, _) = listener.accept().await.unwrap();
+            handle_admin_connection(admin_stream, |request: TestRequest| async move {
+                match request {
+                    TestRequest::Echo(message) => Ok(message),
+                    TestRequest::Reject => Err("rejected".to_string()),
+                }
+            })
+            .await
+            .unwrap();
+        });
+        let response: String =
+            send_admin_request(&admin_address, &TestRequest::Echo("hello".to_string()))
+                .await
+                .unwrap();
+        assert_eq!(response, "hello");
+    }
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn serve_admin_on_owner_only_unix_socket() {
+        let test_dir =
+            std::env::temp_dir().join(format!("ppaass-admin-test-{}", std::process::id()));
+        std::fs::create_dir_all(&test_dir).unwrap();
+        let socket_path = test_dir.join("admin.sock");
+        let admin_address = AdminAddress::Unix(socket_path.clone());
+        // The regular file is never removed
+        std::fs::write(&socket_path, b"not a socket").unwrap();
+        let result = serve_admin(
+            admin_address.clone(),
+            |request: TestRequest| async move {
+                match request {
+                    TestRequest::Echo(message) => Ok(message),
+                    TestRequest::Reject => Err("rejected".to_string()),
+                }
+            },
+            CancellationToken::new(),
+        )
+        .await;
+        assert!(matches!(result, Err(CommonError::InvalidAdminAddress(_))));
+        assert_eq!(std::fs::read(&socket_path).unwrap(), b"not a socket");
+        std::fs::remove_file(&socket_path).unwrap();
+        let stop_token = CancellationToken::new();
+        let admin_task = tokio::spawn(serve_admin(
+            admin_address.clone(),
+            |request: TestRequest| async move {
+                match request {
+                    TestRequest::Echo(message) => Ok(message),
+                    TestRequest::Reject => Err("rejected".to_string()),
+                }
+            },
+            stop_token.clone(),
+        ));
+        let response: String = loop {
+            match send_admin_request(&admin_address, &TestRequest::Echo("hello".to_string())).await
+            {
+                Ok(response) => break response,
+                Err(_) => tokio::time::sleep(std::time::Duration::from_millis(10)).await,
+            }
+        };
+        assert_eq!(response, "hello");
+        let mode = std::fs::metadata(&socket_path)
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+        stop_token.cancel();
+        admin_task.await.unwrap().unwrap();
+        assert!(!socket_path.exists());
+        std::fs::remove_dir_all(&test_dir).unwrap();
+    }
+}
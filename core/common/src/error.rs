@@ -6,4 +6,22 @@ pub enum CommonError {
     Io(#[from] std::io::Error),
     #[error(transparent)]
     ParseLogLevel(#[from] ParseLevelError),
+    #[error("Fail to reload log level: {0}")]
+    ReloadLogLevel(String),
+    #[error("Invalid admin address: {0}")]
+    InvalidAdminAddress(String),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error("Admin request rejected: {0}")]
+    AdminRequestRejected(String),
+    #[error("Admin connection closed without response")]
+    AdminConnectionClosed,
+    #[error("Invalid tls configuration: {0}")]
+    Tls(String),
+    #[error(transparent)]
+    Rustls(#[from] rustls::Error),
+    #[error("Websocket error: {0}")]
+    WebSocket(String),
+    #[error("Quic error: {0}")]
+    Quic(String),
 }
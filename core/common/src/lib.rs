@@ -3,9 +3,54 @@ use std::path::Path;
 use std::str::FromStr;
 use tracing::Level;
 use tracing_appender::non_blocking::WorkerGuard;
+use tracing_subscriber::filter::LevelFilter;
 use tracing_subscriber::fmt::time::ChronoUtc;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::reload::Handle;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload, Registry};
+pub mod admin;
 pub mod error;
+pub mod metrics;
+pub mod quic;
+pub mod reload_trigger;
+pub mod shutdown;
+pub mod tls;
+pub mod tunnel_registry;
+pub mod tunnel_timeout;
+pub mod websocket;
 
+/// Compare the fields between two configuration and collect the changed field names
+#[macro_export]
+macro_rules! changed_fields {
+    ($current:expr, $new:expr, $($field:ident),+) => {{
+        let mut changed = Vec::new();
+        $(
+            if $current.$field != $new.$field {
+                changed.push(stringify!($field));
+            }
+        )+
+        changed
+    }};
+}
+/// The handle to change the max log level after the logger initialized
+#[derive(Clone)]
+pub struct LogLevelHandle {
+    handle: Handle<LevelFilter, Registry>,
+}
+impl LogLevelHandle {
+    /// Change the max log level
+    pub fn set_max_log_level(&self, max_log_level: &str) -> Result<(), CommonError> {
+        let level_filter = LevelFilter::from_level(Level::from_str(max_log_level)?);
+        self.handle
+            .reload(level_filter)
+            .map_err(|e| CommonError::ReloadLogLevel(e.to_string()))
+    }
+    /// The current max log level
+    pub fn max_log_level(&self) -> Option<LevelFilter> {
+        self.handle.clone_current()
+    }
+}
 /// Init the logger
 pub fn init_logger(
     // The folder to store the log file
@@ -14,19 +59,29 @@ pub fn init_logger(
     log_name_prefix: &str,
     // The max log level
     max_log_level: &str,
-) -> Result<WorkerGuard, CommonError> {
+) -> Result<(WorkerGuard, LogLevelHandle), CommonError> {
     let (trace_file_appender, _trace_appender_guard) = tracing_appender::non_blocking(
         tracing_appender::rolling::daily(log_folder, log_name_prefix),
     );
-    tracing_subscriber::fmt()
-        .with_max_level(Level::from_str(max_log_level)?)
-        .with_writer(trace_file_appender)
-        .with_line_number(true)
-        .with_level(true)
-        .with_thread_ids(true)
-        .with_thread_names(true)
-        .with_timer(ChronoUtc::rfc_3339())
-        .with_ansi(false)
+    let (level_filter, level_filter_handle) =
+        reload::Layer::new(LevelFilter::from_level(Level::from_str(max_log_level)?));
+    tracing_subscriber::registry()
+        .with(level_filter)
+        .with(
+            fmt::layer()
+                .with_writer(trace_file_appender)
+                .with_line_number(true)
+                .with_level(true)
+                .with_thread_ids(true)
+                .with_thread_names(true)
+                .with_timer(ChronoUtc::rfc_3339())
+                .with_ansi(false),
+        )
         .init();
-    Ok(_trace_appender_guard)
+    Ok((
+        _trace_appender_guard,
+        LogLevelHandle {
+            handle: level_filter_handle,
+        },
+    ))
 }
@@ -0,0 +1,134 @@
+use prometheus::{Encoder, Registry, TextEncoder};
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info};
+const METRICS_PATH: &str = "/metrics";
+/// The max size of the http request head accepted by the metrics listener
+const MAX_REQUEST_HEAD_SIZE: usize = 8192;
+/// Serve the metrics in the registry with prometheus text format on `GET /metrics`,
+/// the `refresh` is called before each gathering to update the sampled metrics.
+pub async fn serve_metrics<R>(
+    listen_address: SocketAddr,
+    registry: Registry,
+    refresh: R,
+    stop_token: CancellationToken,
+) -> Result<(), std::io::Error>
+where
+    R: Fn() + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind(listen_address).await?;
+    info!("Metrics listener started on: {listen_address}");
+    serve_metrics_on_listener(listener, registry, refresh, stop_token).await
+}
+async fn serve_metrics_on_listener<R>(
+    listener: TcpListener,
+    registry: Registry,
+    refresh: R,
+    stop_token: CancellationToken,
+) -> Result<(), std::io::Error>
+where
+    R: Fn() + Send + Sync + 'static,
+{
+    let refresh = std::sync::Arc::new(refresh);
+    loop {
+        let (metrics_stream, metrics_client_address) = tokio::select! {
+            accept_result = listener.accept() => accept_result?,
+            _ = stop_token.cancelled() => {
+                info!("Metrics listener stopped.");
+                return Ok(());
+            }
+        };
+        let registry = registry.clone();
+        let refresh = refresh.clone();
+        tokio::spawn(async move {
+            refresh();
+            if let Err(e) = handle_metrics_request(metrics_stream, &registry).await {
+                debug!("Fail to serve metrics to [{metrics_client_address}]: {e:?}");
+            }
+        });
+    }
+}
+async fn handle_metrics_request(
+    mut metrics_stream: TcpStream,
+    registry: &Registry,
+) -> Result<(), std::io::Error> {
+    let mut request_head = Vec::new();
+    let mut read_buf = [0u8; 1024];
+    while !request_head.windows(4).any(|window| window == b"\r\n\r\n") {
+        let read_amount = metrics_stream.read(&mut read_buf).await?;
+        if read_amount == 0 || request_head.len() + read_amount > MAX_REQUEST_HEAD_SIZE {
+            return Ok(());
+        }
+        request_head.extend_from_slice(&read_buf[..read_amount]);
+    }
+    let request_line = request_head
+        .split(|byte| *byte == b'\n')
+        .next()
+        .map(String::from_utf8_lossy)
+        .unwrap_or_default();
+    let mut request_line_parts = request_line.split_whitespace();
+    let method = request_line_parts.next().unwrap_or_default();
+    let path = request_line_parts.next().unwrap_or_default();
+    if method != "GET" || path != METRICS_PATH {
+        metrics_stream
+            .write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+            .await?;
+        return Ok(());
+    }
+    let encoder = TextEncoder::new();
+    let mut body = Vec::new();
+    if let Err(e) = encoder.encode(&registry.gather(), &mut body) {
+        error!("Fail to encode metrics: {e:?}");
+        metrics_stream
+            .write_all(b"HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+            .await?;
+        return Ok(());
+    }
+    let response_head = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        encoder.format_type(),
+        body.len()
+    );
+    metrics_stream.write_all(response_head.as_bytes()).await?;
+    metrics_stream.write_all(&body).await?;
+    metrics_stream.shutdown().await
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::IntCounter;
+    async fn request(listen_address: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(listen_address).await.unwrap();
+        stream
+            .write_all(format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").as_bytes())
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+    #[tokio::test]
+    async fn serve_registry_metrics() {
+        let registry = Registry::new();
+        let counter = IntCounter::new("test_total", "test counter").unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let listen_address = listener.local_addr().unwrap();
+        let stop_token = CancellationToken::new();
+        let server = tokio::spawn(serve_metrics_on_listener(
+            listener,
+            registry,
+            move || counter.inc(),
+            stop_token.clone(),
+        ));
+        let response = request(listen_address, METRICS_PATH).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("test_total 1"));
+        let response = request(listen_address, "/other").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found"));
+        stop_token.cancel();
+        server.await.unwrap().unwrap();
+    }
+}
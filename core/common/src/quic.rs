@@ -0,0 +1,404 @@
+use crate::error::CommonError;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures_util::{Sink, Stream};
+use quinn::{Connection, RecvStream, SendStream, VarInt};
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::io::Error;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio_util::codec::{Decoder, Encoder, Framed};
+use tracing::debug;
+/// The alpn of the quic transport between agent and proxy
+pub const QUIC_ALPN: &[u8] = b"ppaass";
+/// The datagrams buffered for one stream, the later ones are dropped like a full udp socket
+const STREAM_DATAGRAM_BUFFER_SIZE: usize = 256;
+/// The size of the stream id before the payload of every datagram
+const DATAGRAM_STREAM_ID_SIZE: usize = 8;
+type DatagramSenders = Arc<Mutex<HashMap<u64, Sender<Bytes>>>>;
+/// The quic connection between agent and proxy, every tunnel runs on one
+/// bidirectional stream, the datagrams are routed to the streams by the stream id
+#[derive(Debug, Clone)]
+pub struct QuicConnection {
+    connection: Connection,
+    datagram_senders: DatagramSenders,
+}
+impl QuicConnection {
+    pub fn new(connection: Connection) -> Self {
+        let datagram_senders = DatagramSenders::default();
+        tokio::spawn(route_datagrams(
+            connection.clone(),
+            datagram_senders.clone(),
+        ));
+        Self {
+            connection,
+            datagram_senders,
+        }
+    }
+    /// The current address of the peer, it changes when the peer migrates
+    pub fn remote_address(&self) -> SocketAddr {
+        self.connection.remote_address()
+    }
+    /// Whether the connection is closed by either side or lost
+    pub fn is_closed(&self) -> bool {
+        self.connection.close_reason().is_some()
+    }
+    /// Close the connection with the reason, all the streams are closed
+    pub fn close(&self, reason: &str) {
+        self.connection
+            .close(VarInt::from_u32(0), reason.as_bytes());
+    }
+    /// Open a stream to the peer, the peer accepts it on the first write
+    pub async fn open_stream(&self) -> Result<QuicStream, CommonError> {
+        let (send_stream, recv_stream) = self
+            .connection
+            .open_bi()
+            .await
+            .map_err(|e| CommonError::Quic(e.to_string()))?;
+        Ok(self.new_stream(send_stream, recv_stream))
+    }
+    /// Accept the next stream opened by the peer, `None` when the connection closed
+    pub async fn accept_stream(&self) -> Option<QuicStream> {
+        match self.connection.accept_bi().await {
+            Ok((send_stream, recv_stream)) => Some(self.new_stream(send_stream, recv_stream)),
+            Err(e) => {
+                debug!(
+                    "Quic connection with [{}] closed: {e:?}",
+                    self.remote_address()
+                );
+                None
+            }
+        }
+    }
+    fn new_stream(&self, send_stream: SendStream, recv_stream: RecvStream) -> QuicStream {
+        let stream_id = u64::from(send_stream.id());
+        // Register before any datagram of the stream can arrive
+        let (datagram_sender, datagram_receiver) = channel(STREAM_DATAGRAM_BUFFER_SIZE);
+        self.lock_datagram_senders()
+            .insert(stream_id, datagram_sender);
+        QuicStream {
+            quic_connection: self.clone(),
+            send_stream,
+            recv_stream,
+            datagrams: Some(QuicDatagrams {
+                quic_connection: self.clone(),
+                stream_id,
+                datagram_receiver,
+            }),
+        }
+    }
+    fn lock_datagram_senders(&self) -> MutexGuard<'_, HashMap<u64, Sender<Bytes>>> {
+        self.datagram_senders
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+async fn route_datagrams(connection: Connection, datagram_senders: DatagramSenders) {
+    while let Ok(mut datagram) = connection.read_datagram().await {
+        if datagram.len() < DATAGRAM_STREAM_ID_SIZE {
+            continue;
+        }
+        let stream_id = datagram.get_u64();
+        let datagram_sender = datagram_senders
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .get(&stream_id)
+            .cloned();
+        if let Some(datagram_sender) = datagram_sender {
+            let _ = datagram_sender.try_send(datagram);
+        }
+    }
+}
+/// One bidirectional stream of the quic connection
+#[derive(Debug)]
+pub struct QuicStream {
+    quic_connection: QuicConnection,
+    send_stream: SendStream,
+    recv_stream: RecvStream,
+    datagrams: Option<QuicDatagrams>,
+}
+impl QuicStream {
+    /// The current address of the peer
+    pub fn remote_address(&self) -> SocketAddr {
+        self.quic_connection.remote_address()
+    }
+    /// Take the datagrams of the stream, `None` when already taken
+    pub fn take_datagrams(&mut self) -> Option<QuicDatagrams> {
+        self.datagrams.take()
+    }
+}
+impl AsyncRead for QuicStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().recv_stream).poll_read(cx, buf)
+    }
+}
+impl AsyncWrite for QuicStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Error>> {
+        Pin::new(&mut self.get_mut().send_stream)
+            .poll_write(cx, buf)
+            .map_err(Error::from)
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.get_mut().send_stream).poll_flush(cx)
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.get_mut().send_stream).poll_shutdown(cx)
+    }
+}
+/// The unreliable datagrams bound to one stream of the quic connection
+#[derive(Debug)]
+pub struct QuicDatagrams {
+    quic_connection: QuicConnection,
+    stream_id: u64,
+    datagram_receiver: Receiver<Bytes>,
+}
+impl QuicDatagrams {
+    /// The max payload size of one datagram, `None` when the peer does not support datagrams
+    pub fn max_size(&self) -> Option<usize> {
+        self.quic_connection
+            .connection
+            .max_datagram_size()
+            .map(|max_datagram_size| max_datagram_size.saturating_sub(DATAGRAM_STREAM_ID_SIZE))
+    }
+    /// Send one datagram, it can be lost or reordered
+    pub fn send(&self, payload: &[u8]) -> Result<(), CommonError> {
+        let mut datagram = BytesMut::with_capacity(DATAGRAM_STREAM_ID_SIZE + payload.len());
+        datagram.put_u64(self.stream_id);
+        datagram.put_slice(payload);
+        self.quic_connection
+            .connection
+            .send_datagram(datagram.freeze())
+            .map_err(|e| CommonError::Quic(e.to_string()))
+    }
+    /// Receive the next datagram
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<Bytes>> {
+        self.datagram_receiver.poll_recv(cx)
+    }
+}
+impl Drop for QuicDatagrams {
+    fn drop(&mut self) {
+        self.quic_connection
+            .lock_datagram_senders()
+            .remove(&self.stream_id);
+    }
+}
+/// The framed udp tunnel, the packets ride on the quic datagrams when given,
+/// the packets exceeding the datagram size still go through the stream
+pub struct DatagramFramed<T, C> {
+    framed: Framed<T, C>,
+    datagrams: Option<(QuicDatagrams, C)>,
+}
+impl<T, C> DatagramFramed<T, C> {
+    /// All the packets go through the stream
+    pub fn new(framed: Framed<T, C>) -> Self {
+        Self {
+            framed,
+            datagrams: None,
+        }
+    }
+    /// The datagram codec is another instance, the stream codec can hold a partial frame
+    pub fn with_datagrams(
+        framed: Framed<T, C>,
+        datagrams: QuicDatagrams,
+        datagram_codec: C,
+    ) -> Self {
+        Self {
+            framed,
+            datagrams: Some((datagrams, datagram_codec)),
+        }
+    }
+}
+impl<T, C> Stream for DatagramFramed<T, C>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+    C: Decoder + Unpin,
+    C::Error: Debug,
+{
+    type Item = Result<C::Item, C::Error>;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if let Some((datagrams, datagram_codec)) = this.datagrams.as_mut() {
+            while let Poll::Ready(Some(datagram)) = datagrams.poll_recv(cx) {
+                // A datagram carries one whole packet, the broken one is skipped
+                // like a lost one instead of closing the tunnel
+                match datagram_codec.decode(&mut BytesMut::from(datagram)) {
+                    Ok(Some(item)) => return Poll::Ready(Some(Ok(item))),
+                    Ok(None) => debug!("Skip the incomplete quic datagram."),
+                    Err(e) => debug!("Skip the broken quic datagram: {e:?}"),
+                }
+            }
+        }
+        Pin::new(&mut this.framed).poll_next(cx)
+    }
+}
+impl<T, C, I> Sink<I> for DatagramFramed<T, C>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+    C: Encoder<I> + Unpin,
+    C::Error: From<Error>,
+{
+    type Error = C::Error;
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.get_mut().framed).poll_ready(cx)
+    }
+    fn start_send(self: Pin<&mut Self>, item: I) -> Result<(), Self::Error> {
+        let this = self.get_mut();
+        let Some((datagrams, datagram_codec)) = this.datagrams.as_mut() else {
+            return Pin::new(&mut this.framed).start_send(item);
+        };
+        let mut packet = BytesMut::new();
+        datagram_codec.encode(item, &mut packet)?;
+        let datagram_sent = datagrams
+            .max_size()
+            .is_some_and(|max_size| packet.len() <= max_size)
+            && datagrams.send(&packet).is_ok();
+        if !datagram_sent {
+            this.framed.write_buffer_mut().extend_from_slice(&packet);
+        }
+        Ok(())
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.get_mut().framed).poll_flush(cx)
+    }
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.get_mut().framed).poll_close(cx)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tls::crypto_provider;
+    use futures_util::{SinkExt, StreamExt};
+    use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
+    use quinn::{ClientConfig, Endpoint, ServerConfig};
+    use rcgen::{generate_simple_self_signed, CertifiedKey};
+    use rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+    use rustls::RootCertStore;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_util::codec::LengthDelimitedCodec;
+    async fn connect_loopback() -> (QuicConnection, QuicConnection) {
+        let CertifiedKey { cert, key_pair } =
+            generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let mut server_tls_config = rustls::ServerConfig::builder_with_provider(crypto_provider())
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![cert.der().clone()],
+                PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key_pair.serialize_der())),
+            )
+            .unwrap();
+        server_tls_config.alpn_protocols = vec![QUIC_ALPN.to_vec()];
+        let server_endpoint = Endpoint::server(
+            ServerConfig::with_crypto(Arc::new(
+                QuicServerConfig::try_from(server_tls_config).unwrap(),
+            )),
+            "127.0.0.1:0".parse().unwrap(),
+        )
+        .unwrap();
+        let mut root_cert_store = RootCertStore::empty();
+        root_cert_store.add(cert.der().clone()).unwrap();
+        let mut client_tls_config = rustls::ClientConfig::builder_with_provider(crypto_provider())
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(root_cert_store)
+            .with_no_client_auth();
+        client_tls_config.alpn_protocols = vec![QUIC_ALPN.to_vec()];
+        let client_endpoint = Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        let connecting = client_endpoint
+            .connect_with(
+                ClientConfig::new(Arc::new(
+                    QuicClientConfig::try_from(client_tls_config).unwrap(),
+                )),
+                server_endpoint.local_addr().unwrap(),
+                "localhost",
+            )
+            .unwrap();
+        let (agent_connection, proxy_connection) = tokio::join!(connecting, async {
+            server_endpoint.accept().await.unwrap().await
+        });
+        (
+            QuicConnection::new(agent_connection.unwrap()),
+            QuicConnection::new(proxy_connection.unwrap()),
+        )
+    }
+    #[tokio::test]
+    async fn relay_stream_and_datagrams_over_quic() {
+        let (agent_connection, proxy_connection) = connect_loopback().await;
+        let mut agent_stream = agent_connection.open_stream().await.unwrap();
+        agent_stream.write_all(b"ping").await.unwrap();
+        let mut proxy_stream = proxy_connection.accept_stream().await.unwrap();
+        let mut ping = [0u8; 4];
+        proxy_stream.read_exact(&mut ping).await.unwrap();
+        assert_eq!(&ping, b"ping");
+        let agent_datagrams = agent_stream.take_datagrams().unwrap();
+        let proxy_datagrams = proxy_stream.take_datagrams().unwrap();
+        let max_size = agent_datagrams.max_size().unwrap();
+        let mut agent_framed = DatagramFramed::with_datagrams(
+            Framed::new(agent_stream, LengthDelimitedCodec::new()),
+            agent_datagrams,
+            LengthDelimitedCodec::new(),
+        );
+        let mut proxy_framed = DatagramFramed::with_datagrams(
+            Framed::new(proxy_stream, LengthDelimitedCodec::new()),
+            proxy_datagrams,
+            LengthDelimitedCodec::new(),
+        );
+        agent_framed
+            .send(Bytes::from_static(b"datagram"))
+            .await
+            .unwrap();
+        assert_eq!(
+            &proxy_framed.next().await.unwrap().unwrap()[..],
+            b"datagram"
+        );
+        // The packet exceeding the datagram size goes through the stream
+        let large_packet = Bytes::from(vec![7u8; max_size * 2]);
+        proxy_framed.send(large_packet.clone()).await.unwrap();
+        assert_eq!(agent_framed.next().await.unwrap().unwrap(), large_packet);
+    }
+    #[tokio::test]
+    async fn skip_broken_datagrams() {
+        let (agent_connection, proxy_connection) = connect_loopback().await;
+        let mut agent_stream = agent_connection.open_stream().await.unwrap();
+        agent_stream.write_all(b"ping").await.unwrap();
+        let mut proxy_stream = proxy_connection.accept_stream().await.unwrap();
+        proxy_stream.read_exact(&mut [0u8; 4]).await.unwrap();
+        let agent_datagrams = agent_stream.take_datagrams().unwrap();
+        let proxy_datagrams = proxy_stream.take_datagrams().unwrap();
+        let mut agent_framed = DatagramFramed::with_datagrams(
+            Framed::new(agent_stream, LengthDelimitedCodec::new()),
+            agent_datagrams,
+            LengthDelimitedCodec::new(),
+        );
+        // The proxy fails to decode the packets longer than 16 bytes
+        let mut proxy_framed = DatagramFramed::with_datagrams(
+            Framed::new(proxy_stream, LengthDelimitedCodec::new()),
+            proxy_datagrams,
+            LengthDelimitedCodec::builder()
+                .max_frame_length(16)
+                .new_codec(),
+        );
+        agent_framed.send(Bytes::from(vec![7u8; 32])).await.unwrap();
+        agent_framed
+            .send(Bytes::from_static(b"datagram"))
+            .await
+            .unwrap();
+        assert_eq!(
+            &proxy_framed.next().await.unwrap().unwrap()[..],
+            b"datagram"
+        );
+    }
+}
@@ -0,0 +1,98 @@
+use std::fs::{metadata, read_dir};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::time::sleep;
+use tracing::{debug, error};
+/// What triggers a reload
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReloadTrigger {
+    /// The SIGHUP signal received
+    Signal,
+    /// The watched paths modified
+    Watch,
+}
+/// Start listening the reload triggers, the SIGHUP signal is always listened on unix,
+/// and the watched paths will be polled with the interval if it is given.
+pub fn listen_reload_trigger(
+    watch_paths: Vec<PathBuf>,
+    watch_interval: Option<Duration>,
+) -> Receiver<ReloadTrigger> {
+    let (reload_trigger_tx, reload_trigger_rx) = channel(1);
+    #[cfg(unix)]
+    listen_sighup(reload_trigger_tx.clone());
+    if let Some(watch_interval) = watch_interval {
+        watch_paths_modification(watch_paths, watch_interval, reload_trigger_tx);
+    }
+    reload_trigger_rx
+}
+#[cfg(unix)]
+fn listen_sighup(reload_trigger_tx: Sender<ReloadTrigger>) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut sighup = match signal(SignalKind::hangup()) {
+        Ok(sighup) => sighup,
+        Err(e) => {
+            error!("Fail to listen SIGHUP signal: {e:?}");
+            return;
+        }
+    };
+    tokio::spawn(async move {
+        while sighup.recv().await.is_some() {
+            debug!("SIGHUP signal received.");
+            if reload_trigger_tx.send(ReloadTrigger::Signal).await.is_err() {
+                return;
+            }
+        }
+    });
+}
+fn watch_paths_modification(
+    watch_paths: Vec<PathBuf>,
+    watch_interval: Duration,
+    reload_trigger_tx: Sender<ReloadTrigger>,
+) {
+    tokio::spawn(async move {
+        let mut last_fingerprint = fingerprint(&watch_paths);
+        loop {
+            sleep(watch_interval).await;
+            let current_fingerprint = fingerprint(&watch_paths);
+            if current_fingerprint == last_fingerprint {
+                continue;
+            }
+            debug!("Watched paths modified: {watch_paths:?}");
+            last_fingerprint = current_fingerprint;
+            if reload_trigger_tx.send(ReloadTrigger::Watch).await.is_err() {
+                return;
+            }
+        }
+    });
+}
+/// The modification time and length of all the files under the paths
+fn fingerprint(paths: &[PathBuf]) -> Vec<(PathBuf, Option<SystemTime>, u64)> {
+    let mut result = Vec::new();
+    paths
+        .iter()
+        .for_each(|path| collect_fingerprint(path, &mut result));
+    result.sort();
+    result
+}
+fn collect_fingerprint(path: &Path, result: &mut Vec<(PathBuf, Option<SystemTime>, u64)>) {
+    let Ok(path_metadata) = metadata(path) else {
+        result.push((path.to_path_buf(), None, 0));
+        return;
+    };
+    if !path_metadata.is_dir() {
+        result.push((
+            path.to_path_buf(),
+            path_metadata.modified().ok(),
+            path_metadata.len(),
+        ));
+        return;
+    }
+    let Ok(entries) = read_dir(path) else {
+        result.push((path.to_path_buf(), None, 0));
+        return;
+    };
+    entries
+        .flatten()
+        .for_each(|entry| collect_fingerprint(&entry.path(), result));
+}
@@ -0,0 +1,119 @@
+use std::future::Future;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{debug, error, info};
+/// Coordinate the graceful shutdown of a server, the server stops accepting
+/// new connections first, then the tunnels are drained until the drain timeout
+/// and the remaining tunnels are cancelled.
+#[derive(Debug, Clone, Default)]
+pub struct ShutdownCoordinator {
+    accept_token: CancellationToken,
+    tunnel_token: CancellationToken,
+    tunnel_tracker: TaskTracker,
+}
+impl ShutdownCoordinator {
+    pub fn new() -> Self {
+        Default::default()
+    }
+    /// The token cancelled when the server should stop accepting connections
+    pub fn accept_token(&self) -> &CancellationToken {
+        &self.accept_token
+    }
+    /// The token cancelled when the active tunnels should be terminated,
+    /// a child token is returned so that the caller can cancel it separately
+    pub fn tunnel_token(&self) -> CancellationToken {
+        self.tunnel_token.child_token()
+    }
+    /// If the shutdown started
+    pub fn is_shutting_down(&self) -> bool {
+        self.accept_token.is_cancelled()
+    }
+    /// Spawn a task belongs to the tunnels, the task will be waited during draining
+    /// and cancelled when the drain timeout reached.
+    pub fn spawn_tunnel_task<F>(&self, task: F) -> JoinHandle<Option<F::Output>>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let tunnel_token = self.tunnel_token.clone();
+        self.tunnel_tracker
+            .spawn(async move { tunnel_token.run_until_cancelled(task).await })
+    }
+    /// The count of the tunnel tasks still running
+    pub fn active_tunnel_task_count(&self) -> usize {
+        self.tunnel_tracker.len()
+    }
+    /// Stop accepting and drain the tunnel tasks, return `true` when all the tunnel tasks
+    /// finished in the drain timeout, otherwise the remaining tasks are cancelled.
+    pub async fn shutdown(&self, drain_timeout: Duration) -> bool {
+        self.accept_token.cancel();
+        self.tunnel_tracker.close();
+        info!(
+            "Start draining {} tunnel tasks in {drain_timeout:?}.",
+            self.tunnel_tracker.len()
+        );
+        if timeout(drain_timeout, self.tunnel_tracker.wait())
+            .await
+            .is_ok()
+        {
+            info!("All tunnel tasks drained.");
+            return true;
+        }
+        info!(
+            "Drain timeout, cancel {} remaining tunnel tasks.",
+            self.tunnel_tracker.len()
+        );
+        self.tunnel_token.cancel();
+        self.tunnel_tracker.wait().await;
+        false
+    }
+}
+/// Wait for the signal to shutdown the server, SIGTERM on unix and Ctrl-C on all platforms
+pub async fn wait_for_shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut sigterm = match signal(SignalKind::terminate()) {
+            Ok(sigterm) => sigterm,
+            Err(e) => {
+                error!("Fail to listen SIGTERM signal: {e:?}");
+                if let Err(e) = tokio::signal::ctrl_c().await {
+                    error!("Fail to listen Ctrl-C signal: {e:?}");
+                }
+                return;
+            }
+        };
+        tokio::select! {
+            _ = sigterm.recv() => debug!("SIGTERM signal received."),
+            _ = tokio::signal::ctrl_c() => debug!("Ctrl-C signal received."),
+        }
+    }
+    #[cfg(not(unix))]
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        error!("Fail to listen Ctrl-C signal: {e:?}");
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::sleep;
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_drains_finished_tasks() {
+        let coordinator = ShutdownCoordinator::new();
+        let task = coordinator.spawn_tunnel_task(sleep(Duration::from_secs(1)));
+        assert!(coordinator.shutdown(Duration::from_secs(5)).await);
+        assert!(coordinator.accept_token().is_cancelled());
+        assert_eq!(task.await.unwrap(), Some(()));
+    }
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_cancels_tasks_after_drain_timeout() {
+        let coordinator = ShutdownCoordinator::new();
+        let task = coordinator.spawn_tunnel_task(sleep(Duration::from_secs(60)));
+        assert!(!coordinator.shutdown(Duration::from_secs(5)).await);
+        assert_eq!(task.await.unwrap(), None);
+        assert_eq!(coordinator.active_tunnel_task_count(), 0);
+    }
+}
@@ -0,0 +1,29 @@
+use crate::error::CommonError;
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+/// The crypto provider of the tls transport between agent and proxy
+pub fn crypto_provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+/// Load the certificates in the pem file
+pub fn load_certificates(cert_file: &Path) -> Result<Vec<CertificateDer<'static>>, CommonError> {
+    let mut cert_reader = BufReader::new(File::open(cert_file)?);
+    let certificates = rustls_pemfile::certs(&mut cert_reader).collect::<Result<Vec<_>, _>>()?;
+    if certificates.is_empty() {
+        return Err(CommonError::Tls(format!(
+            "no certificate found in: {cert_file:?}"
+        )));
+    }
+    Ok(certificates)
+}
+/// Load the first private key in the pem file
+pub fn load_private_key(key_file: &Path) -> Result<PrivateKeyDer<'static>, CommonError> {
+    let mut key_reader = BufReader::new(File::open(key_file)?);
+    rustls_pemfile::private_key(&mut key_reader)?.ok_or(CommonError::Tls(format!(
+        "no private key found in: {key_file:?}"
+    )))
+}
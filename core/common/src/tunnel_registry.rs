@@ -0,0 +1,316 @@
+use chrono::{DateTime, Utc};
+use ppaass_domain::address::UnifiedAddress;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use tracing::error;
+/// A live tunnel
+#[derive(Debug)]
+pub struct TunnelEntry {
+    id: String,
+    /// The id given by the peer to correlate the tunnel, for example the agent tunnel id on proxy
+    correlation_id: Option<String>,
+    user: String,
+    client_address: SocketAddr,
+    destination_address: UnifiedAddress,
+    start_time: DateTime<Utc>,
+    start_instant: Instant,
+    /// The milliseconds from the tunnel start to the last data relayed
+    last_activity_millis: AtomicU64,
+    upload_bytes: AtomicU64,
+    download_bytes: AtomicU64,
+    cancellation_token: CancellationToken,
+}
+impl TunnelEntry {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+    pub fn correlation_id(&self) -> Option<&str> {
+        self.correlation_id.as_deref()
+    }
+    pub fn user(&self) -> &str {
+        &self.user
+    }
+    pub fn client_address(&self) -> SocketAddr {
+        self.client_address
+    }
+    pub fn destination_address(&self) -> &UnifiedAddress {
+        &self.destination_address
+    }
+    pub fn start_time(&self) -> DateTime<Utc> {
+        self.start_time
+    }
+    /// The bytes sent from the client to the destination
+    pub fn upload_bytes(&self) -> u64 {
+        self.upload_bytes.load(Ordering::Relaxed)
+    }
+    /// The bytes sent from the destination to the client
+    pub fn download_bytes(&self) -> u64 {
+        self.download_bytes.load(Ordering::Relaxed)
+    }
+    pub fn add_upload_bytes(&self, bytes: usize) {
+        self.upload_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.touch();
+    }
+    pub fn add_download_bytes(&self, bytes: usize) {
+        self.download_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        self.touch();
+    }
+    /// How long the tunnel has been alive
+    pub fn lifetime(&self) -> Duration {
+        self.start_instant.elapsed()
+    }
+    /// How long the tunnel has not relayed any data in both directions
+    pub fn idle_time(&self) -> Duration {
+        let last_activity =
+            Duration::from_millis(self.last_activity_millis.load(Ordering::Relaxed));
+        self.lifetime().saturating_sub(last_activity)
+    }
+    fn touch(&self) {
+        self.last_activity_millis
+            .store(self.lifetime().as_millis() as u64, Ordering::Relaxed);
+    }
+    /// The token to tear down both directions of the tunnel
+    pub fn cancellation_token(&self) -> &CancellationToken {
+        &self.cancellation_token
+    }
+    pub fn snapshot(&self) -> TunnelSnapshot {
+        TunnelSnapshot {
+            id: self.id.clone(),
+            correlation_id: self.correlation_id.clone(),
+            user: self.user.clone(),
+            client_address: self.client_address,
+            destination_address: self.destination_address.clone(),
+            start_time: self.start_time,
+            upload_bytes: self.upload_bytes(),
+            download_bytes: self.download_bytes(),
+        }
+    }
+}
+/// The point-in-time view of a live tunnel
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TunnelSnapshot {
+    pub id: String,
+    #[serde(default)]
+    pub correlation_id: Option<String>,
+    pub user: String,
+    pub client_address: SocketAddr,
+    pub destination_address: UnifiedAddress,
+    pub start_time: DateTime<Utc>,
+    pub upload_bytes: u64,
+    pub download_bytes: u64,
+}
+/// The registry of all the live tunnels
+#[derive(Debug, Default)]
+pub struct TunnelRegistry {
+    tunnels: RwLock<HashMap<String, Arc<TunnelEntry>>>,
+}
+impl TunnelRegistry {
+    pub fn new() -> Self {
+        Default::default()
+    }
+    /// Register a live tunnel, the tunnel is unregistered when the returned registration dropped.
+    /// The id must be unique, the ids given by the peer should be kept as the correlation id
+    pub fn register(
+        self: &Arc<Self>,
+        id: String,
+        correlation_id: Option<String>,
+        user: String,
+        client_address: SocketAddr,
+        destination_address: UnifiedAddress,
+        cancellation_token: CancellationToken,
+    ) -> TunnelRegistration {
+        let entry = Arc::new(TunnelEntry {
+            id: id.clone(),
+            correlation_id,
+            user,
+            client_address,
+            destination_address,
+            start_time: Utc::now(),
+            start_instant: Instant::now(),
+            last_activity_millis: AtomicU64::new(0),
+            upload_bytes: AtomicU64::new(0),
+            download_bytes: AtomicU64::new(0),
+            cancellation_token,
+        });
+        match self.tunnels.write() {
+            Ok(mut tunnels) => {
+                tunnels.insert(id, entry.clone());
+            }
+            Err(_) => error!("Fail to register tunnel [{id}] because of registry lock poisoned."),
+        }
+        TunnelRegistration {
+            registry: self.clone(),
+            entry,
+        }
+    }
+    pub fn get(&self, id: &str) -> Option<Arc<TunnelEntry>> {
+        let tunnels = self.tunnels.read().ok()?;
+        tunnels.get(id).cloned()
+    }
+    /// The count of the live tunnels
+    pub fn len(&self) -> usize {
+        self.tunnels
+            .read()
+            .map(|tunnels| tunnels.len())
+            .unwrap_or(0)
+    }
+    /// The count of the live tunnels of the user
+    pub fn user_len(&self, user: &str) -> usize {
+        self.tunnels
+            .read()
+            .map(|tunnels| tunnels.values().filter(|entry| entry.user == user).count())
+            .unwrap_or(0)
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// The snapshot of all the live tunnels
+    pub fn list(&self) -> Vec<TunnelSnapshot> {
+        match self.tunnels.read() {
+            Ok(tunnels) => tunnels.values().map(|entry| entry.snapshot()).collect(),
+            Err(_) => {
+                error!("Fail to list tunnels because of registry lock poisoned.");
+                Vec::new()
+            }
+        }
+    }
+    /// Cancel the tunnel, return `false` if the tunnel not exist
+    pub fn cancel(&self, id: &str) -> bool {
+        match self.get(id) {
+            None => false,
+            Some(entry) => {
+                entry.cancellation_token.cancel();
+                true
+            }
+        }
+    }
+    /// Cancel all the tunnels of the user, return the count of the cancelled tunnels
+    pub fn cancel_user(&self, user: &str) -> usize {
+        let Ok(tunnels) = self.tunnels.read() else {
+            error!("Fail to cancel tunnels of user [{user}] because of registry lock poisoned.");
+            return 0;
+        };
+        tunnels
+            .values()
+            .filter(|entry| entry.user == user)
+            .map(|entry| entry.cancellation_token.cancel())
+            .count()
+    }
+    /// Unregister the entry, the newer entry registered with the same id is kept
+    fn unregister(&self, entry: &Arc<TunnelEntry>) {
+        match self.tunnels.write() {
+            Ok(mut tunnels) => {
+                if tunnels
+                    .get(&entry.id)
+                    .is_some_and(|registered| Arc::ptr_eq(registered, entry))
+                {
+                    tunnels.remove(&entry.id);
+                }
+            }
+            Err(_) => error!(
+                "Fail to unregister tunnel [{}] because of registry lock poisoned.",
+                entry.id
+            ),
+        }
+    }
+}
+/// Keep the tunnel registered until dropped
+#[derive(Debug)]
+pub struct TunnelRegistration {
+    registry: Arc<TunnelRegistry>,
+    entry: Arc<TunnelEntry>,
+}
+impl TunnelRegistration {
+    pub fn entry(&self) -> &Arc<TunnelEntry> {
+        &self.entry
+    }
+}
+impl Drop for TunnelRegistration {
+    fn drop(&mut self) {
+        self.registry.unregister(&self.entry);
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn register(registry: &Arc<TunnelRegistry>, id: &str, user: &str) -> TunnelRegistration {
+        register_with_correlation_id(registry, id, None, user)
+    }
+    fn register_with_correlation_id(
+        registry: &Arc<TunnelRegistry>,
+        id: &str,
+        correlation_id: Option<&str>,
+        user: &str,
+    ) -> TunnelRegistration {
+        registry.register(
+            id.to_string(),
+            correlation_id.map(str::to_string),
+            user.to_string(),
+            "127.0.0.1:10000".parse().unwrap(),
+            UnifiedAddress::Domain {
+                host: "example.com".to_string(),
+                port: 443,
+            },
+            CancellationToken::new(),
+        )
+    }
+    #[test]
+    fn registration_drop_unregisters_tunnel() {
+        let registry = Arc::new(TunnelRegistry::new());
+        let registration = register(&registry, "tunnel1", "user1");
+        registration.entry().add_upload_bytes(10);
+        registration.entry().add_download_bytes(20);
+        let snapshot = registry.list();
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(snapshot[0].upload_bytes, 10);
+        assert_eq!(snapshot[0].download_bytes, 20);
+        drop(registration);
+        assert!(registry.is_empty());
+    }
+    #[test]
+    fn cancel_user_cancels_only_user_tunnels() {
+        let registry = Arc::new(TunnelRegistry::new());
+        let tunnel1 = register(&registry, "tunnel1", "user1");
+        let tunnel2 = register(&registry, "tunnel2", "user1");
+        let tunnel3 = register(&registry, "tunnel3", "user2");
+        assert_eq!(registry.cancel_user("user1"), 2);
+        assert!(tunnel1.entry().cancellation_token().is_cancelled());
+        assert!(tunnel2.entry().cancellation_token().is_cancelled());
+        assert!(!tunnel3.entry().cancellation_token().is_cancelled());
+        assert!(registry.cancel("tunnel3"));
+        assert!(!registry.cancel("tunnel4"));
+    }
+    #[test]
+    fn register_same_id_twice() {
+        let registry = Arc::new(TunnelRegistry::new());
+        let tunnel1 = register_with_correlation_id(&registry, "tunnel1", Some("agent1"), "user1");
+        let tunnel2 = register_with_correlation_id(&registry, "tunnel2", Some("agent1"), "user1");
+        assert_eq!(registry.len(), 2);
+        assert_eq!(registry.user_len("user1"), 2);
+        assert!(registry
+            .list()
+            .iter()
+            .all(|tunnel| tunnel.correlation_id.as_deref() == Some("agent1")));
+        drop(tunnel1);
+        assert_eq!(registry.len(), 1);
+        assert_eq!(
+            registry.get("tunnel2").unwrap().correlation_id(),
+            Some("agent1")
+        );
+        let tunnel3 = register(&registry, "tunnel2", "user1");
+        drop(tunnel2);
+        assert!(Arc::ptr_eq(
+            &registry.get("tunnel2").unwrap(),
+            tunnel3.entry()
+        ));
+        drop(tunnel3);
+        assert!(registry.is_empty());
+    }
+}
@@ -0,0 +1,209 @@
+use crate::tunnel_registry::TunnelEntry;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::time::Duration;
+use tokio::time::error::Elapsed;
+use tokio::time::{sleep, timeout};
+/// The timeouts of a kind of tunnel, all in seconds, `None` means no limit
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TunnelTimeoutConfig {
+    /// Close the tunnel when one direction has not received any data in time, while the
+    /// other direction has not relayed any data in time either, so the one-way streams are kept
+    pub direction_idle_timeout: Option<u64>,
+    /// Close the tunnel when both directions have not relayed any data in time
+    pub idle_timeout: Option<u64>,
+    /// Close the tunnel when it lives longer than this
+    pub max_lifetime: Option<u64>,
+}
+impl TunnelTimeoutConfig {
+    pub fn direction_idle_timeout(&self) -> Option<Duration> {
+        self.direction_idle_timeout.map(Duration::from_secs)
+    }
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout.map(Duration::from_secs)
+    }
+    pub fn max_lifetime(&self) -> Option<Duration> {
+        self.max_lifetime.map(Duration::from_secs)
+    }
+}
+/// Why a tunnel timed out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TunnelTimeout {
+    Idle,
+    Lifetime,
+}
+/// Read from one direction of the tunnel, fail when nothing read in the idle timeout
+/// and the tunnel has not relayed any data in the other direction in the idle timeout either
+pub async fn read_with_idle_timeout<F>(
+    tunnel_entry: &TunnelEntry,
+    direction_idle_timeout: Option<Duration>,
+    read: F,
+) -> Result<F::Output, Elapsed>
+where
+    F: Future,
+{
+    let Some(direction_idle_timeout) = direction_idle_timeout else {
+        return Ok(read.await);
+    };
+    tokio::pin!(read);
+    let mut read_timeout = direction_idle_timeout;
+    loop {
+        match timeout(read_timeout, &mut read).await {
+            Ok(output) => return Ok(output),
+            Err(elapsed) => {
+                let idle_time = tunnel_entry.idle_time();
+                if idle_time >= direction_idle_timeout {
+                    return Err(elapsed);
+                }
+                read_timeout = direction_idle_timeout - idle_time;
+            }
+        }
+    }
+}
+/// Complete when the tunnel is idle or lives too long, never complete when no limit configured
+pub async fn wait_tunnel_timeout(
+    tunnel_entry: &TunnelEntry,
+    timeout_config: TunnelTimeoutConfig,
+) -> TunnelTimeout {
+    let idle_timeout = timeout_config.idle_timeout();
+    let max_lifetime = timeout_config.max_lifetime();
+    if idle_timeout.is_none() && max_lifetime.is_none() {
+        return std::future::pending().await;
+    }
+    loop {
+        let mut next_check = Duration::MAX;
+        if let Some(max_lifetime) = max_lifetime {
+            let lifetime = tunnel_entry.lifetime();
+            if lifetime >= max_lifetime {
+                return TunnelTimeout::Lifetime;
+            }
+            next_check = next_check.min(max_lifetime - lifetime);
+        }
+        if let Some(idle_timeout) = idle_timeout {
+            let idle_time = tunnel_entry.idle_time();
+            if idle_time >= idle_timeout {
+                return TunnelTimeout::Idle;
+            }
+            next_check = next_check.min(idle_timeout - idle_time);
+        }
+        sleep(next_check).await;
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel_registry::TunnelRegistry;
+    use ppaass_domain::address::UnifiedAddress;
+    use std::sync::Arc;
+    use tokio_util::sync::CancellationToken;
+    fn new_registry() -> Arc<TunnelRegistry> {
+        Arc::new(TunnelRegistry::new())
+    }
+    fn register(registry: &Arc<TunnelRegistry>) -> crate::tunnel_registry::TunnelRegistration {
+        registry.register(
+            "tunnel1".to_string(),
+            None,
+            "user1".to_string(),
+            "127.0.0.1:10000".parse().unwrap(),
+            UnifiedAddress::Domain {
+                host: "example.com".to_string(),
+                port: 443,
+            },
+            CancellationToken::new(),
+        )
+    }
+    #[tokio::test(start_paused = true)]
+    async fn idle_timeout_is_extended_by_activity() {
+        let registry = new_registry();
+        let registration = register(&registry);
+        let entry = registration.entry().clone();
+        let timeout_config = TunnelTimeoutConfig {
+            idle_timeout: Some(10),
+            ..Default::default()
+        };
+        let start = tokio::time::Instant::now();
+        let activity = {
+            let entry = entry.clone();
+            tokio::spawn(async move {
+                sleep(Duration::from_secs(8)).await;
+                entry.add_upload_bytes(1);
+            })
+        };
+        assert_eq!(
+            wait_tunnel_timeout(&entry, timeout_config).await,
+            TunnelTimeout::Idle
+        );
+        activity.await.unwrap();
+        assert_eq!(start.elapsed().as_secs(), 18);
+    }
+    #[tokio::test(start_paused = true)]
+    async fn max_lifetime_ignores_activity() {
+        let registry = new_registry();
+        let registration = register(&registry);
+        let entry = registration.entry().clone();
+        let timeout_config = TunnelTimeoutConfig {
+            idle_timeout: Some(10),
+            max_lifetime: Some(25),
+            ..Default::default()
+        };
+        let start = tokio::time::Instant::now();
+        let activity = {
+            let entry = entry.clone();
+            tokio::spawn(async move {
+                for _ in 0..5 {
+                    sleep(Duration::from_secs(6)).await;
+                    entry.add_download_bytes(1);
+                }
+            })
+        };
+        assert_eq!(
+            wait_tunnel_timeout(&entry, timeout_config).await,
+            TunnelTimeout::Lifetime
+        );
+        assert_eq!(start.elapsed().as_secs(), 25);
+        activity.abort();
+    }
+    #[tokio::test(start_paused = true)]
+    async fn direction_idle_timeout_fails_slow_read() {
+        let registry = new_registry();
+        let registration = register(&registry);
+        let entry = registration.entry();
+        let start = tokio::time::Instant::now();
+        let result = read_with_idle_timeout(
+            entry,
+            Some(Duration::from_secs(5)),
+            sleep(Duration::from_secs(10)),
+        )
+        .await;
+        assert!(result.is_err());
+        assert_eq!(start.elapsed().as_secs(), 5);
+        let result = read_with_idle_timeout(entry, None, sleep(Duration::from_secs(10))).await;
+        assert!(result.is_ok());
+    }
+    #[tokio::test(start_paused = true)]
+    async fn direction_idle_timeout_keeps_one_way_stream() {
+        let registry = new_registry();
+        let registration = register(&registry);
+        let entry = registration.entry().clone();
+        let start = tokio::time::Instant::now();
+        // Only the download direction relays data, longer than the idle timeout
+        let download = {
+            let entry = entry.clone();
+            tokio::spawn(async move {
+                for _ in 0..6 {
+                    sleep(Duration::from_secs(3)).await;
+                    entry.add_download_bytes(1);
+                }
+            })
+        };
+        let result = read_with_idle_timeout(
+            &entry,
+            Some(Duration::from_secs(5)),
+            std::future::pending::<()>(),
+        )
+        .await;
+        download.await.unwrap();
+        assert!(result.is_err());
+        assert_eq!(start.elapsed().as_secs(), 23);
+    }
+}
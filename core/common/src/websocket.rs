@@ -0,0 +1,149 @@
+use crate::error::CommonError;
+use futures_util::{ready, SinkExt, StreamExt};
+use std::io::Error;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::StatusCode;
+use tokio_tungstenite::tungstenite::{Bytes, Message};
+use tokio_tungstenite::{accept_hdr_async, client_async, WebSocketStream};
+/// Carry the bytes in the binary websocket messages, so the
+/// codecs between agent and proxy can run on the websocket
+#[derive(Debug)]
+pub struct WebSocketTransport<S> {
+    websocket_stream: WebSocketStream<S>,
+    read_buf: Bytes,
+}
+impl<S> WebSocketTransport<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Upgrade the connection with the http request to the host and the path
+    pub async fn connect(stream: S, host: &str, path: &str) -> Result<Self, CommonError> {
+        let request = format!("ws://{host}{path}")
+            .into_client_request()
+            .map_err(|e| CommonError::WebSocket(e.to_string()))?;
+        let (websocket_stream, _) = client_async(request, stream)
+            .await
+            .map_err(|e| CommonError::WebSocket(e.to_string()))?;
+        Ok(Self {
+            websocket_stream,
+            read_buf: Bytes::new(),
+        })
+    }
+    /// Accept the upgrade request on the path, the other paths are answered with not found
+    pub async fn accept(stream: S, path: &str) -> Result<Self, CommonError> {
+        // The error response type is given by the handshake callback
+        #[allow(clippy::result_large_err)]
+        let websocket_stream = accept_hdr_async(stream, |request: &Request, response: Response| {
+            if request.uri().path() == path {
+                return Ok(response);
+            }
+            let mut error_response = ErrorResponse::new(None);
+            *error_response.status_mut() = StatusCode::NOT_FOUND;
+            Err(error_response)
+        })
+        .await
+        .map_err(|e| CommonError::WebSocket(e.to_string()))?;
+        Ok(Self {
+            websocket_stream,
+            read_buf: Bytes::new(),
+        })
+    }
+    /// The underlying connection
+    pub fn get_ref(&self) -> &S {
+        self.websocket_stream.get_ref()
+    }
+}
+impl<S> AsyncRead for WebSocketTransport<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if !this.read_buf.is_empty() {
+                let size = this.read_buf.len().min(buf.remaining());
+                buf.put_slice(&this.read_buf.split_to(size));
+                return Poll::Ready(Ok(()));
+            }
+            match ready!(this.websocket_stream.poll_next_unpin(cx)) {
+                None | Some(Ok(Message::Close(_))) => return Poll::Ready(Ok(())),
+                Some(Ok(Message::Binary(data))) => this.read_buf = data,
+                // The ping is answered by the websocket stream on the next write or flush
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => return Poll::Ready(Err(Error::other(e))),
+            }
+        }
+    }
+}
+impl<S> AsyncWrite for WebSocketTransport<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Error>> {
+        let this = self.get_mut();
+        ready!(this.websocket_stream.poll_ready_unpin(cx)).map_err(Error::other)?;
+        this.websocket_stream
+            .start_send_unpin(Message::Binary(Bytes::copy_from_slice(buf)))
+            .map_err(Error::other)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        self.get_mut()
+            .websocket_stream
+            .poll_flush_unpin(cx)
+            .map_err(Error::other)
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        self.get_mut()
+            .websocket_stream
+            .poll_close_unpin(cx)
+            .map_err(Error::other)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+    #[tokio::test]
+    async fn relay_bytes_over_websocket() {
+        let (agent_io, proxy_io) = duplex(4096);
+        let proxy_task = tokio::spawn(async move {
+            let mut proxy_transport = WebSocketTransport::accept(proxy_io, "/tunnel").await?;
+            let mut ping = [0u8; 4];
+            proxy_transport.read_exact(&mut ping).await?;
+            proxy_transport.write_all(b"pong").await?;
+            proxy_transport.flush().await?;
+            Ok::<_, CommonError>(ping)
+        });
+        let mut agent_transport = WebSocketTransport::connect(agent_io, "proxy.test", "/tunnel")
+            .await
+            .unwrap();
+        agent_transport.write_all(b"pi").await.unwrap();
+        agent_transport.write_all(b"ng").await.unwrap();
+        agent_transport.flush().await.unwrap();
+        let mut pong = [0u8; 4];
+        agent_transport.read_exact(&mut pong).await.unwrap();
+        assert_eq!(&pong, b"pong");
+        assert_eq!(&proxy_task.await.unwrap().unwrap(), b"ping");
+        let (agent_io, proxy_io) = duplex(4096);
+        let proxy_task = tokio::spawn(WebSocketTransport::accept(proxy_io, "/tunnel"));
+        assert!(
+            WebSocketTransport::connect(agent_io, "proxy.test", "/other")
+                .await
+                .is_err()
+        );
+        assert!(proxy_task.await.unwrap().is_err());
+    }
+}
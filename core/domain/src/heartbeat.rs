@@ -13,12 +13,26 @@ impl Default for HeartbeatPing {
 }
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct HeartbeatPong {
+    /// The time of proxy side when the pong is generated
     pub heartbeat_time: DateTime<Utc>,
+    /// The heartbeat time of the ping this pong replies to,
+    /// used by agent to pair the ping and pong, `None` when the pong from an older proxy
+    pub ping_heartbeat_time: Option<DateTime<Utc>>,
+}
+impl HeartbeatPong {
+    pub fn reply(ping: &HeartbeatPing) -> Self {
+        Self {
+            heartbeat_time: Utc::now(),
+            ping_heartbeat_time: Some(ping.heartbeat_time),
+        }
+    }
 }
 impl Default for HeartbeatPong {
     fn default() -> Self {
+        let now = Utc::now();
         Self {
-            heartbeat_time: Utc::now(),
+            heartbeat_time: now,
+            ping_heartbeat_time: Some(now),
         }
     }
 }
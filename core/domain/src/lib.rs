@@ -1,6 +1,6 @@
 use crate::address::UnifiedAddress;
 use crate::heartbeat::{HeartbeatPing, HeartbeatPong};
-use crate::tunnel::{TunnelInitRequest, TunnelInitResponse};
+use crate::tunnel::{TunnelInitFailure, TunnelInitRequest, TunnelInitResponse};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 pub mod address;
@@ -19,6 +19,9 @@ pub enum AgentControlPacket {
 pub enum ProxyControlPacket {
     TunnelInit((String, TunnelInitResponse)),
     Heartbeat(HeartbeatPong),
+    /// The proxy refused the tunnel init request, the connection is closed after it.
+    /// Encoded with the type byte 2, which an older agent rejects as an invalid packet
+    TunnelInitFail(TunnelInitFailure),
 }
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub enum AgentDataPacket {
@@ -27,6 +30,9 @@ pub enum AgentDataPacket {
         destination_address: UnifiedAddress,
         payload: Vec<u8>,
     },
+    /// The sender closed its write side of the tcp stream, the receiver
+    /// should shutdown the write side of its peer
+    TcpEof,
 }
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub enum ProxyDataPacket {
@@ -35,4 +41,7 @@ pub enum ProxyDataPacket {
         destination_address: UnifiedAddress,
         payload: Vec<u8>,
     },
+    /// The sender closed its write side of the tcp stream, the receiver
+    /// should shutdown the write side of its peer
+    TcpEof,
 }
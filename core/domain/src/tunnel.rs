@@ -1,5 +1,6 @@
 use crate::address::UnifiedAddress;
 use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
 #[derive(Deserialize, Serialize, Debug, Clone, Default)]
 pub enum Encryption {
     #[default]
@@ -13,6 +14,8 @@ pub enum TunnelType {
 }
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct TunnelInitRequest {
+    /// The tunnel id generated by agent, used to correlate the agent and proxy logs
+    pub tunnel_id: String,
     pub agent_encryption: Encryption,
     pub auth_token: String,
     pub dst_address: UnifiedAddress,
@@ -22,3 +25,22 @@ pub struct TunnelInitRequest {
 pub struct TunnelInitResponse {
     pub proxy_encryption: Encryption,
 }
+/// The reason why the proxy refused to init the tunnel
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub enum TunnelInitFailure {
+    /// The destination is denied by the access control policy of the proxy
+    AccessDenied(String),
+    /// The traffic quota of the user is used up
+    QuotaExceeded(String),
+    /// The proxy is overloaded and sheds the load
+    ServerBusy(String),
+}
+impl Display for TunnelInitFailure {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TunnelInitFailure::AccessDenied(reason) => write!(f, "access denied, {reason}"),
+            TunnelInitFailure::QuotaExceeded(reason) => write!(f, "quota exceeded, {reason}"),
+            TunnelInitFailure::ServerBusy(reason) => write!(f, "server busy, {reason}"),
+        }
+    }
+}
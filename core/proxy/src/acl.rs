@@ -0,0 +1,419 @@
+use crate::config::Config;
+use crate::error::ProxyError;
+use ppaass_domain::address::UnifiedAddress;
+use ppaass_domain::tunnel::TunnelType;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::fs::read_to_string;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::str::FromStr;
+/// The action of the access control rule
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AclAction {
+    #[default]
+    Allow,
+    Deny,
+}
+/// The tunnel type matched by the access control rule
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AclTunnelType {
+    Tcp,
+    Udp,
+}
+impl From<&TunnelType> for AclTunnelType {
+    fn from(value: &TunnelType) -> Self {
+        match value {
+            TunnelType::Tcp { .. } => AclTunnelType::Tcp,
+            TunnelType::Udp => AclTunnelType::Udp,
+        }
+    }
+}
+/// The ip network written as `10.0.0.0/8`, a single ip is a network with full prefix
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct IpCidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+impl IpCidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => prefix_matches(
+                network.to_bits().into(),
+                ip.to_bits().into(),
+                self.prefix_len + 96,
+            ),
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                prefix_matches(network.to_bits(), ip.to_bits(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+fn prefix_matches(network: u128, ip: u128, prefix_len: u8) -> bool {
+    let mask = u128::MAX.checked_shl(128 - prefix_len as u32).unwrap_or(0);
+    network & mask == ip & mask
+}
+impl FromStr for IpCidr {
+    type Err = ProxyError;
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid_cidr = || ProxyError::InvalidAclPolicy(format!("invalid cidr: {value}"));
+        let (network, prefix_len) = match value.split_once('/') {
+            None => (value, None),
+            Some((network, prefix_len)) => (network, Some(prefix_len)),
+        };
+        let network = network
+            .trim()
+            .parse::<IpAddr>()
+            .map_err(|_| invalid_cidr())?
+            .to_canonical();
+        let max_prefix_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            None => max_prefix_len,
+            Some(prefix_len) => prefix_len
+                .trim()
+                .parse::<u8>()
+                .map_err(|_| invalid_cidr())?,
+        };
+        if prefix_len > max_prefix_len {
+            return Err(invalid_cidr());
+        }
+        Ok(Self {
+            network,
+            prefix_len,
+        })
+    }
+}
+impl Display for IpCidr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+impl From<IpCidr> for String {
+    fn from(value: IpCidr) -> Self {
+        value.to_string()
+    }
+}
+impl TryFrom<String> for IpCidr {
+    type Error = ProxyError;
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PortRangeValue {
+    Single(u16),
+    Range(String),
+}
+/// The port range written as `443` or `"8000-9000"`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "PortRangeValue")]
+pub struct PortRange {
+    start: u16,
+    end: u16,
+}
+impl PortRange {
+    pub fn contains(&self, port: u16) -> bool {
+        self.start <= port && port <= self.end
+    }
+}
+impl TryFrom<PortRangeValue> for PortRange {
+    type Error = ProxyError;
+    fn try_from(value: PortRangeValue) -> Result<Self, Self::Error> {
+        let range = match value {
+            PortRangeValue::Single(port) => {
+                return Ok(Self {
+                    start: port,
+                    end: port,
+                })
+            }
+            PortRangeValue::Range(range) => range,
+        };
+        let invalid_range = || ProxyError::InvalidAclPolicy(format!("invalid port range: {range}"));
+        let (start, end) = range.split_once('-').unwrap_or((&range, &range));
+        let start = start.trim().parse::<u16>().map_err(|_| invalid_range())?;
+        let end = end.trim().parse::<u16>().map_err(|_| invalid_range())?;
+        if start > end {
+            return Err(invalid_range());
+        }
+        Ok(Self { start, end })
+    }
+}
+/// The rule matches when all the given conditions match, an empty condition matches everything
+#[derive(Debug, Clone, Deserialize)]
+pub struct AclRule {
+    action: AclAction,
+    #[serde(default)]
+    cidrs: Vec<IpCidr>,
+    #[serde(default)]
+    ports: Vec<PortRange>,
+    #[serde(default)]
+    domain_suffixes: Vec<String>,
+    #[serde(default)]
+    tunnel_types: Vec<AclTunnelType>,
+}
+impl AclRule {
+    fn matches(&self, destination: &AclDestination, ip: Option<IpAddr>) -> bool {
+        if !self.tunnel_types.is_empty() && !self.tunnel_types.contains(&destination.tunnel_type) {
+            return false;
+        }
+        if !self.ports.is_empty()
+            && !self
+                .ports
+                .iter()
+                .any(|ports| ports.contains(destination.port))
+        {
+            return false;
+        }
+        if !self.domain_suffixes.is_empty() {
+            let Some(host) = destination.host else {
+                return false;
+            };
+            if !self
+                .domain_suffixes
+                .iter()
+                .any(|domain_suffix| domain_suffix_matches(host, domain_suffix))
+            {
+                return false;
+            }
+        }
+        if !self.cidrs.is_empty() {
+            let Some(ip) = ip else {
+                return false;
+            };
+            if !self.cidrs.iter().any(|cidr| cidr.contains(ip)) {
+                return false;
+            }
+        }
+        true
+    }
+}
+/// Match the host against the suffix by labels, `example.com` matches
+/// `example.com` and `www.example.com` but not `badexample.com`
+fn domain_suffix_matches(host: &str, domain_suffix: &str) -> bool {
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    let domain_suffix = domain_suffix
+        .trim_start_matches('.')
+        .trim_end_matches('.')
+        .to_ascii_lowercase();
+    host == domain_suffix || host.ends_with(&format!(".{domain_suffix}"))
+}
+/// The ordered rules, the first matched rule decides the action
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AclRuleSet {
+    default_action: Option<AclAction>,
+    #[serde(default)]
+    rules: Vec<AclRule>,
+}
+impl AclRuleSet {
+    fn matched_rule(
+        &self,
+        destination: &AclDestination,
+        ip: Option<IpAddr>,
+    ) -> Option<(usize, AclAction)> {
+        self.rules
+            .iter()
+            .enumerate()
+            .find(|(_, rule)| rule.matches(destination, ip))
+            .map(|(index, rule)| (index, rule.action))
+    }
+}
+struct AclDestination<'a> {
+    host: Option<&'a str>,
+    port: u16,
+    tunnel_type: AclTunnelType,
+}
+/// The access control policy of the destinations, the global deny rules can't be
+/// overridden by the user, otherwise the rules of the user are checked before the
+/// global allow rules, the default action of the user overrides the global one,
+/// everything is allowed when nothing matched and no default given.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AclPolicy {
+    #[serde(flatten)]
+    global: AclRuleSet,
+    #[serde(default)]
+    users: HashMap<String, AclRuleSet>,
+}
+impl AclPolicy {
+    /// Load the policy from the toml file
+    pub fn load(acl_file_path: &Path) -> Result<Self, ProxyError> {
+        let acl_file_content = read_to_string(acl_file_path)?;
+        toml::from_str::<AclPolicy>(&acl_file_content)
+            .map_err(|e| ProxyError::InvalidAclPolicy(e.to_string()))
+    }
+    /// Load the policy from the `acl_file` of the configuration
+    pub fn from_config(config: &Config) -> Result<Self, ProxyError> {
+        match config.acl_file() {
+            None => Ok(Self::default()),
+            Some(acl_file_path) => Self::load(acl_file_path),
+        }
+    }
+    /// Check the destination after it resolved, the destination is denied when any of the
+    /// resolved addresses is denied. The resolved addresses are empty when the destination
+    /// is resolved by the forward proxy, then the cidr rules only match the ip destination.
+    /// The egress policy of the user account replaces the user rules of the policy.
+    pub fn check(
+        &self,
+        user: &str,
+        user_rule_set: Option<&AclRuleSet>,
+        dst_address: &UnifiedAddress,
+        resolved_addresses: &[SocketAddr],
+        tunnel_type: &TunnelType,
+    ) -> Result<(), String> {
+        let (host, port, ips) = match dst_address {
+            UnifiedAddress::Domain { host, port } => (
+                Some(host.as_str()),
+                *port,
+                resolved_addresses
+                    .iter()
+                    .map(|address| Some(address.ip()))
+                    .collect::<Vec<_>>(),
+            ),
+            UnifiedAddress::Ip(address) => (None, address.port(), vec![Some(address.ip())]),
+        };
+        let ips = if ips.is_empty() { vec![None] } else { ips };
+        let destination = AclDestination {
+            host,
+            port,
+            tunnel_type: tunnel_type.into(),
+        };
+        let user_rule_set = user_rule_set.or_else(|| self.users.get(user));
+        for ip in ips {
+            let global_matched_rule = self
+                .global
+                .matched_rule(&destination, ip)
+                .map(|(index, action)| (format!("global rule {index}"), action));
+            let matched_rule = match global_matched_rule {
+                Some((decided_by, AclAction::Deny)) => Some((decided_by, AclAction::Deny)),
+                global_matched_rule => user_rule_set
+                    .and_then(|user_rule_set| user_rule_set.matched_rule(&destination, ip))
+                    .map(|(index, action)| (format!("rule {index} of user [{user}]"), action))
+                    .or(global_matched_rule),
+            };
+            let (decided_by, action) = match matched_rule {
+                Some(matched_rule) => matched_rule,
+                None => {
+                    match user_rule_set.and_then(|user_rule_set| user_rule_set.default_action) {
+                        Some(action) => (format!("default action of user [{user}]"), action),
+                        None => (
+                            "global default action".to_string(),
+                            self.global.default_action.unwrap_or_default(),
+                        ),
+                    }
+                }
+            };
+            if action == AclAction::Deny {
+                return Err(match ip {
+                    Some(ip) if host.is_some() => {
+                        format!(
+                            "destination {dst_address} resolved to {ip} is denied by {decided_by}"
+                        )
+                    }
+                    _ => format!("destination {dst_address} is denied by {decided_by}"),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    const ACL_POLICY: &str = r#"
+default_action = "allow"
+[[rules]]
+action = "deny"
+cidrs = ["127.0.0.0/8", "10.0.0.0/8", "169.254.0.0/16", "::1"]
+[[rules]]
+action = "deny"
+ports = ["1-21", 25]
+tunnel_types = ["tcp"]
+[[rules]]
+action = "allow"
+domain_suffixes = ["allowed.org"]
+[users.user1]
+default_action = "deny"
+[[users.user1.rules]]
+action = "allow"
+domain_suffixes = ["example.com"]
+[[users.user1.rules]]
+action = "allow"
+cidrs = ["10.1.0.0/16"]
+ports = [25]
+[users.user2]
+[[users.user2.rules]]
+action = "deny"
+domain_suffixes = ["allowed.org"]
+[[users.user2.rules]]
+action = "allow"
+ports = ["8000-9000"]
+"#;
+    fn check(user: &str, dst_address: &str, resolved_address: &str) -> Result<(), String> {
+        let acl_policy = toml::from_str::<AclPolicy>(ACL_POLICY).unwrap();
+        acl_policy.check(
+            user,
+            None,
+            &dst_address.try_into().unwrap(),
+            &[resolved_address.parse().unwrap()],
+            &TunnelType::Tcp { keepalive: true },
+        )
+    }
+    #[test]
+    fn check_destination_after_resolved() {
+        assert!(check("user2", "www.example.com:443", "93.184.215.14:443").is_ok());
+        assert!(check("user2", "metadata.internal:80", "169.254.169.254:80").is_err());
+        assert!(check("user2", "[::ffff:127.0.0.1]:80", "[::ffff:127.0.0.1]:80").is_err());
+        assert!(check("user2", "[::1]:80", "[::1]:80").is_err());
+        assert!(check("user2", "www.example.com:25", "93.184.215.14:25").is_err());
+        assert!(check("user1", "www.example.com:443", "93.184.215.14:443").is_ok());
+        assert!(check("user1", "badexample.com:443", "93.184.215.14:443").is_err());
+        assert!(check("user1", "intranet.example.com:443", "10.1.1.1:443").is_err());
+    }
+    #[test]
+    fn check_user_allow_against_global_deny() {
+        assert!(check("user1", "10.1.1.1:443", "10.1.1.1:443").is_err());
+        assert!(check("user1", "mail.example.com:25", "93.184.215.14:25").is_err());
+        assert!(check("user1", "localhost.example.com:443", "[::1]:443").is_err());
+        assert!(check("user2", "localhost:8080", "127.0.0.1:8080").is_err());
+        // The user can deny more than the global allow rules
+        assert!(check("user3", "www.allowed.org:443", "93.184.215.15:443").is_ok());
+        assert!(check("user2", "www.allowed.org:443", "93.184.215.15:443").is_err());
+    }
+    #[test]
+    fn check_port_rules() {
+        assert!(check("user3", "www.example.com:1", "93.184.215.14:1").is_err());
+        assert!(check("user3", "www.example.com:21", "93.184.215.14:21").is_err());
+        assert!(check("user3", "www.example.com:22", "93.184.215.14:22").is_ok());
+        assert!(check("user3", "www.example.com:26", "93.184.215.14:26").is_ok());
+        assert!(check("user2", "www.allowed.org:8000", "93.184.215.15:8000").is_err());
+        assert!(check("user1", "www.other.com:9000", "93.184.215.16:9000").is_err());
+        let acl_policy = toml::from_str::<AclPolicy>(ACL_POLICY).unwrap();
+        // The port rule is only for the tcp tunnels
+        assert!(acl_policy
+            .check(
+                "user3",
+                None,
+                &"93.184.215.14:21".try_into().unwrap(),
+                &[],
+                &TunnelType::Udp,
+            )
+            .is_ok());
+    }
+    #[test]
+    fn check_domain_suffixes() {
+        assert!(domain_suffix_matches("example.com", "example.com"));
+        assert!(domain_suffix_matches("WWW.Example.com.", ".example.com"));
+        assert!(!domain_suffix_matches("badexample.com", "example.com"));
+        assert!(!domain_suffix_matches(
+            "example.com.evil.net",
+            "example.com"
+        ));
+        assert!(check("user1", "a.b.example.com:443", "93.184.215.14:443").is_ok());
+        assert!(check("user1", "example.com.evil.net:443", "93.184.215.14:443").is_err());
+    }
+}
@@ -0,0 +1,148 @@
+use crate::ban::BanSnapshot;
+use crate::bo::state::ServerState;
+use crate::reload::reload_rsa_crypto;
+use ppaass_codec::RsaCryptoReloadReport;
+use ppaass_common::admin::{serve_admin, AdminAddress};
+use ppaass_common::tunnel_registry::TunnelSnapshot;
+use ppaass_common::LogLevelHandle;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::sync::Arc;
+use tracing::{error, info};
+/// The request to the admin endpoint of the proxy
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum AdminRequest {
+    ListTunnels,
+    KillTunnel { tunnel_id: String },
+    KillUserTunnels { user: String },
+    ListUsers,
+    ReloadKeys,
+    SetLogLevel { level: String },
+    ListBans,
+    Unban { ip: IpAddr },
+}
+/// The response from the admin endpoint of the proxy
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AdminResponse {
+    Tunnels {
+        tunnels: Vec<TunnelSnapshot>,
+    },
+    TunnelsKilled {
+        count: usize,
+    },
+    Users {
+        users: Vec<String>,
+        forward_users: Option<Vec<String>>,
+    },
+    KeysReloaded {
+        rsa_crypto: RsaCryptoReloadReport,
+        forward_rsa_crypto: Option<RsaCryptoReloadReport>,
+    },
+    LogLevelChanged {
+        level: String,
+    },
+    Bans {
+        bans: Vec<BanSnapshot>,
+    },
+    Unbanned {
+        ip: IpAddr,
+    },
+}
+/// The admin endpoint to inspect and control the running proxy
+pub struct AdminServer {
+    server_state: ServerState,
+    log_level_handle: Option<LogLevelHandle>,
+}
+impl AdminServer {
+    pub(crate) fn new(server_state: ServerState, log_level_handle: Option<LogLevelHandle>) -> Self {
+        Self {
+            server_state,
+            log_level_handle,
+        }
+    }
+    fn handle(&self, request: AdminRequest) -> Result<AdminResponse, String> {
+        info!("Receive admin request: {request:?}");
+        match request {
+            AdminRequest::ListTunnels => {
+                let mut tunnels = self.server_state.tunnel_registry().list();
+                tunnels.sort_by_key(|tunnel| tunnel.start_time);
+                Ok(AdminResponse::Tunnels { tunnels })
+            }
+            AdminRequest::KillTunnel { tunnel_id } => {
+                if !self.server_state.tunnel_registry().cancel(&tunnel_id) {
+                    return Err(format!("tunnel not exist: {tunnel_id}"));
+                }
+                Ok(AdminResponse::TunnelsKilled { count: 1 })
+            }
+            AdminRequest::KillUserTunnels { user } => Ok(AdminResponse::TunnelsKilled {
+                count: self.server_state.tunnel_registry().cancel_user(&user),
+            }),
+            AdminRequest::ListUsers => Ok(AdminResponse::Users {
+                users: self.server_state.rsa_crypto_holder().users(),
+                forward_users: self
+                    .server_state
+                    .forward_rsa_crypto_holder()
+                    .as_ref()
+                    .map(|forward_rsa_crypto_holder| forward_rsa_crypto_holder.users()),
+            }),
+            AdminRequest::ReloadKeys => {
+                let (rsa_crypto, forward_rsa_crypto) =
+                    reload_rsa_crypto(&self.server_state).map_err(|e| e.to_string())?;
+                Ok(AdminResponse::KeysReloaded {
+                    rsa_crypto,
+                    forward_rsa_crypto,
+                })
+            }
+            AdminRequest::SetLogLevel { level } => {
+                let log_level_handle = self
+                    .log_level_handle
+                    .as_ref()
+                    .ok_or("log level can not be changed".to_string())?;
+                log_level_handle
+                    .set_max_log_level(&level)
+                    .map_err(|e| e.to_string())?;
+                Ok(AdminResponse::LogLevelChanged { level })
+            }
+            AdminRequest::ListBans => Ok(AdminResponse::Bans {
+                bans: self.server_state.ban_list().list(),
+            }),
+            AdminRequest::Unban { ip } => {
+                if !self.server_state.ban_list().unban(ip) {
+                    return Err(format!("ip not banned: {ip}"));
+                }
+                Ok(AdminResponse::Unbanned { ip })
+            }
+        }
+    }
+    /// Start the admin endpoint when the `admin_listen_address` configured
+    pub fn start(self) {
+        let Some(admin_listen_address) = self.server_state.config().admin_listen_address().clone()
+        else {
+            return;
+        };
+        let admin_address = match admin_listen_address.parse::<AdminAddress>() {
+            Ok(admin_address) => admin_address,
+            Err(e) => {
+                error!("Fail to start admin endpoint: {e:?}");
+                return;
+            }
+        };
+        let stop_token = self
+            .server_state
+            .shutdown_coordinator()
+            .accept_token()
+            .clone();
+        let admin_server = Arc::new(self);
+        tokio::spawn(async move {
+            let handler = move |request: AdminRequest| {
+                let admin_server = admin_server.clone();
+                async move { admin_server.handle(request) }
+            };
+            if let Err(e) = serve_admin(admin_address.clone(), handler, stop_token).await {
+                error!("Fail to serve admin endpoint on [{admin_address}]: {e:?}");
+            }
+        });
+    }
+}
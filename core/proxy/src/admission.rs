@@ -0,0 +1,357 @@
+use crate::rate_limit::TokenBucket;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+/// The ip buckets are pruned when there are more than this count
+const IP_BUCKETS_PRUNE_THRESHOLD: usize = 4096;
+/// The min interval to prune the ip buckets
+const IP_BUCKETS_PRUNE_INTERVAL: Duration = Duration::from_secs(1);
+/// The max concurrent server busy responses to the rejected connections,
+/// the rejected connections are closed directly when reached
+const MAX_SERVER_BUSY_RESPONSES: usize = 64;
+/// The admission limits of the proxy, `None` means no limit
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AdmissionConfig {
+    /// The max concurrent agent connections, the idle pooled connections are counted
+    /// and every stream of a quic connection is counted as one connection
+    pub max_connections: Option<usize>,
+    /// The max concurrent tunnels of all the users
+    pub max_tunnels: Option<usize>,
+    /// The max concurrent tunnels of a user, the `max_tunnels` of the user account takes precedence
+    pub max_user_tunnels: Option<usize>,
+    /// The new connections accepted per second from a source ip
+    pub ip_accept_rate: Option<u64>,
+    /// The new connections accepted at once from a source ip, defaults to the rate
+    pub ip_accept_burst: Option<u64>,
+}
+/// Why the tunnel is not admitted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TunnelAdmissionRejection {
+    /// The proxy reached the max tunnels
+    MaxTunnels(usize),
+    /// The user reached the max tunnels of the user
+    MaxUserTunnels(usize),
+}
+/// Why the agent connection is not admitted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdmissionRejection {
+    /// The source ip opens the connections too fast
+    RateLimited,
+    /// The proxy reached `max_connections`
+    ServerBusy,
+}
+impl AdmissionRejection {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AdmissionRejection::RateLimited => "rate_limited",
+            AdmissionRejection::ServerBusy => "server_busy",
+        }
+    }
+}
+#[derive(Default)]
+struct IpBuckets {
+    rate: u64,
+    burst: u64,
+    buckets: HashMap<IpAddr, TokenBucket>,
+    last_prune_time: Option<Instant>,
+}
+/// The tunnels reserved by the live tunnel permits
+#[derive(Default)]
+struct TunnelCounts {
+    tunnels: usize,
+    user_tunnels: HashMap<String, usize>,
+}
+/// Decide whether to admit the accepted agent connection
+pub struct AdmissionController {
+    connections: Arc<AtomicUsize>,
+    tunnel_counts: Arc<Mutex<TunnelCounts>>,
+    ip_buckets: Mutex<IpBuckets>,
+    server_busy_responses: Arc<Semaphore>,
+}
+impl Default for AdmissionController {
+    fn default() -> Self {
+        Self {
+            connections: Default::default(),
+            tunnel_counts: Default::default(),
+            ip_buckets: Default::default(),
+            server_busy_responses: Arc::new(Semaphore::new(MAX_SERVER_BUSY_RESPONSES)),
+        }
+    }
+}
+impl AdmissionController {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// The count of the live agent connections
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::Relaxed)
+    }
+    /// Admit the agent connection, the connection is counted until the permit dropped
+    pub fn admit_connection(
+        &self,
+        ip: IpAddr,
+        admission_config: &AdmissionConfig,
+    ) -> Result<ConnectionPermit, AdmissionRejection> {
+        if let Some(rate) = admission_config.ip_accept_rate.filter(|rate| *rate > 0) {
+            let burst = admission_config.ip_accept_burst.unwrap_or(rate).max(1);
+            if !self.try_accept_ip(ip.to_canonical(), rate, burst, Instant::now()) {
+                return Err(AdmissionRejection::RateLimited);
+            }
+        }
+        self.admit_stream(admission_config)
+    }
+    /// Admit a stream of the multiplexed agent connection like an agent connection,
+    /// the source ip is already rate limited when the connection admitted
+    pub fn admit_stream(
+        &self,
+        admission_config: &AdmissionConfig,
+    ) -> Result<ConnectionPermit, AdmissionRejection> {
+        let connections = self.connections.fetch_add(1, Ordering::Relaxed);
+        let permit = ConnectionPermit {
+            connections: self.connections.clone(),
+        };
+        match admission_config.max_connections {
+            Some(max_connections) if connections >= max_connections => {
+                Err(AdmissionRejection::ServerBusy)
+            }
+            _ => Ok(permit),
+        }
+    }
+    /// Reserve a tunnel of the user before the tunnel created, the tunnel
+    /// is counted until the permit dropped
+    pub fn admit_tunnel(
+        &self,
+        user: &str,
+        max_tunnels: Option<usize>,
+        max_user_tunnels: Option<usize>,
+    ) -> Result<TunnelPermit, TunnelAdmissionRejection> {
+        let mut tunnel_counts = self
+            .tunnel_counts
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(max_tunnels) = max_tunnels {
+            if tunnel_counts.tunnels >= max_tunnels {
+                return Err(TunnelAdmissionRejection::MaxTunnels(max_tunnels));
+            }
+        }
+        let user_tunnels = tunnel_counts.user_tunnels.get(user).copied().unwrap_or(0);
+        if let Some(max_user_tunnels) = max_user_tunnels {
+            if user_tunnels >= max_user_tunnels {
+                return Err(TunnelAdmissionRejection::MaxUserTunnels(max_user_tunnels));
+            }
+        }
+        tunnel_counts.tunnels += 1;
+        tunnel_counts
+            .user_tunnels
+            .insert(user.to_string(), user_tunnels + 1);
+        Ok(TunnelPermit {
+            tunnel_counts: self.tunnel_counts.clone(),
+            user: user.to_string(),
+        })
+    }
+    /// Reserve the server busy response to the rejected connection, `None` when
+    /// too many responses are in progress and the connection should be closed directly
+    pub fn admit_server_busy_response(&self) -> Option<OwnedSemaphorePermit> {
+        self.server_busy_responses.clone().try_acquire_owned().ok()
+    }
+    fn try_accept_ip(&self, ip: IpAddr, rate: u64, burst: u64, now: Instant) -> bool {
+        let mut ip_buckets = self
+            .ip_buckets
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let ip_buckets = &mut *ip_buckets;
+        if ip_buckets.rate != rate || ip_buckets.burst != burst {
+            ip_buckets.rate = rate;
+            ip_buckets.burst = burst;
+            ip_buckets.buckets.clear();
+        }
+        if ip_buckets.buckets.len() >= IP_BUCKETS_PRUNE_THRESHOLD
+            && ip_buckets.last_prune_time.is_none_or(|last_prune_time| {
+                now.saturating_duration_since(last_prune_time) >= IP_BUCKETS_PRUNE_INTERVAL
+            })
+        {
+            ip_buckets.buckets.retain(|_, bucket| !bucket.is_full(now));
+            ip_buckets.last_prune_time = Some(now);
+        }
+        ip_buckets
+            .buckets
+            .entry(ip)
+            .or_insert_with(|| TokenBucket::new(rate, burst))
+            .try_acquire(1, now)
+    }
+}
+/// Count the agent connection until dropped
+pub struct ConnectionPermit {
+    connections: Arc<AtomicUsize>,
+}
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        self.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+/// Count the tunnel of the user until dropped
+pub struct TunnelPermit {
+    tunnel_counts: Arc<Mutex<TunnelCounts>>,
+    user: String,
+}
+impl Drop for TunnelPermit {
+    fn drop(&mut self) {
+        let mut tunnel_counts = self
+            .tunnel_counts
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        tunnel_counts.tunnels -= 1;
+        if let Some(user_tunnels) = tunnel_counts.user_tunnels.get_mut(&self.user) {
+            *user_tunnels -= 1;
+            if *user_tunnels == 0 {
+                tunnel_counts.user_tunnels.remove(&self.user);
+            }
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn admit_connection_by_limits() {
+        let admission_controller = AdmissionController::new();
+        let admission_config = AdmissionConfig {
+            max_connections: Some(2),
+            ip_accept_rate: Some(1),
+            ip_accept_burst: Some(2),
+            ..Default::default()
+        };
+        let ip1 = "10.0.0.1".parse().unwrap();
+        let ip2 = "10.0.0.2".parse().unwrap();
+        let permit1 = admission_controller
+            .admit_connection(ip1, &admission_config)
+            .unwrap();
+        let permit2 = admission_controller
+            .admit_connection(ip1, &admission_config)
+            .unwrap();
+        assert_eq!(
+            admission_controller
+                .admit_connection(ip1, &admission_config)
+                .err(),
+            Some(AdmissionRejection::RateLimited)
+        );
+        assert_eq!(
+            admission_controller
+                .admit_connection(ip2, &admission_config)
+                .err(),
+            Some(AdmissionRejection::ServerBusy)
+        );
+        drop(permit1);
+        assert!(admission_controller
+            .admit_connection(ip2, &admission_config)
+            .is_ok());
+        assert_eq!(admission_controller.connections(), 1);
+        drop(permit2);
+        assert_eq!(admission_controller.connections(), 0);
+    }
+    #[test]
+    fn admit_stream_by_max_connections() {
+        let admission_controller = AdmissionController::new();
+        let admission_config = AdmissionConfig {
+            max_connections: Some(2),
+            ip_accept_rate: Some(1),
+            ip_accept_burst: Some(1),
+            ..Default::default()
+        };
+        let ip = "10.0.0.1".parse().unwrap();
+        let connection_permit = admission_controller
+            .admit_connection(ip, &admission_config)
+            .unwrap();
+        drop(connection_permit);
+        // The streams are not rate limited by the source ip
+        let stream_permit1 = admission_controller
+            .admit_stream(&admission_config)
+            .unwrap();
+        let _stream_permit2 = admission_controller
+            .admit_stream(&admission_config)
+            .unwrap();
+        assert_eq!(
+            admission_controller.admit_stream(&admission_config).err(),
+            Some(AdmissionRejection::ServerBusy)
+        );
+        drop(stream_permit1);
+        assert!(admission_controller.admit_stream(&admission_config).is_ok());
+    }
+    #[test]
+    fn admit_tunnel_by_limits() {
+        let admission_controller = AdmissionController::new();
+        let tunnel1 = admission_controller
+            .admit_tunnel("user1", Some(3), Some(2))
+            .unwrap();
+        let _tunnel2 = admission_controller
+            .admit_tunnel("user1", Some(3), Some(2))
+            .unwrap();
+        assert_eq!(
+            admission_controller
+                .admit_tunnel("user1", Some(3), Some(2))
+                .err(),
+            Some(TunnelAdmissionRejection::MaxUserTunnels(2))
+        );
+        let _tunnel3 = admission_controller
+            .admit_tunnel("user2", Some(3), Some(2))
+            .unwrap();
+        assert_eq!(
+            admission_controller
+                .admit_tunnel("user2", Some(3), Some(2))
+                .err(),
+            Some(TunnelAdmissionRejection::MaxTunnels(3))
+        );
+        drop(tunnel1);
+        assert!(admission_controller
+            .admit_tunnel("user1", Some(3), Some(2))
+            .is_ok());
+    }
+    #[test]
+    fn admit_concurrent_tunnels_by_limits() {
+        let admission_controller = Arc::new(AdmissionController::new());
+        let barrier = Arc::new(std::sync::Barrier::new(16));
+        let handles = (0..16)
+            .map(|_| {
+                let admission_controller = admission_controller.clone();
+                let barrier = barrier.clone();
+                std::thread::spawn(move || {
+                    barrier.wait();
+                    admission_controller.admit_tunnel("user1", Some(8), Some(4))
+                })
+            })
+            .collect::<Vec<_>>();
+        let tunnel_permits = handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            tunnel_permits
+                .iter()
+                .filter(|tunnel_permit| tunnel_permit.is_ok())
+                .count(),
+            4
+        );
+        drop(tunnel_permits);
+        assert!(admission_controller
+            .tunnel_counts
+            .lock()
+            .unwrap()
+            .user_tunnels
+            .is_empty());
+    }
+    #[test]
+    fn admit_server_busy_responses_by_limit() {
+        let admission_controller = AdmissionController::new();
+        let server_busy_responses = (0..MAX_SERVER_BUSY_RESPONSES)
+            .map(|_| admission_controller.admit_server_busy_response().unwrap())
+            .collect::<Vec<_>>();
+        assert!(admission_controller.admit_server_busy_response().is_none());
+        drop(server_busy_responses);
+        assert!(admission_controller.admit_server_busy_response().is_some());
+    }
+}
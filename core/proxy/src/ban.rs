@@ -0,0 +1,198 @@
+use crate::acl::IpCidr;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+/// The failure records are pruned when there are more than this count of ips
+const FAILURES_PRUNE_THRESHOLD: usize = 4096;
+/// The min interval to prune the failure records and the expired bans
+const PRUNE_INTERVAL: Duration = Duration::from_secs(1);
+/// Ban the agent ips which fail the authentication or the protocol too many times
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BanConfig {
+    /// Ban the ip when it fails this many times in the window, never ban when not given.
+    /// The connections handed to the `fallback_address` are not counted, and it can not
+    /// be used with `websocket` because the ip behind a websocket gateway is the gateway ip
+    pub max_failures: Option<usize>,
+    /// The sliding window in seconds to count the failures
+    pub failure_window: u64,
+    /// The seconds to ban the ip
+    pub ban_time: u64,
+    /// The ip networks which are never banned
+    pub allowlist: Vec<IpCidr>,
+}
+impl Default for BanConfig {
+    fn default() -> Self {
+        Self {
+            max_failures: None,
+            failure_window: 60,
+            ban_time: 600,
+            allowlist: Vec::new(),
+        }
+    }
+}
+impl BanConfig {
+    fn is_allowed(&self, ip: IpAddr) -> bool {
+        self.allowlist.iter().any(|cidr| cidr.contains(ip))
+    }
+}
+/// The banned ip shown on the admin endpoint
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BanSnapshot {
+    pub ip: IpAddr,
+    pub failures: usize,
+    pub banned_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+struct BannedIp {
+    snapshot: BanSnapshot,
+    expire_instant: Instant,
+}
+#[derive(Default)]
+struct BanTables {
+    failures: HashMap<IpAddr, VecDeque<Instant>>,
+    bans: HashMap<IpAddr, BannedIp>,
+    last_prune_time: Option<Instant>,
+}
+/// The failures and the bans of the agent ips, the ipv4 mapped ipv6 address
+/// is treated as the ipv4 address
+#[derive(Default)]
+pub struct BanList {
+    tables: Mutex<BanTables>,
+}
+impl BanList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Check whether the ip is banned, the allowed ip is never banned
+    pub fn is_banned(&self, ip: IpAddr, ban_config: &BanConfig) -> bool {
+        let ip = ip.to_canonical();
+        if ban_config.is_allowed(ip) {
+            return false;
+        }
+        let mut tables = self.lock_tables();
+        match tables.bans.get(&ip) {
+            None => false,
+            Some(banned_ip) if banned_ip.expire_instant <= Instant::now() => {
+                tables.bans.remove(&ip);
+                false
+            }
+            Some(_) => true,
+        }
+    }
+    /// Record an authentication or protocol failure of the ip,
+    /// return `true` when the ip is banned because of this failure
+    pub fn record_failure(&self, ip: IpAddr, ban_config: &BanConfig) -> bool {
+        let Some(max_failures) = ban_config.max_failures.filter(|max| *max > 0) else {
+            return false;
+        };
+        let ip = ip.to_canonical();
+        if ban_config.is_allowed(ip) {
+            return false;
+        }
+        let now = Instant::now();
+        let failure_window = Duration::from_secs(ban_config.failure_window);
+        let mut tables = self.lock_tables();
+        let tables = &mut *tables;
+        if tables.failures.len() >= FAILURES_PRUNE_THRESHOLD
+            && tables.last_prune_time.is_none_or(|last_prune_time| {
+                now.saturating_duration_since(last_prune_time) >= PRUNE_INTERVAL
+            })
+        {
+            tables.failures.retain(|_, failures| {
+                failures
+                    .back()
+                    .is_some_and(|last| now.saturating_duration_since(*last) < failure_window)
+            });
+            tables
+                .bans
+                .retain(|_, banned_ip| banned_ip.expire_instant > now);
+            tables.last_prune_time = Some(now);
+        }
+        let failures = tables.failures.entry(ip).or_default();
+        failures.push_back(now);
+        while failures
+            .front()
+            .is_some_and(|first| now.saturating_duration_since(*first) >= failure_window)
+        {
+            failures.pop_front();
+        }
+        if failures.len() < max_failures {
+            return false;
+        }
+        let failures = failures.len();
+        tables.failures.remove(&ip);
+        let ban_time = Duration::from_secs(ban_config.ban_time);
+        let banned_at = Utc::now();
+        tables.bans.insert(
+            ip,
+            BannedIp {
+                snapshot: BanSnapshot {
+                    ip,
+                    failures,
+                    banned_at,
+                    expires_at: banned_at
+                        + chrono::Duration::from_std(ban_time).unwrap_or(chrono::Duration::MAX),
+                },
+                expire_instant: now + ban_time,
+            },
+        );
+        true
+    }
+    /// The bans not expired
+    pub fn list(&self) -> Vec<BanSnapshot> {
+        let now = Instant::now();
+        let mut bans = self
+            .lock_tables()
+            .bans
+            .values()
+            .filter(|banned_ip| banned_ip.expire_instant > now)
+            .map(|banned_ip| banned_ip.snapshot.clone())
+            .collect::<Vec<_>>();
+        bans.sort_by_key(|ban| ban.banned_at);
+        bans
+    }
+    /// Lift the ban and forget the failures of the ip, return `false` if the ip is not banned
+    pub fn unban(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        let mut tables = self.lock_tables();
+        tables.failures.remove(&ip);
+        tables.bans.remove(&ip).is_some()
+    }
+    fn lock_tables(&self) -> std::sync::MutexGuard<'_, BanTables> {
+        self.tables
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn ban_ip_after_failures() {
+        let ban_list = BanList::new();
+        let ban_config = BanConfig {
+            max_failures: Some(3),
+            allowlist: vec!["10.0.0.0/8".parse().unwrap()],
+            ..Default::default()
+        };
+        let ip = "192.0.2.1".parse().unwrap();
+        assert!(!ban_list.record_failure(ip, &ban_config));
+        assert!(!ban_list.record_failure(ip, &ban_config));
+        assert!(!ban_list.is_banned(ip, &ban_config));
+        assert!(ban_list.record_failure("::ffff:192.0.2.1".parse().unwrap(), &ban_config));
+        assert!(ban_list.is_banned(ip, &ban_config));
+        assert_eq!(ban_list.list()[0].failures, 3);
+        let allowed_ip = "10.1.1.1".parse().unwrap();
+        for _ in 0..3 {
+            assert!(!ban_list.record_failure(allowed_ip, &ban_config));
+        }
+        assert!(!ban_list.is_banned(allowed_ip, &ban_config));
+        assert!(ban_list.unban(ip));
+        assert!(!ban_list.is_banned(ip, &ban_config));
+        assert!(ban_list.list().is_empty());
+    }
+}
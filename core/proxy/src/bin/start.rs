@@ -1,14 +1,16 @@
 use anyhow::Result;
 use clap::Parser;
 use ppaass_common::init_logger;
-use proxy::command::CommandArgs;
+use ppaass_common::shutdown::wait_for_shutdown_signal;
+use proxy::command::{CommandArgs, ProxyCommand};
 use proxy::config::Config;
+use proxy::ctl::run_ctl;
 use proxy::server::ProxyServer;
-use std::fs::read_to_string;
+use proxy::usage::run_usage_export;
 use std::path::PathBuf;
 use std::sync::Arc;
 use tokio::runtime::Builder;
-use tracing::error;
+use tracing::{error, info};
 #[global_allocator]
 static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
 const DEFAULT_CONFIG_FILE: &str = "config.toml";
@@ -18,9 +20,20 @@ pub fn main() -> Result<()> {
     let config_file_path = command
         .config
         .unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG_FILE));
-    let config_file_content = read_to_string(config_file_path)?;
-    let config = Arc::new(toml::from_str::<Config>(&config_file_content)?);
-    let _trace_append_guard = init_logger(
+    match command.command {
+        Some(ProxyCommand::Ctl(ctl_args)) => {
+            let runtime = Builder::new_current_thread().enable_all().build()?;
+            runtime.block_on(run_ctl(ctl_args, &config_file_path))?;
+            return Ok(());
+        }
+        Some(ProxyCommand::Usage(usage_args)) => {
+            run_usage_export(usage_args, &config_file_path)?;
+            return Ok(());
+        }
+        None => {}
+    }
+    let config = Arc::new(Config::load(&config_file_path)?);
+    let (_trace_append_guard, log_level_handle) = init_logger(
         config.log_folder(),
         LOG_FILE_NAME_PREFIX,
         config.max_log_level(),
@@ -38,15 +51,29 @@ pub fn main() -> Result<()> {
                 return;
             }
         };
-        let guard = match server.start().await {
-            Ok(guard) => guard,
+        server.admin_server(Some(log_level_handle.clone())).start();
+        server
+            .config_reloader(config_file_path, Some(log_level_handle))
+            .start();
+        let mut server_handle = match server.start().await {
+            Ok(server_handle) => server_handle,
             Err(e) => {
                 error!("Failed to start server: {}", e);
                 return;
             }
         };
-        if let Err(e) = guard.await {
-            error!("Failed to run server: {}", e);
+        tokio::select! {
+            result = server_handle.wait() => {
+                if let Err(e) = result {
+                    error!("Failed to run server: {}", e);
+                }
+            }
+            _ = wait_for_shutdown_signal() => {
+                info!("Shutdown signal received, start graceful shutdown.");
+                if !server_handle.shutdown().await {
+                    info!("Some tunnels are cancelled because of drain timeout.");
+                }
+            }
         }
     });
     Ok(())
@@ -1,15 +1,107 @@
+use crate::acl::AclPolicy;
+use crate::admission::AdmissionController;
+use crate::ban::BanList;
 use crate::config::Config;
-use crate::crypto::ProxyRsaCryptoHolder;
+use crate::crypto::{ProxyRsaCryptoHolder, UserRsaCryptoHolder};
+use crate::metrics::ProxyMetrics;
+use crate::rate_limit::{BandwidthLimit, RateLimiter, TunnelRateLimit};
+use crate::usage::{QuotaLimit, UsageStore};
 use accessory::Accessors;
+use arc_swap::ArcSwap;
 use derive_builder::Builder;
+use ppaass_common::shutdown::ShutdownCoordinator;
+use ppaass_common::tunnel_registry::TunnelRegistry;
 use std::sync::Arc;
+use tokio_rustls::TlsAcceptor;
 #[derive(Clone, Accessors, Builder)]
 pub struct ServerState {
+    config: Arc<ArcSwap<Config>>,
     #[access(get)]
-    config: Arc<Config>,
+    shutdown_coordinator: ShutdownCoordinator,
     #[access(get)]
-    rsa_crypto_holder: Arc<ProxyRsaCryptoHolder>,
+    tunnel_registry: Arc<TunnelRegistry>,
+    #[access(get)]
+    rsa_crypto_holder: Arc<UserRsaCryptoHolder>,
     #[access(get)]
     #[builder(setter(strip_option), default)]
     forward_rsa_crypto_holder: Option<Arc<ProxyRsaCryptoHolder>>,
+    #[access(get)]
+    metrics: Arc<ProxyMetrics>,
+    acl_policy: Arc<ArcSwap<AclPolicy>>,
+    #[access(get)]
+    rate_limiter: Arc<RateLimiter>,
+    #[access(get)]
+    usage_store: Arc<UsageStore>,
+    #[access(get)]
+    admission_controller: Arc<AdmissionController>,
+    #[access(get)]
+    ban_list: Arc<BanList>,
+    #[access(get)]
+    #[builder(setter(strip_option), default)]
+    tls_acceptor: Option<TlsAcceptor>,
+    #[access(get)]
+    #[builder(setter(strip_option), default)]
+    quic_server_config: Option<quinn::ServerConfig>,
+}
+impl ServerState {
+    /// The current configuration, it can be replaced by reload
+    pub fn config(&self) -> Arc<Config> {
+        self.config.load_full()
+    }
+    /// Replace the configuration, the new configuration
+    /// will take effect on the new connections
+    pub fn swap_config(&self, config: Arc<Config>) -> Arc<Config> {
+        self.config.swap(config)
+    }
+    /// The current access control policy, it can be replaced by reload
+    pub fn acl_policy(&self) -> Arc<AclPolicy> {
+        self.acl_policy.load_full()
+    }
+    /// Replace the access control policy, the new policy
+    /// will take effect on the new tunnels
+    pub fn swap_acl_policy(&self, acl_policy: Arc<AclPolicy>) -> Arc<AclPolicy> {
+        self.acl_policy.swap(acl_policy)
+    }
+    /// The bandwidth limit of the user, the limit of the
+    /// user account takes precedence over the configuration
+    fn user_bandwidth_limit(&self, config: &Config, user: &str) -> BandwidthLimit {
+        let user_account = self.rsa_crypto_holder.user_account(user);
+        user_account
+            .as_ref()
+            .and_then(|user_account| user_account.bandwidth())
+            .unwrap_or_else(|| config.rate_limit().user_limit(user))
+            .clone()
+    }
+    /// The bandwidth buckets for a new tunnel
+    pub fn tunnel_rate_limit(&self, user: &str) -> TunnelRateLimit {
+        let config = self.config();
+        let user_limit = self.user_bandwidth_limit(&config, user);
+        self.rate_limiter
+            .tunnel_rate_limit(user, &user_limit, &config.rate_limit().global)
+    }
+    /// Apply the reloaded bandwidth limits to the buckets of the existing tunnels,
+    /// the buckets of the removed users are dropped
+    pub fn update_rate_limits(&self) {
+        let config = self.config();
+        let users = self.rsa_crypto_holder.users();
+        self.rate_limiter
+            .update_limits(&config.rate_limit().global, |user| {
+                users
+                    .binary_search_by(|existing_user| existing_user.as_str().cmp(user))
+                    .ok()
+                    .map(|_| self.user_bandwidth_limit(&config, user))
+            });
+    }
+    /// The traffic quota of the user, the quota of the user
+    /// account takes precedence over the configuration
+    pub fn user_quota_limit(&self, user: &str) -> QuotaLimit {
+        match self
+            .rsa_crypto_holder
+            .user_account(user)
+            .and_then(|user_account| user_account.quota().copied())
+        {
+            Some(quota_limit) => quota_limit,
+            None => *self.config().quota().user_limit(user),
+        }
+    }
 }
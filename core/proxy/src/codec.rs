@@ -1,4 +1,4 @@
-use crate::crypto::ProxyRsaCryptoHolder;
+use crate::crypto::UserRsaCryptoHolder;
 use crate::error::ProxyError;
 use bytes::BytesMut;
 use ppaass_codec::{
@@ -9,17 +9,33 @@ use ppaass_domain::tunnel::Encryption;
 use ppaass_domain::{AgentControlPacket, AgentDataPacket, ProxyControlPacket, ProxyDataPacket};
 use std::sync::Arc;
 use tokio_util::codec::{Decoder, Encoder};
+/// The control packets are small, the first packet with a longer frame can't be
+/// from an agent, it is rejected without waiting for the rest of the frame
+const MAX_PROBE_FRAME_LENGTH: usize = 64 * 1024;
 pub struct ControlPacketCodec {
-    agent_control_packet_decoder: AgentControlPacketDecoder<ProxyRsaCryptoHolder>,
-    proxy_control_packet_encoder: ProxyControlPacketEncoder<ProxyRsaCryptoHolder>,
+    agent_control_packet_decoder: AgentControlPacketDecoder<UserRsaCryptoHolder>,
+    proxy_control_packet_encoder: ProxyControlPacketEncoder<UserRsaCryptoHolder>,
+    /// The bytes consumed by the decoder before the first packet decoded
+    probe_bytes: Option<BytesMut>,
 }
 impl ControlPacketCodec {
-    pub fn new(rsa_crypto_holder: Arc<ProxyRsaCryptoHolder>) -> Self {
+    pub fn new(rsa_crypto_holder: Arc<UserRsaCryptoHolder>) -> Self {
         Self {
             agent_control_packet_decoder: AgentControlPacketDecoder::new(rsa_crypto_holder.clone()),
             proxy_control_packet_encoder: ProxyControlPacketEncoder::new(rsa_crypto_holder),
+            probe_bytes: None,
         }
     }
+    /// Record the bytes consumed before the first packet decoded,
+    /// so they can be replayed when the connection is not from an agent
+    pub fn with_probe_recording(mut self) -> Self {
+        self.probe_bytes = Some(BytesMut::new());
+        self
+    }
+    /// Take the recorded bytes, `None` when not recording or the first packet decoded
+    pub fn take_probe_bytes(&mut self) -> Option<BytesMut> {
+        self.probe_bytes.take()
+    }
 }
 impl Encoder<ProxyControlPacket> for ControlPacketCodec {
     type Error = ProxyError;
@@ -32,7 +48,30 @@ impl Decoder for ControlPacketCodec {
     type Item = AgentControlPacket;
     type Error = ProxyError;
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        Ok(self.agent_control_packet_decoder.decode(src)?)
+        let Some(probe_bytes) = self.probe_bytes.as_mut() else {
+            return Ok(self.agent_control_packet_decoder.decode(src)?);
+        };
+        // The packet type byte and the frame length of the first packet,
+        // part of them may be consumed by the decoder already
+        let frame_length = probe_bytes
+            .iter()
+            .chain(src.iter())
+            .skip(1)
+            .take(4)
+            .copied()
+            .collect::<Vec<_>>();
+        if let Ok(frame_length) = <[u8; 4]>::try_from(frame_length) {
+            if u32::from_be_bytes(frame_length) as usize > MAX_PROBE_FRAME_LENGTH {
+                return Err(ProxyError::InvalidData);
+            }
+        }
+        let src_snapshot = src.clone();
+        let result = self.agent_control_packet_decoder.decode(src);
+        probe_bytes.extend_from_slice(&src_snapshot[..src_snapshot.len() - src.len()]);
+        if let Ok(Some(_)) = result {
+            self.probe_bytes = None;
+        }
+        Ok(result?)
     }
 }
 pub struct DataPacketCodec {
@@ -60,3 +99,69 @@ impl Decoder for DataPacketCodec {
         Ok(self.agent_data_packet_decoder.decode(src)?)
     }
 }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::ProxyRsaCryptoHolder;
+    use ppaass_codec::AgentControlPacketEncoder;
+    use ppaass_domain::heartbeat::HeartbeatPing;
+    use std::path::Path;
+    fn rsa_crypto_holder() -> Arc<UserRsaCryptoHolder> {
+        let rsa_dir_path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../../resources/proxy/rsa");
+        let rsa_crypto_holder = ProxyRsaCryptoHolder::new(
+            &rsa_dir_path,
+            "AgentPublicKey.pem".to_string(),
+            "ProxyPrivateKey.pem".to_string(),
+        )
+        .unwrap();
+        Arc::new(UserRsaCryptoHolder::RsaDir(rsa_crypto_holder))
+    }
+    #[test]
+    fn record_probe_bytes_before_first_packet() {
+        let mut control_codec = ControlPacketCodec::new(rsa_crypto_holder()).with_probe_recording();
+        let mut src = BytesMut::from(&b"GET / HTTP/1.1\r\n"[..]);
+        assert!(control_codec.decode(&mut src).is_err());
+        let mut probe_bytes = control_codec.take_probe_bytes().unwrap();
+        probe_bytes.extend_from_slice(&src);
+        assert_eq!(&probe_bytes[..], b"GET / HTTP/1.1\r\n");
+        assert!(control_codec.take_probe_bytes().is_none());
+    }
+    #[test]
+    fn reject_probe_with_too_long_frame() {
+        let mut control_codec = ControlPacketCodec::new(rsa_crypto_holder()).with_probe_recording();
+        let mut src = BytesMut::from(&[1u8, 0][..]);
+        assert!(control_codec.decode(&mut src).unwrap().is_none());
+        src.extend_from_slice(&[0x10, 0, 0, b'x']);
+        assert!(matches!(
+            control_codec.decode(&mut src),
+            Err(ProxyError::InvalidData)
+        ));
+        let mut probe_bytes = control_codec.take_probe_bytes().unwrap();
+        probe_bytes.extend_from_slice(&src);
+        assert_eq!(&probe_bytes[..], &[1u8, 0, 0x10, 0, 0, b'x']);
+    }
+    #[test]
+    fn decode_packet_split_into_bytes() {
+        let mut packet_bytes = BytesMut::new();
+        let mut agent_control_packet_encoder = AgentControlPacketEncoder::new(rsa_crypto_holder());
+        for _ in 0..2 {
+            agent_control_packet_encoder
+                .encode(
+                    AgentControlPacket::Heartbeat(HeartbeatPing::default()),
+                    &mut packet_bytes,
+                )
+                .unwrap();
+        }
+        let mut control_codec = ControlPacketCodec::new(rsa_crypto_holder());
+        let mut src = BytesMut::new();
+        let mut decoded = 0;
+        for byte in packet_bytes {
+            src.extend_from_slice(&[byte]);
+            if let Some(AgentControlPacket::Heartbeat(_)) = control_codec.decode(&mut src).unwrap()
+            {
+                decoded += 1;
+            }
+        }
+        assert_eq!(decoded, 2);
+    }
+}
@@ -1,12 +1,72 @@
-use clap::Parser;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::net::IpAddr;
 use std::path::PathBuf;
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 pub struct CommandArgs {
     /// The configuration file path of the proxy
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     pub config: Option<PathBuf>,
     /// The rsa folder path of the proxy
     #[arg(short, long)]
     pub rsa: Option<PathBuf>,
+    #[command(subcommand)]
+    pub command: Option<ProxyCommand>,
+}
+#[derive(Subcommand, Debug)]
+pub enum ProxyCommand {
+    /// Inspect and control the running proxy through the admin endpoint
+    Ctl(CtlArgs),
+    /// Export the traffic usage persisted in the `usage_file`
+    Usage(UsageArgs),
+}
+#[derive(Args, Debug)]
+pub struct CtlArgs {
+    /// The admin address of the running proxy, use the
+    /// `admin_listen_address` in the configuration file when not given
+    #[arg(short, long)]
+    pub admin: Option<String>,
+    #[command(subcommand)]
+    pub action: CtlAction,
+}
+#[derive(Subcommand, Debug)]
+pub enum CtlAction {
+    /// List the active tunnels
+    Tunnels,
+    /// Kill a tunnel
+    Kill { tunnel_id: String },
+    /// Kill all the tunnels of a user
+    KillUser { user: String },
+    /// List the loaded users
+    Users,
+    /// Reload the rsa keys of the users
+    ReloadKeys,
+    /// Change the max log level
+    LogLevel { level: String },
+    /// List the banned agent ips
+    Bans,
+    /// Lift the ban of an agent ip
+    Unban { ip: IpAddr },
+}
+#[derive(Args, Debug)]
+pub struct UsageArgs {
+    /// The output format
+    #[arg(short, long, value_enum, default_value_t = UsageFormat::Csv)]
+    pub format: UsageFormat,
+    /// Summarize the usage by day or by month
+    #[arg(short, long, value_enum, default_value_t = UsagePeriod::Daily)]
+    pub period: UsagePeriod,
+    /// Only export the usage of the user
+    #[arg(short, long)]
+    pub user: Option<String>,
+}
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum UsageFormat {
+    Csv,
+    Json,
+}
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum UsagePeriod {
+    Daily,
+    Monthly,
 }
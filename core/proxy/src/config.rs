@@ -1,6 +1,61 @@
+use crate::admission::AdmissionConfig;
+use crate::ban::BanConfig;
+use crate::error::ProxyError;
+use crate::rate_limit::RateLimitConfig;
+use crate::transport::{QuicConfig, TlsConfig, WebSocketConfig};
+use crate::usage::QuotaConfig;
 use accessory::Accessors;
+use ppaass_common::admin::AdminAddress;
+use ppaass_common::tunnel_timeout::TunnelTimeoutConfig;
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::fs::read_to_string;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use tracing::Level;
+/// The deadlines in seconds of the tunnel init handshake with the agent
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HandshakeTimeoutConfig {
+    /// Wait for the first byte of the first control packet of a new agent connection
+    pub first_byte: u64,
+    /// Wait for the first byte of the next control packet after the first one, the idle pooled
+    /// connections of the agent wait here, so it must be longer than `proxy_connection_max_lifetime`
+    /// of the agent
+    pub idle: u64,
+    /// Receive the full control packet after its first byte arrived
+    pub init_frame: u64,
+    /// Send the tunnel init response to the agent
+    pub init_response: u64,
+}
+impl Default for HandshakeTimeoutConfig {
+    fn default() -> Self {
+        Self {
+            first_byte: 10,
+            idle: 360,
+            init_frame: 10,
+            init_response: 10,
+        }
+    }
+}
+impl HandshakeTimeoutConfig {
+    pub fn first_byte(&self) -> Duration {
+        Duration::from_secs(self.first_byte)
+    }
+    pub fn idle(&self) -> Duration {
+        Duration::from_secs(self.idle)
+    }
+    pub fn init_frame(&self) -> Duration {
+        Duration::from_secs(self.init_frame)
+    }
+    pub fn init_response(&self) -> Duration {
+        Duration::from_secs(self.init_response)
+    }
+}
+fn default_shutdown_drain_timeout() -> u64 {
+    30
+}
 #[derive(Debug, Clone, Serialize, Deserialize, Accessors)]
 pub struct Config {
     #[access(get)]
@@ -8,10 +63,6 @@ pub struct Config {
     #[access(get)]
     worker_threads: usize,
     #[access(get)]
-    dst_read_timeout: Option<u64>,
-    #[access(get)]
-    dst_write_timeout: Option<u64>,
-    #[access(get)]
     dst_connect_timeout: u64,
     #[access(get)]
     dst_tcp_keepalive_interval: u64,
@@ -46,10 +97,6 @@ pub struct Config {
     #[access(get)]
     agent_socket_receive_buffer_size: Option<usize>,
     #[access(get)]
-    agent_connection_write_timeout: Option<u64>,
-    #[access(get)]
-    agent_connection_read_timeout: Option<u64>,
-    #[access(get)]
     server_socket_backlog: u16,
     #[access(get)]
     forward_server_addresses: Option<Vec<String>>,
@@ -57,14 +104,72 @@ pub struct Config {
     forward_auth_token: Option<String>,
     #[access(get)]
     log_folder: PathBuf,
+    #[access(get)]
+    config_watch_interval: Option<u64>,
+    #[access(get)]
+    #[serde(default = "default_shutdown_drain_timeout")]
+    shutdown_drain_timeout: u64,
+    #[access(get)]
+    #[serde(default)]
+    tcp_tunnel_timeout: TunnelTimeoutConfig,
+    #[access(get)]
+    #[serde(default)]
+    udp_tunnel_timeout: TunnelTimeoutConfig,
+    #[access(get)]
+    #[serde(default)]
+    handshake_timeout: HandshakeTimeoutConfig,
+    #[access(get)]
+    #[serde(default)]
+    terminate_revoked_user_tunnels: bool,
+    #[access(get)]
+    metrics_listen_address: Option<String>,
+    #[access(get)]
+    admin_listen_address: Option<String>,
+    #[access(get)]
+    #[serde(default)]
+    rate_limit: RateLimitConfig,
+    #[access(get)]
+    #[serde(default)]
+    quota: QuotaConfig,
+    #[access(get)]
+    #[serde(default)]
+    admission: AdmissionConfig,
+    #[access(get)]
+    #[serde(default)]
+    ban: BanConfig,
+    /// The json lines file to persist the traffic usage of the users, not persisted when not given
+    #[access(get)]
+    usage_file: Option<PathBuf>,
+    /// The interval in seconds to flush the traffic usage
+    #[access(get)]
+    usage_flush_interval: Option<u64>,
+    /// The access control policy file of the destinations, everything is allowed when not given
+    #[access(get)]
+    acl_file: Option<PathBuf>,
+    /// The users file listing the accounts of the users, the users
+    /// are discovered from `rsa_dir` when not given
+    #[access(get)]
+    users_file: Option<PathBuf>,
+    /// The address to hand the connections which are not from the agent, for example
+    /// a local web server, the bytes already read are replayed to it. Only the plain tcp
+    /// agent connections fall back, so it can not be used with `tls` or `websocket`
+    #[access(get)]
+    fallback_address: Option<String>,
+    /// The tls of the agent connections
+    #[access(get)]
+    tls: Option<TlsConfig>,
+    /// The websocket of the agent connections, it runs on the tls when `tls` given
+    #[access(get)]
+    websocket: Option<WebSocketConfig>,
+    /// The quic listener of the agent connections beside the tcp one
+    #[access(get)]
+    quic: Option<QuicConfig>,
 }
 impl Default for Config {
     fn default() -> Self {
         Self {
             port: 80,
             worker_threads: 256,
-            dst_read_timeout: None,
-            dst_write_timeout: None,
             dst_buffer_size: 1024 * 1024 * 8,
             dst_socket_send_buffer_size: None,
             dst_socket_receive_buffer_size: None,
@@ -72,8 +177,6 @@ impl Default for Config {
             max_log_level: "INFO".to_string(),
             rsa_dir: PathBuf::from("/resources/rsa"),
             forward_rsa_dir: PathBuf::from("/resources/forward_rsa"),
-            agent_connection_write_timeout: None,
-            agent_connection_read_timeout: None,
             agent_connection_tcp_keepalive: false,
             agent_connection_tcp_keepalive_interval: 75,
             agent_connection_tcp_keepalive_time: 7200,
@@ -85,9 +188,227 @@ impl Default for Config {
             dst_tcp_keepalive_interval: 75,
             dst_tcp_keepalive_time: 7200,
             dst_tcp_keepalive_retry: 9,
-            forward_server_addresses: Some(vec!["127.0.0.1".to_string()]),
+            forward_server_addresses: None,
             forward_auth_token: None,
             log_folder: PathBuf::from("/logs"),
+            config_watch_interval: None,
+            shutdown_drain_timeout: default_shutdown_drain_timeout(),
+            tcp_tunnel_timeout: TunnelTimeoutConfig::default(),
+            udp_tunnel_timeout: TunnelTimeoutConfig::default(),
+            handshake_timeout: HandshakeTimeoutConfig::default(),
+            metrics_listen_address: None,
+            admin_listen_address: None,
+            acl_file: None,
+            users_file: None,
+            fallback_address: None,
+            tls: None,
+            websocket: None,
+            quic: None,
+            rate_limit: RateLimitConfig::default(),
+            quota: QuotaConfig::default(),
+            admission: AdmissionConfig::default(),
+            ban: BanConfig::default(),
+            usage_file: None,
+            usage_flush_interval: None,
+            terminate_revoked_user_tunnels: false,
+        }
+    }
+}
+impl Config {
+    /// Load the configuration from the toml file and validate it
+    pub fn load(config_file_path: &Path) -> Result<Self, ProxyError> {
+        let config_file_content = read_to_string(config_file_path)?;
+        let config = toml::from_str::<Config>(&config_file_content)
+            .map_err(|e| ProxyError::InvalidConfig(e.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+    /// Validate the configuration
+    pub fn validate(&self) -> Result<(), ProxyError> {
+        if Level::from_str(&self.max_log_level).is_err() {
+            return Err(ProxyError::InvalidConfig(format!(
+                "invalid max_log_level: {}",
+                self.max_log_level
+            )));
         }
+        if self.dst_buffer_size == 0 || self.agent_buffer_size == 0 {
+            return Err(ProxyError::InvalidConfig(
+                "buffer size can not be 0".to_string(),
+            ));
+        }
+        if let Some(forward_server_addresses) = &self.forward_server_addresses {
+            if forward_server_addresses.is_empty() {
+                return Err(ProxyError::InvalidConfig(
+                    "forward_server_addresses can not be empty".to_string(),
+                ));
+            }
+            if self.forward_auth_token.is_none() {
+                return Err(ProxyError::InvalidConfig(
+                    "forward_auth_token must be given when forward_server_addresses given"
+                        .to_string(),
+                ));
+            }
+        }
+        if let Some(metrics_listen_address) = &self.metrics_listen_address {
+            if metrics_listen_address.parse::<SocketAddr>().is_err() {
+                return Err(ProxyError::InvalidConfig(format!(
+                    "invalid metrics_listen_address: {metrics_listen_address}"
+                )));
+            }
+        }
+        if let Some(fallback_address) = &self.fallback_address {
+            if fallback_address.parse::<SocketAddr>().is_err() {
+                return Err(ProxyError::InvalidConfig(format!(
+                    "invalid fallback_address: {fallback_address}"
+                )));
+            }
+            if self.tls.is_some() || self.websocket.is_some() {
+                return Err(ProxyError::InvalidConfig(
+                    "fallback_address can not be used with tls or websocket, the other protocols fail in the tls or websocket handshake"
+                        .to_string(),
+                ));
+            }
+        }
+        if let Some(admin_listen_address) = &self.admin_listen_address {
+            admin_listen_address.parse::<AdminAddress>()?;
+        }
+        if let Some(websocket) = &self.websocket {
+            if !websocket.path.starts_with('/') {
+                return Err(ProxyError::InvalidConfig(format!(
+                    "websocket path must start with '/': {}",
+                    websocket.path
+                )));
+            }
+            if self.ban.max_failures.is_some() {
+                return Err(ProxyError::InvalidConfig(
+                    "ban.max_failures can not be used with websocket, the agent ip is the gateway ip behind a websocket gateway"
+                        .to_string(),
+                ));
+            }
+        }
+        Ok(())
+    }
+    /// The changed fields which can only take effect after restart
+    pub fn restart_required_changes(&self, new_config: &Config) -> Vec<&'static str> {
+        let mut changed = ppaass_common::changed_fields!(
+            self,
+            new_config,
+            port,
+            worker_threads,
+            rsa_dir,
+            forward_rsa_dir,
+            log_folder,
+            server_socket_backlog,
+            agent_connection_tcp_keepalive,
+            agent_connection_tcp_keepalive_interval,
+            agent_connection_tcp_keepalive_time,
+            agent_connection_tcp_keepalive_retry,
+            agent_socket_receive_buffer_size,
+            agent_socket_send_buffer_size,
+            config_watch_interval,
+            metrics_listen_address,
+            admin_listen_address,
+            usage_file,
+            users_file,
+            tls,
+            websocket,
+            quic
+        );
+        // The forward rsa crypto holder is only created on start when forwarding enabled
+        if self.forward_server_addresses.is_some() != new_config.forward_server_addresses.is_some()
+        {
+            changed.push("forward_server_addresses");
+        }
+        changed
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn validate_config() {
+        assert!(Config::default().validate().is_ok());
+        let mut config = Config {
+            forward_server_addresses: Some(vec!["127.0.0.1:90".to_string()]),
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+        config.forward_auth_token = Some("proxy_forward_user1".to_string());
+        assert!(config.validate().is_ok());
+        config.forward_server_addresses = Some(vec![]);
+        assert!(config.validate().is_err());
+        let config = Config {
+            websocket: Some(WebSocketConfig {
+                path: "tunnel".to_string(),
+            }),
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+        let config = Config {
+            websocket: Some(WebSocketConfig {
+                path: "/tunnel".to_string(),
+            }),
+            ban: BanConfig {
+                max_failures: Some(10),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+        let config = Config {
+            fallback_address: Some("127.0.0.1:8080".to_string()),
+            websocket: Some(WebSocketConfig {
+                path: "/tunnel".to_string(),
+            }),
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+        let config = Config {
+            max_log_level: "LOUD".to_string(),
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+    }
+    #[test]
+    fn load_config_without_new_fields() {
+        let mut config = toml::Table::try_from(Config::default()).unwrap();
+        for new_field in [
+            "shutdown_drain_timeout",
+            "tcp_tunnel_timeout",
+            "udp_tunnel_timeout",
+            "handshake_timeout",
+            "terminate_revoked_user_tunnels",
+            "rate_limit",
+            "quota",
+            "admission",
+            "ban",
+        ] {
+            config.remove(new_field);
+        }
+        let config: Config = config.try_into().unwrap();
+        assert_eq!(*config.shutdown_drain_timeout(), 30);
+        assert_eq!(
+            *config.handshake_timeout(),
+            HandshakeTimeoutConfig::default()
+        );
+    }
+    #[test]
+    fn collect_restart_required_changes() {
+        let current_config = Config::default();
+        let new_config = Config {
+            port: 8080,
+            max_log_level: "DEBUG".to_string(),
+            shutdown_drain_timeout: 5,
+            forward_server_addresses: Some(vec!["127.0.0.1:90".to_string()]),
+            forward_auth_token: Some("proxy_forward_user1".to_string()),
+            ..Default::default()
+        };
+        assert!(current_config
+            .restart_required_changes(&current_config.clone())
+            .is_empty());
+        assert_eq!(
+            current_config.restart_required_changes(&new_config),
+            vec!["port", "forward_server_addresses"]
+        );
     }
 }
@@ -1,14 +1,20 @@
+use crate::error::ProxyError;
+use crate::user::{UserAccount, UsersFileRsaCryptoHolder};
+use arc_swap::ArcSwap;
 use ppaass_codec::error::CodecError;
-use ppaass_codec::RsaCryptoHolder;
+use ppaass_codec::{RsaCryptoHolder, RsaCryptoReloadReport};
 use ppaass_crypto::error::CryptoError;
 use ppaass_crypto::rsa::RsaCrypto;
 use std::collections::HashMap;
 use std::fs::{read_dir, File};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use tracing::error;
 pub struct ProxyRsaCryptoHolder {
-    cache: Arc<HashMap<String, Arc<RsaCrypto>>>,
+    rsa_dir_path: PathBuf,
+    public_key_file_name: String,
+    private_key_file_name: String,
+    cache: ArcSwap<HashMap<String, Arc<RsaCrypto>>>,
 }
 impl ProxyRsaCryptoHolder {
     pub fn new(
@@ -16,42 +22,94 @@ impl ProxyRsaCryptoHolder {
         public_key_file_name: String,
         private_key_file_name: String,
     ) -> Result<Self, CryptoError> {
+        let cache = Self::load_rsa_dir(
+            rsa_dir_path,
+            &public_key_file_name,
+            &private_key_file_name,
+            &HashMap::new(),
+        )?;
+        Ok(Self {
+            rsa_dir_path: rsa_dir_path.to_path_buf(),
+            public_key_file_name,
+            private_key_file_name,
+            cache: ArcSwap::from_pointee(cache),
+        })
+    }
+    /// The directory which the rsa crypto loaded from
+    pub fn rsa_dir_path(&self) -> &Path {
+        &self.rsa_dir_path
+    }
+    /// The users which have rsa crypto loaded
+    pub fn users(&self) -> Vec<String> {
+        let mut users = self.cache.load().keys().cloned().collect::<Vec<String>>();
+        users.sort();
+        users
+    }
+    /// Scan the rsa directory again, the users whose directory removed are revoked,
+    /// the users whose key files fail to load keep the previous rsa crypto
+    pub fn reload(&self) -> Result<RsaCryptoReloadReport, CryptoError> {
+        let cache = Self::load_rsa_dir(
+            &self.rsa_dir_path,
+            &self.public_key_file_name,
+            &self.private_key_file_name,
+            &self.cache.load(),
+        )?;
+        let previous_cache = self.cache.swap(Arc::new(cache));
+        Ok(RsaCryptoReloadReport::new(
+            &previous_cache,
+            &self.cache.load(),
+        ))
+    }
+    fn load_rsa_dir(
+        rsa_dir_path: &Path,
+        public_key_file_name: &str,
+        private_key_file_name: &str,
+        previous_cache: &HashMap<String, Arc<RsaCrypto>>,
+    ) -> Result<HashMap<String, Arc<RsaCrypto>>, CryptoError> {
         let mut cache = HashMap::new();
-        let rsa_dir = read_dir(rsa_dir_path)?;
-        rsa_dir.for_each(|entry| {
+        for entry in read_dir(rsa_dir_path)? {
             let Ok(entry) = entry else {
                 error!("fail to read {rsa_dir_path:?} directory");
-                return;
+                continue;
             };
             let user_token = entry.file_name();
-            let user_token = user_token.to_str();
-            let Some(user_token) = user_token else {
+            let Some(user_token) = user_token.to_str() else {
                 error!(
                     "Fail to read {rsa_dir_path:?}{:?} directory because of user token not exist",
                     entry.file_name()
                 );
-                return;
-            };
-            let public_key_path = rsa_dir_path.join(user_token).join(&public_key_file_name);
-            let Ok(public_key_file) = File::open(&public_key_path) else {
-                error!("Fail to read public key file: {public_key_path:?}.");
-                return;
-            };
-            let private_key_path = rsa_dir_path.join(user_token).join(&private_key_file_name);
-            let private_key_path = Path::new(Path::new(&private_key_path));
-            let Ok(private_key_file) = File::open(private_key_path) else {
-                error!("Fail to read private key file :{private_key_path:?}.");
-                return;
+                continue;
             };
-            let Ok(rsa_crypto) = RsaCrypto::new(public_key_file, private_key_file) else {
-                error!("Fail to create rsa crypto for user: {user_token}.");
-                return;
+            let user_dir_path = rsa_dir_path.join(user_token);
+            let rsa_crypto = match Self::load_user_rsa_crypto(
+                &user_dir_path,
+                public_key_file_name,
+                private_key_file_name,
+            ) {
+                Ok(rsa_crypto) => Arc::new(rsa_crypto),
+                Err(e) => {
+                    // The key files may be in the middle of writing, the user is
+                    // only revoked when the directory of the user removed
+                    let Some(previous_rsa_crypto) = previous_cache.get(user_token) else {
+                        error!("Fail to create rsa crypto for user [{user_token}]: {e:?}");
+                        continue;
+                    };
+                    error!("Fail to create rsa crypto for user [{user_token}], keep the previous one: {e:?}");
+                    previous_rsa_crypto.clone()
+                }
             };
-            cache.insert(user_token.to_string(), Arc::new(rsa_crypto));
-        });
-        Ok(Self {
-            cache: Arc::new(cache),
-        })
+            cache.insert(user_token.to_string(), rsa_crypto);
+        }
+        Ok(cache)
+    }
+    fn load_user_rsa_crypto(
+        user_dir_path: &Path,
+        public_key_file_name: &str,
+        private_key_file_name: &str,
+    ) -> Result<RsaCrypto, CryptoError> {
+        let public_key_file = File::open(user_dir_path.join(public_key_file_name))?;
+        let private_key_file = File::open(user_dir_path.join(private_key_file_name))?;
+        RsaCrypto::new(public_key_file, private_key_file)
     }
 }
 impl RsaCryptoHolder for ProxyRsaCryptoHolder {
@@ -59,9 +117,102 @@ impl RsaCryptoHolder for ProxyRsaCryptoHolder {
         &self,
         auth_token: impl AsRef<str>,
     ) -> Result<Option<Arc<RsaCrypto>>, CodecError> {
-        match self.cache.get(auth_token.as_ref()) {
+        match self.cache.load().get(auth_token.as_ref()) {
             None => Ok(None),
             Some(val) => Ok(Some(val.clone())),
         }
     }
 }
+
+/// The holder of the user rsa crypto, the users are discovered from
+/// the rsa directory or listed in the users file
+pub enum UserRsaCryptoHolder {
+    RsaDir(ProxyRsaCryptoHolder),
+    UsersFile(UsersFileRsaCryptoHolder),
+}
+impl UserRsaCryptoHolder {
+    /// The rsa directory or the users file to watch for reload
+    pub fn watch_path(&self) -> &Path {
+        match self {
+            UserRsaCryptoHolder::RsaDir(holder) => holder.rsa_dir_path(),
+            UserRsaCryptoHolder::UsersFile(holder) => holder.users_file_path(),
+        }
+    }
+    pub fn users(&self) -> Vec<String> {
+        match self {
+            UserRsaCryptoHolder::RsaDir(holder) => holder.users(),
+            UserRsaCryptoHolder::UsersFile(holder) => holder.users(),
+        }
+    }
+    /// The account of the user, only the users file has the account
+    pub fn user_account(&self, user_token: &str) -> Option<Arc<UserAccount>> {
+        match self {
+            UserRsaCryptoHolder::RsaDir(_) => None,
+            UserRsaCryptoHolder::UsersFile(holder) => holder.user_account(user_token),
+        }
+    }
+    pub fn reload(&self) -> Result<RsaCryptoReloadReport, ProxyError> {
+        match self {
+            UserRsaCryptoHolder::RsaDir(holder) => Ok(holder.reload()?),
+            UserRsaCryptoHolder::UsersFile(holder) => holder.reload(),
+        }
+    }
+}
+impl RsaCryptoHolder for UserRsaCryptoHolder {
+    fn get_rsa_crypto(
+        &self,
+        auth_token: impl AsRef<str>,
+    ) -> Result<Option<Arc<RsaCrypto>>, CodecError> {
+        match self {
+            UserRsaCryptoHolder::RsaDir(holder) => holder.get_rsa_crypto(auth_token),
+            UserRsaCryptoHolder::UsersFile(holder) => holder.get_rsa_crypto(auth_token),
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{copy, create_dir_all, remove_dir_all, write};
+    #[test]
+    fn keep_user_with_broken_key_on_reload() {
+        let resource_user_dir_path =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("../../resources/proxy/rsa/user1");
+        let rsa_dir_path =
+            std::env::temp_dir().join(format!("ppaass-rsa-dir-test-{}", std::process::id()));
+        let user_dir_path = rsa_dir_path.join("user1");
+        create_dir_all(&user_dir_path).unwrap();
+        for key_file_name in ["AgentPublicKey.pem", "ProxyPrivateKey.pem"] {
+            copy(
+                resource_user_dir_path.join(key_file_name),
+                user_dir_path.join(key_file_name),
+            )
+            .unwrap();
+        }
+        let rsa_crypto_holder = ProxyRsaCryptoHolder::new(
+            &rsa_dir_path,
+            "AgentPublicKey.pem".to_string(),
+            "ProxyPrivateKey.pem".to_string(),
+        )
+        .unwrap();
+        assert_eq!(rsa_crypto_holder.users(), vec!["user1".to_string()]);
+        // The half written key file keeps the user
+        write(
+            user_dir_path.join("ProxyPrivateKey.pem"),
+            "-----BEGIN PRIVATE",
+        )
+        .unwrap();
+        let reload_report = rsa_crypto_holder.reload().unwrap();
+        assert!(reload_report.removed_users.is_empty());
+        assert!(rsa_crypto_holder.get_rsa_crypto("user1").unwrap().is_some());
+        // The new user with broken key is not added
+        create_dir_all(rsa_dir_path.join("user2")).unwrap();
+        let reload_report = rsa_crypto_holder.reload().unwrap();
+        assert!(reload_report.added_users.is_empty());
+        // The user is revoked after the directory removed
+        remove_dir_all(&user_dir_path).unwrap();
+        let reload_report = rsa_crypto_holder.reload().unwrap();
+        assert_eq!(reload_report.removed_users, vec!["user1".to_string()]);
+        assert!(rsa_crypto_holder.get_rsa_crypto("user1").unwrap().is_none());
+        remove_dir_all(&rsa_dir_path).unwrap();
+    }
+}
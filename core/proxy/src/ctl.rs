@@ -0,0 +1,106 @@
+use crate::admin::{AdminRequest, AdminResponse};
+use crate::command::{CtlAction, CtlArgs};
+use crate::config::Config;
+use crate::error::ProxyError;
+use chrono::{SecondsFormat, Utc};
+use ppaass_common::admin::{send_admin_request, AdminAddress};
+use std::path::Path;
+/// Send the action to the admin endpoint of the running proxy and print the response
+pub async fn run_ctl(ctl_args: CtlArgs, config_file_path: &Path) -> Result<(), ProxyError> {
+    let admin_address = match ctl_args.admin {
+        Some(admin_address) => admin_address,
+        None => Config::load(config_file_path)?
+            .admin_listen_address()
+            .clone()
+            .ok_or(ProxyError::InvalidConfig(
+                "admin_listen_address not given".to_string(),
+            ))?,
+    };
+    let admin_address = admin_address.parse::<AdminAddress>()?;
+    let request = match ctl_args.action {
+        CtlAction::Tunnels => AdminRequest::ListTunnels,
+        CtlAction::Kill { tunnel_id } => AdminRequest::KillTunnel { tunnel_id },
+        CtlAction::KillUser { user } => AdminRequest::KillUserTunnels { user },
+        CtlAction::Users => AdminRequest::ListUsers,
+        CtlAction::ReloadKeys => AdminRequest::ReloadKeys,
+        CtlAction::LogLevel { level } => AdminRequest::SetLogLevel { level },
+        CtlAction::Bans => AdminRequest::ListBans,
+        CtlAction::Unban { ip } => AdminRequest::Unban { ip },
+    };
+    let response: AdminResponse = send_admin_request(&admin_address, &request).await?;
+    print_response(response);
+    Ok(())
+}
+fn print_response(response: AdminResponse) {
+    match response {
+        AdminResponse::Tunnels { tunnels } => {
+            println!(
+                "{:<36} {:<36} {:<16} {:<22} {:<32} {:>12} {:>12} {:>8}",
+                "TUNNEL",
+                "AGENT TUNNEL",
+                "USER",
+                "AGENT",
+                "DESTINATION",
+                "UPLOAD",
+                "DOWNLOAD",
+                "AGE(S)"
+            );
+            let now = Utc::now();
+            tunnels.iter().for_each(|tunnel| {
+                println!(
+                    "{:<36} {:<36} {:<16} {:<22} {:<32} {:>12} {:>12} {:>8}",
+                    tunnel.id,
+                    tunnel.correlation_id.as_deref().unwrap_or("-"),
+                    tunnel.user,
+                    tunnel.client_address.to_string(),
+                    tunnel.destination_address.to_string(),
+                    tunnel.upload_bytes,
+                    tunnel.download_bytes,
+                    (now - tunnel.start_time).num_seconds()
+                );
+            });
+        }
+        AdminResponse::TunnelsKilled { count } => println!("{count} tunnels killed"),
+        AdminResponse::Users {
+            users,
+            forward_users,
+        } => {
+            println!("Users: {}", users.join(", "));
+            if let Some(forward_users) = forward_users {
+                println!("Forward users: {}", forward_users.join(", "));
+            }
+        }
+        AdminResponse::KeysReloaded {
+            rsa_crypto,
+            forward_rsa_crypto,
+        } => {
+            println!(
+                "Keys reloaded, added users: {:?}, removed users: {:?}",
+                rsa_crypto.added_users, rsa_crypto.removed_users
+            );
+            if let Some(forward_rsa_crypto) = forward_rsa_crypto {
+                println!(
+                    "Forward keys reloaded, added users: {:?}, removed users: {:?}",
+                    forward_rsa_crypto.added_users, forward_rsa_crypto.removed_users
+                );
+            }
+        }
+        AdminResponse::LogLevelChanged { level } => println!("Log level changed to {level}"),
+        AdminResponse::Bans { bans } => {
+            println!(
+                "{:<40} {:>8} {:<26} {:<26}",
+                "IP", "FAILURES", "BANNED AT", "EXPIRES AT"
+            );
+            bans.iter().for_each(|ban| {
+                println!(
+                    "{:<40} {:>8} {:<26} {:<26}",
+                    ban.ip.to_string(),
+                    ban.failures,
+                    ban.banned_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+                    ban.expires_at.to_rfc3339_opts(SecondsFormat::Secs, true)
+                );
+            });
+        }
+        AdminResponse::Unbanned { ip } => println!("{ip} unbanned"),
+    }
+}
@@ -41,6 +41,9 @@ impl Decoder for DestinationDataTcpCodec {
                     None => Ok(None),
                     Some(ProxyDataPacket::Tcp(data)) => Ok(Some(BytesMut::from_iter(data))),
                     Some(ProxyDataPacket::Udp { .. }) => Err(ProxyError::InvalidData),
+                    // The forward proxy closed the write side, an empty data
+                    // is decoded because raw destination never decodes empty data
+                    Some(ProxyDataPacket::TcpEof) => Ok(Some(BytesMut::new())),
                 }
             }
         }
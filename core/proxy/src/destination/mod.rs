@@ -1,6 +1,39 @@
+use crate::bo::state::ServerState;
+use crate::error::ProxyError;
+use ppaass_domain::address::UnifiedAddress;
+use ppaass_domain::tunnel::TunnelType;
+use std::net::SocketAddr;
+use tokio::net::lookup_host;
 mod codec;
 mod tcp;
 mod udp;
 pub use codec::DestinationDataTcpCodec;
 pub use tcp::new_tcp_destination;
 pub use udp::new_udp_destination;
+/// Resolve the destination before connecting so that the access control policy
+/// checks the exact addresses to connect, the addresses are empty when the
+/// tcp destination is resolved by the forward proxy.
+pub async fn resolve_destination(
+    dst_address: &UnifiedAddress,
+    tunnel_type: &TunnelType,
+    server_state: &ServerState,
+) -> Result<Vec<SocketAddr>, ProxyError> {
+    if matches!(tunnel_type, TunnelType::Tcp { .. })
+        && server_state.config().forward_server_addresses().is_some()
+    {
+        return Ok(Vec::new());
+    }
+    let resolved_dst_addresses = match dst_address {
+        UnifiedAddress::Ip(dst_address) => vec![*dst_address],
+        UnifiedAddress::Domain { host, port } => {
+            lookup_host((host.as_str(), *port)).await?.collect()
+        }
+    };
+    if resolved_dst_addresses.is_empty() {
+        return Err(ProxyError::Io(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("no address resolved for destination: {dst_address}"),
+        )));
+    }
+    Ok(resolved_dst_addresses)
+}
@@ -16,13 +16,15 @@ use tokio::time::timeout;
 use tokio_util::codec::{Framed, FramedParts};
 use tracing::error;
 pub async fn new_tcp_destination(
+    tunnel_id: &str,
     dst_address: &UnifiedAddress,
+    resolved_dst_addresses: &[SocketAddr],
     keepalive: bool,
     server_state: ServerState,
 ) -> Result<Framed<TcpStream, DestinationDataTcpCodec>, ProxyError> {
     let dst_socket_addresses: Vec<SocketAddr> =
         match server_state.config().forward_server_addresses() {
-            None => dst_address.try_into()?,
+            None => resolved_dst_addresses.to_vec(),
             Some(forward_addresses) => forward_addresses
                 .iter()
                 .map_while(|addr| {
@@ -79,12 +81,6 @@ pub async fn new_tcp_destination(
         dest_socket.set_tcp_keepalive(&keepalive)?;
     }
     dest_socket.set_nodelay(true)?;
-    if let Some(read_timeout) = server_state.config().dst_read_timeout() {
-        dest_socket.set_read_timeout(Some(Duration::from_secs(*read_timeout)))?;
-    }
-    if let Some(write_timeout) = server_state.config().dst_write_timeout() {
-        dest_socket.set_write_timeout(Some(Duration::from_secs(*write_timeout)))?;
-    }
     dest_socket.set_linger(None)?;
     let destination_framed = match server_state.config().forward_server_addresses() {
         None => Framed::with_capacity(
@@ -112,26 +108,34 @@ pub async fn new_tcp_destination(
             );
             let agent_encryption = Encryption::Aes(random_32_bytes());
             let tunnel_init = AgentControlPacket::TunnelInit(TunnelInitRequest {
+                tunnel_id: tunnel_id.to_owned(),
                 agent_encryption: agent_encryption.clone(),
                 auth_token: forward_auth_token,
                 dst_address: dst_address.clone(),
                 tunnel_type: TunnelType::Tcp { keepalive },
             });
-            tunnel_init_framed.send(tunnel_init).await?;
-            let TunnelInitResponse { proxy_encryption } = match tunnel_init_framed.next().await {
-                None => {
-                    return Err(ProxyError::ForwardProxyTcpConnectionExhausted);
-                }
-                Some(Ok(response)) => match response {
-                    ProxyControlPacket::TunnelInit((_, tunnel_init_response)) => {
-                        tunnel_init_response
-                    }
-                    ProxyControlPacket::Heartbeat(_) => return Err(ProxyError::InvalidData),
-                },
-                Some(Err(e)) => {
-                    return Err(e);
+            // The forward proxy handshake is a part of connecting destination
+            let forward_tunnel_init = async {
+                tunnel_init_framed.send(tunnel_init).await?;
+                match tunnel_init_framed.next().await {
+                    None => Err(ProxyError::ForwardProxyTcpConnectionExhausted),
+                    Some(Ok(response)) => match response {
+                        ProxyControlPacket::TunnelInit((_, tunnel_init_response)) => {
+                            Ok(tunnel_init_response)
+                        }
+                        ProxyControlPacket::Heartbeat(_) => Err(ProxyError::InvalidData),
+                        ProxyControlPacket::TunnelInitFail(tunnel_init_failure) => {
+                            Err(ProxyError::ForwardTunnelInitRejected(tunnel_init_failure))
+                        }
+                    },
+                    Some(Err(e)) => Err(e),
                 }
             };
+            let TunnelInitResponse { proxy_encryption } = timeout(
+                Duration::from_secs(*server_state.config().dst_connect_timeout()),
+                forward_tunnel_init,
+            )
+            .await??;
             let FramedParts {
                 io: dst_tcp_stream, ..
             } = tunnel_init_framed.into_parts();
@@ -1,7 +1,10 @@
 use crate::bo::state::ServerStateBuilderError;
 use ppaass_codec::error::CodecError;
+use ppaass_common::error::CommonError;
 use ppaass_crypto::error::CryptoError;
 use ppaass_domain::error::DomainError;
+use ppaass_domain::tunnel::TunnelInitFailure;
+use std::net::AddrParseError;
 use thiserror::Error;
 #[derive(Debug, Error)]
 pub enum ProxyError {
@@ -21,13 +24,56 @@ pub enum ProxyError {
     FromHex(#[from] CodecError),
     #[error(transparent)]
     DstConnectTimeout(#[from] tokio::time::error::Elapsed),
+    #[error("Agent first byte timeout")]
+    AgentFirstByteTimeout,
+    #[error("Agent init frame timeout")]
+    AgentInitFrameTimeout,
+    #[error("Agent init response timeout")]
+    AgentInitResponseTimeout,
     #[error("Invalid data type")]
     InvalidData,
     #[error("Forward proxy tcp connection exhausted")]
     ForwardProxyTcpConnectionExhausted,
+    #[error("Relay idle timeout")]
+    RelayIdleTimeout,
+    #[error("Invalid configuration: {0}")]
+    InvalidConfig(String),
+    #[error(transparent)]
+    Common(#[from] CommonError),
+    #[error(transparent)]
+    Metrics(#[from] prometheus::Error),
+    #[error(transparent)]
+    AddrParse(#[from] AddrParseError),
+    #[error("Invalid acl policy: {0}")]
+    InvalidAclPolicy(String),
+    #[error("Invalid users file: {0}")]
+    InvalidUsersFile(String),
+    #[error("Access denied: {0}")]
+    AccessDenied(String),
+    #[error("Quota exceeded: {0}")]
+    QuotaExceeded(String),
+    #[error("Server busy: {0}")]
+    ServerBusy(String),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Tls(#[from] rustls::Error),
+    #[error("Tunnel init rejected by forward proxy: {0}")]
+    ForwardTunnelInitRejected(TunnelInitFailure),
+}
+impl ProxyError {
+    /// The errors caused by the authentication or the protocol, the io errors
+    /// and the timeouts are not counted, they happen on the slow links as well
+    pub fn is_protocol_failure(&self) -> bool {
+        match self {
+            ProxyError::FromHex(e) => !matches!(e.kind(), "io" | "encryption_holder_lock"),
+            ProxyError::Crypto(_) | ProxyError::Domain(_) | ProxyError::InvalidData => true,
+            _ => false,
+        }
+    }
 }
 impl From<ProxyError> for std::io::Error {
     fn from(value: ProxyError) -> Self {
-        std::io::Error::new(std::io::ErrorKind::Other, value)
+        std::io::Error::other(value)
     }
 }
@@ -2,5 +2,6 @@ mod relay;
 mod tunnel;
 pub use relay::start_relay;
 pub use relay::RelayStartRequest;
+pub(crate) use tunnel::send_agent_control_packet;
 pub use tunnel::tunnel_init;
 pub use tunnel::TunnelInitResult;
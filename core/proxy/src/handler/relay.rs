@@ -1,83 +1,417 @@
+use crate::admission::{ConnectionPermit, TunnelPermit};
 use crate::bo::state::ServerState;
 use crate::codec::DataPacketCodec;
 use crate::destination::DestinationDataTcpCodec;
 use crate::error::ProxyError;
+use crate::transport::AgentStream;
 use bytes::BytesMut;
-use futures_util::StreamExt;
+use futures_util::{SinkExt, StreamExt};
+use ppaass_common::quic::DatagramFramed;
+use ppaass_common::tunnel_timeout::{read_with_idle_timeout, wait_tunnel_timeout};
 use ppaass_domain::address::UnifiedAddress;
 use ppaass_domain::tunnel::Encryption;
-use ppaass_domain::{AgentDataPacket, ProxyDataPacket};
+use ppaass_domain::{generate_uuid, AgentDataPacket, ProxyDataPacket};
+use std::future::Future;
+use std::sync::Arc;
 use tokio::net::{TcpStream, UdpSocket};
-use tokio_stream::StreamExt as TokioStreamExt;
+use tokio::task::JoinHandle;
 use tokio_util::codec::Framed;
-use tracing::error;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info};
+#[allow(clippy::large_enum_variant)]
 pub enum RelayStartRequest {
     Tcp {
         agent_encryption: Encryption,
         proxy_encryption: Encryption,
         destination_tcp_framed: Framed<TcpStream, DestinationDataTcpCodec>,
         destination_address: UnifiedAddress,
+        auth_token: String,
+        tunnel_id: String,
+        tunnel_permit: TunnelPermit,
     },
-    #[allow(unused)]
     Udp {
         agent_encryption: Encryption,
         proxy_encryption: Encryption,
         destination_udp_socket: UdpSocket,
         destination_address: UnifiedAddress,
+        auth_token: String,
+        tunnel_id: String,
+        tunnel_permit: TunnelPermit,
     },
 }
-async fn tcp_relay(
-    agent_tcp_stream: TcpStream,
-    server_state: ServerState,
+struct TcpRelayRequest {
+    agent_stream: AgentStream,
     agent_encryption: Encryption,
     proxy_encryption: Encryption,
     destination_tcp_framed: Framed<TcpStream, DestinationDataTcpCodec>,
     destination_address: UnifiedAddress,
+    auth_token: String,
+    tunnel_id: String,
+    connection_permit: ConnectionPermit,
+    tunnel_permit: TunnelPermit,
+}
+async fn tcp_relay(
+    tcp_relay_request: TcpRelayRequest,
+    server_state: ServerState,
 ) -> Result<(), ProxyError> {
+    let TcpRelayRequest {
+        agent_stream,
+        agent_encryption,
+        proxy_encryption,
+        destination_tcp_framed,
+        destination_address,
+        auth_token,
+        tunnel_id,
+        connection_permit,
+        tunnel_permit,
+    } = tcp_relay_request;
+    let shutdown_coordinator = server_state.shutdown_coordinator();
+    let tunnel_registration = server_state.tunnel_registry().register(
+        generate_uuid(),
+        Some(tunnel_id),
+        auth_token.clone(),
+        agent_stream.peer_addr()?,
+        destination_address.clone(),
+        shutdown_coordinator.tunnel_token(),
+    );
+    debug!(
+        tunnel_id = { tunnel_registration.entry().id() },
+        agent_tunnel_id = { tunnel_registration.entry().correlation_id() },
+        "Tunnel registered."
+    );
     let agent_data_framed = Framed::with_capacity(
-        agent_tcp_stream,
+        agent_stream,
         DataPacketCodec::new(agent_encryption, proxy_encryption),
         *server_state.config().agent_buffer_size(),
     );
-    let (destination_tcp_framed_tx, destination_tcp_framed_rx) = destination_tcp_framed.split();
-    let (agent_data_framed_tx, agent_data_framed_rx) = agent_data_framed.split();
-    let destination_address_clone = destination_address.clone();
-    let agent_data_framed_rx = agent_data_framed_rx.map_while(move |agent_data_packet| {
-        let agent_data_packet = match agent_data_packet {
-            Ok(agent_data_packet) => agent_data_packet,
-            Err(e) => {
-                error!(
-                    destination_address = { format!("{destination_address_clone}") },
-                    "Failed to read agent data: {}", e
-                );
-                return Some(Err(e));
+    let (mut destination_tcp_framed_tx, mut destination_tcp_framed_rx) =
+        destination_tcp_framed.split();
+    let (mut agent_data_framed_tx, mut agent_data_framed_rx) = agent_data_framed.split();
+    let user_traffic_counter = server_state.usage_store().user_counter(&auth_token);
+    let tunnel_rate_limit = Arc::new(server_state.tunnel_rate_limit(&auth_token));
+    let tunnel_timeout_config = *server_state.config().tcp_tunnel_timeout();
+    let direction_idle_timeout = tunnel_timeout_config.direction_idle_timeout();
+    let agent_to_destination = {
+        let tunnel_entry = tunnel_registration.entry().clone();
+        let metrics = server_state.metrics().clone();
+        let tunnel_rate_limit = tunnel_rate_limit.clone();
+        let user_traffic_counter = user_traffic_counter.clone();
+        async move {
+            while let Some(agent_data_packet) = read_with_idle_timeout(
+                &tunnel_entry,
+                direction_idle_timeout,
+                agent_data_framed_rx.next(),
+            )
+            .await
+            .map_err(|_| ProxyError::RelayIdleTimeout)?
+            {
+                let agent_data = match agent_data_packet? {
+                    AgentDataPacket::Tcp(data) => data,
+                    AgentDataPacket::Udp { .. } => return Err(ProxyError::InvalidData),
+                    AgentDataPacket::TcpEof => {
+                        debug!(
+                            tunnel_id = { tunnel_entry.id() },
+                            "Receive end of stream from agent."
+                        );
+                        break;
+                    }
+                };
+                // Wait for the tokens before reading more, the agent is throttled by tcp backpressure
+                tunnel_rate_limit.acquire_upload(agent_data.len()).await;
+                tunnel_entry.add_upload_bytes(agent_data.len());
+                user_traffic_counter.add_upload_bytes(agent_data.len());
+                metrics.add_upload_bytes(tunnel_entry.user(), agent_data.len());
+                destination_tcp_framed_tx
+                    .send(BytesMut::from_iter(agent_data))
+                    .await?;
+            }
+            // Shutdown the write side of the destination connection
+            destination_tcp_framed_tx.close().await?;
+            Ok::<(), ProxyError>(())
+        }
+    };
+    let destination_to_agent = {
+        let tunnel_entry = tunnel_registration.entry().clone();
+        let metrics = server_state.metrics().clone();
+        let tunnel_rate_limit = tunnel_rate_limit.clone();
+        let user_traffic_counter = user_traffic_counter.clone();
+        async move {
+            while let Some(destination_data) = read_with_idle_timeout(
+                &tunnel_entry,
+                direction_idle_timeout,
+                destination_tcp_framed_rx.next(),
+            )
+            .await
+            .map_err(|_| ProxyError::RelayIdleTimeout)?
+            {
+                let destination_data = destination_data?;
+                if destination_data.is_empty() {
+                    // The forward proxy closed the write side
+                    break;
+                }
+                tunnel_rate_limit
+                    .acquire_download(destination_data.len())
+                    .await;
+                tunnel_entry.add_download_bytes(destination_data.len());
+                user_traffic_counter.add_download_bytes(destination_data.len());
+                metrics.add_download_bytes(tunnel_entry.user(), destination_data.len());
+                agent_data_framed_tx
+                    .send(ProxyDataPacket::Tcp(destination_data.to_vec()))
+                    .await?;
             }
+            // Destination closed the write side, tell the agent to half-close the client
+            debug!(
+                tunnel_id = { tunnel_entry.id() },
+                "Destination data exhausted, send end of stream to agent."
+            );
+            agent_data_framed_tx.send(ProxyDataPacket::TcpEof).await?;
+            Ok::<(), ProxyError>(())
+        }
+    };
+    let tunnel_token = tunnel_registration.entry().cancellation_token().clone();
+    let agent_to_destination = spawn_relay_direction(
+        &server_state,
+        tunnel_token.clone(),
+        destination_address.clone(),
+        agent_to_destination,
+    );
+    let destination_to_agent = spawn_relay_direction(
+        &server_state,
+        tunnel_token.clone(),
+        destination_address,
+        destination_to_agent,
+    );
+    let metrics = server_state.metrics().clone();
+    metrics.inc_active_tunnels(&auth_token);
+    shutdown_coordinator.spawn_tunnel_task(async move {
+        // The agent connection and the tunnel are counted until the relay finished
+        let _connection_permit = connection_permit;
+        let _tunnel_permit = tunnel_permit;
+        let relay_finished = async {
+            let _ = agent_to_destination.await;
+            let _ = destination_to_agent.await;
         };
-        match agent_data_packet {
-            AgentDataPacket::Tcp(data) => Some(Ok(BytesMut::from_iter(data))),
-            AgentDataPacket::Udp { payload, .. } => Some(Ok(BytesMut::from_iter(payload))),
+        tokio::pin!(relay_finished);
+        tokio::select! {
+            _ = &mut relay_finished => {}
+            tunnel_timeout = wait_tunnel_timeout(tunnel_registration.entry(), tunnel_timeout_config) => {
+                info!(
+                    tunnel_id = { tunnel_registration.entry().id() },
+                    "Close tunnel because of timeout: {tunnel_timeout:?}"
+                );
+                tunnel_token.cancel();
+                relay_finished.await;
+            }
         }
+        debug!(
+            tunnel_id = { tunnel_registration.entry().id() },
+            "Tunnel closed, upload bytes: {}, download bytes: {}",
+            tunnel_registration.entry().upload_bytes(),
+            tunnel_registration.entry().download_bytes()
+        );
+        metrics.dec_active_tunnels(&auth_token);
     });
-    let destination_address_clone = destination_address.clone();
-    let destination_tcp_framed_rx = destination_tcp_framed_rx.map_while(move |destination_item| {
-        let destination_data = match destination_item {
-            Ok(destination_data) => destination_data,
-            Err(e) => {
-                error!(
-                    destination_address = { format!("{destination_address_clone}") },
-                    "Failed to read destination data: {e:?}"
-                );
-                return Some(Err(e));
+    Ok(())
+}
+/// The max size of the udp datagram received from destination
+const UDP_DATAGRAM_MAX_SIZE: usize = 65535;
+struct UdpRelayRequest {
+    agent_stream: AgentStream,
+    agent_encryption: Encryption,
+    proxy_encryption: Encryption,
+    destination_udp_socket: UdpSocket,
+    destination_address: UnifiedAddress,
+    auth_token: String,
+    tunnel_id: String,
+    connection_permit: ConnectionPermit,
+    tunnel_permit: TunnelPermit,
+}
+async fn udp_relay(
+    udp_relay_request: UdpRelayRequest,
+    server_state: ServerState,
+) -> Result<(), ProxyError> {
+    let UdpRelayRequest {
+        mut agent_stream,
+        agent_encryption,
+        proxy_encryption,
+        destination_udp_socket,
+        destination_address,
+        auth_token,
+        tunnel_id,
+        connection_permit,
+        tunnel_permit,
+    } = udp_relay_request;
+    let shutdown_coordinator = server_state.shutdown_coordinator();
+    let tunnel_registration = server_state.tunnel_registry().register(
+        generate_uuid(),
+        Some(tunnel_id),
+        auth_token.clone(),
+        agent_stream.peer_addr()?,
+        destination_address.clone(),
+        shutdown_coordinator.tunnel_token(),
+    );
+    debug!(
+        tunnel_id = { tunnel_registration.entry().id() },
+        agent_tunnel_id = { tunnel_registration.entry().correlation_id() },
+        "Tunnel registered."
+    );
+    let agent_quic_datagrams = agent_stream.take_quic_datagrams();
+    let agent_data_framed = Framed::with_capacity(
+        agent_stream,
+        DataPacketCodec::new(agent_encryption.clone(), proxy_encryption.clone()),
+        *server_state.config().agent_buffer_size(),
+    );
+    let agent_data_framed = match agent_quic_datagrams {
+        None => DatagramFramed::new(agent_data_framed),
+        Some(agent_quic_datagrams) => DatagramFramed::with_datagrams(
+            agent_data_framed,
+            agent_quic_datagrams,
+            DataPacketCodec::new(agent_encryption, proxy_encryption),
+        ),
+    };
+    let (mut agent_data_framed_tx, mut agent_data_framed_rx) = agent_data_framed.split();
+    let destination_udp_socket = Arc::new(destination_udp_socket);
+    let tunnel_token = tunnel_registration.entry().cancellation_token().clone();
+    let user_traffic_counter = server_state.usage_store().user_counter(&auth_token);
+    let tunnel_rate_limit = Arc::new(server_state.tunnel_rate_limit(&auth_token));
+    let tunnel_timeout_config = *server_state.config().udp_tunnel_timeout();
+    let direction_idle_timeout = tunnel_timeout_config.direction_idle_timeout();
+    let agent_to_destination = {
+        let tunnel_entry = tunnel_registration.entry().clone();
+        let metrics = server_state.metrics().clone();
+        let tunnel_rate_limit = tunnel_rate_limit.clone();
+        let user_traffic_counter = user_traffic_counter.clone();
+        let destination_udp_socket = destination_udp_socket.clone();
+        let tunnel_token = tunnel_token.clone();
+        async move {
+            while let Some(agent_data_packet) = read_with_idle_timeout(
+                &tunnel_entry,
+                direction_idle_timeout,
+                agent_data_framed_rx.next(),
+            )
+            .await
+            .map_err(|_| ProxyError::RelayIdleTimeout)?
+            {
+                let payload = match agent_data_packet? {
+                    AgentDataPacket::Udp { payload, .. } => payload,
+                    AgentDataPacket::TcpEof => break,
+                    AgentDataPacket::Tcp(_) => return Err(ProxyError::InvalidData),
+                };
+                tunnel_rate_limit.acquire_upload(payload.len()).await;
+                tunnel_entry.add_upload_bytes(payload.len());
+                user_traffic_counter.add_upload_bytes(payload.len());
+                metrics.add_upload_bytes(tunnel_entry.user(), payload.len());
+                destination_udp_socket.send(&payload).await?;
+            }
+            // Udp has no half-close, the tunnel is done when the agent stops sending
+            debug!(
+                tunnel_id = { tunnel_entry.id() },
+                "Agent udp data exhausted, close the tunnel."
+            );
+            tunnel_token.cancel();
+            Ok::<(), ProxyError>(())
+        }
+    };
+    let destination_to_agent = {
+        let tunnel_entry = tunnel_registration.entry().clone();
+        let metrics = server_state.metrics().clone();
+        let tunnel_rate_limit = tunnel_rate_limit.clone();
+        let user_traffic_counter = user_traffic_counter.clone();
+        let destination_address = destination_address.clone();
+        async move {
+            let mut datagram = vec![0u8; UDP_DATAGRAM_MAX_SIZE];
+            loop {
+                let datagram_size = read_with_idle_timeout(
+                    &tunnel_entry,
+                    direction_idle_timeout,
+                    destination_udp_socket.recv(&mut datagram),
+                )
+                .await
+                .map_err(|_| ProxyError::RelayIdleTimeout)??;
+                tunnel_rate_limit.acquire_download(datagram_size).await;
+                tunnel_entry.add_download_bytes(datagram_size);
+                user_traffic_counter.add_download_bytes(datagram_size);
+                metrics.add_download_bytes(tunnel_entry.user(), datagram_size);
+                agent_data_framed_tx
+                    .send(ProxyDataPacket::Udp {
+                        destination_address: destination_address.clone(),
+                        payload: datagram[..datagram_size].to_vec(),
+                    })
+                    .await?;
             }
+        }
+    };
+    let agent_to_destination = spawn_relay_direction(
+        &server_state,
+        tunnel_token.clone(),
+        destination_address.clone(),
+        agent_to_destination,
+    );
+    let destination_to_agent = spawn_relay_direction(
+        &server_state,
+        tunnel_token.clone(),
+        destination_address,
+        destination_to_agent,
+    );
+    let metrics = server_state.metrics().clone();
+    metrics.inc_active_tunnels(&auth_token);
+    shutdown_coordinator.spawn_tunnel_task(async move {
+        // The agent connection and the tunnel are counted until the relay finished
+        let _connection_permit = connection_permit;
+        let _tunnel_permit = tunnel_permit;
+        let relay_finished = async {
+            let _ = agent_to_destination.await;
+            let _ = destination_to_agent.await;
         };
-        Some(Ok(ProxyDataPacket::Tcp(destination_data.to_vec())))
+        tokio::pin!(relay_finished);
+        tokio::select! {
+            _ = &mut relay_finished => {}
+            tunnel_timeout = wait_tunnel_timeout(tunnel_registration.entry(), tunnel_timeout_config) => {
+                info!(
+                    tunnel_id = { tunnel_registration.entry().id() },
+                    "Close udp tunnel because of timeout: {tunnel_timeout:?}"
+                );
+                tunnel_token.cancel();
+                relay_finished.await;
+            }
+        }
+        debug!(
+            tunnel_id = { tunnel_registration.entry().id() },
+            "Udp tunnel closed, upload bytes: {}, download bytes: {}",
+            tunnel_registration.entry().upload_bytes(),
+            tunnel_registration.entry().download_bytes()
+        );
+        metrics.dec_active_tunnels(&auth_token);
     });
-    tokio::spawn(agent_data_framed_rx.forward(destination_tcp_framed_tx));
-    tokio::spawn(destination_tcp_framed_rx.forward(agent_data_framed_tx));
     Ok(())
 }
+/// Spawn one direction of the relay, the whole tunnel is cancelled when the direction fails
+fn spawn_relay_direction<F>(
+    server_state: &ServerState,
+    tunnel_token: CancellationToken,
+    destination_address: UnifiedAddress,
+    relay_direction: F,
+) -> JoinHandle<Option<()>>
+where
+    F: Future<Output = Result<(), ProxyError>> + Send + 'static,
+{
+    let metrics = server_state.metrics().clone();
+    server_state
+        .shutdown_coordinator()
+        .spawn_tunnel_task(async move {
+            if let Some(Err(e)) = tunnel_token.run_until_cancelled(relay_direction).await {
+                metrics.record_error(&e);
+                error!(
+                    destination_address = { format!("{destination_address}") },
+                    "Fail to relay, close the tunnel: {e:?}"
+                );
+                tunnel_token.cancel();
+            }
+        })
+}
 pub async fn start_relay(
-    agent_tcp_stream: TcpStream,
+    agent_stream: AgentStream,
+    connection_permit: ConnectionPermit,
     relay_start_request: RelayStartRequest,
     server_state: ServerState,
 ) -> Result<(), ProxyError> {
@@ -87,19 +421,50 @@ pub async fn start_relay(
             proxy_encryption,
             destination_tcp_framed,
             destination_address,
+            auth_token,
+            tunnel_id,
+            tunnel_permit,
         } => {
             tcp_relay(
-                agent_tcp_stream,
+                TcpRelayRequest {
+                    agent_stream,
+                    agent_encryption,
+                    proxy_encryption,
+                    destination_tcp_framed,
+                    destination_address,
+                    auth_token,
+                    tunnel_id,
+                    connection_permit,
+                    tunnel_permit,
+                },
                 server_state,
-                agent_encryption,
-                proxy_encryption,
-                destination_tcp_framed,
-                destination_address,
             )
             .await
         }
-        RelayStartRequest::Udp { .. } => {
-            unimplemented!("Udp relay is not implemented yet")
+        RelayStartRequest::Udp {
+            agent_encryption,
+            proxy_encryption,
+            destination_udp_socket,
+            destination_address,
+            auth_token,
+            tunnel_id,
+            tunnel_permit,
+        } => {
+            udp_relay(
+                UdpRelayRequest {
+                    agent_stream,
+                    agent_encryption,
+                    proxy_encryption,
+                    destination_udp_socket,
+                    destination_address,
+                    auth_token,
+                    tunnel_id,
+                    connection_permit,
+                    tunnel_permit,
+                },
+                server_state,
+            )
+            .await
         }
     }
 }
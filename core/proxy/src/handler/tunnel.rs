@@ -1,46 +1,201 @@
+use crate::admission::{TunnelAdmissionRejection, TunnelPermit};
 use crate::bo::state::ServerState;
 use crate::codec::ControlPacketCodec;
-use crate::destination::{new_tcp_destination, new_udp_destination, DestinationDataTcpCodec};
+use crate::config::Config;
+use crate::destination::{
+    new_tcp_destination, new_udp_destination, resolve_destination, DestinationDataTcpCodec,
+};
 use crate::error::ProxyError;
+use crate::metrics::{RESULT_FAIL, RESULT_SUCCESS};
+use crate::transport::AgentStream;
 use futures_util::SinkExt;
 use ppaass_crypto::random_32_bytes;
 use ppaass_domain::address::UnifiedAddress;
-use ppaass_domain::tunnel::{Encryption, TunnelInitRequest, TunnelInitResponse, TunnelType};
+use ppaass_domain::tunnel::{
+    Encryption, TunnelInitFailure, TunnelInitRequest, TunnelInitResponse, TunnelType,
+};
 use ppaass_domain::ProxyControlPacket;
+use std::time::Duration;
 use tokio::net::{TcpStream, UdpSocket};
+use tokio::time::{timeout, Instant};
 use tokio_util::codec::{Framed, FramedParts};
+use tracing::{debug, warn};
+#[allow(clippy::large_enum_variant)]
 pub enum TunnelInitResult {
     Tcp {
         agent_encryption: Encryption,
         proxy_encryption: Encryption,
         destination_tcp_framed: Framed<TcpStream, DestinationDataTcpCodec>,
-        agent_tcp_stream: TcpStream,
+        agent_stream: AgentStream,
         destination_address: UnifiedAddress,
+        auth_token: String,
+        tunnel_id: String,
+        tunnel_permit: TunnelPermit,
     },
     Udp {
         agent_encryption: Encryption,
         proxy_encryption: Encryption,
         destination_udp_socket: UdpSocket,
-        agent_tcp_stream: TcpStream,
+        agent_stream: AgentStream,
         destination_address: UnifiedAddress,
+        auth_token: String,
+        tunnel_id: String,
+        tunnel_permit: TunnelPermit,
     },
 }
 /// Create tunnel in proxy side
 pub async fn tunnel_init(
-    mut agent_control_framed: Framed<TcpStream, ControlPacketCodec>,
+    agent_control_framed: Framed<AgentStream, ControlPacketCodec>,
     tunnel_init_request: TunnelInitRequest,
     server_state: ServerState,
+) -> Result<TunnelInitResult, ProxyError> {
+    let tunnel_init_start = Instant::now();
+    let auth_token = tunnel_init_request.auth_token.clone();
+    let protocol = match tunnel_init_request.tunnel_type {
+        TunnelType::Tcp { .. } => "tcp",
+        TunnelType::Udp => "udp",
+    };
+    let tunnel_init_result =
+        concrete_tunnel_init(agent_control_framed, tunnel_init_request, &server_state).await;
+    let tunnel_init_result_label = if tunnel_init_result.is_ok() {
+        RESULT_SUCCESS
+    } else {
+        RESULT_FAIL
+    };
+    server_state.metrics().record_tunnel_init(
+        &auth_token,
+        protocol,
+        tunnel_init_result_label,
+        tunnel_init_start.elapsed(),
+    );
+    tunnel_init_result
+}
+async fn concrete_tunnel_init(
+    mut agent_control_framed: Framed<AgentStream, ControlPacketCodec>,
+    tunnel_init_request: TunnelInitRequest,
+    server_state: &ServerState,
 ) -> Result<TunnelInitResult, ProxyError> {
     let TunnelInitRequest {
+        tunnel_id,
         agent_encryption,
         auth_token,
         dst_address,
         tunnel_type,
     } = tunnel_init_request;
+    debug!(
+        tunnel_id = { tunnel_id.as_str() },
+        "Init tunnel for user [{auth_token}] to destination: {dst_address}"
+    );
+    let config = server_state.config();
+    let user_account = server_state.rsa_crypto_holder().user_account(&auth_token);
+    if let Some(user_account) = &user_account {
+        if !user_account.allow_tunnel_type(&tunnel_type) {
+            let protocol = match tunnel_type {
+                TunnelType::Tcp { .. } => "tcp",
+                TunnelType::Udp => "udp",
+            };
+            let reason = format!("{protocol} tunnel is not allowed");
+            warn!(
+                tunnel_id = { tunnel_id.as_str() },
+                "Deny tunnel for user [{auth_token}]: {reason}"
+            );
+            return Err(reject_tunnel_init(
+                &mut agent_control_framed,
+                TunnelInitFailure::AccessDenied(reason),
+                &config,
+            )
+            .await);
+        }
+    }
+    let max_user_tunnels = user_account
+        .as_ref()
+        .and_then(|user_account| user_account.max_tunnels())
+        .or(config.admission().max_user_tunnels);
+    // Reserve the tunnel before resolving and connecting the destination, so the
+    // concurrent tunnel inits can not exceed the limits together
+    let tunnel_permit = match server_state.admission_controller().admit_tunnel(
+        &auth_token,
+        config.admission().max_tunnels,
+        max_user_tunnels,
+    ) {
+        Ok(tunnel_permit) => tunnel_permit,
+        Err(tunnel_admission_rejection) => {
+            let tunnel_init_failure = match tunnel_admission_rejection {
+                TunnelAdmissionRejection::MaxTunnels(max_tunnels) => {
+                    TunnelInitFailure::ServerBusy(format!("max {max_tunnels} tunnels reached"))
+                }
+                TunnelAdmissionRejection::MaxUserTunnels(max_user_tunnels) => {
+                    TunnelInitFailure::QuotaExceeded(format!(
+                        "max {max_user_tunnels} tunnels of user reached"
+                    ))
+                }
+            };
+            warn!(
+                tunnel_id = { tunnel_id.as_str() },
+                "Refuse tunnel for user [{auth_token}]: {tunnel_init_failure}"
+            );
+            return Err(reject_tunnel_init(
+                &mut agent_control_framed,
+                tunnel_init_failure,
+                &config,
+            )
+            .await);
+        }
+    };
+    let resolved_dst_addresses = timeout(
+        Duration::from_secs(*config.dst_connect_timeout()),
+        resolve_destination(&dst_address, &tunnel_type, server_state),
+    )
+    .await??;
+    if let Err(reason) = server_state.acl_policy().check(
+        &auth_token,
+        user_account
+            .as_ref()
+            .and_then(|user_account| user_account.acl()),
+        &dst_address,
+        &resolved_dst_addresses,
+        &tunnel_type,
+    ) {
+        warn!(
+            tunnel_id = { tunnel_id.as_str() },
+            "Deny tunnel for user [{auth_token}]: {reason}"
+        );
+        return Err(reject_tunnel_init(
+            &mut agent_control_framed,
+            TunnelInitFailure::AccessDenied(reason),
+            &config,
+        )
+        .await);
+    }
+    if let Err(reason) = server_state
+        .usage_store()
+        .check_quota(&auth_token, &server_state.user_quota_limit(&auth_token))
+    {
+        warn!(
+            tunnel_id = { tunnel_id.as_str() },
+            "Refuse tunnel for user [{auth_token}]: {reason}"
+        );
+        return Err(reject_tunnel_init(
+            &mut agent_control_framed,
+            TunnelInitFailure::QuotaExceeded(reason),
+            &config,
+        )
+        .await);
+    }
     match &tunnel_type {
         TunnelType::Tcp { keepalive } => {
-            let destination_tcp_framed =
-                new_tcp_destination(&dst_address, *keepalive, server_state.clone()).await?;
+            let destination_connect_start = Instant::now();
+            let destination_tcp_framed = new_tcp_destination(
+                &tunnel_id,
+                &dst_address,
+                &resolved_dst_addresses,
+                *keepalive,
+                server_state.clone(),
+            )
+            .await?;
+            server_state
+                .metrics()
+                .observe_destination_connect(&auth_token, destination_connect_start.elapsed());
             let proxy_encryption = Encryption::Aes(random_32_bytes());
 
             let tunnel_init_response = TunnelInitResponse {
@@ -48,40 +203,84 @@ pub async fn tunnel_init(
             };
             let proxy_control_packet =
                 ProxyControlPacket::TunnelInit((auth_token.clone(), tunnel_init_response));
-            agent_control_framed.send(proxy_control_packet).await?;
+            send_agent_control_packet(&mut agent_control_framed, proxy_control_packet, &config)
+                .await?;
             let FramedParts {
-                io: agent_tcp_stream,
-                ..
+                io: agent_stream, ..
             } = agent_control_framed.into_parts();
             Ok(TunnelInitResult::Tcp {
                 agent_encryption,
                 proxy_encryption,
                 destination_tcp_framed,
-                agent_tcp_stream,
+                agent_stream,
                 destination_address: dst_address,
+                auth_token,
+                tunnel_id,
+                tunnel_permit,
             })
         }
         TunnelType::Udp => {
+            let destination_connect_start = Instant::now();
             let destination_udp_socket =
-                new_udp_destination(&dst_address, server_state.clone()).await?;
+                new_udp_destination(&resolved_dst_addresses, server_state.clone()).await?;
+            server_state
+                .metrics()
+                .observe_destination_connect(&auth_token, destination_connect_start.elapsed());
             let proxy_encryption = Encryption::Aes(random_32_bytes());
             let tunnel_init_response = TunnelInitResponse {
                 proxy_encryption: proxy_encryption.clone(),
             };
             let proxy_control_packet =
                 ProxyControlPacket::TunnelInit((auth_token.clone(), tunnel_init_response));
-            agent_control_framed.send(proxy_control_packet).await?;
+            send_agent_control_packet(&mut agent_control_framed, proxy_control_packet, &config)
+                .await?;
             let FramedParts {
-                io: agent_tcp_stream,
-                ..
+                io: agent_stream, ..
             } = agent_control_framed.into_parts();
             Ok(TunnelInitResult::Udp {
                 agent_encryption,
                 proxy_encryption,
                 destination_udp_socket,
-                agent_tcp_stream,
+                agent_stream,
                 destination_address: dst_address,
+                auth_token,
+                tunnel_id,
+                tunnel_permit,
             })
         }
     }
 }
+/// Tell the agent the tunnel is rejected, return the error of the rejection
+async fn reject_tunnel_init(
+    agent_control_framed: &mut Framed<AgentStream, ControlPacketCodec>,
+    tunnel_init_failure: TunnelInitFailure,
+    config: &Config,
+) -> ProxyError {
+    if let Err(e) = send_agent_control_packet(
+        agent_control_framed,
+        ProxyControlPacket::TunnelInitFail(tunnel_init_failure.clone()),
+        config,
+    )
+    .await
+    {
+        return e;
+    }
+    match tunnel_init_failure {
+        TunnelInitFailure::AccessDenied(reason) => ProxyError::AccessDenied(reason),
+        TunnelInitFailure::QuotaExceeded(reason) => ProxyError::QuotaExceeded(reason),
+        TunnelInitFailure::ServerBusy(reason) => ProxyError::ServerBusy(reason),
+    }
+}
+/// Send the control packet to the agent in the `init_response` deadline
+pub(crate) async fn send_agent_control_packet(
+    agent_control_framed: &mut Framed<AgentStream, ControlPacketCodec>,
+    proxy_control_packet: ProxyControlPacket,
+    config: &Config,
+) -> Result<(), ProxyError> {
+    timeout(
+        config.handshake_timeout().init_response(),
+        agent_control_framed.send(proxy_control_packet),
+    )
+    .await
+    .map_err(|_| ProxyError::AgentInitResponseTimeout)?
+}
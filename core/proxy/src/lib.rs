@@ -1,9 +1,20 @@
+pub mod acl;
+pub mod admin;
+pub mod admission;
+pub mod ban;
 pub mod bo;
 mod codec;
 pub mod command;
 pub mod config;
 mod crypto;
+pub mod ctl;
 mod destination;
 mod error;
 mod handler;
+mod metrics;
+pub mod rate_limit;
+pub mod reload;
 pub mod server;
+pub mod transport;
+pub mod usage;
+pub mod user;
@@ -0,0 +1,157 @@
+use crate::error::ProxyError;
+use prometheus::{
+    exponential_buckets, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry,
+};
+use std::fmt::{Debug, Formatter};
+use std::time::Duration;
+const METRICS_NAMESPACE: &str = "ppaass_proxy";
+pub const RESULT_SUCCESS: &str = "success";
+pub const RESULT_FAIL: &str = "fail";
+/// The prometheus metrics of the proxy, the tunnel metrics are labelled by `auth_token`
+pub struct ProxyMetrics {
+    registry: Registry,
+    accepted_connections: IntCounterVec,
+    rejected_connections: IntCounterVec,
+    tunnels: IntCounterVec,
+    relay_bytes: IntCounterVec,
+    active_tunnels: IntGaugeVec,
+    tunnel_init_latency: HistogramVec,
+    destination_connect_latency: HistogramVec,
+    codec_errors: IntCounterVec,
+}
+impl Debug for ProxyMetrics {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ProxyMetrics").finish_non_exhaustive()
+    }
+}
+impl ProxyMetrics {
+    pub fn new() -> Result<Self, ProxyError> {
+        let registry = Registry::new_custom(Some(METRICS_NAMESPACE.to_string()), None)?;
+        let accepted_connections = IntCounterVec::new(
+            Opts::new(
+                "accepted_connections_total",
+                "The agent connections accepted by protocol and result",
+            ),
+            &["protocol", "result"],
+        )?;
+        let rejected_connections = IntCounterVec::new(
+            Opts::new(
+                "rejected_connections_total",
+                "The agent connections rejected by admission control by reason",
+            ),
+            &["reason"],
+        )?;
+        let tunnels = IntCounterVec::new(
+            Opts::new(
+                "tunnels_total",
+                "The tunnels initialized by user, protocol and result",
+            ),
+            &["auth_token", "protocol", "result"],
+        )?;
+        let relay_bytes = IntCounterVec::new(
+            Opts::new(
+                "relay_bytes_total",
+                "The bytes relayed by user and direction",
+            ),
+            &["auth_token", "direction"],
+        )?;
+        let active_tunnels = IntGaugeVec::new(
+            Opts::new("active_tunnels", "The tunnels in relaying by user"),
+            &["auth_token"],
+        )?;
+        let tunnel_init_latency = HistogramVec::new(
+            HistogramOpts::new(
+                "tunnel_init_latency_seconds",
+                "The latency of tunnel initialization by user",
+            )
+            .buckets(exponential_buckets(0.005, 2f64, 14)?),
+            &["auth_token"],
+        )?;
+        let destination_connect_latency = HistogramVec::new(
+            HistogramOpts::new(
+                "destination_connect_latency_seconds",
+                "The latency of connecting destination by user",
+            )
+            .buckets(exponential_buckets(0.005, 2f64, 14)?),
+            &["auth_token"],
+        )?;
+        let codec_errors = IntCounterVec::new(
+            Opts::new("codec_errors_total", "The crypto and codec errors by kind"),
+            &["kind"],
+        )?;
+        registry.register(Box::new(accepted_connections.clone()))?;
+        registry.register(Box::new(rejected_connections.clone()))?;
+        registry.register(Box::new(tunnels.clone()))?;
+        registry.register(Box::new(relay_bytes.clone()))?;
+        registry.register(Box::new(active_tunnels.clone()))?;
+        registry.register(Box::new(tunnel_init_latency.clone()))?;
+        registry.register(Box::new(destination_connect_latency.clone()))?;
+        registry.register(Box::new(codec_errors.clone()))?;
+        Ok(Self {
+            registry,
+            accepted_connections,
+            rejected_connections,
+            tunnels,
+            relay_bytes,
+            active_tunnels,
+            tunnel_init_latency,
+            destination_connect_latency,
+            codec_errors,
+        })
+    }
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+    pub fn record_accepted_connection(&self, protocol: &str, result: &str) {
+        self.accepted_connections
+            .with_label_values(&[protocol, result])
+            .inc();
+    }
+    pub fn record_rejected_connection(&self, reason: &str) {
+        self.rejected_connections.with_label_values(&[reason]).inc();
+    }
+    pub fn record_tunnel_init(
+        &self,
+        auth_token: &str,
+        protocol: &str,
+        result: &str,
+        latency: Duration,
+    ) {
+        self.tunnels
+            .with_label_values(&[auth_token, protocol, result])
+            .inc();
+        self.tunnel_init_latency
+            .with_label_values(&[auth_token])
+            .observe(latency.as_secs_f64());
+    }
+    pub fn observe_destination_connect(&self, auth_token: &str, latency: Duration) {
+        self.destination_connect_latency
+            .with_label_values(&[auth_token])
+            .observe(latency.as_secs_f64());
+    }
+    pub fn add_upload_bytes(&self, auth_token: &str, bytes: usize) {
+        self.relay_bytes
+            .with_label_values(&[auth_token, "upload"])
+            .inc_by(bytes as u64);
+    }
+    pub fn add_download_bytes(&self, auth_token: &str, bytes: usize) {
+        self.relay_bytes
+            .with_label_values(&[auth_token, "download"])
+            .inc_by(bytes as u64);
+    }
+    pub fn inc_active_tunnels(&self, auth_token: &str) {
+        self.active_tunnels.with_label_values(&[auth_token]).inc();
+    }
+    pub fn dec_active_tunnels(&self, auth_token: &str) {
+        self.active_tunnels.with_label_values(&[auth_token]).dec();
+    }
+    /// Count the error when it is caused by crypto or codec
+    pub fn record_error(&self, error: &ProxyError) {
+        let kind = match error {
+            ProxyError::FromHex(e) => e.kind(),
+            ProxyError::Crypto(_) => "crypto",
+            _ => return,
+        };
+        self.codec_errors.with_label_values(&[kind]).inc();
+    }
+}
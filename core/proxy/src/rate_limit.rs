@@ -0,0 +1,356 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::time::{sleep, Instant};
+/// The bandwidth limit in bytes per second, `None` means no limit,
+/// the burst is the bytes can be sent at once and defaults to one second of the rate
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BandwidthLimit {
+    pub upload_bytes_per_second: Option<u64>,
+    pub download_bytes_per_second: Option<u64>,
+    pub upload_burst_bytes: Option<u64>,
+    pub download_burst_bytes: Option<u64>,
+}
+/// The global cap is shared by all the tunnels, the user limit is shared by all
+/// the tunnels of the `auth_token`, the users not listed use `default_user`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RateLimitConfig {
+    #[serde(default)]
+    pub global: BandwidthLimit,
+    #[serde(default)]
+    pub default_user: BandwidthLimit,
+    #[serde(default)]
+    pub users: HashMap<String, BandwidthLimit>,
+}
+impl RateLimitConfig {
+    pub fn user_limit(&self, user: &str) -> &BandwidthLimit {
+        self.users.get(user).unwrap_or(&self.default_user)
+    }
+}
+struct TokenBucketState {
+    rate: u64,
+    burst: u64,
+    tokens: f64,
+    last_refill_time: Instant,
+}
+/// The token bucket allows the tokens go negative, the caller waits until
+/// the debt paid back, so the large reads are throttled as well as the small ones.
+/// A bucket with zero rate is unlimited.
+pub struct TokenBucket {
+    state: Mutex<TokenBucketState>,
+}
+impl TokenBucket {
+    pub fn new(rate: u64, burst: u64) -> Self {
+        Self {
+            state: Mutex::new(TokenBucketState {
+                rate,
+                burst,
+                tokens: burst as f64,
+                last_refill_time: Instant::now(),
+            }),
+        }
+    }
+    fn refill(&self, now: Instant) -> MutexGuard<'_, TokenBucketState> {
+        let mut state = self
+            .state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let elapsed = now.saturating_duration_since(state.last_refill_time);
+        state.tokens =
+            (state.tokens + elapsed.as_secs_f64() * state.rate as f64).min(state.burst as f64);
+        state.last_refill_time = now;
+        state
+    }
+    /// Change the limit in place, the tokens already refilled are kept up to the new burst
+    pub fn update_limit(&self, rate: u64, burst: u64, now: Instant) {
+        let mut state = self.refill(now);
+        state.rate = rate;
+        state.burst = burst;
+        state.tokens = state.tokens.min(burst as f64);
+    }
+    /// Take the tokens and return how long the caller should wait
+    fn reserve(&self, amount: usize, now: Instant) -> Duration {
+        let mut state = self.refill(now);
+        if state.rate == 0 {
+            return Duration::ZERO;
+        }
+        state.tokens -= amount as f64;
+        if state.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-state.tokens / state.rate as f64)
+        }
+    }
+    /// Take the tokens only when there are enough, never go into debt
+    pub fn try_acquire(&self, amount: usize, now: Instant) -> bool {
+        let mut state = self.refill(now);
+        if state.rate == 0 {
+            return true;
+        }
+        if state.tokens < amount as f64 {
+            return false;
+        }
+        state.tokens -= amount as f64;
+        true
+    }
+    /// The bucket is full when it is not used for a while
+    pub fn is_full(&self, now: Instant) -> bool {
+        let state = self.refill(now);
+        state.tokens >= state.burst as f64
+    }
+    /// Wait until the bytes are allowed to send
+    pub async fn acquire(&self, amount: usize) {
+        let wait = self.reserve(amount, Instant::now());
+        if !wait.is_zero() {
+            sleep(wait).await;
+        }
+    }
+}
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum BucketKey {
+    GlobalUpload,
+    GlobalDownload,
+    UserUpload(String),
+    UserDownload(String),
+}
+/// The rate and the burst of the bucket, `None` when there is no limit
+fn bucket_limit(rate: Option<u64>, burst: Option<u64>) -> Option<(u64, u64)> {
+    let rate = rate.filter(|rate| *rate > 0)?;
+    Some((rate, burst.unwrap_or(rate).max(1)))
+}
+/// The buckets of one tunnel, the user bucket is checked before the global one
+#[derive(Default)]
+pub struct TunnelRateLimit {
+    upload_buckets: Vec<Arc<TokenBucket>>,
+    download_buckets: Vec<Arc<TokenBucket>>,
+}
+impl TunnelRateLimit {
+    pub async fn acquire_upload(&self, amount: usize) {
+        for bucket in &self.upload_buckets {
+            bucket.acquire(amount).await;
+        }
+    }
+    pub async fn acquire_download(&self, amount: usize) {
+        for bucket in &self.download_buckets {
+            bucket.acquire(amount).await;
+        }
+    }
+}
+/// Hold the buckets shared by the tunnels. The limit of the bucket is changed in place
+/// by reload, so the existing tunnels take the new limit as well.
+#[derive(Default)]
+pub struct RateLimiter {
+    buckets: Mutex<HashMap<BucketKey, Arc<TokenBucket>>>,
+}
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// The buckets for a new tunnel of the user
+    pub fn tunnel_rate_limit(
+        &self,
+        user: &str,
+        user_limit: &BandwidthLimit,
+        global_limit: &BandwidthLimit,
+    ) -> TunnelRateLimit {
+        let mut buckets = self
+            .buckets
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut bucket = |key: BucketKey, rate: Option<u64>, burst: Option<u64>| {
+            let (rate, burst) = bucket_limit(rate, burst)?;
+            let bucket = buckets
+                .entry(key)
+                .and_modify(|bucket| bucket.update_limit(rate, burst, Instant::now()))
+                .or_insert_with(|| Arc::new(TokenBucket::new(rate, burst)));
+            Some(bucket.clone())
+        };
+        let upload_buckets = [
+            bucket(
+                BucketKey::UserUpload(user.to_owned()),
+                user_limit.upload_bytes_per_second,
+                user_limit.upload_burst_bytes,
+            ),
+            bucket(
+                BucketKey::GlobalUpload,
+                global_limit.upload_bytes_per_second,
+                global_limit.upload_burst_bytes,
+            ),
+        ];
+        let download_buckets = [
+            bucket(
+                BucketKey::UserDownload(user.to_owned()),
+                user_limit.download_bytes_per_second,
+                user_limit.download_burst_bytes,
+            ),
+            bucket(
+                BucketKey::GlobalDownload,
+                global_limit.download_bytes_per_second,
+                global_limit.download_burst_bytes,
+            ),
+        ];
+        TunnelRateLimit {
+            upload_buckets: upload_buckets.into_iter().flatten().collect(),
+            download_buckets: download_buckets.into_iter().flatten().collect(),
+        }
+    }
+    /// Apply the reloaded limits to the buckets in place. The buckets of the removed users are
+    /// dropped, the bucket whose limit is removed stops throttling the existing tunnels.
+    /// `user_limit` returns `None` when the user is removed.
+    pub fn update_limits(
+        &self,
+        global_limit: &BandwidthLimit,
+        user_limit: impl Fn(&str) -> Option<BandwidthLimit>,
+    ) {
+        let mut buckets = self
+            .buckets
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let now = Instant::now();
+        buckets.retain(|key, bucket| {
+            let limit = match key {
+                BucketKey::GlobalUpload => bucket_limit(
+                    global_limit.upload_bytes_per_second,
+                    global_limit.upload_burst_bytes,
+                ),
+                BucketKey::GlobalDownload => bucket_limit(
+                    global_limit.download_bytes_per_second,
+                    global_limit.download_burst_bytes,
+                ),
+                BucketKey::UserUpload(user) => match user_limit(user) {
+                    None => return false,
+                    Some(user_limit) => bucket_limit(
+                        user_limit.upload_bytes_per_second,
+                        user_limit.upload_burst_bytes,
+                    ),
+                },
+                BucketKey::UserDownload(user) => match user_limit(user) {
+                    None => return false,
+                    Some(user_limit) => bucket_limit(
+                        user_limit.download_bytes_per_second,
+                        user_limit.download_burst_bytes,
+                    ),
+                },
+            };
+            match limit {
+                Some((rate, burst)) => {
+                    bucket.update_limit(rate, burst, now);
+                    true
+                }
+                None => {
+                    bucket.update_limit(0, 0, now);
+                    false
+                }
+            }
+        });
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn token_bucket_throttle_after_burst() {
+        let token_bucket = TokenBucket::new(1000, 500);
+        let start = Instant::now();
+        assert_eq!(token_bucket.reserve(500, start), Duration::ZERO);
+        assert_eq!(token_bucket.reserve(1000, start), Duration::from_secs(1));
+        // The debt is paid back after one second and the burst refilled after another half
+        let refilled = start + Duration::from_millis(1500);
+        assert_eq!(token_bucket.reserve(500, refilled), Duration::ZERO);
+        assert_eq!(
+            token_bucket.reserve(100, refilled),
+            Duration::from_millis(100)
+        );
+    }
+    #[test]
+    fn tunnels_of_user_share_bucket() {
+        let rate_limiter = RateLimiter::new();
+        let mut rate_limit_config = RateLimitConfig::default();
+        rate_limit_config.users.insert(
+            "user1".to_string(),
+            BandwidthLimit {
+                upload_bytes_per_second: Some(1000),
+                ..Default::default()
+            },
+        );
+        let tunnel1 = rate_limiter.tunnel_rate_limit(
+            "user1",
+            rate_limit_config.user_limit("user1"),
+            &rate_limit_config.global,
+        );
+        let tunnel2 = rate_limiter.tunnel_rate_limit(
+            "user1",
+            rate_limit_config.user_limit("user1"),
+            &rate_limit_config.global,
+        );
+        assert!(Arc::ptr_eq(
+            &tunnel1.upload_buckets[0],
+            &tunnel2.upload_buckets[0]
+        ));
+        assert!(tunnel1.download_buckets.is_empty());
+        assert!(rate_limiter
+            .tunnel_rate_limit(
+                "user2",
+                rate_limit_config.user_limit("user2"),
+                &rate_limit_config.global
+            )
+            .upload_buckets
+            .is_empty());
+    }
+    #[tokio::test(start_paused = true)]
+    async fn update_limits_of_existing_tunnels() {
+        let rate_limiter = RateLimiter::new();
+        let mut rate_limit_config = RateLimitConfig {
+            global: BandwidthLimit {
+                download_bytes_per_second: Some(10000),
+                ..Default::default()
+            },
+            default_user: BandwidthLimit {
+                upload_bytes_per_second: Some(1000),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let tunnel_rate_limit = |user: &str, rate_limit_config: &RateLimitConfig| {
+            rate_limiter.tunnel_rate_limit(
+                user,
+                rate_limit_config.user_limit(user),
+                &rate_limit_config.global,
+            )
+        };
+        let user1_tunnel = tunnel_rate_limit("user1", &rate_limit_config);
+        let user2_tunnel = tunnel_rate_limit("user2", &rate_limit_config);
+        rate_limit_config.global = BandwidthLimit::default();
+        rate_limit_config.default_user.upload_bytes_per_second = Some(2000);
+        // The user2 is removed
+        rate_limiter.update_limits(&rate_limit_config.global, |user| {
+            (user == "user1").then(|| rate_limit_config.user_limit(user).clone())
+        });
+        let now = Instant::now();
+        // The burst stays at the old 1000 bytes until it refilled at the new rate
+        let user1_upload_bucket = &user1_tunnel.upload_buckets[0];
+        assert_eq!(user1_upload_bucket.reserve(1000, now), Duration::ZERO);
+        assert_eq!(
+            user1_upload_bucket.reserve(1000, now),
+            Duration::from_millis(500)
+        );
+        // The removed global limit stops throttling
+        assert_eq!(
+            user1_tunnel.download_buckets[0].reserve(1000000, now),
+            Duration::ZERO
+        );
+        // The new tunnel shares the updated bucket, the removed user gets a new bucket
+        assert!(Arc::ptr_eq(
+            user1_upload_bucket,
+            &tunnel_rate_limit("user1", &rate_limit_config).upload_buckets[0]
+        ));
+        assert!(!Arc::ptr_eq(
+            &user2_tunnel.upload_buckets[0],
+            &tunnel_rate_limit("user2", &rate_limit_config).upload_buckets[0]
+        ));
+        assert!(tunnel_rate_limit("user1", &rate_limit_config)
+            .download_buckets
+            .is_empty());
+    }
+}
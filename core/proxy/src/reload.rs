@@ -0,0 +1,263 @@
+use crate::acl::AclPolicy;
+use crate::bo::state::ServerState;
+use crate::config::Config;
+use crate::error::ProxyError;
+use ppaass_codec::RsaCryptoReloadReport;
+use ppaass_common::reload_trigger::listen_reload_trigger;
+use ppaass_common::LogLevelHandle;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, warn};
+/// The result of a configuration reload, the configuration file and
+/// the rsa directories are reloaded independently
+#[derive(Debug)]
+pub struct ConfigReloadReport {
+    /// The changed fields which can only take effect after restart,
+    /// the error when the configuration file can not be loaded
+    pub restart_required_changes: Result<Vec<&'static str>, ProxyError>,
+    /// The users changed in the rsa directory or the users file,
+    /// and the users changed in the forward rsa directory
+    pub rsa_crypto: Result<(RsaCryptoReloadReport, Option<RsaCryptoReloadReport>), ProxyError>,
+}
+/// Scan the rsa directories or load the users file again, the tunnels of the removed users are
+/// terminated when `terminate_revoked_user_tunnels` is enabled.
+/// The current bandwidth limits are applied to the existing tunnels as well.
+pub(crate) fn reload_rsa_crypto(
+    server_state: &ServerState,
+) -> Result<(RsaCryptoReloadReport, Option<RsaCryptoReloadReport>), ProxyError> {
+    let rsa_crypto = server_state.rsa_crypto_holder().reload();
+    server_state.update_rate_limits();
+    let rsa_crypto = rsa_crypto?;
+    if *server_state.config().terminate_revoked_user_tunnels() {
+        rsa_crypto.removed_users.iter().for_each(|user_token| {
+            let cancelled = server_state.tunnel_registry().cancel_user(user_token);
+            info!("Terminate {cancelled} tunnels of revoked user: {user_token}");
+        });
+    }
+    let forward_rsa_crypto = match server_state.forward_rsa_crypto_holder() {
+        None => None,
+        Some(forward_rsa_crypto_holder) => Some(forward_rsa_crypto_holder.reload()?),
+    };
+    Ok((rsa_crypto, forward_rsa_crypto))
+}
+/// Reload the configuration file and the rsa directories into the running server
+pub struct ConfigReloader {
+    config_file_path: PathBuf,
+    server_state: ServerState,
+    log_level_handle: Option<LogLevelHandle>,
+}
+impl ConfigReloader {
+    pub fn new(
+        config_file_path: PathBuf,
+        server_state: ServerState,
+        log_level_handle: Option<LogLevelHandle>,
+    ) -> Self {
+        Self {
+            config_file_path,
+            server_state,
+            log_level_handle,
+        }
+    }
+    /// Reload the configuration file and the rsa directories, a broken configuration
+    /// file does not stop the removed users from being revoked
+    pub fn reload(&self) -> ConfigReloadReport {
+        let restart_required_changes = self.reload_config();
+        let rsa_crypto = reload_rsa_crypto(&self.server_state);
+        ConfigReloadReport {
+            restart_required_changes,
+            rsa_crypto,
+        }
+    }
+    /// Load and validate the configuration file, then swap it into the server,
+    /// the existing tunnels keep going with the old configuration.
+    /// The access control policy file is loaded again for the new tunnels.
+    fn reload_config(&self) -> Result<Vec<&'static str>, ProxyError> {
+        let new_config = Config::load(&self.config_file_path)?;
+        let new_acl_policy = AclPolicy::from_config(&new_config)?;
+        let current_config = self.server_state.config();
+        let restart_required_changes = current_config.restart_required_changes(&new_config);
+        let log_level_changed = current_config.max_log_level() != new_config.max_log_level();
+        let new_max_log_level = new_config.max_log_level().to_owned();
+        self.server_state.swap_config(Arc::new(new_config));
+        self.server_state.swap_acl_policy(Arc::new(new_acl_policy));
+        if log_level_changed {
+            if let Some(log_level_handle) = &self.log_level_handle {
+                log_level_handle.set_max_log_level(&new_max_log_level)?;
+            }
+        }
+        Ok(restart_required_changes)
+    }
+    /// Reload the configuration when SIGHUP received or the
+    /// configuration file modified if `config_watch_interval` is given
+    pub fn start(self) {
+        let watch_interval = self
+            .server_state
+            .config()
+            .config_watch_interval()
+            .map(Duration::from_secs);
+        let mut watch_paths = vec![
+            self.config_file_path.clone(),
+            self.server_state
+                .rsa_crypto_holder()
+                .watch_path()
+                .to_path_buf(),
+        ];
+        if let Some(forward_rsa_crypto_holder) = self.server_state.forward_rsa_crypto_holder() {
+            watch_paths.push(forward_rsa_crypto_holder.rsa_dir_path().to_path_buf());
+        }
+        if let Some(acl_file_path) = self.server_state.config().acl_file() {
+            watch_paths.push(acl_file_path.clone());
+        }
+        let mut reload_trigger_rx = listen_reload_trigger(watch_paths, watch_interval);
+        tokio::spawn(async move {
+            while let Some(reload_trigger) = reload_trigger_rx.recv().await {
+                info!(
+                    "Reload configuration [{:?}] because of {reload_trigger:?}",
+                    self.config_file_path
+                );
+                let ConfigReloadReport {
+                    restart_required_changes,
+                    rsa_crypto,
+                } = self.reload();
+                match restart_required_changes {
+                    Ok(restart_required_changes) if restart_required_changes.is_empty() => {
+                        info!("Configuration reloaded.");
+                    }
+                    Ok(restart_required_changes) => {
+                        warn!("Configuration reloaded, but these changes only take effect after restart: {restart_required_changes:?}");
+                    }
+                    Err(e) => {
+                        error!("Fail to reload configuration, keep using the current one: {e:?}");
+                    }
+                }
+                match rsa_crypto {
+                    Ok((rsa_crypto, forward_rsa_crypto)) => {
+                        info!(
+                            "Rsa crypto reloaded, added users: {:?}, revoked users: {:?}",
+                            rsa_crypto.added_users, rsa_crypto.removed_users
+                        );
+                        if let Some(forward_rsa_crypto) = forward_rsa_crypto {
+                            info!(
+                                "Forward rsa crypto reloaded, added users: {:?}, removed users: {:?}",
+                                forward_rsa_crypto.added_users, forward_rsa_crypto.removed_users
+                            );
+                        }
+                    }
+                    Err(e) => {
+                        error!("Fail to reload rsa crypto, keep using the current one: {e:?}");
+                    }
+                }
+            }
+        });
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::admission::AdmissionController;
+    use crate::ban::BanList;
+    use crate::bo::state::ServerStateBuilder;
+    use crate::crypto::{ProxyRsaCryptoHolder, UserRsaCryptoHolder};
+    use crate::metrics::ProxyMetrics;
+    use crate::rate_limit::RateLimiter;
+    use crate::usage::UsageStore;
+    use arc_swap::ArcSwap;
+    use ppaass_common::shutdown::ShutdownCoordinator;
+    use ppaass_common::tunnel_registry::TunnelRegistry;
+    use std::fs::{copy, create_dir_all, remove_dir_all, write};
+    use std::path::Path;
+    fn server_state(config: Config) -> ServerState {
+        let rsa_crypto_holder = ProxyRsaCryptoHolder::new(
+            config.rsa_dir(),
+            "AgentPublicKey.pem".to_string(),
+            "ProxyPrivateKey.pem".to_string(),
+        )
+        .unwrap();
+        ServerStateBuilder::default()
+            .acl_policy(Arc::new(ArcSwap::from_pointee(
+                AclPolicy::from_config(&config).unwrap(),
+            )))
+            .config(Arc::new(ArcSwap::from_pointee(config)))
+            .shutdown_coordinator(ShutdownCoordinator::new())
+            .tunnel_registry(Arc::new(TunnelRegistry::new()))
+            .rsa_crypto_holder(Arc::new(UserRsaCryptoHolder::RsaDir(rsa_crypto_holder)))
+            .metrics(Arc::new(ProxyMetrics::new().unwrap()))
+            .rate_limiter(Arc::new(RateLimiter::new()))
+            .usage_store(Arc::new(UsageStore::open(None).unwrap()))
+            .admission_controller(Arc::new(AdmissionController::new()))
+            .ban_list(Arc::new(BanList::new()))
+            .build()
+            .unwrap()
+    }
+    #[test]
+    fn reload_rsa_crypto_even_when_config_invalid() {
+        let test_dir =
+            std::env::temp_dir().join(format!("ppaass-reload-test-{}", std::process::id()));
+        let rsa_dir_path = test_dir.join("rsa");
+        let user_rsa_dir_path =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("../../resources/proxy/rsa/user1");
+        for user in ["user1", "user2"] {
+            create_dir_all(rsa_dir_path.join(user)).unwrap();
+            for key_file_name in ["AgentPublicKey.pem", "ProxyPrivateKey.pem"] {
+                copy(
+                    user_rsa_dir_path.join(key_file_name),
+                    rsa_dir_path.join(user).join(key_file_name),
+                )
+                .unwrap();
+            }
+        }
+        let config_file_path = test_dir.join("config.toml");
+        // The rsa directory goes first, the top level keys can not follow the tables
+        let current_config: Config = toml::from_str(&format!(
+            "rsa_dir = {rsa_dir_path:?}\n{}",
+            toml::to_string(&Config::default())
+                .unwrap()
+                .replace("rsa_dir = \"/resources/rsa\"\n", "")
+        ))
+        .unwrap();
+        let config_reloader =
+            ConfigReloader::new(config_file_path.clone(), server_state(current_config), None);
+        let new_config_content = toml::to_string(&*config_reloader.server_state.config())
+            .unwrap()
+            .replace("port = 80\n", "port = 8080\n")
+            .replace(
+                "shutdown_drain_timeout = 30\n",
+                "shutdown_drain_timeout = 5\n",
+            );
+        write(&config_file_path, &new_config_content).unwrap();
+        let config_reload_report = config_reloader.reload();
+        assert_eq!(
+            config_reload_report.restart_required_changes.unwrap(),
+            vec!["port"]
+        );
+        assert_eq!(*config_reloader.server_state.config().port(), 8080);
+        assert_eq!(
+            *config_reloader
+                .server_state
+                .config()
+                .shutdown_drain_timeout(),
+            5
+        );
+        // The user is revoked while the configuration is broken
+        write(&config_file_path, "port = \"not a port\"").unwrap();
+        remove_dir_all(rsa_dir_path.join("user2")).unwrap();
+        let config_reload_report = config_reloader.reload();
+        remove_dir_all(&test_dir).unwrap();
+        assert!(config_reload_report.restart_required_changes.is_err());
+        let (rsa_crypto, forward_rsa_crypto) = config_reload_report.rsa_crypto.unwrap();
+        assert_eq!(rsa_crypto.removed_users, vec!["user2".to_string()]);
+        assert!(forward_rsa_crypto.is_none());
+        assert_eq!(
+            config_reloader.server_state.rsa_crypto_holder().users(),
+            vec!["user1".to_string()]
+        );
+        assert_eq!(
+            *config_reloader
+                .server_state
+                .config()
+                .shutdown_drain_timeout(),
+            5
+        );
+    }
+}
@@ -1,39 +1,107 @@
+use crate::acl::AclPolicy;
+use crate::admin::AdminServer;
+use crate::admission::{AdmissionController, AdmissionRejection, ConnectionPermit};
+use crate::ban::BanList;
 use crate::bo::state::{ServerState, ServerStateBuilder};
 use crate::codec::ControlPacketCodec;
-use crate::config::Config;
-use crate::crypto::ProxyRsaCryptoHolder;
+use crate::config::{Config, HandshakeTimeoutConfig};
+use crate::crypto::{ProxyRsaCryptoHolder, UserRsaCryptoHolder};
 use crate::error::ProxyError;
 use crate::handler;
 use crate::handler::{RelayStartRequest, TunnelInitResult};
-use chrono::Utc;
+use crate::metrics::{ProxyMetrics, RESULT_FAIL, RESULT_SUCCESS};
+use crate::rate_limit::RateLimiter;
+use crate::reload::ConfigReloader;
+use crate::transport::AgentStream;
+use crate::usage::UsageStore;
+use crate::user::UsersFileRsaCryptoHolder;
+use arc_swap::ArcSwap;
+use bytes::BytesMut;
 use futures_util::{SinkExt, StreamExt};
+use ppaass_common::metrics::serve_metrics;
+use ppaass_common::quic::QuicConnection;
+use ppaass_common::shutdown::ShutdownCoordinator;
+use ppaass_common::tunnel_registry::TunnelRegistry;
+use ppaass_common::websocket::WebSocketTransport;
+use ppaass_common::LogLevelHandle;
 use ppaass_domain::heartbeat::HeartbeatPong;
+use ppaass_domain::tunnel::TunnelInitFailure;
 use ppaass_domain::{AgentControlPacket, ProxyControlPacket};
+use quinn::{Endpoint, Incoming};
 use socket2::{SockRef, TcpKeepalive};
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
+use tokio::io::{copy_bidirectional, AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::task::JoinHandle;
+use tokio::task::{JoinError, JoinHandle};
+use tokio::time::{interval, timeout};
 use tokio_util::codec::Framed;
-use tracing::{debug, error};
+use tracing::{debug, error, info, warn};
 const USER_AGENT_PUBLIC_KEY: &str = "AgentPublicKey.pem";
 const USER_PROXY_PRIVATE_KEY: &str = "ProxyPrivateKey.pem";
 const FORWARD_AGENT_PRIVATE_KEY: &str = "AgentPrivateKey.pem";
 const FORWARD_PROXY_PUBLIC_KEY: &str = "ProxyPublicKey.pem";
+/// The time to send the server busy response to the rejected agent connection
+const SERVER_BUSY_RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+/// The default interval to flush the traffic usage
+const DEFAULT_USAGE_FLUSH_INTERVAL: Duration = Duration::from_secs(60);
 pub struct ProxyServer {
     server_state: ServerState,
 }
+/// The handle of the started proxy server
+pub struct ProxyServerHandle {
+    server_state: ServerState,
+    accept_task: JoinHandle<()>,
+}
+impl ProxyServerHandle {
+    /// Wait until the server stops accepting agent connections
+    pub async fn wait(&mut self) -> Result<(), JoinError> {
+        (&mut self.accept_task).await
+    }
+    /// Stop accepting agent connections and drain the tunnels until
+    /// the `shutdown_drain_timeout`, return `true` when all the tunnels drained.
+    pub async fn shutdown(self) -> bool {
+        let drain_timeout =
+            Duration::from_secs(*self.server_state.config().shutdown_drain_timeout());
+        let drained = self
+            .server_state
+            .shutdown_coordinator()
+            .shutdown(drain_timeout)
+            .await;
+        if let Err(e) = self.server_state.usage_store().flush() {
+            error!("Fail to flush traffic usage on shutdown: {e:?}");
+        }
+        drained
+    }
+}
 impl ProxyServer {
     pub fn new(config: Arc<Config>) -> Result<Self, ProxyError> {
         let mut server_state_builder = ServerStateBuilder::default();
         let mut server_state_builder = server_state_builder
-            .config(config.clone())
-            .rsa_crypto_holder(Arc::new(ProxyRsaCryptoHolder::new(
+            .config(Arc::new(ArcSwap::new(config.clone())))
+            .shutdown_coordinator(ShutdownCoordinator::new())
+            .tunnel_registry(Arc::new(TunnelRegistry::new()))
+            .metrics(Arc::new(ProxyMetrics::new()?))
+            .acl_policy(Arc::new(ArcSwap::from_pointee(AclPolicy::from_config(
+                &config,
+            )?)))
+            .rate_limiter(Arc::new(RateLimiter::new()))
+            .admission_controller(Arc::new(AdmissionController::new()))
+            .ban_list(Arc::new(BanList::new()))
+            .usage_store(Arc::new(UsageStore::open(config.usage_file().clone())?));
+        let rsa_crypto_holder = match config.users_file() {
+            None => UserRsaCryptoHolder::RsaDir(ProxyRsaCryptoHolder::new(
                 config.rsa_dir(),
                 USER_AGENT_PUBLIC_KEY.to_owned(),
                 USER_PROXY_PRIVATE_KEY.to_owned(),
-            )?));
+            )?),
+            Some(users_file_path) => {
+                UserRsaCryptoHolder::UsersFile(UsersFileRsaCryptoHolder::new(users_file_path)?)
+            }
+        };
+        server_state_builder = server_state_builder.rsa_crypto_holder(Arc::new(rsa_crypto_holder));
         if config.forward_server_addresses().is_some() {
             server_state_builder =
                 server_state_builder.forward_rsa_crypto_holder(Arc::new(ProxyRsaCryptoHolder::new(
@@ -42,132 +110,543 @@ impl ProxyServer {
                     FORWARD_AGENT_PRIVATE_KEY.to_owned(),
                 )?))
         }
+        if let Some(tls_config) = config.tls() {
+            server_state_builder = server_state_builder.tls_acceptor(tls_config.build_acceptor()?);
+        }
+        if let Some(quic_config) = config.quic() {
+            server_state_builder =
+                server_state_builder.quic_server_config(quic_config.build_server_config()?);
+        }
 
         Ok(Self {
             server_state: server_state_builder.build()?,
         })
     }
+    /// The live tunnels of the server
+    pub fn tunnel_registry(&self) -> Arc<TunnelRegistry> {
+        self.server_state.tunnel_registry().clone()
+    }
+    /// The reloader to reload the configuration file into the server
+    pub fn config_reloader(
+        &self,
+        config_file_path: PathBuf,
+        log_level_handle: Option<LogLevelHandle>,
+    ) -> ConfigReloader {
+        ConfigReloader::new(
+            config_file_path,
+            self.server_state.clone(),
+            log_level_handle,
+        )
+    }
+    /// The admin endpoint to inspect and control the running server
+    pub fn admin_server(&self, log_level_handle: Option<LogLevelHandle>) -> AdminServer {
+        AdminServer::new(self.server_state.clone(), log_level_handle)
+    }
     fn spawn_agent_task(
         agent_tcp_stream: TcpStream,
         agent_socket_address: SocketAddr,
+        connection_permit: ConnectionPermit,
         server_state: ServerState,
     ) {
-        tokio::spawn(async move {
-            let mut control_framed = Framed::with_capacity(
-                agent_tcp_stream,
-                ControlPacketCodec::new(server_state.rsa_crypto_holder().clone()),
-                *server_state.config().agent_buffer_size(),
-            );
-            loop {
-                let agent_control_packet = control_framed.next().await;
-                match agent_control_packet {
-                    None => {
+        // Only the started relays are drained on shutdown, the connections
+        // still in handshake or idle in the pool are closed on shutdown
+        let accept_token = server_state.shutdown_coordinator().accept_token().clone();
+        tokio::spawn(accept_token.run_until_cancelled_owned(async move {
+            let agent_stream =
+                match Self::accept_agent_stream(agent_tcp_stream, &server_state).await {
+                    Ok(agent_stream) => agent_stream,
+                    Err(e) => {
                         debug!(
                             agent_socket_address = { format!("{agent_socket_address}") },
-                            "Agent connection exhausted."
+                            "Fail to accept agent connection: {e:?}"
                         );
                         return;
                     }
-                    Some(Err(e)) => {
-                        error!(
-                            agent_socket_address = { format!("{agent_socket_address}") },
-                            "Fail to receive agent control packet: {:?}", e
-                        );
-                        return;
-                    }
-                    Some(Ok(AgentControlPacket::TunnelInit(tunnel_init_request))) => {
-                        let tunnel_init_result = match handler::tunnel_init(
-                            control_framed,
-                            tunnel_init_request,
-                            server_state.clone(),
-                        )
-                        .await
+                };
+            Self::serve_agent_stream(
+                agent_stream,
+                agent_socket_address,
+                connection_permit,
+                server_state,
+            )
+            .await;
+        }));
+    }
+    /// Serve the control packets of the agent connection until the tunnel
+    /// started, the connection is a tcp connection or a quic stream
+    async fn serve_agent_stream(
+        agent_stream: AgentStream,
+        agent_socket_address: SocketAddr,
+        connection_permit: ConnectionPermit,
+        server_state: ServerState,
+    ) {
+        let mut control_codec = ControlPacketCodec::new(server_state.rsa_crypto_holder().clone());
+        if server_state.config().fallback_address().is_some() {
+            control_codec = control_codec.with_probe_recording();
+        }
+        let mut control_framed = Framed::with_capacity(
+            agent_stream,
+            control_codec,
+            *server_state.config().agent_buffer_size(),
+        );
+        let mut control_packet_received = false;
+        loop {
+            let agent_control_packet = Self::next_agent_control_packet(
+                &mut control_framed,
+                *server_state.config().handshake_timeout(),
+                control_packet_received,
+            )
+            .await;
+            match agent_control_packet {
+                Ok(None) => {
+                    debug!(
+                        agent_socket_address = { format!("{agent_socket_address}") },
+                        "Agent connection exhausted."
+                    );
+                    return;
+                }
+                Err(ProxyError::AgentFirstByteTimeout) => {
+                    debug!(
+                        agent_socket_address = { format!("{agent_socket_address}") },
+                        "Close agent connection because of no control packet received in time."
+                    );
+                    return;
+                }
+                Err(e) => {
+                    // The short requests of the other protocols wait for the rest of
+                    // the control packet until the init frame timeout
+                    if e.is_protocol_failure() || matches!(e, ProxyError::AgentInitFrameTimeout) {
+                        let fallback_address = server_state.config().fallback_address().clone();
+                        let probe_bytes = control_framed.codec_mut().take_probe_bytes();
+                        if let (Some(fallback_address), Some(mut probe_bytes)) =
+                            (fallback_address, probe_bytes)
                         {
-                            Ok(tunnel_init_result) => tunnel_init_result,
-                            Err(e) => {
-                                error!(
-                                    agent_socket_address = { format!("{agent_socket_address}") },
-                                    "Fail to init tunnel: {e:?}"
+                            // The connections handed to the fallback are not counted by the ban,
+                            // the visitors of the fallback server must not be banned
+                            debug!(
+                                agent_socket_address = { format!("{agent_socket_address}") },
+                                "Hand unrecognized connection to fallback address: {e:?}"
+                            );
+                            let control_framed_parts = control_framed.into_parts();
+                            probe_bytes.extend_from_slice(&control_framed_parts.read_buf);
+                            if let Err(e) = Self::relay_to_fallback(
+                                control_framed_parts.io,
+                                probe_bytes,
+                                &fallback_address,
+                                &server_state,
+                            )
+                            .await
+                            {
+                                debug!(
+                                    agent_socket_address =
+                                        { format!("{agent_socket_address}") },
+                                    "Fail to relay unrecognized connection to fallback address: {e:?}"
                                 );
-                                return;
-                            }
-                        };
-                        match tunnel_init_result {
-                            TunnelInitResult::Tcp {
-                                agent_encryption,
-                                proxy_encryption,
-                                destination_tcp_framed,
-                                agent_tcp_stream,
-                                destination_address,
-                            } => {
-                                if let Err(e) = handler::start_relay(
-                                    agent_tcp_stream,
-                                    RelayStartRequest::Tcp {
-                                        agent_encryption,
-                                        proxy_encryption,
-                                        destination_tcp_framed,
-                                        destination_address,
-                                    },
-                                    server_state,
-                                )
-                                .await
-                                {
-                                    error!(
-                                        agent_socket_address =
-                                            { format!("{agent_socket_address}") },
-                                        "Fail to start relay tcp data: {e:?}"
-                                    );
-                                }
-                            }
-                            TunnelInitResult::Udp {
-                                agent_encryption,
-                                proxy_encryption,
-                                destination_udp_socket,
-                                agent_tcp_stream,
-                                destination_address,
-                            } => {
-                                if let Err(e) = handler::start_relay(
-                                    agent_tcp_stream,
-                                    RelayStartRequest::Udp {
-                                        agent_encryption,
-                                        proxy_encryption,
-                                        destination_udp_socket,
-                                        destination_address,
-                                    },
-                                    server_state,
-                                )
-                                .await
-                                {
-                                    error!(
-                                        agent_socket_address =
-                                            { format!("{agent_socket_address}") },
-                                        "Fail to start relay udp data: {e:?}"
-                                    );
-                                }
                             }
+                            return;
                         }
-                        return;
                     }
-                    Some(Ok(AgentControlPacket::Heartbeat(heartbeat_ping))) => {
-                        debug!(
+                    if e.is_protocol_failure()
+                        && server_state
+                            .ban_list()
+                            .record_failure(agent_socket_address.ip(), server_state.config().ban())
+                    {
+                        warn!(
                             agent_socket_address = { format!("{agent_socket_address}") },
-                            "Heartbeat ping received: {:?}", heartbeat_ping
+                            "Ban agent ip because of too many failures."
                         );
-                        if let Err(e) = control_framed
-                            .send(ProxyControlPacket::Heartbeat(HeartbeatPong {
-                                heartbeat_time: Utc::now(),
-                            }))
-                            .await
-                        {
+                    }
+                    server_state.metrics().record_error(&e);
+                    error!(
+                        agent_socket_address = { format!("{agent_socket_address}") },
+                        "Fail to receive agent control packet: {:?}", e
+                    );
+                    return;
+                }
+                Ok(Some(AgentControlPacket::TunnelInit(tunnel_init_request))) => {
+                    let tunnel_init_result = match handler::tunnel_init(
+                        control_framed,
+                        tunnel_init_request,
+                        server_state.clone(),
+                    )
+                    .await
+                    {
+                        Ok(tunnel_init_result) => tunnel_init_result,
+                        Err(e) => {
+                            server_state.metrics().record_error(&e);
                             error!(
                                 agent_socket_address = { format!("{agent_socket_address}") },
-                                "Fail to send heartbeat pong back to agent: {e:?}"
+                                "Fail to init tunnel: {e:?}"
                             );
                             return;
                         }
+                    };
+                    match tunnel_init_result {
+                        TunnelInitResult::Tcp {
+                            agent_encryption,
+                            proxy_encryption,
+                            destination_tcp_framed,
+                            agent_stream,
+                            destination_address,
+                            auth_token,
+                            tunnel_id,
+                            tunnel_permit,
+                        } => {
+                            if let Err(e) = handler::start_relay(
+                                agent_stream,
+                                connection_permit,
+                                RelayStartRequest::Tcp {
+                                    agent_encryption,
+                                    proxy_encryption,
+                                    destination_tcp_framed,
+                                    destination_address,
+                                    auth_token,
+                                    tunnel_id,
+                                    tunnel_permit,
+                                },
+                                server_state,
+                            )
+                            .await
+                            {
+                                error!(
+                                    agent_socket_address = { format!("{agent_socket_address}") },
+                                    "Fail to start relay tcp data: {e:?}"
+                                );
+                            }
+                        }
+                        TunnelInitResult::Udp {
+                            agent_encryption,
+                            proxy_encryption,
+                            destination_udp_socket,
+                            agent_stream,
+                            destination_address,
+                            auth_token,
+                            tunnel_id,
+                            tunnel_permit,
+                        } => {
+                            if let Err(e) = handler::start_relay(
+                                agent_stream,
+                                connection_permit,
+                                RelayStartRequest::Udp {
+                                    agent_encryption,
+                                    proxy_encryption,
+                                    destination_udp_socket,
+                                    destination_address,
+                                    auth_token,
+                                    tunnel_id,
+                                    tunnel_permit,
+                                },
+                                server_state,
+                            )
+                            .await
+                            {
+                                error!(
+                                    agent_socket_address = { format!("{agent_socket_address}") },
+                                    "Fail to start relay udp data: {e:?}"
+                                );
+                            }
+                        }
+                    }
+                    return;
+                }
+                Ok(Some(AgentControlPacket::Heartbeat(heartbeat_ping))) => {
+                    debug!(
+                        agent_socket_address = { format!("{agent_socket_address}") },
+                        "Heartbeat ping received: {:?}", heartbeat_ping
+                    );
+                    if let Err(e) = handler::send_agent_control_packet(
+                        &mut control_framed,
+                        ProxyControlPacket::Heartbeat(HeartbeatPong::reply(&heartbeat_ping)),
+                        &server_state.config(),
+                    )
+                    .await
+                    {
+                        error!(
+                            agent_socket_address = { format!("{agent_socket_address}") },
+                            "Fail to send heartbeat pong back to agent: {e:?}"
+                        );
+                        return;
                     }
+                    control_packet_received = true;
                 }
             }
+        }
+    }
+    /// Wrap the agent connection with tls and websocket when configured, the first byte must
+    /// arrive in the `first_byte` deadline and the handshakes finish in the `init_frame` deadline
+    async fn accept_agent_stream(
+        agent_tcp_stream: TcpStream,
+        server_state: &ServerState,
+    ) -> Result<AgentStream, ProxyError> {
+        let config = server_state.config();
+        if server_state.tls_acceptor().is_none() && config.websocket().is_none() {
+            return Ok(AgentStream::Tcp(agent_tcp_stream));
+        }
+        let handshake_timeout = *config.handshake_timeout();
+        timeout(
+            handshake_timeout.first_byte(),
+            agent_tcp_stream.peek(&mut [0u8; 1]),
+        )
+        .await
+        .map_err(|_| ProxyError::AgentFirstByteTimeout)??;
+        timeout(handshake_timeout.init_frame(), async {
+            let agent_stream = match server_state.tls_acceptor() {
+                None => AgentStream::Tcp(agent_tcp_stream),
+                Some(tls_acceptor) => {
+                    AgentStream::Tls(Box::new(tls_acceptor.accept(agent_tcp_stream).await?))
+                }
+            };
+            match config.websocket() {
+                None => Ok(agent_stream),
+                Some(websocket) => Ok(AgentStream::WebSocket(Box::new(
+                    WebSocketTransport::accept(agent_stream, &websocket.path).await?,
+                ))),
+            }
+        })
+        .await
+        .map_err(|_| ProxyError::AgentInitFrameTimeout)?
+    }
+    /// Receive the next control packet from the agent, the first byte must arrive in the
+    /// `first_byte` deadline for the first control packet and in the `idle` deadline for
+    /// the following ones, the full packet must arrive in the `init_frame` deadline
+    async fn next_agent_control_packet(
+        control_framed: &mut Framed<AgentStream, ControlPacketCodec>,
+        handshake_timeout: HandshakeTimeoutConfig,
+        control_packet_received: bool,
+    ) -> Result<Option<AgentControlPacket>, ProxyError> {
+        if control_framed.read_buffer().is_empty() {
+            let first_byte_timeout = if control_packet_received {
+                handshake_timeout.idle()
+            } else {
+                handshake_timeout.first_byte()
+            };
+            let mut first_byte = [0u8; 1];
+            let first_byte_size = timeout(
+                first_byte_timeout,
+                control_framed.get_mut().read(&mut first_byte),
+            )
+            .await
+            .map_err(|_| ProxyError::AgentFirstByteTimeout)??;
+            if first_byte_size == 0 {
+                return Ok(None);
+            }
+            control_framed
+                .read_buffer_mut()
+                .extend_from_slice(&first_byte);
+        }
+        timeout(handshake_timeout.init_frame(), control_framed.next())
+            .await
+            .map_err(|_| ProxyError::AgentInitFrameTimeout)?
+            .transpose()
+    }
+    /// Replay the bytes already read to the fallback address and relay the
+    /// rest of the connection, so the port looks like the fallback server
+    async fn relay_to_fallback(
+        mut agent_stream: AgentStream,
+        probe_bytes: BytesMut,
+        fallback_address: &str,
+        server_state: &ServerState,
+    ) -> Result<(), ProxyError> {
+        let mut fallback_tcp_stream = timeout(
+            Duration::from_secs(*server_state.config().dst_connect_timeout()),
+            TcpStream::connect(fallback_address),
+        )
+        .await??;
+        fallback_tcp_stream.write_all(&probe_bytes).await?;
+        copy_bidirectional(&mut agent_stream, &mut fallback_tcp_stream).await?;
+        Ok(())
+    }
+    /// Tell the agent the proxy is busy before closing the connection,
+    /// so the agent fails the tunnel explicitly instead of waiting.
+    /// The responses are bounded, the connection is closed directly when too many in progress
+    fn spawn_server_busy_task(agent_tcp_stream: TcpStream, server_state: ServerState) {
+        let Some(server_busy_response_permit) = server_state
+            .admission_controller()
+            .admit_server_busy_response()
+        else {
+            return;
+        };
+        let accept_token = server_state.shutdown_coordinator().accept_token().clone();
+        tokio::spawn(accept_token.run_until_cancelled_owned(async move {
+            let _server_busy_response_permit = server_busy_response_permit;
+            let _ = timeout(SERVER_BUSY_RESPONSE_TIMEOUT, async {
+                let agent_stream =
+                    Self::accept_agent_stream(agent_tcp_stream, &server_state).await?;
+                let mut control_framed = Framed::new(
+                    agent_stream,
+                    ControlPacketCodec::new(server_state.rsa_crypto_holder().clone()),
+                );
+                control_framed
+                    .send(ProxyControlPacket::TunnelInitFail(
+                        TunnelInitFailure::ServerBusy("max connections reached".to_string()),
+                    ))
+                    .await
+            })
+            .await;
+        }));
+    }
+    /// Start the metrics listener when the `metrics_listen_address` configured
+    fn start_metrics_listener(server_state: &ServerState) -> Result<(), ProxyError> {
+        let Some(metrics_listen_address) = server_state.config().metrics_listen_address().clone()
+        else {
+            return Ok(());
+        };
+        let metrics_listen_address = metrics_listen_address.parse::<SocketAddr>()?;
+        let registry = server_state.metrics().registry().clone();
+        let stop_token = server_state.shutdown_coordinator().accept_token().clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_metrics(metrics_listen_address, registry, || {}, stop_token).await
+            {
+                error!("Fail to serve metrics on [{metrics_listen_address}]: {e:?}");
+            }
+        });
+        Ok(())
+    }
+    /// Flush the traffic usage periodically until the server stops accepting
+    fn start_usage_flusher(server_state: &ServerState) {
+        let flush_interval = server_state
+            .config()
+            .usage_flush_interval()
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_USAGE_FLUSH_INTERVAL);
+        let usage_store = server_state.usage_store().clone();
+        let stop_token = server_state.shutdown_coordinator().accept_token().clone();
+        tokio::spawn(stop_token.run_until_cancelled_owned(async move {
+            let mut flush_interval = interval(flush_interval);
+            flush_interval.tick().await;
+            loop {
+                flush_interval.tick().await;
+                if let Err(e) = usage_store.flush() {
+                    error!("Fail to flush traffic usage: {e:?}");
+                }
+            }
+        }));
+    }
+    /// Accept the quic agent connections when the `quic` configured,
+    /// every stream of the connection is served like one agent tcp connection
+    fn start_quic_listener(server_state: &ServerState) -> Result<(), ProxyError> {
+        let (Some(quic_server_config), Some(quic)) = (
+            server_state.quic_server_config().clone(),
+            server_state.config().quic().clone(),
+        ) else {
+            return Ok(());
+        };
+        let quic_socket_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), quic.port);
+        let quic_endpoint = Endpoint::server(quic_server_config, quic_socket_addr)?;
+        let server_state = server_state.clone();
+        tokio::spawn(async move {
+            let accept_token = server_state.shutdown_coordinator().accept_token().clone();
+            loop {
+                let incoming = tokio::select! {
+                    incoming = quic_endpoint.accept() => match incoming {
+                        Some(incoming) => incoming,
+                        None => return,
+                    },
+                    _ = accept_token.cancelled() => {
+                        info!("Stop accepting agent quic connections because of shutdown.");
+                        return;
+                    }
+                };
+                server_state
+                    .metrics()
+                    .record_accepted_connection("quic", RESULT_SUCCESS);
+                let agent_socket_addr = incoming.remote_address();
+                if server_state
+                    .ban_list()
+                    .is_banned(agent_socket_addr.ip(), server_state.config().ban())
+                {
+                    server_state.metrics().record_rejected_connection("banned");
+                    debug!(
+                        agent_socket_address = { format!("{agent_socket_addr}") },
+                        "Drop agent quic connection from banned ip."
+                    );
+                    incoming.ignore();
+                    continue;
+                }
+                // The quic connection is counted by its streams, every stream is
+                // served like one agent tcp connection
+                if let Err(admission_rejection) = server_state
+                    .admission_controller()
+                    .admit_connection(agent_socket_addr.ip(), server_state.config().admission())
+                {
+                    server_state
+                        .metrics()
+                        .record_rejected_connection(admission_rejection.as_str());
+                    warn!(
+                        agent_socket_address = { format!("{agent_socket_addr}") },
+                        "Reject agent quic connection: {admission_rejection:?}"
+                    );
+                    incoming.refuse();
+                    continue;
+                }
+                Self::spawn_quic_connection_task(incoming, agent_socket_addr, server_state.clone());
+            }
+        });
+        Ok(())
+    }
+    fn spawn_quic_connection_task(
+        incoming: Incoming,
+        agent_socket_address: SocketAddr,
+        server_state: ServerState,
+    ) {
+        tokio::spawn(async move {
+            let handshake_timeout = *server_state.config().handshake_timeout();
+            let quic_connection = match timeout(handshake_timeout.init_frame(), incoming).await {
+                Ok(Ok(connection)) => QuicConnection::new(connection),
+                Ok(Err(e)) => {
+                    debug!(
+                        agent_socket_address = { format!("{agent_socket_address}") },
+                        "Fail to accept agent quic connection: {e:?}"
+                    );
+                    return;
+                }
+                Err(_) => {
+                    debug!(
+                        agent_socket_address = { format!("{agent_socket_address}") },
+                        "Fail to accept agent quic connection because of timeout."
+                    );
+                    return;
+                }
+            };
+            let shutdown_coordinator = server_state.shutdown_coordinator().clone();
+            loop {
+                let quic_stream = tokio::select! {
+                    quic_stream = quic_connection.accept_stream() => match quic_stream {
+                        Some(quic_stream) => quic_stream,
+                        None => return,
+                    },
+                    _ = shutdown_coordinator.accept_token().cancelled() => return,
+                };
+                // The address changes when the agent migrates to another network
+                let agent_socket_address = quic_stream.remote_address();
+                let connection_permit = match server_state
+                    .admission_controller()
+                    .admit_stream(server_state.config().admission())
+                {
+                    Ok(connection_permit) => connection_permit,
+                    Err(admission_rejection) => {
+                        server_state
+                            .metrics()
+                            .record_rejected_connection(admission_rejection.as_str());
+                        warn!(
+                            agent_socket_address = { format!("{agent_socket_address}") },
+                            "Reject agent quic stream: {admission_rejection:?}"
+                        );
+                        continue;
+                    }
+                };
+                let server_state = server_state.clone();
+                let accept_token = shutdown_coordinator.accept_token().clone();
+                tokio::spawn(accept_token.run_until_cancelled_owned(async move {
+                    Self::serve_agent_stream(
+                        AgentStream::Quic(quic_stream),
+                        agent_socket_address,
+                        connection_permit,
+                        server_state,
+                    )
+                    .await
+                }));
+            }
         });
     }
     async fn concrete_start_server(server_state: ServerState) -> Result<(), ProxyError> {
@@ -201,28 +680,252 @@ impl ProxyServer {
             server_socket.set_tcp_keepalive(&keepalive)?;
         }
         server_socket.set_linger(None)?;
-        if let Some(read_timeout) = server_state.config().agent_connection_read_timeout() {
-            server_socket.set_read_timeout(Some(Duration::from_secs(*read_timeout)))?;
-        }
-        if let Some(write_timeout) = server_state.config().agent_connection_write_timeout() {
-            server_socket.set_write_timeout(Some(Duration::from_secs(*write_timeout)))?;
-        }
+        Self::start_metrics_listener(&server_state)?;
+        Self::start_usage_flusher(&server_state);
+        Self::start_quic_listener(&server_state)?;
+        let shutdown_coordinator = server_state.shutdown_coordinator().clone();
         loop {
-            let (agent_tcp_stream, agent_socket_addr) = server_listener.accept().await?;
+            let (agent_tcp_stream, agent_socket_addr) = tokio::select! {
+                accept_result = server_listener.accept() => match accept_result {
+                    Ok(accepted) => {
+                        server_state.metrics().record_accepted_connection("tcp", RESULT_SUCCESS);
+                        accepted
+                    }
+                    Err(e) => {
+                        server_state.metrics().record_accepted_connection("tcp", RESULT_FAIL);
+                        return Err(e.into());
+                    }
+                },
+                _ = shutdown_coordinator.accept_token().cancelled() => {
+                    info!("Stop accepting agent connections because of shutdown.");
+                    return Ok(());
+                }
+            };
             debug!(
                 agent_socket_address = { format!("{agent_socket_addr}") },
                 "Accept agent tcp connection."
             );
-            Self::spawn_agent_task(agent_tcp_stream, agent_socket_addr, server_state.clone());
+            if server_state
+                .ban_list()
+                .is_banned(agent_socket_addr.ip(), server_state.config().ban())
+            {
+                server_state.metrics().record_rejected_connection("banned");
+                debug!(
+                    agent_socket_address = { format!("{agent_socket_addr}") },
+                    "Drop agent tcp connection from banned ip."
+                );
+                continue;
+            }
+            let connection_permit = match server_state
+                .admission_controller()
+                .admit_connection(agent_socket_addr.ip(), server_state.config().admission())
+            {
+                Ok(connection_permit) => connection_permit,
+                Err(admission_rejection) => {
+                    server_state
+                        .metrics()
+                        .record_rejected_connection(admission_rejection.as_str());
+                    warn!(
+                        agent_socket_address = { format!("{agent_socket_addr}") },
+                        "Reject agent tcp connection: {admission_rejection:?}"
+                    );
+                    if admission_rejection == AdmissionRejection::ServerBusy {
+                        Self::spawn_server_busy_task(agent_tcp_stream, server_state.clone());
+                    }
+                    continue;
+                }
+            };
+            Self::spawn_agent_task(
+                agent_tcp_stream,
+                agent_socket_addr,
+                connection_permit,
+                server_state.clone(),
+            );
         }
     }
-    pub async fn start(&self) -> Result<JoinHandle<()>, ProxyError> {
+    pub async fn start(&self) -> Result<ProxyServerHandle, ProxyError> {
         let server_state = self.server_state.clone();
-        let handler = tokio::spawn(async move {
+        let accept_task = tokio::spawn(async move {
             if let Err(e) = Self::concrete_start_server(server_state).await {
                 error!("Fail to start server: {e:?}")
             }
         });
-        Ok(handler)
+        Ok(ProxyServerHandle {
+            server_state: self.server_state.clone(),
+            accept_task,
+        })
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ban::BanConfig;
+    use std::path::Path;
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+    use tokio::time::Instant;
+    /// The control framed of the accepted agent connection and the agent side of it
+    async fn agent_control_framed() -> (Framed<AgentStream, ControlPacketCodec>, TcpStream) {
+        let rsa_crypto_holder = ProxyRsaCryptoHolder::new(
+            &Path::new(env!("CARGO_MANIFEST_DIR")).join("../../resources/proxy/rsa"),
+            USER_AGENT_PUBLIC_KEY.to_owned(),
+            USER_PROXY_PRIVATE_KEY.to_owned(),
+        )
+        .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let agent_tcp_stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (proxy_tcp_stream, _) = listener.accept().await.unwrap();
+        let control_framed = Framed::new(
+            AgentStream::Tcp(proxy_tcp_stream),
+            ControlPacketCodec::new(Arc::new(UserRsaCryptoHolder::RsaDir(rsa_crypto_holder))),
+        );
+        (control_framed, agent_tcp_stream)
+    }
+    #[tokio::test(start_paused = true)]
+    async fn wait_first_control_packet_in_first_byte_deadline() {
+        let (mut control_framed, _agent_tcp_stream) = agent_control_framed().await;
+        let handshake_timeout = HandshakeTimeoutConfig::default();
+        let start = Instant::now();
+        let result =
+            ProxyServer::next_agent_control_packet(&mut control_framed, handshake_timeout, false)
+                .await;
+        assert!(matches!(result, Err(ProxyError::AgentFirstByteTimeout)));
+        assert_eq!(start.elapsed(), handshake_timeout.first_byte());
+    }
+    #[tokio::test(start_paused = true)]
+    async fn wait_next_control_packet_in_idle_deadline() {
+        let (mut control_framed, _agent_tcp_stream) = agent_control_framed().await;
+        let handshake_timeout = HandshakeTimeoutConfig::default();
+        let start = Instant::now();
+        let result =
+            ProxyServer::next_agent_control_packet(&mut control_framed, handshake_timeout, true)
+                .await;
+        assert!(matches!(result, Err(ProxyError::AgentFirstByteTimeout)));
+        assert_eq!(start.elapsed(), handshake_timeout.idle());
+    }
+    #[tokio::test(start_paused = true)]
+    async fn wait_full_control_packet_in_init_frame_deadline() {
+        let (mut control_framed, mut agent_tcp_stream) = agent_control_framed().await;
+        agent_tcp_stream.write_all(&[0u8]).await.unwrap();
+        let handshake_timeout = HandshakeTimeoutConfig::default();
+        let start = Instant::now();
+        let result =
+            ProxyServer::next_agent_control_packet(&mut control_framed, handshake_timeout, false)
+                .await;
+        assert!(matches!(result, Err(ProxyError::AgentInitFrameTimeout)));
+        assert_eq!(start.elapsed(), handshake_timeout.init_frame());
+    }
+    /// Start the proxy server with the fallback server, the fallback server replies
+    /// every connection and sends the bytes it received to the receiver
+    async fn start_fallback_proxy_server(
+        config_entries: toml::Table,
+    ) -> (ProxyServerHandle, u16, UnboundedReceiver<Vec<u8>>) {
+        let fallback_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let fallback_address = fallback_listener.local_addr().unwrap();
+        let (fallback_request_sender, fallback_request_receiver) = unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let (mut fallback_tcp_stream, _) = fallback_listener.accept().await.unwrap();
+                let mut request = vec![0u8; 1024];
+                let request_size = fallback_tcp_stream.read(&mut request).await.unwrap();
+                request.truncate(request_size);
+                let _ = fallback_request_sender.send(request);
+                fallback_tcp_stream
+                    .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok")
+                    .await
+                    .unwrap();
+            }
+        });
+        let proxy_port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let mut config = toml::Table::try_from(Config::default()).unwrap();
+        config.insert("port".to_string(), i64::from(proxy_port).into());
+        config.insert(
+            "rsa_dir".to_string(),
+            concat!(env!("CARGO_MANIFEST_DIR"), "/../../resources/proxy/rsa").into(),
+        );
+        config.insert(
+            "fallback_address".to_string(),
+            fallback_address.to_string().into(),
+        );
+        config.extend(config_entries);
+        let config: Config = config.try_into().unwrap();
+        config.validate().unwrap();
+        let proxy_server = ProxyServer::new(Arc::new(config)).unwrap();
+        let proxy_server_handle = proxy_server.start().await.unwrap();
+        (proxy_server_handle, proxy_port, fallback_request_receiver)
+    }
+    /// Send the request to the proxy server and read the response until the connection closed
+    async fn send_to_proxy_server(proxy_port: u16, request: &[u8]) -> Vec<u8> {
+        let mut client_tcp_stream = timeout(Duration::from_secs(10), async {
+            loop {
+                match TcpStream::connect(("127.0.0.1", proxy_port)).await {
+                    Ok(client_tcp_stream) => return client_tcp_stream,
+                    Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+                }
+            }
+        })
+        .await
+        .unwrap();
+        client_tcp_stream.write_all(request).await.unwrap();
+        let mut response = Vec::new();
+        timeout(
+            Duration::from_secs(30),
+            client_tcp_stream.read_to_end(&mut response),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        response
+    }
+    #[tokio::test]
+    async fn relay_unrecognized_connection_to_fallback_address() {
+        let (proxy_server_handle, proxy_port, mut fallback_request_receiver) =
+            start_fallback_proxy_server(toml::Table::new()).await;
+        let response =
+            send_to_proxy_server(proxy_port, b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert_eq!(response, b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok");
+        let request = fallback_request_receiver.recv().await.unwrap();
+        assert!(request.starts_with(b"GET / HTTP/1.1\r\n"));
+        proxy_server_handle.shutdown().await;
+    }
+    #[tokio::test]
+    async fn relay_probe_with_valid_first_byte_to_fallback_address() {
+        let (proxy_server_handle, proxy_port, mut fallback_request_receiver) =
+            start_fallback_proxy_server(toml::Table::new()).await;
+        let probe = b"\x01\x00\x10\x00\x00garbage";
+        let start = Instant::now();
+        let response = send_to_proxy_server(proxy_port, probe).await;
+        // Relayed without waiting for the rest of the frame until the init frame timeout
+        assert!(start.elapsed() < HandshakeTimeoutConfig::default().init_frame());
+        assert_eq!(response, b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok");
+        assert_eq!(fallback_request_receiver.recv().await.unwrap(), probe);
+        proxy_server_handle.shutdown().await;
+    }
+    #[tokio::test]
+    async fn never_ban_connections_handed_to_fallback_address() {
+        let mut config_entries = toml::Table::new();
+        config_entries.insert(
+            "ban".to_string(),
+            toml::Table::try_from(BanConfig {
+                max_failures: Some(1),
+                ..BanConfig::default()
+            })
+            .unwrap()
+            .into(),
+        );
+        let (proxy_server_handle, proxy_port, _fallback_request_receiver) =
+            start_fallback_proxy_server(config_entries).await;
+        for _ in 0..3 {
+            let response =
+                send_to_proxy_server(proxy_port, b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                    .await;
+            assert_eq!(response, b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok");
+        }
+        proxy_server_handle.shutdown().await;
     }
 }
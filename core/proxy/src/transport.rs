@@ -0,0 +1,160 @@
+use crate::error::ProxyError;
+use ppaass_common::quic::{QuicDatagrams, QuicStream, QUIC_ALPN};
+use ppaass_common::tls::{crypto_provider, load_certificates, load_private_key};
+use ppaass_common::websocket::WebSocketTransport;
+use quinn::crypto::rustls::QuicServerConfig;
+use quinn::{TransportConfig, VarInt};
+use rustls::ServerConfig;
+use serde::{Deserialize, Serialize};
+use std::io::Error;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+/// The max concurrent tunnels of one quic connection
+const QUIC_MAX_CONCURRENT_STREAMS: u32 = 1024;
+/// The tls of the agent connections, the agent connections are plain tcp when not given
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TlsConfig {
+    /// The pem file of the certificate chain
+    pub cert_file: PathBuf,
+    /// The pem file of the private key
+    pub key_file: PathBuf,
+}
+impl TlsConfig {
+    /// Build the acceptor with the certificate and the private key
+    pub fn build_acceptor(&self) -> Result<TlsAcceptor, ProxyError> {
+        Ok(TlsAcceptor::from(Arc::new(build_tls_server_config(
+            &self.cert_file,
+            &self.key_file,
+        )?)))
+    }
+}
+/// Accept the agent connections on quic as well, the agents select
+/// the quic transport with the `quic://` entries of the proxy addresses
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuicConfig {
+    /// The udp port to listen on
+    pub port: u16,
+    /// The pem file of the certificate chain
+    pub cert_file: PathBuf,
+    /// The pem file of the private key
+    pub key_file: PathBuf,
+}
+impl QuicConfig {
+    /// Build the quic server with the certificate and the private key,
+    /// the agents keep their connections when they migrate to another network
+    pub fn build_server_config(&self) -> Result<quinn::ServerConfig, ProxyError> {
+        let mut tls_server_config = build_tls_server_config(&self.cert_file, &self.key_file)?;
+        tls_server_config.alpn_protocols = vec![QUIC_ALPN.to_vec()];
+        let quic_server_config = QuicServerConfig::try_from(tls_server_config)
+            .map_err(|e| ProxyError::InvalidConfig(format!("invalid quic tls config: {e}")))?;
+        let mut transport_config = TransportConfig::default();
+        transport_config.max_concurrent_bidi_streams(VarInt::from_u32(QUIC_MAX_CONCURRENT_STREAMS));
+        let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(quic_server_config));
+        server_config
+            .transport_config(Arc::new(transport_config))
+            .migration(true);
+        Ok(server_config)
+    }
+}
+fn build_tls_server_config(cert_file: &Path, key_file: &Path) -> Result<ServerConfig, ProxyError> {
+    let certificates = load_certificates(cert_file)?;
+    let private_key = load_private_key(key_file)?;
+    Ok(ServerConfig::builder_with_provider(crypto_provider())
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certificates, private_key)?)
+}
+/// Accept the agent connections as websocket, so the proxy can sit behind
+/// the http gateways and the reverse proxies
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WebSocketConfig {
+    /// The path to accept the upgrade requests, the other paths are answered with not found
+    pub path: String,
+}
+/// The transport of the agent connection
+#[derive(Debug)]
+pub enum AgentStream {
+    Tcp(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+    WebSocket(Box<WebSocketTransport<AgentStream>>),
+    Quic(QuicStream),
+}
+impl AgentStream {
+    /// The address of the agent, it is the address of the reverse proxy when the proxy behind it
+    pub fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        match self {
+            AgentStream::Tcp(tcp_stream) => tcp_stream.peer_addr(),
+            AgentStream::Tls(tls_stream) => tls_stream.get_ref().0.peer_addr(),
+            AgentStream::WebSocket(websocket_transport) => {
+                websocket_transport.get_ref().peer_addr()
+            }
+            AgentStream::Quic(quic_stream) => Ok(quic_stream.remote_address()),
+        }
+    }
+    /// The datagrams of the quic stream for the udp tunnel, `None` on the other transports
+    pub fn take_quic_datagrams(&mut self) -> Option<QuicDatagrams> {
+        match self {
+            AgentStream::Quic(quic_stream) => quic_stream.take_datagrams(),
+            _ => None,
+        }
+    }
+}
+impl AsyncRead for AgentStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            AgentStream::Tcp(tcp_stream) => Pin::new(tcp_stream).poll_read(cx, buf),
+            AgentStream::Tls(tls_stream) => Pin::new(tls_stream).poll_read(cx, buf),
+            AgentStream::WebSocket(websocket_transport) => {
+                Pin::new(websocket_transport).poll_read(cx, buf)
+            }
+            AgentStream::Quic(quic_stream) => Pin::new(quic_stream).poll_read(cx, buf),
+        }
+    }
+}
+impl AsyncWrite for AgentStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Error>> {
+        match self.get_mut() {
+            AgentStream::Tcp(tcp_stream) => Pin::new(tcp_stream).poll_write(cx, buf),
+            AgentStream::Tls(tls_stream) => Pin::new(tls_stream).poll_write(cx, buf),
+            AgentStream::WebSocket(websocket_transport) => {
+                Pin::new(websocket_transport).poll_write(cx, buf)
+            }
+            AgentStream::Quic(quic_stream) => Pin::new(quic_stream).poll_write(cx, buf),
+        }
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        match self.get_mut() {
+            AgentStream::Tcp(tcp_stream) => Pin::new(tcp_stream).poll_flush(cx),
+            AgentStream::Tls(tls_stream) => Pin::new(tls_stream).poll_flush(cx),
+            AgentStream::WebSocket(websocket_transport) => {
+                Pin::new(websocket_transport).poll_flush(cx)
+            }
+            AgentStream::Quic(quic_stream) => Pin::new(quic_stream).poll_flush(cx),
+        }
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        match self.get_mut() {
+            AgentStream::Tcp(tcp_stream) => Pin::new(tcp_stream).poll_shutdown(cx),
+            AgentStream::Tls(tls_stream) => Pin::new(tls_stream).poll_shutdown(cx),
+            AgentStream::WebSocket(websocket_transport) => {
+                Pin::new(websocket_transport).poll_shutdown(cx)
+            }
+            AgentStream::Quic(quic_stream) => Pin::new(quic_stream).poll_shutdown(cx),
+        }
+    }
+}
@@ -0,0 +1,377 @@
+use crate::command::{UsageArgs, UsageFormat, UsagePeriod};
+use crate::config::Config;
+use crate::error::ProxyError;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{rename, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tracing::warn;
+const DATE_FORMAT: &str = "%Y-%m-%d";
+/// The length of `YYYY-MM` prefix of the date
+const MONTH_LEN: usize = 7;
+/// The traffic quota of a user in bytes of both directions, `None` means no limit
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuotaLimit {
+    pub daily_bytes: Option<u64>,
+    pub monthly_bytes: Option<u64>,
+}
+/// The quota of the users, the users not listed use `default_user`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuotaConfig {
+    #[serde(default)]
+    pub default_user: QuotaLimit,
+    #[serde(default)]
+    pub users: HashMap<String, QuotaLimit>,
+}
+impl QuotaConfig {
+    pub fn user_limit(&self, user: &str) -> &QuotaLimit {
+        self.users.get(user).unwrap_or(&self.default_user)
+    }
+}
+/// The bytes relayed for a user in a period, the period is
+/// the UTC date `YYYY-MM-DD` or the month `YYYY-MM`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UsageRecord {
+    pub user: String,
+    pub period: String,
+    pub upload_bytes: u64,
+    pub download_bytes: u64,
+}
+impl UsageRecord {
+    pub fn total_bytes(&self) -> u64 {
+        self.upload_bytes + self.download_bytes
+    }
+}
+/// The bytes relayed by the tunnels of a user since last flush
+#[derive(Debug, Default)]
+pub struct UserTrafficCounter {
+    upload_bytes: AtomicU64,
+    download_bytes: AtomicU64,
+}
+impl UserTrafficCounter {
+    pub fn add_upload_bytes(&self, bytes: usize) {
+        self.upload_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+    pub fn add_download_bytes(&self, bytes: usize) {
+        self.download_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+    fn pending(&self) -> u64 {
+        self.upload_bytes.load(Ordering::Relaxed) + self.download_bytes.load(Ordering::Relaxed)
+    }
+    fn take(&self) -> (u64, u64) {
+        (
+            self.upload_bytes.swap(0, Ordering::Relaxed),
+            self.download_bytes.swap(0, Ordering::Relaxed),
+        )
+    }
+}
+type DailyUsage = BTreeMap<(String, String), (u64, u64)>;
+#[derive(Default)]
+struct UsageTables {
+    /// The usage of all the days, keyed by user and date
+    daily: DailyUsage,
+    /// The usage flushed into memory but not appended to the usage file yet
+    unpersisted: DailyUsage,
+}
+/// Account the traffic of the users by day. The counters are flushed periodically,
+/// the flushed usage is appended to the usage file as json lines when it is given.
+pub struct UsageStore {
+    usage_file_path: Option<PathBuf>,
+    counters: Mutex<HashMap<String, Arc<UserTrafficCounter>>>,
+    tables: Mutex<UsageTables>,
+}
+impl UsageStore {
+    /// Load the usage file and compact it into one line per user and day
+    pub fn open(usage_file_path: Option<PathBuf>) -> Result<Self, ProxyError> {
+        let mut tables = UsageTables::default();
+        if let Some(usage_file_path) = &usage_file_path {
+            tables.daily = read_daily_usage(usage_file_path)?;
+            write_usage_file(usage_file_path, &tables.daily)?;
+        }
+        Ok(Self {
+            usage_file_path,
+            counters: Mutex::new(HashMap::new()),
+            tables: Mutex::new(tables),
+        })
+    }
+    /// The counter shared by all the tunnels of the user
+    pub fn user_counter(&self, user: &str) -> Arc<UserTrafficCounter> {
+        self.counters
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .entry(user.to_owned())
+            .or_default()
+            .clone()
+    }
+    /// Move the counters into today's usage and append them to the usage file,
+    /// the usage failed to append is retried by the next flush
+    pub fn flush(&self) -> Result<(), ProxyError> {
+        let today = Utc::now().format(DATE_FORMAT).to_string();
+        let mut tables = self
+            .tables
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let tables = &mut *tables;
+        self.counters
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .iter()
+            .for_each(|(user, counter)| {
+                let (upload_bytes, download_bytes) = counter.take();
+                if upload_bytes == 0 && download_bytes == 0 {
+                    return;
+                }
+                let key = (user.clone(), today.clone());
+                for table in [&mut tables.daily, &mut tables.unpersisted] {
+                    let usage = table.entry(key.clone()).or_default();
+                    usage.0 += upload_bytes;
+                    usage.1 += download_bytes;
+                }
+            });
+        let Some(usage_file_path) = &self.usage_file_path else {
+            tables.unpersisted.clear();
+            return Ok(());
+        };
+        if tables.unpersisted.is_empty() {
+            return Ok(());
+        }
+        append_usage_file(usage_file_path, &tables.unpersisted)?;
+        tables.unpersisted.clear();
+        Ok(())
+    }
+    /// Check the usage of today and this month including the bytes not flushed yet
+    pub fn check_quota(&self, user: &str, quota_limit: &QuotaLimit) -> Result<(), String> {
+        if quota_limit.daily_bytes.is_none() && quota_limit.monthly_bytes.is_none() {
+            return Ok(());
+        }
+        let today = Utc::now().format(DATE_FORMAT).to_string();
+        let pending = self.user_counter(user).pending();
+        let tables = self
+            .tables
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(daily_bytes) = quota_limit.daily_bytes {
+            let used = tables
+                .daily
+                .get(&(user.to_owned(), today.clone()))
+                .map(|(upload, download)| upload + download)
+                .unwrap_or_default()
+                + pending;
+            if used >= daily_bytes {
+                return Err(format!(
+                    "daily quota {daily_bytes} bytes used up, used {used} bytes on {today}"
+                ));
+            }
+        }
+        if let Some(monthly_bytes) = quota_limit.monthly_bytes {
+            let month = &today[..MONTH_LEN];
+            let used = tables
+                .daily
+                .range((user.to_owned(), month.to_owned())..)
+                .take_while(|((usage_user, date), _)| usage_user == user && date.starts_with(month))
+                .map(|(_, (upload, download))| upload + download)
+                .sum::<u64>()
+                + pending;
+            if used >= monthly_bytes {
+                return Err(format!(
+                    "monthly quota {monthly_bytes} bytes used up, used {used} bytes in {month}"
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+fn parse_usage_line(line: &str) -> Option<UsageRecord> {
+    serde_json::from_str::<UsageRecord>(line).ok()
+}
+/// Read the usage file into the usage by user and date, the broken lines
+/// such as the last line written partially on crash are skipped
+fn read_daily_usage(usage_file_path: &Path) -> Result<DailyUsage, ProxyError> {
+    let usage_file = match File::open(usage_file_path) {
+        Ok(usage_file) => usage_file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(DailyUsage::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut daily = DailyUsage::new();
+    for line in BufReader::new(usage_file).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let Some(usage_record) = parse_usage_line(&line) else {
+            warn!("Skip the broken line of usage file [{usage_file_path:?}]: {line}");
+            continue;
+        };
+        let usage = daily
+            .entry((usage_record.user, usage_record.period))
+            .or_default();
+        usage.0 += usage_record.upload_bytes;
+        usage.1 += usage_record.download_bytes;
+    }
+    Ok(daily)
+}
+fn write_usage_lines(writer: &mut impl Write, usage: &DailyUsage) -> Result<(), ProxyError> {
+    for ((user, date), (upload_bytes, download_bytes)) in usage {
+        let usage_record = UsageRecord {
+            user: user.clone(),
+            period: date.clone(),
+            upload_bytes: *upload_bytes,
+            download_bytes: *download_bytes,
+        };
+        serde_json::to_writer(&mut *writer, &usage_record)?;
+        writer.write_all(b"\n")?;
+    }
+    Ok(())
+}
+/// Replace the usage file with a compacted one atomically
+fn write_usage_file(usage_file_path: &Path, daily: &DailyUsage) -> Result<(), ProxyError> {
+    let mut compacted_file_path = usage_file_path.as_os_str().to_owned();
+    compacted_file_path.push(".compact");
+    let compacted_file_path = PathBuf::from(compacted_file_path);
+    let mut writer = BufWriter::new(File::create(&compacted_file_path)?);
+    write_usage_lines(&mut writer, daily)?;
+    writer
+        .into_inner()
+        .map_err(|e| e.into_error())?
+        .sync_all()?;
+    rename(&compacted_file_path, usage_file_path)?;
+    Ok(())
+}
+fn append_usage_file(usage_file_path: &Path, usage: &DailyUsage) -> Result<(), ProxyError> {
+    let usage_file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(usage_file_path)?;
+    let mut writer = BufWriter::new(usage_file);
+    write_usage_lines(&mut writer, usage)?;
+    writer
+        .into_inner()
+        .map_err(|e| e.into_error())?
+        .sync_data()?;
+    Ok(())
+}
+/// Read the usage file and summarize it by day or by month
+pub fn read_usage_records(
+    usage_file_path: &Path,
+    monthly: bool,
+) -> Result<Vec<UsageRecord>, ProxyError> {
+    let mut summary = DailyUsage::new();
+    for ((user, date), (upload_bytes, download_bytes)) in read_daily_usage(usage_file_path)? {
+        let period = if monthly {
+            date[..MONTH_LEN.min(date.len())].to_owned()
+        } else {
+            date
+        };
+        let usage = summary.entry((user, period)).or_default();
+        usage.0 += upload_bytes;
+        usage.1 += download_bytes;
+    }
+    Ok(summary
+        .into_iter()
+        .map(
+            |((user, period), (upload_bytes, download_bytes))| UsageRecord {
+                user,
+                period,
+                upload_bytes,
+                download_bytes,
+            },
+        )
+        .collect())
+}
+/// Print the usage persisted in the `usage_file`, the usage not flushed by the running proxy is not included
+pub fn run_usage_export(usage_args: UsageArgs, config_file_path: &Path) -> Result<(), ProxyError> {
+    let config = Config::load(config_file_path)?;
+    let usage_file_path = config
+        .usage_file()
+        .as_ref()
+        .ok_or(ProxyError::InvalidConfig(
+            "usage_file not given".to_string(),
+        ))?;
+    let monthly = matches!(usage_args.period, UsagePeriod::Monthly);
+    let usage_records = read_usage_records(usage_file_path, monthly)?
+        .into_iter()
+        .filter(|usage_record| {
+            usage_args
+                .user
+                .as_ref()
+                .is_none_or(|user| *user == usage_record.user)
+        })
+        .collect::<Vec<_>>();
+    let mut stdout = std::io::stdout().lock();
+    match usage_args.format {
+        UsageFormat::Json => {
+            serde_json::to_writer_pretty(&mut stdout, &usage_records)?;
+            writeln!(stdout)?;
+        }
+        UsageFormat::Csv => {
+            writeln!(
+                stdout,
+                "user,period,upload_bytes,download_bytes,total_bytes"
+            )?;
+            for usage_record in &usage_records {
+                writeln!(
+                    stdout,
+                    "{},{},{},{},{}",
+                    csv_field(&usage_record.user),
+                    usage_record.period,
+                    usage_record.upload_bytes,
+                    usage_record.download_bytes,
+                    usage_record.total_bytes()
+                )?;
+            }
+        }
+    }
+    Ok(())
+}
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn flush_usage_and_check_quota() {
+        let usage_file_path =
+            std::env::temp_dir().join(format!("ppaass-usage-test-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&usage_file_path);
+        let usage_store = UsageStore::open(Some(usage_file_path.clone())).unwrap();
+        let quota_limit = QuotaLimit {
+            daily_bytes: None,
+            monthly_bytes: Some(1000),
+        };
+        let user_counter = usage_store.user_counter("user1");
+        user_counter.add_upload_bytes(300);
+        user_counter.add_download_bytes(400);
+        assert!(usage_store.check_quota("user1", &quota_limit).is_ok());
+        usage_store.flush().unwrap();
+        user_counter.add_download_bytes(300);
+        assert!(usage_store.check_quota("user1", &quota_limit).is_err());
+        assert!(usage_store.check_quota("user2", &quota_limit).is_ok());
+        usage_store.flush().unwrap();
+        // The usage survives restart and the two appended lines are compacted into one
+        drop(usage_store);
+        let usage_store = UsageStore::open(Some(usage_file_path.clone())).unwrap();
+        assert!(usage_store.check_quota("user1", &quota_limit).is_err());
+        let usage_records = read_usage_records(&usage_file_path, true).unwrap();
+        assert_eq!(usage_records.len(), 1);
+        assert_eq!(usage_records[0].upload_bytes, 300);
+        assert_eq!(usage_records[0].download_bytes, 700);
+        assert_eq!(
+            std::fs::read_to_string(&usage_file_path)
+                .unwrap()
+                .lines()
+                .count(),
+            1
+        );
+        std::fs::remove_file(&usage_file_path).unwrap();
+    }
+}
@@ -0,0 +1,259 @@
+use crate::acl::{AclRuleSet, AclTunnelType};
+use crate::error::ProxyError;
+use crate::rate_limit::BandwidthLimit;
+use crate::usage::QuotaLimit;
+use arc_swap::ArcSwap;
+use chrono::{DateTime, Utc};
+use ppaass_codec::error::CodecError;
+use ppaass_codec::{RsaCryptoHolder, RsaCryptoReloadReport};
+use ppaass_crypto::error::CryptoError;
+use ppaass_crypto::rsa::RsaCrypto;
+use ppaass_domain::tunnel::TunnelType;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs::{read_to_string, File};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::{error, warn};
+/// The account of a user in the users file, the key paths are
+/// relative to the directory of the users file.
+/// The limits not given fall back to the proxy configuration.
+#[derive(Debug, Clone, Deserialize)]
+pub struct UserAccount {
+    public_key_file: PathBuf,
+    private_key_file: PathBuf,
+    #[serde(default = "default_enabled")]
+    enabled: bool,
+    /// The expiry time in RFC 3339, for example `2027-01-01T00:00:00Z`
+    expires_at: Option<DateTime<Utc>>,
+    /// The allowed tunnel types, empty means all the tunnel types
+    #[serde(default)]
+    tunnel_types: Vec<AclTunnelType>,
+    max_tunnels: Option<usize>,
+    bandwidth: Option<BandwidthLimit>,
+    quota: Option<QuotaLimit>,
+    /// The egress policy of the user, it replaces the user rules in the acl file
+    acl: Option<AclRuleSet>,
+}
+fn default_enabled() -> bool {
+    true
+}
+impl UserAccount {
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at <= Utc::now())
+    }
+    pub fn max_tunnels(&self) -> Option<usize> {
+        self.max_tunnels
+    }
+    pub fn bandwidth(&self) -> Option<&BandwidthLimit> {
+        self.bandwidth.as_ref()
+    }
+    pub fn quota(&self) -> Option<&QuotaLimit> {
+        self.quota.as_ref()
+    }
+    pub fn acl(&self) -> Option<&AclRuleSet> {
+        self.acl.as_ref()
+    }
+    /// Check whether the tunnel type is allowed for the user
+    pub fn allow_tunnel_type(&self, tunnel_type: &TunnelType) -> bool {
+        self.tunnel_types.is_empty() || self.tunnel_types.contains(&tunnel_type.into())
+    }
+}
+#[derive(Debug, Deserialize)]
+struct UsersFile {
+    #[serde(default)]
+    users: HashMap<String, UserAccount>,
+}
+struct UserEntry {
+    account: Arc<UserAccount>,
+    rsa_crypto: Arc<RsaCrypto>,
+}
+/// Hold the rsa crypto and the account of the users listed in the users file,
+/// the disabled users are not loaded and the expired users are refused.
+pub struct UsersFileRsaCryptoHolder {
+    users_file_path: PathBuf,
+    cache: ArcSwap<HashMap<String, Arc<UserEntry>>>,
+}
+impl UsersFileRsaCryptoHolder {
+    pub fn new(users_file_path: &Path) -> Result<Self, ProxyError> {
+        let cache = Self::load_users_file(users_file_path, &HashMap::new())?;
+        Ok(Self {
+            users_file_path: users_file_path.to_path_buf(),
+            cache: ArcSwap::from_pointee(cache),
+        })
+    }
+    /// The users file which the users loaded from
+    pub fn users_file_path(&self) -> &Path {
+        &self.users_file_path
+    }
+    /// The enabled users which are not expired
+    pub fn users(&self) -> Vec<String> {
+        let mut users = self
+            .cache
+            .load()
+            .iter()
+            .filter(|(_, user_entry)| !user_entry.account.is_expired())
+            .map(|(user_token, _)| user_token.clone())
+            .collect::<Vec<String>>();
+        users.sort();
+        users
+    }
+    /// The account of the enabled user
+    pub fn user_account(&self, user_token: &str) -> Option<Arc<UserAccount>> {
+        self.cache
+            .load()
+            .get(user_token)
+            .map(|user_entry| user_entry.account.clone())
+    }
+    /// Load the users file again, the removed, disabled and expired users are revoked
+    pub fn reload(&self) -> Result<RsaCryptoReloadReport, ProxyError> {
+        let cache = Self::load_users_file(&self.users_file_path, &self.cache.load())?;
+        let previous_cache = self.cache.swap(Arc::new(cache));
+        Ok(RsaCryptoReloadReport::new(
+            &Self::active_users(&previous_cache),
+            &Self::active_users(&self.cache.load()),
+        ))
+    }
+    fn active_users(cache: &HashMap<String, Arc<UserEntry>>) -> HashMap<String, ()> {
+        cache
+            .iter()
+            .filter(|(_, user_entry)| !user_entry.account.is_expired())
+            .map(|(user_token, _)| (user_token.clone(), ()))
+            .collect()
+    }
+    /// Load the users file, the listed users whose key files fail to load keep the
+    /// previous rsa crypto, the key files may be in the middle of writing
+    fn load_users_file(
+        users_file_path: &Path,
+        previous_cache: &HashMap<String, Arc<UserEntry>>,
+    ) -> Result<HashMap<String, Arc<UserEntry>>, ProxyError> {
+        let users_file_content = read_to_string(users_file_path)?;
+        let users_file = toml::from_str::<UsersFile>(&users_file_content)
+            .map_err(|e| ProxyError::InvalidUsersFile(e.to_string()))?;
+        let users_dir_path = users_file_path.parent().unwrap_or(Path::new("."));
+        let mut cache = HashMap::new();
+        for (user_token, account) in users_file.users {
+            if !account.enabled {
+                warn!("Skip disabled user: {user_token}");
+                continue;
+            }
+            let rsa_crypto = match Self::load_user_rsa_crypto(users_dir_path, &account) {
+                Ok(rsa_crypto) => Arc::new(rsa_crypto),
+                Err(e) => {
+                    let Some(previous_user_entry) = previous_cache.get(&user_token) else {
+                        error!("Fail to create rsa crypto for user [{user_token}]: {e:?}");
+                        continue;
+                    };
+                    error!("Fail to create rsa crypto for user [{user_token}], keep the previous one: {e:?}");
+                    previous_user_entry.rsa_crypto.clone()
+                }
+            };
+            cache.insert(
+                user_token,
+                Arc::new(UserEntry {
+                    account: Arc::new(account),
+                    rsa_crypto,
+                }),
+            );
+        }
+        Ok(cache)
+    }
+    fn load_user_rsa_crypto(
+        users_dir_path: &Path,
+        account: &UserAccount,
+    ) -> Result<RsaCrypto, CryptoError> {
+        let public_key_file = File::open(users_dir_path.join(&account.public_key_file))?;
+        let private_key_file = File::open(users_dir_path.join(&account.private_key_file))?;
+        RsaCrypto::new(public_key_file, private_key_file)
+    }
+}
+impl RsaCryptoHolder for UsersFileRsaCryptoHolder {
+    fn get_rsa_crypto(
+        &self,
+        auth_token: impl AsRef<str>,
+    ) -> Result<Option<Arc<RsaCrypto>>, CodecError> {
+        match self.cache.load().get(auth_token.as_ref()) {
+            Some(user_entry) if !user_entry.account.is_expired() => {
+                Ok(Some(user_entry.rsa_crypto.clone()))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::write;
+    #[test]
+    fn load_users_file() {
+        let rsa_dir_path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../../resources/proxy/rsa");
+        let users_file_path =
+            std::env::temp_dir().join(format!("ppaass-users-test-{}.toml", std::process::id()));
+        let users_file_content = format!(
+            r#"
+[users.user1]
+public_key_file = "{rsa_dir}/user1/AgentPublicKey.pem"
+private_key_file = "{rsa_dir}/user1/ProxyPrivateKey.pem"
+tunnel_types = ["tcp"]
+max_tunnels = 2
+[users.user1.quota]
+daily_bytes = 1024
+[users.user2]
+public_key_file = "{rsa_dir}/user1/AgentPublicKey.pem"
+private_key_file = "{rsa_dir}/user1/ProxyPrivateKey.pem"
+expires_at = "2000-01-01T00:00:00Z"
+[users.user3]
+public_key_file = "{rsa_dir}/user1/AgentPublicKey.pem"
+private_key_file = "{rsa_dir}/user1/ProxyPrivateKey.pem"
+enabled = false
+"#,
+            rsa_dir = rsa_dir_path.display()
+        );
+        write(&users_file_path, users_file_content).unwrap();
+        let holder = UsersFileRsaCryptoHolder::new(&users_file_path).unwrap();
+        std::fs::remove_file(&users_file_path).unwrap();
+        assert_eq!(holder.users(), vec!["user1".to_string()]);
+        assert!(holder.get_rsa_crypto("user1").unwrap().is_some());
+        assert!(holder.get_rsa_crypto("user2").unwrap().is_none());
+        assert!(holder.get_rsa_crypto("user3").unwrap().is_none());
+        let account = holder.user_account("user1").unwrap();
+        assert!(account.allow_tunnel_type(&TunnelType::Tcp { keepalive: true }));
+        assert!(!account.allow_tunnel_type(&TunnelType::Udp));
+        assert_eq!(account.max_tunnels(), Some(2));
+        assert_eq!(account.quota().unwrap().daily_bytes, Some(1024));
+    }
+    #[test]
+    fn keep_user_with_broken_key_on_reload() {
+        let rsa_dir_path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../../resources/proxy/rsa");
+        let users_file_path = std::env::temp_dir().join(format!(
+            "ppaass-users-reload-test-{}.toml",
+            std::process::id()
+        ));
+        let write_users_file = |private_key_file_name: Option<&str>| {
+            let users_file_content = match private_key_file_name {
+                None => "[users]".to_string(),
+                Some(private_key_file_name) => format!(
+                    r#"
+[users.user1]
+public_key_file = "{rsa_dir}/user1/AgentPublicKey.pem"
+private_key_file = "{rsa_dir}/user1/{private_key_file_name}"
+"#,
+                    rsa_dir = rsa_dir_path.display()
+                ),
+            };
+            write(&users_file_path, users_file_content).unwrap();
+        };
+        write_users_file(Some("ProxyPrivateKey.pem"));
+        let holder = UsersFileRsaCryptoHolder::new(&users_file_path).unwrap();
+        write_users_file(Some("NotExistPrivateKey.pem"));
+        let reload_report = holder.reload().unwrap();
+        assert!(reload_report.removed_users.is_empty());
+        assert!(holder.get_rsa_crypto("user1").unwrap().is_some());
+        write_users_file(None);
+        let reload_report = holder.reload().unwrap();
+        std::fs::remove_file(&users_file_path).unwrap();
+        assert_eq!(reload_report.removed_users, vec!["user1".to_string()]);
+        assert!(holder.get_rsa_crypto("user1").unwrap().is_none());
+    }
+}